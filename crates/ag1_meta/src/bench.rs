@@ -0,0 +1,359 @@
+//! Round-trip benchmark of the bus path to an agent: how long messages take
+//! to be answered, how many are answered per second, and how much of each
+//! round trip is spent sending versus waiting for the reply.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use bus::{Bus, Envelope, EnvelopeKind};
+use serde_json::json;
+
+use crate::{delegate_envelope, wait_replies, WaitMode};
+
+/// Sender name on benchmark envelopes.
+pub const BENCH_AGENT_NAME: &str = "ag1-bench";
+
+/// What [`run_bench`] sends.
+#[derive(Debug, Clone)]
+pub struct BenchConfig {
+    /// Messages to send
+    pub count: usize,
+    /// Most messages awaiting a reply at once
+    pub concurrency: usize,
+    /// Length of each message's text
+    pub payload_bytes: usize,
+    /// How long each message waits for its reply
+    pub timeout_ms: u64,
+}
+
+impl Default for BenchConfig {
+    fn default() -> Self {
+        BenchConfig { count: 100, concurrency: 4, payload_bytes: 64, timeout_ms: 10_000 }
+    }
+}
+
+/// How one benchmark message went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleOutcome {
+    Ok,
+    /// Not sent, or answered with an `error` envelope
+    Error,
+    Timeout,
+}
+
+impl SampleOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SampleOutcome::Ok => "ok",
+            SampleOutcome::Error => "error",
+            SampleOutcome::Timeout => "timeout",
+        }
+    }
+}
+
+/// One benchmark message's timings.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchSample {
+    pub outcome: SampleOutcome,
+    /// Spent in [`Bus::send`]
+    pub send: Duration,
+    /// From the send returning to the reply, timeout or failure
+    pub wait: Duration,
+}
+
+impl BenchSample {
+    pub fn round_trip(&self) -> Duration {
+        self.send + self.wait
+    }
+}
+
+/// Statistics over a benchmark's samples. Latencies and the send/wait split
+/// only count [`SampleOutcome::Ok`] samples.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchReport {
+    /// In the order the messages were sent
+    pub samples: Vec<BenchSample>,
+    /// Wall time of the whole run
+    pub elapsed: Duration,
+    pub ok: usize,
+    pub errors: usize,
+    pub timeouts: usize,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+    /// Ok replies per second of `elapsed`
+    pub throughput: f64,
+    pub mean_send: Duration,
+    pub mean_wait: Duration,
+}
+
+/// The nearest-rank `p`th percentile of `sorted`, ascending; zero when empty.
+pub fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn mean(durations: impl Iterator<Item = Duration>) -> Duration {
+    let (sum, n) = durations.fold((Duration::ZERO, 0u32), |(sum, n), d| (sum + d, n + 1));
+    if n == 0 { Duration::ZERO } else { sum / n }
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+impl BenchReport {
+    pub fn from_samples(samples: Vec<BenchSample>, elapsed: Duration) -> Self {
+        let count = |outcome| samples.iter().filter(|s| s.outcome == outcome).count();
+        let (ok, errors, timeouts) = (count(SampleOutcome::Ok), count(SampleOutcome::Error), count(SampleOutcome::Timeout));
+        let oks = || samples.iter().filter(|s| s.outcome == SampleOutcome::Ok);
+        let mut latencies: Vec<Duration> = oks().map(BenchSample::round_trip).collect();
+        latencies.sort();
+        let secs = elapsed.as_secs_f64();
+        BenchReport {
+            ok,
+            errors,
+            timeouts,
+            p50: percentile(&latencies, 50.0),
+            p90: percentile(&latencies, 90.0),
+            p99: percentile(&latencies, 99.0),
+            max: latencies.last().copied().unwrap_or_default(),
+            throughput: if secs > 0.0 { ok as f64 / secs } else { 0.0 },
+            mean_send: mean(oks().map(|s| s.send)),
+            mean_wait: mean(oks().map(|s| s.wait)),
+            elapsed,
+            samples,
+        }
+    }
+
+    /// One row per sample, in milliseconds:
+    /// `index,outcome,send_ms,wait_ms,round_trip_ms`.
+    pub fn to_csv(&self) -> String {
+        let mut out = String::from("index,outcome,send_ms,wait_ms,round_trip_ms\n");
+        for (i, s) in self.samples.iter().enumerate() {
+            out.push_str(&format!(
+                "{},{},{:.3},{:.3},{:.3}\n",
+                i,
+                s.outcome.as_str(),
+                millis(s.send),
+                millis(s.wait),
+                millis(s.round_trip()),
+            ));
+        }
+        out
+    }
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} messages in {:.2}s: {} ok, {} errors, {} timeouts",
+            self.samples.len(),
+            self.elapsed.as_secs_f64(),
+            self.ok,
+            self.errors,
+            self.timeouts,
+        )?;
+        writeln!(
+            f,
+            "latency  p50={:.1}ms  p90={:.1}ms  p99={:.1}ms  max={:.1}ms",
+            millis(self.p50),
+            millis(self.p90),
+            millis(self.p99),
+            millis(self.max),
+        )?;
+        writeln!(f, "throughput  {:.1} msg/s", self.throughput)?;
+        write!(f, "mean send={:.1}ms  wait={:.1}ms", millis(self.mean_send), millis(self.mean_wait))
+    }
+}
+
+/// A message sent and not yet answered.
+struct InFlight {
+    index: usize,
+    cid: String,
+    send: Duration,
+    sent: Instant,
+}
+
+/// Send `cfg.count` messages to `inbox`, at most `cfg.concurrency` of them
+/// awaiting a reply at once, and time each one's round trip.
+///
+/// Replies are collected from `reply_stream` by [`wait_replies`], as with
+/// [`crate::delegate_many`]. Each message's text is `cfg.payload_bytes`
+/// long, so an echo agent answers with the same size.
+pub async fn run_bench(bus: &Bus, inbox: &str, reply_stream: &str, cfg: &BenchConfig) -> Result<BenchReport> {
+    let timeout = Duration::from_millis(cfg.timeout_ms);
+    let text = "x".repeat(cfg.payload_bytes);
+    let mut samples: Vec<Option<BenchSample>> = vec![None; cfg.count];
+    let mut next = 0;
+    let mut in_flight: Vec<InFlight> = Vec::new();
+    let started = Instant::now();
+
+    loop {
+        while in_flight.len() < cfg.concurrency.max(1) && next < cfg.count {
+            let env = delegate_envelope(
+                reply_stream,
+                inbox,
+                BENCH_AGENT_NAME,
+                json!({ "text": text }),
+                json!({}),
+                "user",
+                EnvelopeKind::Message.as_str(),
+            );
+            let send_started = Instant::now();
+            let res = bus.send(inbox, &env).await;
+            let send = send_started.elapsed();
+            match res {
+                Ok(_) => in_flight.push(InFlight {
+                    index: next,
+                    cid: env.correlation_id.unwrap_or_default(),
+                    send,
+                    sent: Instant::now(),
+                }),
+                Err(e) => {
+                    eprintln!("[AG1_meta] Could not send benchmark message to {}: {}", inbox, e);
+                    samples[next] = Some(BenchSample { outcome: SampleOutcome::Error, send, wait: Duration::ZERO });
+                }
+            }
+            next += 1;
+        }
+        let Some(next_deadline) = in_flight.iter().map(|f| f.sent + timeout).min() else { break };
+
+        let cids: Vec<String> = in_flight.iter().map(|f| f.cid.clone()).collect();
+        let wait_ms = next_deadline.saturating_duration_since(Instant::now()).as_millis() as u64;
+        let replies = wait_replies(bus, &cids, reply_stream, WaitMode::Any(1), wait_ms).await?;
+        for (cid, reply) in replies {
+            let Some(reply) = reply else { continue };
+            let Some(pos) = in_flight.iter().position(|f| f.cid == cid) else { continue };
+            let f = in_flight.swap_remove(pos);
+            let outcome = if reply.kind() == Some(EnvelopeKind::Error) { SampleOutcome::Error } else { SampleOutcome::Ok };
+            samples[f.index] = Some(BenchSample { outcome, send: f.send, wait: f.sent.elapsed() });
+        }
+
+        let now = Instant::now();
+        let (expired, waiting): (Vec<_>, Vec<_>) = in_flight.into_iter().partition(|f| f.sent + timeout <= now);
+        in_flight = waiting;
+        for f in expired {
+            samples[f.index] = Some(BenchSample { outcome: SampleOutcome::Timeout, send: f.send, wait: f.sent.elapsed() });
+        }
+    }
+
+    let samples = samples.into_iter().map(|s| s.expect("every message has a sample")).collect();
+    Ok(BenchReport::from_samples(samples, started.elapsed()))
+}
+
+/// Answer every envelope on `inbox`, from the start of the stream, with its
+/// own content on its `reply_to`, until the task is dropped. Meant for
+/// self-contained benchmark runs on a stream nothing else uses.
+pub async fn serve_echo(bus: &Bus, inbox: &str) {
+    let mut last_id = "0".to_string();
+    loop {
+        let env = match bus.recv_block(inbox, &last_id, 1000).await {
+            Ok(Some(entry)) => {
+                last_id = entry.id;
+                match entry.envelope {
+                    Some(env) => env,
+                    None => continue,
+                }
+            }
+            Ok(None) => continue,
+            Err(e) => {
+                eprintln!("[AG1_meta] Echo agent failed to read {}: {}", inbox, e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let Some(reply_to) = env.reply_to.clone() else { continue };
+        let mut reply: Envelope = env;
+        reply.role = "assistant".into();
+        reply.agent_name = Some("Echo".into());
+        reply.envelope_id = None;
+        reply.set_kind(EnvelopeKind::MessageReply);
+        if let Err(e) = bus.send(&reply_to, &reply).await {
+            eprintln!("[AG1_meta] Echo agent failed to reply on {}: {}", reply_to, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_redis_url;
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    fn sample(outcome: SampleOutcome, send_ms: u64, wait_ms: u64) -> BenchSample {
+        BenchSample { outcome, send: ms(send_ms), wait: ms(wait_ms) }
+    }
+
+    #[test]
+    fn percentiles_use_the_nearest_rank() {
+        let sorted: Vec<Duration> = (1..=10).map(ms).collect();
+        assert_eq!(percentile(&sorted, 50.0), ms(5));
+        assert_eq!(percentile(&sorted, 90.0), ms(9));
+        assert_eq!(percentile(&sorted, 99.0), ms(10));
+        assert_eq!(percentile(&sorted, 0.0), ms(1));
+        assert_eq!(percentile(&[ms(7)], 99.0), ms(7));
+        assert_eq!(percentile(&[], 50.0), Duration::ZERO);
+    }
+
+    #[test]
+    fn reports_only_time_ok_samples() {
+        let mut samples: Vec<BenchSample> = (1..=10).rev().map(|n| sample(SampleOutcome::Ok, 1, n * 10 - 1)).collect();
+        samples.push(sample(SampleOutcome::Error, 500, 0));
+        samples.push(sample(SampleOutcome::Timeout, 2, 5000));
+
+        let report = BenchReport::from_samples(samples, Duration::from_secs(2));
+        assert_eq!((report.ok, report.errors, report.timeouts), (10, 1, 1));
+        assert_eq!(report.p50, ms(50));
+        assert_eq!(report.p90, ms(90));
+        assert_eq!(report.p99, ms(100));
+        assert_eq!(report.max, ms(100));
+        assert_eq!(report.throughput, 5.0);
+        assert_eq!(report.mean_send, ms(1));
+        assert_eq!(report.mean_wait, Duration::from_micros(54_000));
+    }
+
+    #[test]
+    fn csv_has_a_row_per_sample_in_milliseconds() {
+        let samples = vec![
+            sample(SampleOutcome::Ok, 2, 10),
+            BenchSample { outcome: SampleOutcome::Timeout, send: Duration::from_micros(1500), wait: ms(1000) },
+        ];
+        let report = BenchReport::from_samples(samples, ms(1010));
+        assert_eq!(
+            report.to_csv(),
+            "index,outcome,send_ms,wait_ms,round_trip_ms\n\
+             0,ok,2.000,10.000,12.000\n\
+             1,timeout,1.500,1000.000,1001.500\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn every_message_to_an_echo_agent_is_answered() {
+        let Some(redis_url) = test_redis_url() else { return };
+        let id = uuid::Uuid::new_v4();
+        let inbox = format!("AG1:test:bench:{id}:inbox");
+        let echo_bus = Bus::new(&redis_url).unwrap();
+        let echo_inbox = inbox.clone();
+        let echo = tokio::spawn(async move { serve_echo(&echo_bus, &echo_inbox).await });
+
+        let bus = Bus::new(&redis_url).unwrap();
+        let cfg = BenchConfig { count: 8, concurrency: 3, payload_bytes: 256, timeout_ms: 5000 };
+        let report = run_bench(&bus, &inbox, &format!("AG1:test:bench:{id}:replies"), &cfg).await.unwrap();
+        echo.abort();
+
+        assert_eq!(report.samples.len(), 8);
+        assert_eq!(report.ok, 8, "{report}");
+        assert!(report.p50 <= report.p90 && report.p90 <= report.p99 && report.p99 <= report.max);
+        assert!(report.throughput > 0.0);
+    }
+}
@@ -0,0 +1,486 @@
+//! Opt-in caching of delegation replies, for reference agents asked the same
+//! question again within minutes ("what's the schema of table X"), each time
+//! otherwise a full round trip on their side.
+//!
+//! Only requests marked cacheable take part, and error replies are never
+//! stored. A [`MemoryCache`] serves one process; a [`RedisCache`] is shared by
+//! every process using the same Redis.
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use bus::{Bus, Envelope, EnvelopeKind};
+use serde::Serialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::{delegate_to_name_with_opts, DelegateOptions, RegistrySource, DEFAULT_AGENT_NAME};
+
+/// How long a cached reply is served when the policy doesn't say.
+pub const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(10 * 60);
+/// Replies a [`MemoryCache`] holds when the policy doesn't say.
+pub const DEFAULT_CACHE_ENTRIES: usize = 1000;
+/// Prefix of the keys a [`RedisCache`] keeps replies under: `AG1:cache:{target}:{key}`.
+pub const CACHE_KEY_PREFIX: &str = "AG1:cache";
+
+/// What a request is cached under, beside its target.
+#[derive(Debug, Clone, Copy)]
+pub enum CacheKey {
+    /// [`content_hash`] of the request content.
+    ContentHash,
+    /// The caller's own key for the content, e.g. to leave out fields that vary.
+    Custom(fn(&Value) -> String),
+}
+
+/// Which replies a [`Delegator`] caches, for how long and under what key.
+#[derive(Debug, Clone, Copy)]
+pub struct CachePolicy {
+    pub ttl: Duration,
+    /// Most replies a [`MemoryCache`] keeps; the least recently used go first
+    pub max_entries: usize,
+    pub key: CacheKey,
+}
+
+impl Default for CachePolicy {
+    fn default() -> Self {
+        Self { ttl: DEFAULT_CACHE_TTL, max_entries: DEFAULT_CACHE_ENTRIES, key: CacheKey::ContentHash }
+    }
+}
+
+impl CachePolicy {
+    /// Key the request `content` is cached under.
+    pub fn key_for(&self, content: &Value) -> String {
+        match self.key {
+            CacheKey::ContentHash => content_hash(content),
+            CacheKey::Custom(key) => key(content),
+        }
+    }
+}
+
+/// Hex SHA-256 of `content` as canonical JSON: compact, object keys sorted,
+/// so the same question hashes the same whatever order its fields came in.
+pub fn content_hash(content: &Value) -> String {
+    let mut canonical = String::new();
+    write_canonical(content, &mut canonical);
+    let mut hex = String::with_capacity(64);
+    for byte in Sha256::digest(canonical.as_bytes()) {
+        let _ = write!(hex, "{byte:02x}");
+    }
+    hex
+}
+
+fn write_canonical(value: &Value, out: &mut String) {
+    match value {
+        Value::Object(map) => {
+            let mut keys: Vec<_> = map.keys().collect();
+            keys.sort();
+            out.push('{');
+            for (i, key) in keys.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&Value::from(key.as_str()).to_string());
+                out.push(':');
+                write_canonical(&map[key], out);
+            }
+            out.push('}');
+        }
+        Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_canonical(item, out);
+            }
+            out.push(']');
+        }
+        other => out.push_str(&other.to_string()),
+    }
+}
+
+/// Where cached replies are kept, by target and [`CachePolicy::key_for`] key.
+#[async_trait::async_trait]
+pub trait ReplyCache: Send + Sync {
+    /// The reply stored for `key`, unless there is none or it has expired.
+    async fn get(&self, target: &str, key: &str) -> Result<Option<Envelope>>;
+    async fn put(&self, target: &str, key: &str, reply: &Envelope, ttl: Duration) -> Result<()>;
+}
+
+/// Replies kept in this process, the least recently used dropped first once
+/// there are more than `max_entries`.
+pub struct MemoryCache {
+    max_entries: usize,
+    entries: Mutex<Entries>,
+}
+
+#[derive(Default)]
+struct Entries {
+    by_key: HashMap<(String, String), Cached>,
+    /// Bumped on every use, so the entry with the lowest `used` is the least recent
+    clock: u64,
+}
+
+struct Cached {
+    reply: Envelope,
+    expires: Instant,
+    used: u64,
+}
+
+impl MemoryCache {
+    pub fn new(max_entries: usize) -> Self {
+        Self { max_entries, entries: Mutex::default() }
+    }
+}
+
+#[async_trait::async_trait]
+impl ReplyCache for MemoryCache {
+    async fn get(&self, target: &str, key: &str) -> Result<Option<Envelope>> {
+        let mut entries = self.entries.lock().unwrap();
+        let id = (target.to_string(), key.to_string());
+        match entries.by_key.get(&id) {
+            Some(cached) if cached.expires <= Instant::now() => {
+                entries.by_key.remove(&id);
+                Ok(None)
+            }
+            Some(_) => {
+                entries.clock += 1;
+                let clock = entries.clock;
+                let cached = entries.by_key.get_mut(&id).expect("entry looked up above");
+                cached.used = clock;
+                Ok(Some(cached.reply.clone()))
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn put(&self, target: &str, key: &str, reply: &Envelope, ttl: Duration) -> Result<()> {
+        if self.max_entries == 0 {
+            return Ok(());
+        }
+        let mut entries = self.entries.lock().unwrap();
+        entries.clock += 1;
+        let cached = Cached { reply: reply.clone(), expires: Instant::now() + ttl, used: entries.clock };
+        entries.by_key.insert((target.to_string(), key.to_string()), cached);
+        // One over at most, so one linear scan for the oldest will do
+        if entries.by_key.len() > self.max_entries {
+            let oldest = entries.by_key.iter().min_by_key(|(_, c)| c.used).map(|(id, _)| id.clone());
+            if let Some(oldest) = oldest {
+                entries.by_key.remove(&oldest);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Replies kept in Redis under `AG1:cache:{target}:{key}`, expiring there, so
+/// every process delegating through the same Redis shares them.
+#[derive(Clone)]
+pub struct RedisCache {
+    bus: Bus,
+}
+
+impl RedisCache {
+    pub fn new(redis_url: &str) -> Result<Self> {
+        Ok(Self { bus: Bus::new(redis_url)? })
+    }
+
+    fn redis_key(target: &str, key: &str) -> String {
+        format!("{CACHE_KEY_PREFIX}:{target}:{key}")
+    }
+}
+
+#[async_trait::async_trait]
+impl ReplyCache for RedisCache {
+    async fn get(&self, target: &str, key: &str) -> Result<Option<Envelope>> {
+        let stored = self.bus.get_value(&Self::redis_key(target, key)).await?;
+        Ok(stored.map(|json| serde_json::from_str(&json)).transpose()?)
+    }
+
+    async fn put(&self, target: &str, key: &str, reply: &Envelope, ttl: Duration) -> Result<()> {
+        let json = serde_json::to_string(reply)?;
+        self.bus.set_with_ttl(&Self::redis_key(target, key), &json, ttl).await?;
+        Ok(())
+    }
+}
+
+/// How a [`Delegator`]'s cache has done since it was built.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct CacheStats {
+    /// Cacheable requests answered from the cache
+    pub hits: u64,
+    /// Cacheable requests delegated because nothing was cached for them
+    pub misses: u64,
+    /// Replies put in the cache
+    pub stores: u64,
+}
+
+/// Delegates to agents by name, answering cacheable requests from a
+/// [`ReplyCache`] when one is configured.
+pub struct Delegator {
+    redis_url: String,
+    registry: Arc<dyn RegistrySource>,
+    agent_name: String,
+    cache: Option<(CachePolicy, Arc<dyn ReplyCache>)>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    stores: AtomicU64,
+}
+
+impl Delegator {
+    /// A delegator without a cache, sending as [`DEFAULT_AGENT_NAME`].
+    pub fn new(redis_url: impl Into<String>, registry: Arc<dyn RegistrySource>) -> Self {
+        Self {
+            redis_url: redis_url.into(),
+            registry,
+            agent_name: DEFAULT_AGENT_NAME.into(),
+            cache: None,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            stores: AtomicU64::new(0),
+        }
+    }
+
+    /// Send requests as `agent_name`, unless their [`DelegateOptions::agent_name`] says otherwise.
+    pub fn agent_name(mut self, agent_name: impl Into<String>) -> Self {
+        self.agent_name = agent_name.into();
+        self
+    }
+
+    /// Cache replies in this process, in a [`MemoryCache`] of `policy.max_entries`.
+    pub fn with_cache(self, policy: CachePolicy) -> Self {
+        let store = Arc::new(MemoryCache::new(policy.max_entries));
+        self.with_cache_store(policy, store)
+    }
+
+    /// Cache replies in `store`, e.g. a [`RedisCache`] shared with other processes.
+    pub fn with_cache_store(mut self, policy: CachePolicy, store: Arc<dyn ReplyCache>) -> Self {
+        self.cache = Some((policy, store));
+        self
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            stores: self.stores.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Delegate `content` to `target` and return its reply, see
+    /// [`delegate_to_name_with_opts`]. A cacheable request is first looked up
+    /// in the cache: a hit is the stored reply as it was, timestamp included,
+    /// with `meta.cache_hit` set; a miss is delegated and its reply stored,
+    /// unless it is an error. Failing cache lookups and stores are logged and
+    /// otherwise ignored.
+    pub async fn delegate(&self, target: &str, content: Value, meta: Value, opts: &DelegateOptions) -> Result<Envelope> {
+        let cacheable = opts.cacheable || meta.get("cacheable").and_then(Value::as_bool) == Some(true);
+        // Registry lookups ignore case, so the cache does too
+        let cached = match &self.cache {
+            Some((policy, store)) if cacheable => Some((policy, store, target.to_lowercase(), policy.key_for(&content))),
+            _ => None,
+        };
+
+        if let Some((_, store, target_key, key)) = &cached {
+            match store.get(target_key, key).await {
+                Ok(Some(mut reply)) => {
+                    self.hits.fetch_add(1, Ordering::Relaxed);
+                    eprintln!("[AG1_meta] Cache hit for {} ({})", target, key);
+                    if !reply.meta.is_object() {
+                        reply.meta = json!({});
+                    }
+                    reply.meta["cache_hit"] = json!(true);
+                    return Ok(reply);
+                }
+                Ok(None) => {}
+                Err(e) => eprintln!("[AG1_meta] Cache lookup for {} failed, delegating: {}", target, e),
+            }
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let opts = DelegateOptions { agent_name: opts.agent_name.clone().or_else(|| Some(self.agent_name.clone())), ..opts.clone() };
+        let reply = delegate_to_name_with_opts(&self.redis_url, self.registry.as_ref(), target, content, meta, &opts).await?;
+
+        if let Some((policy, store, target_key, key)) = &cached {
+            if reply.kind() != Some(EnvelopeKind::Error) {
+                match store.put(target_key, key, &reply, policy.ttl).await {
+                    Ok(()) => {
+                        self.stores.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Err(e) => eprintln!("[AG1_meta] Caching the reply from {} failed: {}", target, e),
+                }
+            }
+        }
+        Ok(reply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+    use crate::tests::test_redis_url;
+    use crate::{AgentInfo, DelegateError, Registry, RequestContext};
+
+    /// "Echo", answering every envelope with its own content (an error for
+    /// text "fail"), and the number of envelopes it has answered.
+    fn echo_registry(redis_url: &str) -> (Arc<dyn RegistrySource>, Arc<AtomicUsize>) {
+        let id = uuid::Uuid::new_v4();
+        let inbox = format!("AG1:test:cache:{id}:inbox");
+        let answered = Arc::new(AtomicUsize::new(0));
+        let (agent_inbox, count) = (inbox.clone(), answered.clone());
+        let bus = Bus::new(redis_url).unwrap();
+        tokio::spawn(async move {
+            let mut last_id = "0".to_string();
+            loop {
+                let Ok(Some(entry)) = bus.recv_block(&agent_inbox, &last_id, 1000).await else { continue };
+                last_id = entry.id;
+                let Some(env) = entry.envelope else { continue };
+                let mut reply = env.clone();
+                reply.role = "assistant".into();
+                reply.agent_name = Some("Echo".into());
+                reply.envelope_id = None;
+                let kind = if env.try_get_text() == Some("fail") { EnvelopeKind::Error } else { EnvelopeKind::MessageReply };
+                reply.set_kind(kind);
+                count.fetch_add(1, Ordering::SeqCst);
+                bus.send(env.reply_to.as_deref().unwrap(), &reply).await.unwrap();
+            }
+        });
+        let agents = vec![AgentInfo { name: "Echo".into(), inbox, ..Default::default() }];
+        (Arc::new(Registry::from_agents(agents, format!("AG1:test:cache:{id}:replies"))), answered)
+    }
+
+    fn reply(text: &str) -> Envelope {
+        let mut env = crate::create_envelope(json!({ "text": text }), "assistant", None);
+        env.set_kind(EnvelopeKind::MessageReply);
+        env
+    }
+
+    #[test]
+    fn content_hash_ignores_field_order() {
+        let a: Value = serde_json::from_str(r#"{"text":"schema","args":{"table":"x","db":"main"}}"#).unwrap();
+        let b: Value = serde_json::from_str(r#"{"args":{"db":"main","table":"x"},"text":"schema"}"#).unwrap();
+        assert_eq!(content_hash(&a), content_hash(&b));
+        assert_eq!(content_hash(&a).len(), 64);
+        assert_ne!(content_hash(&a), content_hash(&json!({ "text": "schema", "args": { "table": "y", "db": "main" } })));
+
+        let policy = CachePolicy { key: CacheKey::Custom(|c| c["args"]["table"].to_string()), ..Default::default() };
+        assert_eq!(policy.key_for(&a), "\"x\"");
+    }
+
+    #[tokio::test]
+    async fn memory_cache_expires_and_drops_the_least_recently_used() {
+        let cache = MemoryCache::new(2);
+        let ttl = Duration::from_secs(60);
+        cache.put("echo", "a", &reply("a"), ttl).await.unwrap();
+        cache.put("echo", "b", &reply("b"), ttl).await.unwrap();
+        assert!(cache.get("echo", "a").await.unwrap().is_some());
+        cache.put("echo", "c", &reply("c"), ttl).await.unwrap();
+        assert!(cache.get("echo", "b").await.unwrap().is_none(), "b was the least recently used");
+        assert_eq!(cache.get("echo", "a").await.unwrap().unwrap().text_or_empty(), "a");
+        assert!(cache.get("other", "a").await.unwrap().is_none());
+
+        cache.put("echo", "short", &reply("short"), Duration::from_millis(50)).await.unwrap();
+        assert!(cache.get("echo", "short").await.unwrap().is_some());
+        tokio::time::sleep(Duration::from_millis(80)).await;
+        assert!(cache.get("echo", "short").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn cacheable_requests_are_delegated_once_per_ttl() {
+        let Some(redis_url) = test_redis_url() else { return };
+        let (registry, answered) = echo_registry(&redis_url);
+        let policy = CachePolicy { ttl: Duration::from_millis(1500), ..Default::default() };
+        let delegator = Delegator::new(&redis_url, registry).agent_name("tester").with_cache(policy);
+        let cacheable = DelegateOptions { timeout_ms: 5000, cacheable: true, ..Default::default() };
+
+        let first = delegator.delegate("Echo", json!({ "text": "schema of x" }), json!({}), &cacheable).await.unwrap();
+        assert_eq!(first.meta.get("cache_hit"), None);
+        let second = delegator.delegate("echo", json!({ "text": "schema of x" }), json!({}), &cacheable).await.unwrap();
+        assert_eq!(second.meta["cache_hit"], true);
+        assert_eq!((second.correlation_id.as_deref(), second.timestamp), (first.correlation_id.as_deref(), first.timestamp));
+        assert_eq!(answered.load(Ordering::SeqCst), 1);
+
+        // Marked in meta instead of the options; a different question misses
+        let plain = DelegateOptions { timeout_ms: 5000, ..Default::default() };
+        let by_meta = delegator.delegate("Echo", json!({ "text": "schema of x" }), json!({ "cacheable": true }), &plain).await.unwrap();
+        assert_eq!(by_meta.meta["cache_hit"], true);
+        delegator.delegate("Echo", json!({ "text": "schema of y" }), json!({}), &cacheable).await.unwrap();
+        // Not cacheable: always delegated, never counted
+        delegator.delegate("Echo", json!({ "text": "schema of x" }), json!({}), &plain).await.unwrap();
+        assert_eq!(answered.load(Ordering::SeqCst), 3);
+
+        // Errors aren't cached
+        for _ in 0..2 {
+            let failed = delegator.delegate("Echo", json!({ "text": "fail" }), json!({}), &cacheable).await.unwrap();
+            assert_eq!(failed.kind(), Some(EnvelopeKind::Error));
+        }
+        assert_eq!(answered.load(Ordering::SeqCst), 5);
+        assert_eq!(delegator.cache_stats(), CacheStats { hits: 2, misses: 4, stores: 2 });
+
+        tokio::time::sleep(Duration::from_millis(1600)).await;
+        let expired = delegator.delegate("Echo", json!({ "text": "schema of x" }), json!({}), &cacheable).await.unwrap();
+        assert_eq!(expired.meta.get("cache_hit"), None);
+        assert_eq!(answered.load(Ordering::SeqCst), 6);
+    }
+
+    #[tokio::test]
+    async fn an_expired_deadline_fails_without_sending() {
+        let agents = vec![AgentInfo { name: "Echo".into(), inbox: "AG1:agent:Echo:inbox".into(), ..Default::default() }];
+        let registry = Arc::new(Registry::from_agents(agents, "AG1:test:cache:replies"));
+        // Nothing listens here: getting as far as the bus would fail differently
+        let delegator = Delegator::new("redis://127.0.0.1:1", registry);
+        let mut parent = reply("parent");
+        parent.set_deadline(chrono::Utc::now() + chrono::Duration::milliseconds(100));
+        let opts = DelegateOptions { context: RequestContext::from_parent(&parent), ..Default::default() };
+
+        let err = delegator.delegate("Echo", json!({ "text": "too late" }), json!({}), &opts).await.unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(DelegateError::DeadlineExceeded { agent, .. }) if agent == "Echo"), "{err:#}");
+    }
+
+    #[tokio::test]
+    async fn nested_delegations_wait_no_longer_than_their_parent() {
+        let Some(redis_url) = test_redis_url() else { return };
+        let (registry, _) = echo_registry(&redis_url);
+        let delegator = Delegator::new(&redis_url, registry);
+        let mut parent = reply("parent");
+        let parent_deadline = chrono::Utc::now() + chrono::Duration::seconds(3);
+        parent.set_deadline(parent_deadline);
+        let opts = DelegateOptions { timeout_ms: 30_000, context: RequestContext::from_parent(&parent), ..Default::default() };
+        assert!(opts.context.timeout_within("Echo", 30_000).unwrap() <= 3000 - crate::deadline_margin_ms());
+
+        // Echo answers with the request, deadline header and all
+        let echoed = delegator.delegate("Echo", json!({ "text": "sub-task" }), json!({}), &opts).await.unwrap();
+        let deadline = echoed.deadline().expect("the request carried a deadline");
+        assert!(deadline < parent_deadline, "{deadline} is not before {parent_deadline}");
+    }
+
+    #[tokio::test]
+    async fn redis_cache_is_shared_between_delegators() {
+        let Some(redis_url) = test_redis_url() else { return };
+        let (registry, answered) = echo_registry(&redis_url);
+        let shared = |registry| {
+            let store = Arc::new(RedisCache::new(&redis_url).unwrap());
+            Delegator::new(&redis_url, registry).with_cache_store(CachePolicy::default(), store)
+        };
+        let (one, two) = (shared(registry.clone()), shared(registry));
+        let opts = DelegateOptions { timeout_ms: 5000, cacheable: true, ..Default::default() };
+        // Unique text, so nothing cached by an earlier run is hit
+        let content = json!({ "text": format!("schema of {}", uuid::Uuid::new_v4()) });
+
+        let first = one.delegate("Echo", content.clone(), json!({}), &opts).await.unwrap();
+        let second = two.delegate("Echo", content.clone(), json!({}), &opts).await.unwrap();
+        assert_eq!(second.meta["cache_hit"], true);
+        assert_eq!(second.correlation_id, first.correlation_id);
+        assert_eq!(answered.load(Ordering::SeqCst), 1);
+        assert_eq!(two.cache_stats(), CacheStats { hits: 1, misses: 0, stores: 0 });
+
+        let key = RedisCache::redis_key("echo", &content_hash(&content));
+        assert!(Bus::new(&redis_url).unwrap().get_value(&key).await.unwrap().is_some());
+    }
+}
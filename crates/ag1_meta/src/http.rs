@@ -0,0 +1,117 @@
+//! Delegation to agents served over HTTP (see [`crate::AgentInfo::endpoint`]).
+
+use anyhow::Result;
+use bus::Envelope;
+
+#[cfg(feature = "http-delegate")]
+use crate::DelegateError;
+
+/// POST `env` as JSON to `{endpoint}/invoke` and take the response body as
+/// the reply envelope. Fails with [`DelegateError::Timeout`] when no response
+/// arrives within `timeout_ms`, and on any non-2xx status.
+#[cfg(feature = "http-delegate")]
+pub(crate) async fn invoke(endpoint: &str, env: &Envelope, timeout_ms: u64) -> Result<Envelope> {
+    let url = format!("{}/invoke", endpoint.trim_end_matches('/'));
+    let cid = env.correlation_id.clone().unwrap_or_default();
+    eprintln!("[AG1_meta] POSTing envelope to {} (cid={})", url, cid);
+    let res = reqwest::Client::new()
+        .post(&url)
+        .timeout(std::time::Duration::from_millis(timeout_ms))
+        .json(env)
+        .send()
+        .await;
+    let res = match res {
+        Ok(res) => res,
+        Err(e) if e.is_timeout() => {
+            let agent = env.target.clone().unwrap_or_else(|| endpoint.to_string());
+            return Err(DelegateError::Timeout { agent, waited_ms: timeout_ms, cid }.into());
+        }
+        Err(e) => return Err(anyhow::anyhow!("POST {url} failed: {e}")),
+    };
+    let status = res.status();
+    if !status.is_success() {
+        let body = res.text().await.unwrap_or_default();
+        anyhow::bail!("POST {url} returned {status}: {body}");
+    }
+    res.json::<Envelope>()
+        .await
+        .map_err(|e| anyhow::anyhow!("POST {url} did not return an envelope: {e}"))
+}
+
+/// Without the `http-delegate` feature, agents with an endpoint can't be delegated to.
+#[cfg(not(feature = "http-delegate"))]
+pub(crate) async fn invoke(endpoint: &str, _env: &Envelope, _timeout_ms: u64) -> Result<Envelope> {
+    anyhow::bail!("agent endpoint {endpoint} needs ag1_meta built with the http-delegate feature")
+}
+
+#[cfg(all(test, feature = "http-delegate"))]
+mod tests {
+    use serde_json::json;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::delegate_envelope;
+
+    /// Serve one HTTP request on a local port with `status` and `body`,
+    /// handing back what was requested. Gives the endpoint URL.
+    async fn serve_once(status: &'static str, body: String) -> (String, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            // Headers, then a body of the Content-Length they give
+            loop {
+                let n = socket.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request);
+                if let Some(end) = text.find("\r\n\r\n") {
+                    let len = text[..end]
+                        .lines()
+                        .find_map(|l| l.to_ascii_lowercase().strip_prefix("content-length:").map(|v| v.trim().parse::<usize>().unwrap()))
+                        .unwrap_or(0);
+                    if request.len() >= end + 4 + len {
+                        break;
+                    }
+                }
+            }
+            let response = format!(
+                "HTTP/1.1 {status}\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        (endpoint, server)
+    }
+
+    #[tokio::test]
+    async fn invoke_posts_the_envelope_and_reads_the_reply() {
+        let env = delegate_envelope("AG1:agent:GooseAgent:inbox", "Web", "tester", json!({ "text": "hi" }), json!({}), "user", "message");
+        let mut reply = env.clone();
+        reply.role = "assistant".into();
+        reply.set_text("hello");
+        let (endpoint, server) = serve_once("200 OK", serde_json::to_string(&reply).unwrap()).await;
+
+        let got = invoke(&format!("{endpoint}/"), &env, 5000).await.unwrap();
+        assert_eq!(got.text_or_empty(), "hello");
+        assert_eq!(got.correlation_id, env.correlation_id);
+        let request = server.await.unwrap();
+        assert!(request.starts_with("POST /invoke "), "{request}");
+        assert!(request.contains(env.correlation_id.as_deref().unwrap()), "{request}");
+    }
+
+    #[tokio::test]
+    async fn invoke_fails_on_an_error_status() {
+        let env = delegate_envelope("AG1:agent:GooseAgent:inbox", "Web", "tester", json!({ "text": "hi" }), json!({}), "user", "message");
+        let (endpoint, _server) = serve_once("503 Service Unavailable", "busy".to_string()).await;
+
+        let err = invoke(&endpoint, &env, 5000).await.unwrap_err();
+        assert!(err.to_string().contains("503"), "{err}");
+    }
+}
@@ -0,0 +1,110 @@
+use std::sync::OnceLock;
+
+/// Inbox classes accepted when `AG1_INBOX_CLASSES` is not set.
+pub const DEFAULT_INBOX_CLASSES: &[&str] = &["agent", "service", "edge"];
+
+/// Why an inbox name doesn't follow `AG1:<class>:<id...>:inbox`.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum InboxError {
+    #[error("inbox {0:?} contains whitespace")]
+    Whitespace(String),
+    #[error("inbox {0:?} does not start with \"AG1:\"")]
+    Prefix(String),
+    #[error("inbox {0:?} does not end with \":inbox\"")]
+    Suffix(String),
+    #[error("inbox {0:?} has no id between its class and \":inbox\"")]
+    MissingId(String),
+    #[error("inbox {inbox:?} has class {class:?}, expected one of {allowed:?}")]
+    Class { inbox: String, class: String, allowed: Vec<String> },
+}
+
+/// The inbox naming scheme: `AG1:<class>:<id...>:inbox`, where the class is
+/// one of `classes` and the id is one or more non-empty `:`-separated parts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InboxScheme {
+    pub classes: Vec<String>,
+}
+
+impl Default for InboxScheme {
+    fn default() -> Self {
+        Self { classes: DEFAULT_INBOX_CLASSES.iter().map(|c| c.to_string()).collect() }
+    }
+}
+
+impl InboxScheme {
+    /// The default scheme, with the classes replaced by the comma-separated
+    /// `AG1_INBOX_CLASSES` when that is set.
+    pub fn from_env() -> Self {
+        let mut scheme = Self::default();
+        if let Ok(classes) = std::env::var("AG1_INBOX_CLASSES") {
+            let classes: Vec<String> =
+                classes.split(',').map(|c| c.trim().to_string()).filter(|c| !c.is_empty()).collect();
+            if !classes.is_empty() {
+                scheme.classes = classes;
+            }
+        }
+        scheme
+    }
+
+    /// Process-wide scheme, read from the environment once.
+    pub fn global() -> &'static InboxScheme {
+        static SCHEME: OnceLock<InboxScheme> = OnceLock::new();
+        SCHEME.get_or_init(Self::from_env)
+    }
+
+    pub fn validate(&self, inbox: &str) -> Result<(), InboxError> {
+        if inbox.contains(char::is_whitespace) {
+            return Err(InboxError::Whitespace(inbox.into()));
+        }
+        let Some(rest) = inbox.strip_prefix("AG1:") else {
+            return Err(InboxError::Prefix(inbox.into()));
+        };
+        let Some(rest) = rest.strip_suffix(":inbox") else {
+            return Err(InboxError::Suffix(inbox.into()));
+        };
+        let (class, id) = rest.split_once(':').unwrap_or((rest, ""));
+        if id.split(':').any(str::is_empty) {
+            return Err(InboxError::MissingId(inbox.into()));
+        }
+        if !self.classes.iter().any(|c| c == class) {
+            return Err(InboxError::Class { inbox: inbox.into(), class: class.into(), allowed: self.classes.clone() });
+        }
+        Ok(())
+    }
+}
+
+/// Check `inbox` against [`InboxScheme::global`].
+pub fn validate_inbox(inbox: &str) -> Result<(), InboxError> {
+    InboxScheme::global().validate(inbox)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_inboxes() {
+        let scheme = InboxScheme::default();
+        for inbox in ["AG1:agent:Echo:inbox", "AG1:edge:fetch:main:inbox", "AG1:service:echo_activation:inbox"] {
+            assert_eq!(scheme.validate(inbox), Ok(()), "{inbox}");
+        }
+    }
+
+    #[test]
+    fn invalid_inboxes_say_what_is_wrong() {
+        let scheme = InboxScheme::default();
+        assert!(matches!(scheme.validate("AG1 agent Echo inbox"), Err(InboxError::Whitespace(_))));
+        assert!(matches!(scheme.validate("ag1:agent:Echo:inbox"), Err(InboxError::Prefix(_))));
+        assert!(matches!(scheme.validate("AG1:agent:Echo:outbox"), Err(InboxError::Suffix(_))));
+        assert!(matches!(scheme.validate("AG1:agent:inbox"), Err(InboxError::MissingId(_))));
+        assert!(matches!(scheme.validate("AG1:agent::inbox"), Err(InboxError::MissingId(_))));
+        assert!(matches!(scheme.validate("AG1:edge:fetch::inbox"), Err(InboxError::MissingId(_))));
+        let err = scheme.validate("AG1:test:Echo:inbox").unwrap_err();
+        assert!(matches!(&err, InboxError::Class { class, .. } if class == "test"));
+        assert!(err.to_string().contains("expected one of"));
+
+        let custom = InboxScheme { classes: vec!["test".into()] };
+        assert_eq!(custom.validate("AG1:test:Echo:inbox"), Ok(()));
+        assert!(custom.validate("AG1:agent:Echo:inbox").is_err());
+    }
+}
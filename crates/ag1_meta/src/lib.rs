@@ -29,6 +29,7 @@ pub fn create_envelope(content: serde_json::Value, role: &str, meta: Option<serd
     let content = normalize_content(content);
     Envelope {
         content,
+        content_type: None,
         role: role.to_string(),
         meta: meta.unwrap_or_else(|| serde_json::json!({})),
         session_code: None,
@@ -50,134 +51,357 @@ pub fn create_envelope(content: serde_json::Value, role: &str, meta: Option<serd
         consumer_group: None,
         consumer_id: None,
         delivery_count: None,
+        priority: None,
     }
 }
+pub mod bench;
+mod cache;
+mod http;
+mod inbox;
+mod many;
 mod registry;
-pub use registry::{Registry, AgentInfo};
+pub mod registry_service;
+mod reply;
+mod wait;
+pub use cache::{
+    content_hash, CacheKey, CachePolicy, CacheStats, Delegator, MemoryCache, RedisCache, ReplyCache,
+    CACHE_KEY_PREFIX, DEFAULT_CACHE_ENTRIES, DEFAULT_CACHE_TTL,
+};
+pub use inbox::{validate_inbox, InboxError, InboxScheme, DEFAULT_INBOX_CLASSES};
+pub use many::{delegate_many, DelegateOutcome, DelegateRequest};
+pub use registry::{Registry, RegistrySource, AgentInfo, Discovery, EntryReport, Issue, Severity};
+pub use reply::{ReplyMatch, ReplyMatcher, DEFAULT_REPLY_TYPES};
+pub use wait::{wait_replies, WaitMode};
 
-use anyhow::{bail, Result};
-use bus::{Bus, Envelope};
+use anyhow::Result;
+use bus::{Budget, Bus, Envelope, EnvelopeKind, RedactionPolicy, StartPos, StreamInfo};
 use serde_json::{json, Value};
 use uuid::Uuid;
 use chrono::Utc;
 
-/// Delegates a message to an agent with the given name and options.
+/// Why a delegation produced no reply. [`delegate_with_opts`] returns it
+/// directly; the other `delegate*` functions return it inside their
+/// `anyhow::Error`, so use `downcast_ref` to branch on it there.
+#[derive(Debug, thiserror::Error)]
+pub enum DelegateError {
+    /// The registry has no agent by this name.
+    #[error("unknown agent: {0}")]
+    AgentNotFound(String),
+    /// `fail_fast` found no consumer group with a live consumer on the target inbox.
+    #[error("no consumer is reading {stream}")]
+    NoConsumer { stream: String },
+    #[error("{agent} sent no reply within {waited_ms} ms (cid={cid})")]
+    Timeout { agent: String, waited_ms: u64, cid: String },
+    /// The caller's [`RequestContext::deadline`] leaves no time to wait for
+    /// a reply, so nothing was sent.
+    #[error("deadline {deadline} leaves no time to delegate to {agent}")]
+    DeadlineExceeded { agent: String, deadline: chrono::DateTime<Utc> },
+    /// Sending the request or reading replies failed.
+    #[error(transparent)]
+    BusError(bus::BusError),
+    /// A reply on the stream was not a valid envelope.
+    #[error("undecodable reply: {0}")]
+    DeserializationError(serde_json::Error),
+}
+
+impl From<bus::BusError> for DelegateError {
+    fn from(e: bus::BusError) -> Self {
+        match e {
+            bus::BusError::Json(e) => DelegateError::DeserializationError(e),
+            e => DelegateError::BusError(e),
+        }
+    }
+}
+
+/// Who and what a delegation is done for, stamped on its request envelope so
+/// the agent, its replies and the journals can attribute it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RequestContext {
+    pub user_id: Option<String>,
+    pub task_id: Option<String>,
+    /// Put first in the envelope's `trace`, ahead of the hops the bus adds
+    pub trace_id: Option<String>,
+    /// When whoever the caller works for stops waiting: delegations wait no
+    /// longer than up to [`deadline_margin_ms`] before it
+    pub deadline: Option<chrono::DateTime<Utc>>,
+}
+
+impl RequestContext {
+    /// Set `env.user_id`, `env.task_id` and the first `trace` entry from the
+    /// fields this context has, leaving the others as they are.
+    pub fn apply(&self, env: &mut Envelope) {
+        if let Some(user_id) = &self.user_id {
+            env.user_id = Some(user_id.clone());
+        }
+        if let Some(task_id) = &self.task_id {
+            env.task_id = Some(task_id.clone());
+        }
+        if let Some(trace_id) = &self.trace_id {
+            env.trace.insert(0, trace_id.clone());
+        }
+    }
+
+    /// The context of work done for `parent`: its user, task and deadline.
+    pub fn from_parent(parent: &Envelope) -> Self {
+        Self {
+            user_id: parent.user_id.clone(),
+            task_id: parent.task_id.clone(),
+            trace_id: None,
+            deadline: parent.deadline(),
+        }
+    }
+
+    /// `timeout_ms`, cut down to end [`deadline_margin_ms`] before the
+    /// deadline. Fails with [`DelegateError::DeadlineExceeded`] if that leaves
+    /// no time at all.
+    pub fn timeout_within(&self, agent: &str, timeout_ms: u64) -> Result<u64, DelegateError> {
+        let Some(deadline) = self.deadline else { return Ok(timeout_ms) };
+        let left_ms = (deadline - Utc::now()).num_milliseconds() - deadline_margin_ms() as i64;
+        if left_ms <= 0 {
+            return Err(DelegateError::DeadlineExceeded { agent: agent.to_string(), deadline });
+        }
+        Ok(timeout_ms.min(left_ms as u64))
+    }
+}
+
+/// How a delegation is sent and its reply waited for. The default is a user
+/// message from [`DEFAULT_AGENT_NAME`] with the agent's own timeout.
+#[derive(Debug, Clone)]
+pub struct DelegateOptions {
+    /// Who the request is from; `None` is [`DEFAULT_AGENT_NAME`], or the
+    /// [`Delegator`]'s own name when it sends
+    pub agent_name: Option<String>,
+    pub role: String,
+    pub envelope_type: String,
+    /// 0 uses the agent's [`AgentInfo::timeout_ms`]
+    pub timeout_ms: u64,
+    /// Check the target inbox for a consumer first, failing with
+    /// [`DelegateError::NoConsumer`] instead of waiting out the timeout
+    pub fail_fast: bool,
+    /// Answer from the cache when possible and cache the reply; a request
+    /// whose `meta.cacheable` is `true` is cacheable too. Only
+    /// [`Delegator::delegate`] has a cache
+    pub cacheable: bool,
+    pub matcher: ReplyMatcher,
+    pub context: RequestContext,
+    /// Limits for the target, sent as `meta.budget` in place of any there
+    pub budget: Option<Budget>,
+}
+
+impl Default for DelegateOptions {
+    fn default() -> Self {
+        Self {
+            agent_name: None,
+            role: "user".into(),
+            envelope_type: EnvelopeKind::Message.into(),
+            timeout_ms: 0,
+            fail_fast: false,
+            cacheable: false,
+            matcher: ReplyMatcher::default(),
+            context: RequestContext::default(),
+            budget: None,
+        }
+    }
+}
+
+impl DelegateOptions {
+    /// Who the request is sent as.
+    pub fn agent_name(&self) -> &str {
+        self.agent_name.as_deref().unwrap_or(DEFAULT_AGENT_NAME)
+    }
+
+    /// Put the context and budget on the request envelope `env`, as the
+    /// delegate functions do before sending it.
+    pub fn apply(&self, env: &mut Envelope) {
+        self.context.apply(env);
+        if let Some(budget) = &self.budget {
+            budget.insert_into(&mut env.meta);
+        }
+    }
+}
+
+/// How long before the caller's deadline a delegation stops waiting, when
+/// `AG1_DEADLINE_MARGIN_MS` doesn't say.
+pub const DEFAULT_DEADLINE_MARGIN_MS: u64 = 500;
+
+/// Time kept back from a [`RequestContext::deadline`] to pass the reply on
+/// before it: `AG1_DEADLINE_MARGIN_MS`, read once, else [`DEFAULT_DEADLINE_MARGIN_MS`].
+pub fn deadline_margin_ms() -> u64 {
+    static MARGIN: std::sync::OnceLock<u64> = std::sync::OnceLock::new();
+    *MARGIN.get_or_init(|| {
+        std::env::var("AG1_DEADLINE_MARGIN_MS").ok().and_then(|v| v.parse().ok()).unwrap_or(DEFAULT_DEADLINE_MARGIN_MS)
+    })
+}
+
+/// Stamp `env` with the time its sender stops waiting, `timeout_ms` from now.
+fn set_reply_deadline(env: &mut Envelope, timeout_ms: u64) {
+    env.set_deadline(Utc::now() + chrono::Duration::milliseconds(timeout_ms as i64));
+}
+
+/// `agent_name` stamped on delegation envelopes when the caller hasn't configured one.
+pub const DEFAULT_AGENT_NAME: &str = "ag1goose";
+
+/// Stream the Goose agent takes its replies on when none is configured.
+pub const DEFAULT_GOOSE_INBOX: &str = "AG1:agent:GooseAgent:inbox";
+
+/// Reply timeout for a `timeout_ms` of 0 when the agent's registry entry sets none.
+pub const DEFAULT_TIMEOUT_MS: u64 = 30_000;
+
+/// Longest single blocking read while waiting for replies.
+const RECV_SLICE_MS: u64 = 800;
+/// Consumer group replies are read with, shared by every delegator on a reply stream.
+const REPLY_GROUP: &str = "ag1_meta";
+
+/// True when some consumer group on the stream has at least one consumer.
+///
+/// Agents that read with plain `XREAD` leave no trace here, so this is only a
+/// reliable liveness signal for consumer-group agents.
+fn has_consumer(info: &StreamInfo) -> bool {
+    info.groups.iter().any(|g| g.consumers > 0)
+}
+
+async fn ensure_consumer(bus: &Bus, stream: &str) -> Result<(), DelegateError> {
+    let info = bus.stream_info(stream).await?;
+    if !has_consumer(&info) {
+        eprintln!("[AG1_meta] No consumer on {} ({} groups), failing fast", stream, info.groups.len());
+        return Err(DelegateError::NoConsumer { stream: stream.to_string() });
+    }
+    Ok(())
+}
+
+/// Delegates a message to an agent with the given name, sent and waited for
+/// as `opts` says (see [`delegate_with_opts`]). An `opts.timeout_ms` of 0 uses the
+/// agent's [`AgentInfo::timeout_ms`].
+///
+/// An agent with an [`AgentInfo::endpoint`] is sent the envelope over HTTP
+/// instead, and its response is the reply (`http-delegate` feature).
 pub async fn delegate_to_name_with_opts(
     redis_url: &str,
-    registry: &Registry,
-    agent_name: &str,
+    registry: &dyn RegistrySource,
+    target_name: &str,
     content: serde_json::Value,
     meta: serde_json::Value,
-    role: &str,
-    envelope_type: &str,
-    timeout_ms: u64,
+    opts: &DelegateOptions,
 ) -> Result<Envelope> {
-    println!("[AG1_META] Delegating to agent: {}", agent_name);
-    println!("[AG1_META] Content: {}", serde_json::to_string_pretty(&content).unwrap_or_default());
-    println!("[AG1_META] Meta: {}", serde_json::to_string_pretty(&meta).unwrap_or_default());
-    println!("[AG1_meta] delegate_to_name_with_opts - Looking up agent: {}", agent_name);
+    eprintln!("[AG1_META] Delegating to agent: {} (as {})", target_name, opts.agent_name());
+    eprintln!("[AG1_meta] delegate_to_name_with_opts - Looking up agent: {}", target_name);
     
     // List all available agents for debugging
-    println!("[AG1_meta] Available agents in registry:");
-    for info in registry.list() {
-        println!("  - {} (inbox: {})", info.name, info.inbox);
+    eprintln!("[AG1_meta] Available agents in registry:");
+    for info in registry.list().await? {
+        eprintln!("  - {} (inbox: {})", info.name, info.inbox);
     }
     
     // Look up the agent in the registry
-    let info = registry.get(agent_name)
+    let info = registry.get(target_name).await?
         .ok_or_else(|| {
-            println!("[AG1_meta] ERROR: Unknown agent: {}", agent_name);
-            anyhow::anyhow!("unknown agent: {}", agent_name)
+            eprintln!("[AG1_meta] ERROR: Unknown agent: {}", target_name);
+            DelegateError::AgentNotFound(target_name.to_string())
         })?;
         
-    println!("[AG1_meta] Found agent: {} -> {}", agent_name, info.inbox);
+    eprintln!("[AG1_meta] Found agent: {} -> {}", target_name, info.inbox);
+    let opts = DelegateOptions { timeout_ms: info.timeout_ms(opts.timeout_ms), ..opts.clone() };
+    if let Some(endpoint) = &info.endpoint {
+        let timeout_ms = opts.context.timeout_within(target_name, opts.timeout_ms)?;
+        let mut env = delegate_envelope(
+            registry.goose_inbox(), target_name, opts.agent_name(), content, meta, &opts.role, &opts.envelope_type,
+        );
+        opts.apply(&mut env);
+        set_reply_deadline(&mut env, timeout_ms);
+        return http::invoke(endpoint, &env, timeout_ms).await;
+    }
     
-    delegate_with_opts(
-        redis_url, &info.inbox, &registry.goose_inbox, agent_name,
-        content, meta, role, envelope_type, timeout_ms
-    ).await
+    Ok(delegate_with_opts(redis_url, &info.inbox, registry.goose_inbox(), target_name, content, meta, &opts).await?)
 }
 
 
+/// Send `env` to the inbox of `target_name` and return the stream entry id,
+/// without waiting for (or listening to) any reply.
+pub async fn send_to_name(redis_url: &str, registry: &dyn RegistrySource, target_name: &str, env: &Envelope) -> Result<String> {
+    let info = registry.get(target_name).await?
+        .ok_or_else(|| DelegateError::AgentNotFound(target_name.to_string()))?;
+    let bus = Bus::new(redis_url)?;
+    let id = bus.send(&info.inbox, env).await?;
+    eprintln!("[AG1_meta] Sent {} to {} (cid={:?})", id, info.inbox, env.correlation_id);
+    Ok(id)
+}
+
+/// Send a prebuilt `env` (e.g. a [`delegate_envelope`] signed afterwards) to
+/// the inbox of `target_name` and wait for the reply `matcher` accepts on its `reply_to`.
+/// A `timeout_ms` of 0 uses the agent's [`AgentInfo::timeout_ms`]. Agents with
+/// an [`AgentInfo::endpoint`] are POSTed `env` instead.
+pub async fn delegate_envelope_to_name(
+    redis_url: &str,
+    registry: &dyn RegistrySource,
+    target_name: &str,
+    env: &Envelope,
+    timeout_ms: u64,
+    fail_fast: bool,
+    matcher: &ReplyMatcher,
+) -> Result<Envelope> {
+    let info = registry.get(target_name).await?
+        .ok_or_else(|| DelegateError::AgentNotFound(target_name.to_string()))?;
+    let timeout_ms = info.timeout_ms(timeout_ms);
+    if let Some(endpoint) = &info.endpoint {
+        return http::invoke(endpoint, env, timeout_ms).await;
+    }
+    let in_stream = env.reply_to.as_deref()
+        .ok_or_else(|| anyhow::anyhow!("envelope to {} has no reply_to", target_name))?;
+    let bus = Bus::new(redis_url)?;
+    if fail_fast {
+        ensure_consumer(&bus, &info.inbox).await?;
+    }
+    Ok(send_and_await_reply(&bus, &info.inbox, in_stream, target_name, env, timeout_ms, matcher).await?)
+}
+
 pub async fn delegate_to_name(
     redis_url: &str,
-    reg: &Registry,
+    reg: &dyn RegistrySource,
     target_name: &str,
     content: serde_json::Value,
     meta: serde_json::Value,
     timeout_ms: u64,
 ) -> Result<Envelope> {
-    println!("[AG1_meta] delegate_to_name - Looking up agent: {}", target_name);
+    eprintln!("[AG1_meta] delegate_to_name - Looking up agent: {}", target_name);
     
     // List all available agents for debugging
-    println!("[AG1_meta] Available agents in registry:");
-    for info in reg.list() {
-        println!("  - {} (inbox: {})", info.name, info.inbox);
+    eprintln!("[AG1_meta] Available agents in registry:");
+    for info in reg.list().await? {
+        eprintln!("  - {} (inbox: {})", info.name, info.inbox);
     }
     
-    let info = reg.get(target_name)
+    let info = reg.get(target_name).await?
         .ok_or_else(|| {
-            println!("[AG1_meta] ERROR: Unknown agent: {}", target_name);
-            anyhow::anyhow!("unknown agent: {}", target_name)
+            eprintln!("[AG1_meta] ERROR: Unknown agent: {}", target_name);
+            DelegateError::AgentNotFound(target_name.to_string())
         })?;
         
-    println!("[AG1_meta] Found agent: {} -> {}", target_name, info.inbox);
+    eprintln!("[AG1_meta] Found agent: {} -> {}", target_name, info.inbox);
+    let timeout_ms = info.timeout_ms(timeout_ms);
+    if let Some(endpoint) = &info.endpoint {
+        let env = delegate_envelope(reg.goose_inbox(), target_name, DEFAULT_AGENT_NAME, content, meta, "user", EnvelopeKind::Message.as_str());
+        return http::invoke(endpoint, &env, timeout_ms).await;
+    }
+    validate_inbox(&info.inbox)?;
     
-    delegate(redis_url, &info.inbox, &reg.goose_inbox, target_name, content, meta, timeout_ms).await
+    delegate(redis_url, &info.inbox, reg.goose_inbox(), target_name, content, meta, timeout_ms).await
 }
 
-pub async fn delegate_with_opts(
-    redis_url: &str,
-    out_stream: &str,
+/// Build the request envelope `delegate_with_opts` sends from `agent_name` to
+/// `target`, with a fresh correlation id (also used as the envelope id) and
+/// timestamp. Replies are expected on `in_stream`.
+pub fn delegate_envelope(
     in_stream: &str,
     target: &str,
+    agent_name: &str,
     content: serde_json::Value,
     meta: serde_json::Value,
     role: &str,
     envelope_type: &str,
-    timeout_ms: u64,
-) -> Result<Envelope> {
-    println!("[AG1_meta] delegate_with_opts - Starting delegation");
-    println!("  - redis_url: {}", redis_url);
-    println!("  - out_stream: {}", out_stream);
-    println!("  - in_stream: {}", in_stream);
-    println!("  - target: {}", target);
-    println!("  - content: {}", content);
-    println!("  - meta: {}", meta);
-    println!("  - role: {}", role);
-    println!("  - envelope_type: {}", envelope_type);
-    println!("  - timeout_ms: {}", timeout_ms);
-    println!("[AG1_meta] delegate_with_opts called with:");
-    println!("  - redis_url: {}", redis_url);
-    println!("  - out_stream: {}", out_stream);
-    println!("  - in_stream: {}", in_stream);
-    println!("  - target: {}", target);
-    println!("  - content: {}", content);
-    println!("  - meta: {}", meta);
-    println!("  - role: {}", role);
-    println!("  - envelope_type: {}", envelope_type);
-    println!("  - timeout_ms: {}", timeout_ms);
-    println!("[AG1_meta] delegate_with_opts - Starting");
-    println!("[AG1_meta]   redis_url: {}", redis_url);
-    println!("[AG1_meta]   out_stream: {}", out_stream);
-    println!("[AG1_meta]   in_stream: {}", in_stream);
-    println!("[AG1_meta]   target: {}", target);
-    println!("[AG1_meta]   content: {}", content);
-    println!("[AG1_meta]   role: {}", role);
-    println!("[AG1_meta]   envelope_type: {}", envelope_type);
-    println!("[AG1_meta]   timeout_ms: {}", timeout_ms);
-    println!("[AG1_meta] Creating new Bus instance");
-    let bus = Bus::new(redis_url)?;
-    println!("[AG1_meta] Bus instance created");
-    let group = "ag1_meta";
-    let consumer_id = Uuid::new_v4().to_string();
-    if let Err(e) = bus.create_consumer_group(in_stream, group).await {
-        println!("[AG1_meta] failed to create consumer group: {}", e);
-    }
+) -> Envelope {
     let cid = Uuid::new_v4().to_string();
     let now = Utc::now().to_rfc3339();
 
-    println!("[AG1_meta] Creating envelope");
     // Ensure content is properly formatted as an object with a text field
     let content = match content {
         Value::String(s) => json!({ "text": s }),
@@ -192,8 +416,7 @@ pub async fn delegate_with_opts(
     };
     // Double-check the format is correct
     let content = normalize_content(content);
-    println!("[AG1_meta] POST Normalisation Envelope content: {}", content);
-    
+
     // Ensure the content is an object with a text field
     let content = if let Value::Object(obj) = content {
         if !obj.contains_key("text") {
@@ -207,13 +430,12 @@ pub async fn delegate_with_opts(
         json!({ "text": content.to_string() })
     };
 
-    let env = Envelope {
+    Envelope {
         role: role.to_string(),
         content,
+        content_type: None,
         session_code: None,
-        agent_name: Some("ag1goose".into()),
-        //agent_name: Some(target.to_string()),
-        //agent_name: Some(env::var("AGENT_NAME").unwrap_or_else(|_| "ag1goose".to_string())),
+        agent_name: Some(agent_name.to_string()),
         usage: json!({}),
         billing_hint: None,
         trace: vec![],
@@ -232,44 +454,252 @@ pub async fn delegate_with_opts(
         consumer_group: None,
         consumer_id: None,
         delivery_count: None,
-    };
+        priority: None,
+    }
+}
 
-    println!("[AG1_meta] Sending envelope to stream: {}", out_stream);
-    println!("[AG1_meta] Envelope content: {:#?}", env);
-    match bus.send(out_stream, &env).await {
-        Ok(_) => println!("[AG1_meta] Envelope sent successfully"),
+/// Send to `out_stream` and wait up to `opts.timeout_ms` for the correlated reply on `in_stream`
+/// that `opts.matcher` accepts. Correlated envelopes it rejects are logged and skipped.
+///
+/// The envelope carries the user, task and trace id `opts.context` has, the
+/// `opts.budget` as `meta.budget`, and an `x-deadline` header for when this
+/// stops waiting. A `context` deadline shortens the timeout (see
+/// [`RequestContext::timeout_within`]); one that leaves no time fails with
+/// [`DelegateError::DeadlineExceeded`] before anything is sent.
+///
+/// With `opts.fail_fast`, the target inbox is checked for a consumer first and
+/// [`DelegateError::NoConsumer`] is returned instead of waiting out the timeout.
+#[tracing::instrument(
+    name = "ag1.delegate",
+    skip_all,
+    fields(target = %target, stream = %out_stream, correlation_id = tracing::field::Empty),
+)]
+pub async fn delegate_with_opts(
+    redis_url: &str,
+    out_stream: &str,
+    in_stream: &str,
+    target: &str,
+    content: serde_json::Value,
+    meta: serde_json::Value,
+    opts: &DelegateOptions,
+) -> Result<Envelope, DelegateError> {
+    eprintln!("[AG1_meta] delegate_with_opts - Starting delegation");
+    eprintln!("  - redis_url: {}", redis_url);
+    eprintln!("  - out_stream: {}", out_stream);
+    eprintln!("  - in_stream: {}", in_stream);
+    eprintln!("  - target: {}", target);
+    eprintln!("  - role: {}", opts.role);
+    eprintln!("  - envelope_type: {}", opts.envelope_type);
+    eprintln!("  - timeout_ms: {}", opts.timeout_ms);
+    eprintln!("[AG1_meta] delegate_with_opts called with:");
+    eprintln!("  - redis_url: {}", redis_url);
+    eprintln!("  - out_stream: {}", out_stream);
+    eprintln!("  - in_stream: {}", in_stream);
+    eprintln!("  - target: {}", target);
+    eprintln!("  - role: {}", opts.role);
+    eprintln!("  - envelope_type: {}", opts.envelope_type);
+    eprintln!("  - timeout_ms: {}", opts.timeout_ms);
+    eprintln!("[AG1_meta] delegate_with_opts - Starting");
+    eprintln!("[AG1_meta]   redis_url: {}", redis_url);
+    eprintln!("[AG1_meta]   out_stream: {}", out_stream);
+    eprintln!("[AG1_meta]   in_stream: {}", in_stream);
+    eprintln!("[AG1_meta]   target: {}", target);
+    eprintln!("[AG1_meta]   agent_name: {}", opts.agent_name());
+    eprintln!("[AG1_meta]   role: {}", opts.role);
+    eprintln!("[AG1_meta]   envelope_type: {}", opts.envelope_type);
+    eprintln!("[AG1_meta]   timeout_ms: {}", opts.timeout_ms);
+    eprintln!("[AG1_meta]   fail_fast: {}", opts.fail_fast);
+    let timeout_ms = opts.context.timeout_within(target, opts.timeout_ms)?;
+    eprintln!("[AG1_meta] Creating new Bus instance");
+    let bus = Bus::new(redis_url)?;
+    eprintln!("[AG1_meta] Bus instance created");
+    if opts.fail_fast {
+        ensure_consumer(&bus, out_stream).await?;
+    }
+    eprintln!("[AG1_meta] Creating envelope");
+    let mut env = delegate_envelope(in_stream, target, opts.agent_name(), content, meta, &opts.role, &opts.envelope_type);
+    opts.apply(&mut env);
+    set_reply_deadline(&mut env, timeout_ms);
+    send_and_await_reply(&bus, out_stream, in_stream, target, &env, timeout_ms, &opts.matcher).await
+}
+
+/// Send `env` to `out_stream` and wait up to `timeout_ms` for the reply to it
+/// on `in_stream` that `matcher` accepts.
+async fn send_and_await_reply(
+    bus: &Bus,
+    out_stream: &str,
+    in_stream: &str,
+    target: &str,
+    env: &Envelope,
+    timeout_ms: u64,
+    matcher: &ReplyMatcher,
+) -> Result<Envelope, DelegateError> {
+    let group = REPLY_GROUP;
+    let consumer_id = Uuid::new_v4().to_string();
+    // At the end: replies come after the request, and earlier traffic isn't ours
+    if let Err(e) = bus.create_consumer_group_at(in_stream, group, &StartPos::Latest).await {
+        eprintln!("[AG1_meta] failed to create consumer group: {}", e);
+    }
+    let cid = env.correlation_id.clone().unwrap_or_default();
+    tracing::Span::current().record("correlation_id", cid.as_str());
+
+    eprintln!("[AG1_meta] Sending envelope to stream: {}", out_stream);
+    eprintln!("[AG1_meta] Envelope: {:#}", env.redacted(RedactionPolicy::global()));
+    match bus.send(out_stream, env).await {
+        Ok(_) => eprintln!("[AG1_meta] Envelope sent successfully"),
         Err(e) => {
-            println!("[ERROR] Failed to send envelope: {}", e);
+            eprintln!("[ERROR] Failed to send envelope: {}", e);
             return Err(e.into());
         }
     }
 
     let start = std::time::Instant::now();
-    let slice_ms: u64 = 800;
 
     loop {
         let elapsed = start.elapsed().as_millis() as u64;
         if elapsed >= timeout_ms {
-            bail!("no reply within {} ms (cid={})", timeout_ms, cid);
+            return Err(DelegateError::Timeout { agent: target.to_string(), waited_ms: elapsed, cid });
         }
-        let block = slice_ms.min(timeout_ms - elapsed);
+        let block = RECV_SLICE_MS.min(timeout_ms - elapsed);
 
-        if let Some(reply) = bus
+        if let Some(delivery) = bus
             .recv_block_group(in_stream, group, &consumer_id, block)
             .await?
         {
-            if reply.correlation_id.as_deref() == Some(&cid) {
-                if let Some(id) = &reply.envelope_id {
-                    let _ = bus.ack_message(in_stream, group, id).await;
+            let _ = delivery.ack().await;
+            let reply = delivery.envelope;
+            match matcher.check(&reply, &cid, target) {
+                ReplyMatch::Accept => return Ok(reply),
+                ReplyMatch::Unrelated => {}
+                ReplyMatch::Mismatch(reason) => {
+                    tracing::warn!(envelope = %reply, %reason, "Skipping correlated envelope");
+                    eprintln!("[AG1_meta] Skipping {}: {}", reply, reason);
                 }
-                return Ok(reply);
-            } else if let Some(id) = &reply.envelope_id {
-                let _ = bus.ack_message(in_stream, group, id).await;
             }
         }
     }
 }
 
+/// Delegate to `target_name` as `opts` says and hand every correlated reply to `on_reply` until one
+/// with `envelope_type` `"stream_end"` or `"error"` arrives (it is handed over too).
+/// `opts.matcher` isn't consulted: every correlated reply is part of the stream.
+///
+/// Fails with [`DelegateError::Timeout`] if the end marker has not arrived within
+/// `opts.timeout_ms`, even if some replies were already streamed. A `timeout_ms`
+/// of 0 uses the agent's [`AgentInfo::timeout_ms`]; a `context` deadline shortens it.
+#[tracing::instrument(
+    name = "ag1.delegate_streaming",
+    skip_all,
+    fields(target = %target_name, stream = tracing::field::Empty, correlation_id = tracing::field::Empty),
+)]
+pub async fn delegate_streaming<F>(
+    redis_url: &str,
+    registry: &dyn RegistrySource,
+    target_name: &str,
+    content: serde_json::Value,
+    meta: serde_json::Value,
+    opts: &DelegateOptions,
+    mut on_reply: F,
+) -> Result<()>
+where
+    F: FnMut(&Envelope) -> Result<()>,
+{
+    let info = registry.get(target_name).await?
+        .ok_or_else(|| DelegateError::AgentNotFound(target_name.to_string()))?;
+    let timeout_ms = opts.context.timeout_within(target_name, info.timeout_ms(opts.timeout_ms))?;
+    let in_stream = registry.goose_inbox();
+
+    let bus = Bus::new(redis_url)?;
+    let group = REPLY_GROUP;
+    let consumer_id = Uuid::new_v4().to_string();
+    // At the end: replies come after the request, and earlier traffic isn't ours
+    if let Err(e) = bus.create_consumer_group_at(in_stream, group, &StartPos::Latest).await {
+        eprintln!("[AG1_meta] failed to create consumer group: {}", e);
+    }
+    if opts.fail_fast {
+        ensure_consumer(&bus, &info.inbox).await?;
+    }
+
+    let mut env = delegate_envelope(
+        in_stream, target_name, opts.agent_name(), content, meta, &opts.role, &opts.envelope_type,
+    );
+    opts.apply(&mut env);
+    set_reply_deadline(&mut env, timeout_ms);
+    let cid = env.correlation_id.clone().unwrap_or_default();
+    tracing::Span::current()
+        .record("stream", info.inbox.as_str())
+        .record("correlation_id", cid.as_str());
+    eprintln!("[AG1_meta] Streaming delegation to {} (cid={})", info.inbox, cid);
+    bus.send(&info.inbox, &env).await?;
+
+    let start = std::time::Instant::now();
+    loop {
+        let elapsed = start.elapsed().as_millis() as u64;
+        if elapsed >= timeout_ms {
+            return Err(DelegateError::Timeout { agent: target_name.to_string(), waited_ms: elapsed, cid }.into());
+        }
+        let block = RECV_SLICE_MS.min(timeout_ms - elapsed);
+
+        let Some(delivery) = bus.recv_block_group(in_stream, group, &consumer_id, block).await? else {
+            continue;
+        };
+        let _ = delivery.ack().await;
+        let reply = delivery.envelope;
+        if reply.correlation_id.as_deref() != Some(&cid) {
+            continue;
+        }
+        on_reply(&reply)?;
+        if matches!(reply.kind(), Some(EnvelopeKind::StreamEnd | EnvelopeKind::Error)) {
+            return Ok(());
+        }
+    }
+}
+
+/// What [`delegate_collect`] gathered.
+#[derive(Debug, Clone, Default)]
+pub struct PartialResult {
+    pub correlation_id: String,
+    /// Every correlated reply, in arrival order
+    pub envelopes: Vec<Envelope>,
+    /// The deadline passed before a `stream_end` or `error` arrived. Replies
+    /// arriving later stay on the reply stream, for [`wait_replies`] to pick up.
+    pub timed_out: bool,
+}
+
+/// [`delegate_streaming`], collecting the replies. Running out of time isn't
+/// an error here: whatever arrived comes back with `timed_out` set, so a
+/// nearly finished answer can still be used.
+pub async fn delegate_collect(
+    redis_url: &str,
+    registry: &dyn RegistrySource,
+    target_name: &str,
+    content: serde_json::Value,
+    meta: serde_json::Value,
+    opts: &DelegateOptions,
+) -> Result<PartialResult> {
+    let mut envelopes = Vec::new();
+    let streamed = delegate_streaming(
+        redis_url, registry, target_name, content, meta, opts,
+        |reply| {
+            envelopes.push(reply.clone());
+            Ok(())
+        },
+    )
+    .await;
+    match streamed {
+        Ok(()) => Ok(PartialResult {
+            correlation_id: envelopes.first().and_then(|e| e.correlation_id.clone()).unwrap_or_default(),
+            envelopes,
+            timed_out: false,
+        }),
+        Err(e) => match e.downcast::<DelegateError>() {
+            Ok(DelegateError::Timeout { cid, .. }) => Ok(PartialResult { correlation_id: cid, envelopes, timed_out: true }),
+            Ok(other) => Err(other.into()),
+            Err(e) => Err(e),
+        },
+    }
+}
+
 pub async fn delegate(
     redis_url: &str,
     out_stream: &str,
@@ -279,20 +709,42 @@ pub async fn delegate(
     meta: serde_json::Value,
     timeout_ms: u64,
 ) -> Result<Envelope> {
-    delegate_with_opts(
-        redis_url, out_stream, in_stream, target,
-        content, meta, "user", "message", timeout_ms
-    ).await
+    let opts = DelegateOptions { timeout_ms, ..Default::default() };
+    Ok(delegate_with_opts(redis_url, out_stream, in_stream, target, content, meta, &opts).await?)
 }
 
-/*
-#[allow(dead_code)]
-fn valid_stream(s: &str) -> bool {
-    // AG1:<class>:<id...>:inbox
-    let parts: Vec<&str> = s.split(':').collect();
-    parts.len() >= 4 &&
-    parts[0] == "AG1" &&
-    matches!(parts[1], "agent" | "service" | "edge") &&
-    parts.last() == Some(&"inbox")
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// The Redis the end-to-end tests run against, from `AG1_TEST_REDIS_URL`.
+    /// Without it those tests return early.
+    pub(crate) fn test_redis_url() -> Option<String> {
+        let url = std::env::var("AG1_TEST_REDIS_URL").ok().filter(|url| !url.is_empty());
+        if url.is_none() {
+            eprintln!("AG1_TEST_REDIS_URL is not set, skipping");
+        }
+        url
+    }
+
+    #[test]
+    fn options_put_their_context_and_budget_on_the_request() {
+        let mut env = delegate_envelope(
+            "AG1:test:replies", "Echo", "tester", json!({ "text": "hi" }),
+            json!({ "budget": { "max_tool_calls": 9 }, "lang": "en" }), "user", "message",
+        );
+        let opts = DelegateOptions {
+            context: RequestContext { user_id: Some("u-1".into()), ..Default::default() },
+            budget: Some(Budget { max_output_tokens: Some(500), ..Default::default() }),
+            ..Default::default()
+        };
+        opts.apply(&mut env);
+        assert_eq!(env.user_id.as_deref(), Some("u-1"));
+        assert_eq!(env.meta, json!({ "budget": { "max_output_tokens": 500 }, "lang": "en" }));
+
+        // Without a budget, one already in meta is left alone
+        let before = env.meta.clone();
+        DelegateOptions::default().apply(&mut env);
+        assert_eq!(env.meta, before);
+    }
 }
-*/
\ No newline at end of file
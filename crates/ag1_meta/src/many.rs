@@ -0,0 +1,270 @@
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use bus::{Bus, EnvelopeKind};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{delegate_envelope, wait_replies, DelegateError, RegistrySource, WaitMode};
+
+/// One delegation for [`delegate_many`].
+#[derive(Debug, Clone)]
+pub struct DelegateRequest {
+    pub target: String,
+    pub content: Value,
+    pub meta: Value,
+    /// 0 uses the agent's [`crate::AgentInfo::timeout_ms`]
+    pub timeout_ms: u64,
+}
+
+/// How one [`DelegateRequest`] went.
+#[derive(Debug, Clone, Serialize)]
+pub struct DelegateOutcome {
+    pub target: String,
+    /// `None` when the request was never sent
+    pub correlation_id: Option<String>,
+    /// True for a reply that isn't an `error` envelope
+    pub ok: bool,
+    /// From sending the request to its reply, timeout or failure
+    pub elapsed_ms: u64,
+    /// The reply's content, error replies included
+    pub content: Option<Value>,
+    pub error: Option<String>,
+}
+
+/// A request sent and not yet answered.
+struct InFlight {
+    index: usize,
+    target: String,
+    cid: String,
+    timeout_ms: u64,
+    started: Instant,
+    deadline: Instant,
+}
+
+impl InFlight {
+    /// Record how this request went in its slot of `outcomes`.
+    fn settle(self, outcomes: &mut [Option<DelegateOutcome>], content: Option<Value>, error: Option<String>) {
+        outcomes[self.index] = Some(DelegateOutcome {
+            target: self.target,
+            correlation_id: Some(self.cid),
+            ok: error.is_none(),
+            elapsed_ms: self.started.elapsed().as_millis() as u64,
+            content,
+            error,
+        });
+    }
+}
+
+/// Delegate each of `requests` as `agent_name`, with at most `max_parallel`
+/// of them awaiting a reply at once, and return how each went in request order.
+///
+/// Every reply is collected from the registry's reply stream by
+/// [`wait_replies`], so the whole batch shares one reader and one consumer
+/// group. A request that can't be sent, times out or is answered with an
+/// error only fails its own [`DelegateOutcome`].
+pub async fn delegate_many(
+    redis_url: &str,
+    registry: &dyn RegistrySource,
+    agent_name: &str,
+    requests: Vec<DelegateRequest>,
+    max_parallel: usize,
+) -> Result<Vec<DelegateOutcome>> {
+    let bus = Bus::new(redis_url)?;
+    let in_stream = registry.goose_inbox();
+    let mut outcomes: Vec<Option<DelegateOutcome>> = vec![None; requests.len()];
+    let mut queue = requests.into_iter().enumerate();
+    let mut in_flight: Vec<InFlight> = Vec::new();
+
+    loop {
+        while in_flight.len() < max_parallel.max(1) {
+            let Some((index, req)) = queue.next() else { break };
+            let started = Instant::now();
+            match send_request(&bus, registry, agent_name, in_stream, &req).await {
+                Ok((cid, timeout_ms)) => in_flight.push(InFlight {
+                    index,
+                    target: req.target,
+                    cid,
+                    timeout_ms,
+                    started,
+                    deadline: started + Duration::from_millis(timeout_ms),
+                }),
+                Err(e) => {
+                    eprintln!("[AG1_meta] Could not delegate to {}: {}", req.target, e);
+                    outcomes[index] = Some(DelegateOutcome {
+                        target: req.target,
+                        correlation_id: None,
+                        ok: false,
+                        elapsed_ms: started.elapsed().as_millis() as u64,
+                        content: None,
+                        error: Some(e.to_string()),
+                    });
+                }
+            }
+        }
+        let Some(next_deadline) = in_flight.iter().map(|f| f.deadline).min() else { break };
+
+        let cids: Vec<String> = in_flight.iter().map(|f| f.cid.clone()).collect();
+        let wait_ms = next_deadline.saturating_duration_since(Instant::now()).as_millis() as u64;
+        let replies = match wait_replies(&bus, &cids, in_stream, WaitMode::Any(1), wait_ms).await {
+            Ok(replies) => replies,
+            Err(e) => {
+                for f in in_flight.drain(..) {
+                    f.settle(&mut outcomes, None, Some(e.to_string()));
+                }
+                continue;
+            }
+        };
+        for (cid, reply) in replies {
+            let Some(reply) = reply else { continue };
+            let Some(pos) = in_flight.iter().position(|f| f.cid == cid) else { continue };
+            let f = in_flight.swap_remove(pos);
+            let error = (reply.kind() == Some(EnvelopeKind::Error)).then(|| reply.text_or_empty().to_string());
+            f.settle(&mut outcomes, Some(reply.content), error);
+        }
+
+        let now = Instant::now();
+        let (expired, waiting): (Vec<_>, Vec<_>) = in_flight.into_iter().partition(|f| f.deadline <= now);
+        in_flight = waiting;
+        for f in expired {
+            let error = DelegateError::Timeout { agent: f.target.clone(), waited_ms: f.timeout_ms, cid: f.cid.clone() }.to_string();
+            f.settle(&mut outcomes, None, Some(error));
+        }
+    }
+
+    Ok(outcomes.into_iter().map(|o| o.expect("every request has an outcome")).collect())
+}
+
+/// Send `req` with replies on `in_stream`, giving its correlation id and reply timeout.
+async fn send_request(
+    bus: &Bus,
+    registry: &dyn RegistrySource,
+    agent_name: &str,
+    in_stream: &str,
+    req: &DelegateRequest,
+) -> Result<(String, u64)> {
+    let info = registry.get(&req.target).await?
+        .ok_or_else(|| DelegateError::AgentNotFound(req.target.clone()))?;
+    let env = delegate_envelope(
+        in_stream,
+        &req.target,
+        agent_name,
+        req.content.clone(),
+        req.meta.clone(),
+        "user",
+        EnvelopeKind::Message.as_str(),
+    );
+    let id = bus.send(&info.inbox, &env).await?;
+    let cid = env.correlation_id.unwrap_or_default();
+    eprintln!("[AG1_meta] Sent {} to {} (cid={})", id, info.inbox, cid);
+    Ok((cid, info.timeout_ms(req.timeout_ms)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use bus::Envelope;
+    use serde_json::json;
+
+    use super::*;
+    use crate::tests::test_redis_url;
+    use crate::{AgentInfo, Registry};
+
+    /// Answers every envelope on `inbox` with its own content after `delay_ms`,
+    /// each on a task of its own; text "fail" gets an error reply. `busy`
+    /// counts the envelopes being answered, `most_busy` its highest value.
+    fn spawn_echo_agent(redis_url: String, inbox: String, delay_ms: u64, busy: Arc<AtomicUsize>, most_busy: Arc<AtomicUsize>) {
+        tokio::spawn(async move {
+            let bus = Bus::new(&redis_url).unwrap();
+            let mut last_id = "0".to_string();
+            loop {
+                let env = match bus.recv_block(&inbox, &last_id, 1000).await {
+                    Ok(Some(entry)) => {
+                        last_id = entry.id;
+                        match entry.envelope {
+                            Some(env) => env,
+                            None => continue,
+                        }
+                    }
+                    Ok(None) => continue,
+                    Err(_) => {
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                };
+                let (bus, busy, most_busy) = (bus.clone(), busy.clone(), most_busy.clone());
+                tokio::spawn(async move {
+                    most_busy.fetch_max(busy.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+                    busy.fetch_sub(1, Ordering::SeqCst);
+                    let mut reply: Envelope = env.clone();
+                    reply.role = "assistant".into();
+                    reply.agent_name = Some("Echo".into());
+                    reply.envelope_id = None;
+                    let kind = if env.try_get_text() == Some("fail") { EnvelopeKind::Error } else { EnvelopeKind::MessageReply };
+                    reply.set_kind(kind);
+                    bus.send(env.reply_to.as_deref().unwrap(), &reply).await.unwrap();
+                });
+            }
+        });
+    }
+
+    /// "Echo" answered after `delay_ms`, and "Gone", whose inbox nobody reads.
+    fn registry(redis_url: &str, delay_ms: u64) -> (Registry, Arc<AtomicUsize>) {
+        let id = uuid::Uuid::new_v4();
+        let inbox = format!("AG1:test:echo:{id}:inbox");
+        let agents = vec![
+            AgentInfo { name: "Echo".into(), inbox: inbox.clone(), ..Default::default() },
+            AgentInfo { name: "Gone".into(), inbox: format!("AG1:test:gone:{id}:inbox"), ..Default::default() },
+        ];
+        let most_busy = Arc::new(AtomicUsize::new(0));
+        spawn_echo_agent(redis_url.into(), inbox, delay_ms, Arc::new(AtomicUsize::new(0)), most_busy.clone());
+        (Registry::from_agents(agents, format!("AG1:test:echo:{id}:replies")), most_busy)
+    }
+
+    fn request(target: &str, text: &str, timeout_ms: u64) -> DelegateRequest {
+        DelegateRequest { target: target.into(), content: json!({ "text": text }), meta: json!({}), timeout_ms }
+    }
+
+    #[tokio::test]
+    async fn failures_stay_with_their_own_request() {
+        let Some(redis_url) = test_redis_url() else { return };
+        let (reg, _) = registry(&redis_url, 0);
+        let requests = vec![
+            request("Echo", "one", 5000),
+            request("Gone", "two", 1000),
+            request("Nobody", "three", 5000),
+            request("Echo", "fail", 5000),
+            request("Echo", "five", 5000),
+        ];
+        let outcomes = delegate_many(&redis_url, &reg, "tester", requests, 4).await.unwrap();
+
+        let oks: Vec<bool> = outcomes.iter().map(|o| o.ok).collect();
+        assert_eq!(oks, [true, false, false, false, true]);
+        assert_eq!(outcomes[0].content.as_ref().unwrap()["text"], "one");
+        assert_eq!(outcomes[4].content.as_ref().unwrap()["text"], "five");
+        assert!(outcomes[1].error.as_deref().unwrap().contains("no reply within 1000 ms"));
+        assert!(outcomes[1].elapsed_ms >= 1000);
+        assert_eq!(outcomes[2].error.as_deref(), Some("unknown agent: Nobody"));
+        assert!(outcomes[2].correlation_id.is_none());
+        assert_eq!(outcomes[3].error.as_deref(), Some("fail"));
+        assert_eq!(outcomes[3].content.as_ref().unwrap()["text"], "fail");
+    }
+
+    #[tokio::test]
+    async fn no_more_than_max_parallel_are_outstanding() {
+        let Some(redis_url) = test_redis_url() else { return };
+        let (reg, most_busy) = registry(&redis_url, 300);
+        let requests = (0..6).map(|i| request("Echo", &i.to_string(), 5000)).collect();
+        let started = Instant::now();
+        let outcomes = delegate_many(&redis_url, &reg, "tester", requests, 2).await.unwrap();
+
+        assert!(outcomes.iter().all(|o| o.ok), "{outcomes:?}");
+        let texts: Vec<&Value> = outcomes.iter().map(|o| &o.content.as_ref().unwrap()["text"]).collect();
+        assert_eq!(texts, ["0", "1", "2", "3", "4", "5"]);
+        assert_eq!(most_busy.load(Ordering::SeqCst), 2);
+        assert!(started.elapsed() >= Duration::from_millis(900));
+    }
+}
@@ -1,7 +1,14 @@
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fs, path::Path};
+use std::{collections::{BTreeMap, HashMap, HashSet}, fmt, fs, path::Path, str::FromStr};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+use bus::{escape_glob, Bus, Capabilities};
+
+use crate::validate_inbox;
+
+/// Registry fields held as plain text in an agent's Redis hash; the rest are JSON.
+const TEXT_FIELDS: &[&str] = &["target_inbox", "description", "connector_type", "endpoint"];
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct AgentInfo {
     pub name: String,
     pub inbox: String,
@@ -13,50 +20,219 @@ pub struct AgentInfo {
     pub connector_details: serde_json::Value,
     #[serde(default)]
     pub capabilities_keywords: Vec<String>,
+    /// Reply timeout for delegations that pass a `timeout_ms` of 0
+    #[serde(default)]
+    pub default_timeout_ms: Option<u64>,
+    /// Base URL of an agent served over HTTP: delegations by name POST the
+    /// envelope to `{endpoint}/invoke` instead of sending it to `inbox`
+    #[serde(default)]
+    pub endpoint: Option<String>,
+    /// Free-form metadata, e.g. `{"owner": "team-ai", "env": "prod"}`
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
+}
+
+/// Where agent records come from: a [`Registry`] loaded from the map file, or
+/// a [`crate::registry_service::RemoteRegistry`] asking the registry service.
+/// The `delegate*` functions resolve agent names through one.
+#[async_trait::async_trait]
+pub trait RegistrySource: Send + Sync {
+    /// Every agent, sorted by name.
+    async fn list(&self) -> anyhow::Result<Vec<AgentInfo>>;
+    async fn get(&self, name: &str) -> anyhow::Result<Option<AgentInfo>>;
+    async fn find_by_capability(&self, keyword: &str) -> anyhow::Result<Vec<AgentInfo>>;
+    /// Stream delegations ask for their replies on
+    fn goose_inbox(&self) -> &str;
+}
+
+/// Whether a registry file is combined with the agents that announced
+/// themselves on the bus (see [`bus::capabilities`]).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Discovery {
+    /// The file and the announced agents; the file wins where names match
+    Merge,
+    /// The file alone
+    #[default]
+    Static,
+    /// The announced agents alone
+    DiscoveredOnly,
+}
+
+impl FromStr for Discovery {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "merge" => Ok(Self::Merge),
+            "static" => Ok(Self::Static),
+            "discovered-only" => Ok(Self::DiscoveredOnly),
+            other => anyhow::bail!("discovery must be \"merge\", \"static\" or \"discovered-only\", not {other:?}"),
+        }
+    }
+}
+
+impl fmt::Display for Discovery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Merge => "merge",
+            Self::Static => "static",
+            Self::DiscoveredOnly => "discovered-only",
+        })
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Registry {
+    /// Keyed by lowercased name, so lookups ignore case; each
+    /// [`AgentInfo::name`] keeps the casing it was registered with.
     by_name: HashMap<String, AgentInfo>,
+    /// Lowercased names of the agents added by [`Self::merge_discovered`]
+    discovered: HashSet<String>,
     pub goose_inbox: String,
 }
 
 impl Registry {
-    /// Load your **map-shaped** JSON and derive AgentInfo rows.
+    /// Load your **map-shaped** JSON and derive AgentInfo rows. Fails on the
+    /// first agent whose inbox doesn't follow the [`crate::InboxScheme`], and
+    /// on names that differ only in case.
     pub fn load_map<P: AsRef<Path>>(path: P, goose_inbox: impl Into<String>) -> anyhow::Result<Self> {
         let text = fs::read_to_string(path)?;
         let raw: HashMap<String, serde_json::Value> = serde_json::from_str(&text)?;
 
-        let mut by_name = HashMap::new();
+        let mut reg = Self { by_name: HashMap::new(), discovered: HashSet::new(), goose_inbox: goose_inbox.into() };
         for (name, v) in raw {
-            let inbox = v.get("target_inbox")
-                .and_then(|s| s.as_str())
-                .ok_or_else(|| anyhow::anyhow!("agent {name} missing target_inbox"))?
-                .to_string();
-
-            let description = v.get("description").and_then(|s| s.as_str()).map(|s| s.to_string());
-            let connector_type = v.get("connector_type").and_then(|s| s.as_str()).map(|s| s.to_string());
-            let connector_details = v.get("connector_details").cloned().unwrap_or_default();
-            let capabilities_keywords = v.get("capabilities_keywords")
-                .and_then(|a| a.as_array())
-                .map(|a| a.iter().filter_map(|x| x.as_str().map(|s| s.to_string())).collect())
-                .unwrap_or_default();
-
-            let info = AgentInfo {
-                name: name.clone(),
-                inbox,
-                description,
-                connector_type,
-                connector_details,
-                capabilities_keywords,
-            };
-            by_name.insert(name, info);
-        }
-
-        Ok(Self {
-            by_name,
-            goose_inbox: goose_inbox.into(),
-        })
+            let info = agent_from_value(&name, &v)?;
+            validate_inbox(&info.inbox).map_err(|e| anyhow::anyhow!("agent {name}: {e}"))?;
+            reg.insert_unique(info)?;
+        }
+        Ok(reg)
+    }
+
+    /// The registry `discovery` asks for: the map file at `path` (see
+    /// [`Self::load_map`]), the agents announced on `stream` of `bus` (see
+    /// [`Bus::discover_capabilities`]), or both. Only [`Discovery::Static`]
+    /// leaves Redis alone, and only [`Discovery::DiscoveredOnly`] doesn't
+    /// read the file.
+    pub async fn load_with_discovery<P: AsRef<Path>>(
+        path: P,
+        goose_inbox: impl Into<String>,
+        discovery: Discovery,
+        bus: &Bus,
+        stream: &str,
+    ) -> anyhow::Result<Self> {
+        let mut reg = match discovery {
+            Discovery::Static => return Self::load_map(path, goose_inbox),
+            Discovery::Merge => Self::load_map(path, goose_inbox)?,
+            Discovery::DiscoveredOnly => Self::from_agents(Vec::new(), goose_inbox),
+        };
+        let announced = bus.discover_capabilities(stream).await?;
+        reg.merge_discovered(announced.into_iter().map(AgentInfo::from).collect());
+        Ok(reg)
+    }
+
+    /// Load the agents that registered themselves in Redis as hashes at
+    /// `{key_prefix}:{name}`, one field per key of a map-file entry (see
+    /// [`Self::sync_to_redis`]). Fails on the first unreadable agent or
+    /// inbox that doesn't follow the [`crate::InboxScheme`], and on names
+    /// that differ only in case.
+    pub async fn load_from_redis(bus: &Bus, key_prefix: &str, goose_inbox: &str) -> anyhow::Result<Self> {
+        let mut reg = Self { by_name: HashMap::new(), discovered: HashSet::new(), goose_inbox: goose_inbox.into() };
+        let prefix = format!("{key_prefix}:");
+        for key in bus.scan_keys(&format!("{}*", escape_glob(&prefix))).await? {
+            let Some(name) = key.strip_prefix(&prefix) else { continue };
+            let fields = bus.hash_get_all(&key).await?;
+            let info = agent_from_fields(name, fields)?;
+            validate_inbox(&info.inbox).map_err(|e| anyhow::anyhow!("agent {name}: {e}"))?;
+            reg.insert_unique(info)?;
+        }
+        Ok(reg)
+    }
+
+    /// Write every agent to Redis as the hash at `{key_prefix}:{name}`, for
+    /// [`Self::load_from_redis`]: `target_inbox`, `description` and
+    /// `connector_type` as text, the other map-file keys as JSON. Each hash
+    /// is replaced whole; agents in Redis but not here are left alone.
+    pub async fn sync_to_redis(&self, bus: &Bus, key_prefix: &str) -> anyhow::Result<()> {
+        for info in self.list() {
+            bus.hash_replace(&format!("{key_prefix}:{}", info.name), &agent_to_fields(info)).await?;
+        }
+        Ok(())
+    }
+
+    /// Build a registry in memory. A later agent replaces an earlier one with the same name.
+    pub fn from_agents(agents: Vec<AgentInfo>, goose_inbox: impl Into<String>) -> Self {
+        let mut reg = Self { by_name: HashMap::new(), discovered: HashSet::new(), goose_inbox: goose_inbox.into() };
+        for agent in agents {
+            reg.insert(agent);
+        }
+        reg
+    }
+
+    /// Every agent of both registries, `other`'s winning where names match,
+    /// e.g. `Registry::load_map(..)?.merge(Registry::load_from_redis(..).await?)`.
+    /// The `goose_inbox` is `self`'s.
+    pub fn merge(mut self, other: Registry) -> Registry {
+        for (key, agent) in other.by_name {
+            self.insert(agent);
+            if other.discovered.contains(&key) {
+                self.discovered.insert(key);
+            }
+        }
+        self
+    }
+
+    /// Add agents that announced themselves, e.g. from
+    /// [`Bus::discover_capabilities`]. Agents already registered otherwise
+    /// are kept as they are, so the registry file wins over an announcement;
+    /// earlier announcements are replaced. Records with an inbox that doesn't
+    /// follow the [`crate::InboxScheme`] are skipped.
+    pub fn merge_discovered(&mut self, records: Vec<AgentInfo>) {
+        for info in records {
+            let key = info.name.to_lowercase();
+            if self.by_name.contains_key(&key) && !self.discovered.contains(&key) {
+                continue;
+            }
+            if let Err(e) = validate_inbox(&info.inbox) {
+                eprintln!("[AG1_meta] Skipping discovered agent {}: {}", info.name, e);
+                continue;
+            }
+            self.by_name.insert(key.clone(), info);
+            self.discovered.insert(key);
+        }
+    }
+
+    /// Whether `name` (ignoring case) came from [`Self::merge_discovered`]
+    /// rather than the registry file or Redis.
+    pub fn is_discovered(&self, name: &str) -> bool {
+        self.discovered.contains(&name.to_lowercase())
+    }
+
+    /// Add `agent`, returning the one it replaces: names match ignoring case.
+    pub fn insert(&mut self, agent: AgentInfo) -> Option<AgentInfo> {
+        let key = agent.name.to_lowercase();
+        self.discovered.remove(&key);
+        self.by_name.insert(key, agent)
+    }
+
+    /// [`Self::insert`] for loading, where an agent whose name differs from
+    /// one already loaded only in case would silently replace it.
+    fn insert_unique(&mut self, agent: AgentInfo) -> anyhow::Result<()> {
+        if let Some(other) = self.by_name.get(&agent.name.to_lowercase()) {
+            let mut names = [other.name.as_str(), agent.name.as_str()];
+            names.sort();
+            anyhow::bail!("agents {} and {} differ only in case", names[0], names[1]);
+        }
+        self.insert(agent);
+        Ok(())
+    }
+
+    /// Write the agents back as a **map-shaped** JSON file that [`Self::load_map`] reads.
+    pub fn save_map<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<()> {
+        let map: BTreeMap<&str, serde_json::Value> = self.by_name.values()
+            .map(|info| (info.name.as_str(), agent_to_value(info)))
+            .collect();
+        fs::write(path, serde_json::to_string_pretty(&map)? + "\n")?;
+        Ok(())
     }
 
     pub fn list(&self) -> Vec<&AgentInfo> {
@@ -65,7 +241,582 @@ impl Registry {
         v
     }
 
+    /// The agent called `name`, ignoring case.
     pub fn get(&self, name: &str) -> Option<&AgentInfo> {
-        self.by_name.get(name)
+        self.by_name.get(&name.to_lowercase())
+    }
+
+    /// Agents listing `keyword` among their `capabilities_keywords`, ignoring
+    /// ASCII case, sorted by name.
+    pub fn find_by_capability(&self, keyword: &str) -> Vec<&AgentInfo> {
+        self.list()
+            .into_iter()
+            .filter(|a| a.capabilities_keywords.iter().any(|k| k.eq_ignore_ascii_case(keyword)))
+            .collect()
+    }
+
+    /// Agents tagged `key` = `value` (both matched exactly), sorted by name.
+    pub fn find_by_tag(&self, key: &str, value: &str) -> Vec<&AgentInfo> {
+        self.list()
+            .into_iter()
+            .filter(|a| a.tags.get(key).is_some_and(|v| v == value))
+            .collect()
+    }
+
+    /// Validate every entry of a map-shaped registry file without stopping at
+    /// the first bad one. Reports are sorted by agent name.
+    pub fn check_map<P: AsRef<Path>>(path: P) -> anyhow::Result<Vec<EntryReport>> {
+        let text = fs::read_to_string(path)?;
+        let raw: HashMap<String, serde_json::Value> = serde_json::from_str(&text)?;
+
+        let mut reports: Vec<EntryReport> = raw
+            .iter()
+            .map(|(name, v)| {
+                let line = key_line(&text, name);
+                match agent_from_value(name, v) {
+                    Ok(info) => EntryReport { name: name.clone(), line, issues: info.validate(), info: Some(info) },
+                    Err(e) => EntryReport { name: name.clone(), line, info: None, issues: vec![Issue::error(e.to_string())] },
+                }
+            })
+            .collect();
+        reports.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(reports)
+    }
+}
+
+#[async_trait::async_trait]
+impl RegistrySource for Registry {
+    async fn list(&self) -> anyhow::Result<Vec<AgentInfo>> {
+        Ok(Registry::list(self).into_iter().cloned().collect())
+    }
+
+    async fn get(&self, name: &str) -> anyhow::Result<Option<AgentInfo>> {
+        Ok(Registry::get(self, name).cloned())
+    }
+
+    async fn find_by_capability(&self, keyword: &str) -> anyhow::Result<Vec<AgentInfo>> {
+        Ok(Registry::find_by_capability(self, keyword).into_iter().cloned().collect())
+    }
+
+    fn goose_inbox(&self) -> &str {
+        &self.goose_inbox
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Warning,
+    /// The agent can't be delegated to
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Issue {
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Issue {
+    pub fn warning(message: impl Into<String>) -> Self {
+        Self { severity: Severity::Warning, message: message.into() }
+    }
+
+    pub fn error(message: impl Into<String>) -> Self {
+        Self { severity: Severity::Error, message: message.into() }
+    }
+}
+
+/// Result of checking one registry entry.
+#[derive(Debug, Clone)]
+pub struct EntryReport {
+    pub name: String,
+    /// 1-based line of the entry's key in the registry file
+    pub line: Option<usize>,
+    /// `None` when the entry couldn't be read as an agent at all
+    pub info: Option<AgentInfo>,
+    pub issues: Vec<Issue>,
+}
+
+impl EntryReport {
+    pub fn is_valid(&self) -> bool {
+        self.issues.iter().all(|i| i.severity != Severity::Error)
+    }
+}
+
+/// The agent an announcement describes. What it says about the envelopes it
+/// reads goes in `connector_details`.
+impl From<Capabilities> for AgentInfo {
+    fn from(caps: Capabilities) -> Self {
+        let mut details = serde_json::json!({
+            "envelope_types": caps.envelope_types,
+            "schema_versions": caps.schema_versions,
+        });
+        if let Some(bytes) = caps.max_payload_bytes {
+            details["max_payload_bytes"] = bytes.into();
+        }
+        AgentInfo {
+            name: caps.name,
+            inbox: caps.inbox,
+            description: caps.description,
+            connector_type: Some("bus".into()),
+            connector_details: details,
+            capabilities_keywords: caps.capabilities_keywords,
+            ..Default::default()
+        }
+    }
+}
+
+impl AgentInfo {
+    /// The reply timeout for a delegation asking for `requested` ms: itself,
+    /// unless it is 0, which means this agent's `default_timeout_ms` (or
+    /// [`crate::DEFAULT_TIMEOUT_MS`] when the registry gives none).
+    pub fn timeout_ms(&self, requested: u64) -> u64 {
+        match requested {
+            0 => self.default_timeout_ms.unwrap_or(crate::DEFAULT_TIMEOUT_MS),
+            ms => ms,
+        }
+    }
+
+    /// Problems with this record. Any `Severity::Error` makes it unusable.
+    pub fn validate(&self) -> Vec<Issue> {
+        let mut issues = Vec::new();
+        if self.name.trim().is_empty() {
+            issues.push(Issue::error("name is empty"));
+        }
+        if self.inbox.trim().is_empty() {
+            issues.push(Issue::error("inbox is empty"));
+        } else if let Err(e) = validate_inbox(&self.inbox) {
+            issues.push(Issue::error(e.to_string()));
+        }
+        if let Some(endpoint) = &self.endpoint {
+            if !endpoint.starts_with("http://") && !endpoint.starts_with("https://") {
+                issues.push(Issue::error(format!("endpoint {endpoint} is not an http(s) URL")));
+            }
+        }
+        if self.description.as_deref().unwrap_or("").trim().is_empty() {
+            issues.push(Issue::warning("no description"));
+        }
+        issues
+    }
+}
+
+fn agent_from_value(name: &str, v: &serde_json::Value) -> anyhow::Result<AgentInfo> {
+    let inbox = v.get("target_inbox")
+        .and_then(|s| s.as_str())
+        .ok_or_else(|| anyhow::anyhow!("agent {name} missing target_inbox"))?
+        .to_string();
+
+    let description = v.get("description").and_then(|s| s.as_str()).map(|s| s.to_string());
+    let connector_type = v.get("connector_type").and_then(|s| s.as_str()).map(|s| s.to_string());
+    let connector_details = v.get("connector_details").cloned().unwrap_or_default();
+    let capabilities_keywords = v.get("capabilities_keywords")
+        .and_then(|a| a.as_array())
+        .map(|a| a.iter().filter_map(|x| x.as_str().map(|s| s.to_string())).collect())
+        .unwrap_or_default();
+    let default_timeout_ms = match v.get("default_timeout_ms") {
+        None | Some(serde_json::Value::Null) => None,
+        Some(t) => Some(t.as_u64().filter(|t| *t > 0).ok_or_else(|| {
+            anyhow::anyhow!("agent {name}: default_timeout_ms must be a positive number of milliseconds, got {t}")
+        })?),
+    };
+
+    let endpoint = v.get("endpoint").and_then(|s| s.as_str()).map(|s| s.to_string());
+    let tags = match v.get("tags") {
+        None | Some(serde_json::Value::Null) => HashMap::new(),
+        Some(serde_json::Value::Object(map)) => map
+            .iter()
+            .map(|(key, tag)| match tag.as_str() {
+                Some(tag) => Ok((key.clone(), tag.to_string())),
+                None => Err(anyhow::anyhow!("agent {name}: tag {key} must be a string, got {tag}")),
+            })
+            .collect::<anyhow::Result<_>>()?,
+        Some(other) => anyhow::bail!("agent {name}: tags must be an object of strings, got {other}"),
+    };
+
+    Ok(AgentInfo {
+        name: name.to_string(),
+        inbox,
+        description,
+        connector_type,
+        connector_details,
+        capabilities_keywords,
+        default_timeout_ms,
+        endpoint,
+        tags,
+    })
+}
+
+/// Inverse of [`agent_from_value`]; fields at their defaults are left out.
+fn agent_to_value(info: &AgentInfo) -> serde_json::Value {
+    let mut v = serde_json::json!({ "target_inbox": info.inbox });
+    if let Some(description) = &info.description {
+        v["description"] = description.as_str().into();
+    }
+    if let Some(connector_type) = &info.connector_type {
+        v["connector_type"] = connector_type.as_str().into();
+    }
+    if !info.connector_details.is_null() {
+        v["connector_details"] = info.connector_details.clone();
+    }
+    if !info.capabilities_keywords.is_empty() {
+        v["capabilities_keywords"] = info.capabilities_keywords.clone().into();
+    }
+    if let Some(timeout_ms) = info.default_timeout_ms {
+        v["default_timeout_ms"] = timeout_ms.into();
+    }
+    if let Some(endpoint) = &info.endpoint {
+        v["endpoint"] = endpoint.as_str().into();
+    }
+    if !info.tags.is_empty() {
+        v["tags"] = serde_json::json!(info.tags);
+    }
+    v
+}
+
+/// An agent's Redis hash fields: [`agent_to_value`]'s keys, [`TEXT_FIELDS`]
+/// as they are and the rest as JSON.
+fn agent_to_fields(info: &AgentInfo) -> Vec<(String, String)> {
+    let serde_json::Value::Object(map) = agent_to_value(info) else { unreachable!() };
+    map.into_iter()
+        .map(|(key, v)| match v {
+            serde_json::Value::String(s) if TEXT_FIELDS.contains(&key.as_str()) => (key, s),
+            v => (key, v.to_string()),
+        })
+        .collect()
+}
+
+/// Inverse of [`agent_to_fields`].
+fn agent_from_fields(name: &str, fields: HashMap<String, String>) -> anyhow::Result<AgentInfo> {
+    let mut map = serde_json::Map::new();
+    for (key, text) in fields {
+        let v = if TEXT_FIELDS.contains(&key.as_str()) {
+            serde_json::Value::String(text)
+        } else {
+            serde_json::from_str(&text).map_err(|e| anyhow::anyhow!("agent {name}: {key} is not JSON: {e}"))?
+        };
+        map.insert(key, v);
+    }
+    agent_from_value(name, &serde_json::Value::Object(map))
+}
+
+/// Line on which `"name":` first appears, as a best-effort pointer into the file.
+fn key_line(text: &str, name: &str) -> Option<usize> {
+    let key = format!("\"{name}\"");
+    text.lines().position(|line| {
+        line.find(&key)
+            .is_some_and(|at| line[at + key.len()..].trim_start().starts_with(':'))
+    }).map(|i| i + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_redis_url;
+
+    fn agent(name: &str, inbox: &str) -> AgentInfo {
+        AgentInfo { name: name.into(), inbox: inbox.into(), ..Default::default() }
+    }
+
+    #[test]
+    fn registry_built_in_memory_resolves_agents() {
+        let mut reg = Registry::from_agents(
+            vec![agent("Echo", "AG1:agent:Echo:inbox"), agent("Search", "AG1:agent:Search:inbox")],
+            "AG1:agent:GooseAgent:inbox",
+        );
+        assert_eq!(reg.get("Echo").map(|a| a.inbox.as_str()), Some("AG1:agent:Echo:inbox"));
+        assert!(reg.get("Missing").is_none());
+        assert_eq!(reg.goose_inbox, "AG1:agent:GooseAgent:inbox");
+
+        let replaced = reg.insert(agent("Echo", "AG1:agent:Echo2:inbox"));
+        assert_eq!(replaced.map(|a| a.inbox), Some("AG1:agent:Echo:inbox".to_string()));
+        let names: Vec<_> = reg.list().iter().map(|a| a.name.as_str()).collect();
+        assert_eq!(names, ["Echo", "Search"]);
+        assert_eq!(reg.get("Echo").unwrap().inbox, "AG1:agent:Echo2:inbox");
+    }
+
+    #[test]
+    fn merged_registries_prefer_the_other_on_conflicts() {
+        let file = Registry::from_agents(
+            vec![agent("Echo", "AG1:agent:Echo:inbox"), agent("Search", "AG1:agent:Search:inbox")],
+            "AG1:agent:GooseAgent:inbox",
+        );
+        let redis = Registry::from_agents(
+            vec![agent("Search", "AG1:agent:Search2:inbox"), agent("Summarize", "AG1:agent:Summarize:inbox")],
+            "AG1:agent:Other:inbox",
+        );
+
+        let merged = file.merge(redis);
+        let names: Vec<_> = merged.list().iter().map(|a| a.name.as_str()).collect();
+        assert_eq!(names, ["Echo", "Search", "Summarize"]);
+        assert_eq!(merged.get("Search").unwrap().inbox, "AG1:agent:Search2:inbox");
+        assert_eq!(merged.get("Echo").unwrap().inbox, "AG1:agent:Echo:inbox");
+        assert_eq!(merged.goose_inbox, "AG1:agent:GooseAgent:inbox");
+    }
+
+    #[test]
+    fn discovered_agents_never_replace_registered_ones() {
+        let mut reg = Registry::from_agents(vec![agent("Echo", "AG1:agent:Echo:inbox")], "AG1:agent:GooseAgent:inbox");
+        reg.merge_discovered(vec![
+            agent("echo", "AG1:agent:Impostor:inbox"),
+            agent("Search", "AG1:agent:Search:inbox"),
+            agent("Bad", "nowhere"),
+        ]);
+        assert_eq!(reg.get("Echo").unwrap().inbox, "AG1:agent:Echo:inbox");
+        assert!(!reg.is_discovered("Echo"));
+        assert!(reg.is_discovered("search"));
+        assert!(reg.get("Bad").is_none());
+
+        // A later announcement replaces an earlier one, and registering the agent ends it
+        reg.merge_discovered(vec![agent("Search", "AG1:agent:Search2:inbox")]);
+        assert_eq!(reg.get("Search").unwrap().inbox, "AG1:agent:Search2:inbox");
+        reg.insert(agent("Search", "AG1:agent:Search:inbox"));
+        assert!(!reg.is_discovered("Search"));
+
+        let caps = Capabilities {
+            name: "Summarize".into(),
+            inbox: "AG1:agent:Summarize:inbox".into(),
+            capabilities_keywords: vec!["summaries".into()],
+            envelope_types: vec!["ping".into(), "message".into()],
+            max_payload_bytes: Some(1024),
+            ..Default::default()
+        };
+        reg.merge_discovered(vec![caps.into()]);
+        let found = reg.find_by_capability("summaries");
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].connector_details["envelope_types"], serde_json::json!(["ping", "message"]));
+        assert_eq!(found[0].connector_details["max_payload_bytes"], 1024);
+    }
+
+    #[test]
+    fn discovery_modes_parse_from_their_names() {
+        for mode in [Discovery::Merge, Discovery::Static, Discovery::DiscoveredOnly] {
+            assert_eq!(mode.to_string().parse::<Discovery>().unwrap(), mode);
+        }
+        assert!("both".parse::<Discovery>().is_err());
+    }
+
+    #[test]
+    fn names_are_looked_up_ignoring_case() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("registry.json");
+        let map = serde_json::json!({
+            "GooseAgent": { "target_inbox": "AG1:agent:GooseAgent:inbox" },
+            "echo": { "target_inbox": "AG1:agent:echo:inbox" }
+        });
+        fs::write(&path, map.to_string()).unwrap();
+
+        let mut reg = Registry::load_map(&path, "AG1:agent:GooseAgent:inbox").unwrap();
+        for name in ["GooseAgent", "gooseagent", "GOOSEAGENT"] {
+            assert_eq!(reg.get(name).map(|a| a.name.as_str()), Some("GooseAgent"), "{name}");
+        }
+        assert_eq!(reg.get("Echo").map(|a| a.name.as_str()), Some("echo"));
+
+        // Names keep their casing, and one differing only in case replaces it
+        let replaced = reg.insert(agent("ECHO", "AG1:agent:ECHO:inbox"));
+        assert_eq!(replaced.map(|a| a.name), Some("echo".to_string()));
+        let names: Vec<_> = reg.list().iter().map(|a| a.name.as_str()).collect();
+        assert_eq!(names, ["ECHO", "GooseAgent"]);
+        reg.save_map(&path).unwrap();
+        let saved: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert!(saved.get("ECHO").is_some() && saved.get("GooseAgent").is_some(), "{saved}");
+    }
+
+    #[test]
+    fn save_map_round_trips_what_load_map_reads() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("registry.json");
+        let original = serde_json::json!({
+            "Search": {
+                "target_inbox": "AG1:agent:Search:inbox",
+                "description": "Web search",
+                "connector_type": "mcp",
+                "connector_details": { "url": "http://search", "headers": { "x-key": 1 }, "tags": [null, 2.5] },
+                "capabilities_keywords": ["search", "web"],
+                "ignored_extra": true
+            }
+        });
+        fs::write(&path, original.to_string()).unwrap();
+
+        let mut reg = Registry::load_map(&path, "AG1:agent:GooseAgent:inbox").unwrap();
+        reg.insert(agent("Echo", "AG1:agent:Echo:inbox"));
+        reg.save_map(&path).unwrap();
+        let reloaded = Registry::load_map(&path, "AG1:agent:GooseAgent:inbox").unwrap();
+
+        assert_eq!(reloaded.list(), reg.list());
+        assert_eq!(
+            reloaded.get("Search").unwrap().connector_details,
+            original["Search"]["connector_details"]
+        );
+    }
+
+    #[test]
+    fn agent_timeouts_come_from_the_registry_when_unset() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("registry.json");
+        let map = serde_json::json!({
+            "Slow": { "target_inbox": "AG1:agent:Slow:inbox", "default_timeout_ms": 120000 },
+            "Fast": { "target_inbox": "AG1:agent:Fast:inbox", "default_timeout_ms": 5000 },
+            "Plain": { "target_inbox": "AG1:agent:Plain:inbox" }
+        });
+        fs::write(&path, map.to_string()).unwrap();
+
+        let reg = Registry::load_map(&path, "AG1:agent:GooseAgent:inbox").unwrap();
+        assert_eq!(reg.get("Slow").unwrap().timeout_ms(0), 120_000);
+        assert_eq!(reg.get("Fast").unwrap().timeout_ms(0), 5_000);
+        assert_eq!(reg.get("Plain").unwrap().timeout_ms(0), crate::DEFAULT_TIMEOUT_MS);
+        // An explicit timeout wins over the configured one
+        assert_eq!(reg.get("Slow").unwrap().timeout_ms(1_000), 1_000);
+
+        reg.save_map(&path).unwrap();
+        let reloaded = Registry::load_map(&path, "AG1:agent:GooseAgent:inbox").unwrap();
+        assert_eq!(reloaded.list(), reg.list());
+
+        fs::write(&path, serde_json::json!({
+            "Bad": { "target_inbox": "AG1:agent:Bad:inbox", "default_timeout_ms": "soon" }
+        }).to_string()).unwrap();
+        let err = Registry::load_map(&path, "AG1:agent:GooseAgent:inbox").unwrap_err();
+        assert!(err.to_string().contains("default_timeout_ms"), "{err}");
+    }
+
+    #[test]
+    fn endpoints_must_be_http_urls() {
+        let web = |endpoint: &str| AgentInfo {
+            description: Some("Web agent".into()),
+            endpoint: Some(endpoint.into()),
+            ..agent("Web", "AG1:agent:Web:inbox")
+        };
+        assert_eq!(web("https://agents.example/web").validate(), []);
+        let issues = web("agents.example/web").validate();
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].severity, Severity::Error);
+        assert!(issues[0].message.contains("agents.example/web"), "{issues:?}");
+    }
+
+    #[test]
+    fn agents_are_found_by_tag() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("registry.json");
+        let map = serde_json::json!({
+            "Search": { "target_inbox": "AG1:agent:Search:inbox", "tags": { "owner": "team-ai", "env": "prod" } },
+            "Echo": { "target_inbox": "AG1:agent:Echo:inbox", "tags": { "owner": "team-ai", "env": "dev" } },
+            "Plain": { "target_inbox": "AG1:agent:Plain:inbox" }
+        });
+        fs::write(&path, map.to_string()).unwrap();
+
+        let reg = Registry::load_map(&path, "AG1:agent:GooseAgent:inbox").unwrap();
+        let names = |found: Vec<&AgentInfo>| found.iter().map(|a| a.name.clone()).collect::<Vec<_>>();
+        assert_eq!(names(reg.find_by_tag("owner", "team-ai")), ["Echo", "Search"]);
+        assert_eq!(names(reg.find_by_tag("env", "prod")), ["Search"]);
+        assert!(reg.find_by_tag("env", "Prod").is_empty());
+        assert!(reg.find_by_tag("team-ai", "owner").is_empty());
+        assert!(reg.get("Plain").unwrap().tags.is_empty());
+
+        reg.save_map(&path).unwrap();
+        assert_eq!(Registry::load_map(&path, "AG1:agent:GooseAgent:inbox").unwrap().list(), reg.list());
+
+        fs::write(&path, serde_json::json!({
+            "Bad": { "target_inbox": "AG1:agent:Bad:inbox", "tags": { "sla_ms": 500 } }
+        }).to_string()).unwrap();
+        let err = Registry::load_map(&path, "AG1:agent:GooseAgent:inbox").unwrap_err();
+        assert!(err.to_string().contains("tag sla_ms must be a string"), "{err}");
+    }
+
+    #[test]
+    fn names_that_differ_only_in_case_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("registry.json");
+        fs::write(&path, serde_json::json!({
+            "Foo": { "target_inbox": "AG1:agent:Foo:inbox" },
+            "foo": { "target_inbox": "AG1:agent:foo:inbox" }
+        }).to_string()).unwrap();
+        let err = Registry::load_map(&path, "AG1:agent:GooseAgent:inbox").unwrap_err();
+        assert_eq!(err.to_string(), "agents Foo and foo differ only in case");
+    }
+
+    fn search_agent() -> AgentInfo {
+        AgentInfo {
+            description: Some("42".into()),
+            connector_type: Some("mcp".into()),
+            connector_details: serde_json::json!({ "url": "http://search", "tags": [null, 2.5] }),
+            capabilities_keywords: vec!["search".into(), "web".into()],
+            default_timeout_ms: Some(90_000),
+            endpoint: Some("http://search:8080".into()),
+            tags: HashMap::from([("owner".into(), "team-ai".into()), ("sla_ms".into(), "500".into())]),
+            ..agent("Search", "AG1:agent:Search:inbox")
+        }
+    }
+
+    #[test]
+    fn redis_hash_fields_round_trip() {
+        let info = search_agent();
+        let fields = agent_to_fields(&info);
+        let text = |key: &str| fields.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str());
+        assert_eq!(text("target_inbox"), Some("AG1:agent:Search:inbox"));
+        assert_eq!(text("description"), Some("42"));
+        assert_eq!(text("capabilities_keywords"), Some(r#"["search","web"]"#));
+        assert_eq!(text("default_timeout_ms"), Some("90000"));
+        assert_eq!(text("endpoint"), Some("http://search:8080"));
+        let tags: serde_json::Value = serde_json::from_str(text("tags").unwrap()).unwrap();
+        assert_eq!(tags, serde_json::json!({ "owner": "team-ai", "sla_ms": "500" }));
+        assert_eq!(agent_from_fields("Search", fields.into_iter().collect()).unwrap(), info);
+
+        let bare = agent("Echo", "AG1:agent:Echo:inbox");
+        assert_eq!(agent_to_fields(&bare), [("target_inbox".to_string(), bare.inbox.clone())]);
+
+        let bad = HashMap::from([
+            ("target_inbox".to_string(), "AG1:agent:Bad:inbox".to_string()),
+            ("capabilities_keywords".to_string(), "search, web".to_string()),
+        ]);
+        let err = agent_from_fields("Bad", bad).unwrap_err();
+        assert!(err.to_string().contains("capabilities_keywords is not JSON"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn agents_registered_in_redis_are_loaded_back() {
+        let Some(redis_url) = test_redis_url() else { return };
+        let bus = Bus::new(&redis_url).unwrap();
+        let prefix = format!("ag1:test:registry:{}", uuid::Uuid::new_v4());
+        let reg = Registry::from_agents(
+            vec![search_agent(), agent("Echo", "AG1:agent:Echo:inbox")],
+            "AG1:agent:GooseAgent:inbox",
+        );
+        reg.sync_to_redis(&bus, &prefix).await.unwrap();
+
+        let loaded = Registry::load_from_redis(&bus, &prefix, "AG1:agent:Other:inbox").await.unwrap();
+        assert_eq!(loaded.list(), reg.list());
+        assert_eq!(loaded.goose_inbox, "AG1:agent:Other:inbox");
+
+        bus.hash_replace(&format!("{prefix}:Bad"), &[("target_inbox".into(), "nowhere".into())]).await.unwrap();
+        let err = Registry::load_from_redis(&bus, &prefix, "AG1:agent:Other:inbox").await.unwrap_err();
+        assert!(err.to_string().contains("agent Bad"), "{err}");
+        for name in ["Search", "Echo", "Bad"] {
+            bus.hash_replace(&format!("{prefix}:{name}"), &[]).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn announced_agents_are_loaded_in_merge_mode() {
+        let Some(redis_url) = test_redis_url() else { return };
+        let bus = Bus::new(&redis_url).unwrap();
+        let stream = format!("ag1:test:capabilities:{}", uuid::Uuid::new_v4());
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("registry.json");
+        let map = serde_json::json!({ "Echo": { "target_inbox": "AG1:agent:Echo:inbox" } });
+        fs::write(&path, map.to_string()).unwrap();
+        for (name, inbox) in [("Echo", "AG1:agent:Impostor:inbox"), ("Search", "AG1:agent:Search:inbox")] {
+            let caps = Capabilities { name: name.into(), inbox: inbox.into(), ..Default::default() };
+            bus.announce_capabilities(&stream, &caps).await.unwrap();
+        }
+
+        let load = |discovery| Registry::load_with_discovery(&path, "AG1:agent:GooseAgent:inbox", discovery, &bus, &stream);
+        let reg = load(Discovery::Merge).await.unwrap();
+        assert_eq!(reg.get("Search").map(|a| a.inbox.as_str()), Some("AG1:agent:Search:inbox"));
+        assert!(reg.is_discovered("Search"));
+        assert_eq!(reg.get("Echo").unwrap().inbox, "AG1:agent:Echo:inbox");
+        assert!(!reg.is_discovered("Echo"));
+
+        assert!(load(Discovery::Static).await.unwrap().get("Search").is_none());
+        let only = load(Discovery::DiscoveredOnly).await.unwrap();
+        assert_eq!(only.get("Echo").unwrap().inbox, "AG1:agent:Impostor:inbox");
     }
 }
@@ -0,0 +1,293 @@
+//! The agent registry over the bus, for processes without the registry file.
+//!
+//! [`run`] answers `control` envelopes on the service inbox whose
+//! `content.command` is `list`, `describe` (with a `name`) or `find` (with a
+//! capability `keyword`). The `control_reply` carries `agents`, or `agent`
+//! (`null` for an unknown name); anything else gets an `error` reply.
+//! [`RemoteRegistry`] is the client side.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use bus::{Bus, Envelope, EnvelopeKind};
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::{AgentInfo, Registry, RegistrySource, ReplyMatcher};
+
+/// Well-known inbox of the registry service.
+pub const REGISTRY_SERVICE_INBOX: &str = "AG1:service:Registry:inbox";
+
+/// `agent_name` the service answers as.
+pub const SERVICE_NAME: &str = "Registry";
+
+/// How long [`RemoteRegistry`] reuses an answer unless told otherwise.
+pub const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(10);
+
+const SERVICE_GROUP: &str = "registry-service";
+const SERVICE_BLOCK_MS: u64 = 5_000;
+const REQUEST_TIMEOUT_MS: u64 = 5_000;
+/// How long a [`RemoteRegistry`]'s reply stream outlives its last request.
+const REPLY_STREAM_TTL: Duration = Duration::from_secs(600);
+
+/// Answer registry requests arriving on `inbox` from `registry`. Only
+/// returns when reading the inbox fails.
+pub async fn run(bus: &Bus, registry: &Registry, inbox: &str) -> Result<()> {
+    let consumer = Uuid::new_v4().to_string();
+    tracing::info!(inbox, agents = registry.list().len(), "registry service started");
+
+    loop {
+        let Some(delivery) = bus.ensure_group_and_recv(inbox, SERVICE_GROUP, &consumer, SERVICE_BLOCK_MS).await? else {
+            continue;
+        };
+        let request = &delivery.envelope;
+        match request.reply_to.as_deref() {
+            Some(reply_to) => {
+                if let Err(e) = bus.send(reply_to, &answer(registry, request)).await {
+                    tracing::warn!(%request, error = %e, "failed to answer registry request");
+                }
+            }
+            None => tracing::warn!(%request, "registry request has no reply_to, dropped"),
+        }
+        if let Err(e) = delivery.ack().await {
+            tracing::warn!(id = %delivery.entry_id, error = %e, "failed to ack registry request");
+        }
+    }
+}
+
+/// The reply [`run`] sends to `request`.
+pub fn answer(registry: &Registry, request: &Envelope) -> Envelope {
+    let mut reply = match respond(registry, request) {
+        Ok(content) => {
+            let mut reply = Envelope {
+                role: "assistant".into(),
+                content,
+                target: request.agent_name.clone(),
+                reply_to: request.reply_to.clone(),
+                timestamp: Some(chrono::Utc::now().to_rfc3339()),
+                envelope_id: Some(Uuid::new_v4().to_string()),
+                correlation_id: request.correlation_id.clone(),
+                ..Default::default()
+            };
+            reply.set_kind(EnvelopeKind::ControlReply);
+            reply
+        }
+        Err(e) => request.clone().into_error_reply(&e.to_string()),
+    };
+    reply.agent_name = Some(SERVICE_NAME.into());
+    reply
+}
+
+fn respond(registry: &Registry, request: &Envelope) -> Result<Value> {
+    if request.kind() != Some(EnvelopeKind::Control) {
+        return Err(anyhow!("registry requests are control envelopes, got {:?}", request.envelope_type));
+    }
+    let command = request.content.get("command").and_then(Value::as_str).unwrap_or_default();
+    let arg = |key: &str| {
+        request.content
+            .get(key)
+            .and_then(Value::as_str)
+            .ok_or_else(|| anyhow!("registry command {command:?} needs a {key}"))
+    };
+
+    let mut content = match command {
+        "list" => json!({ "agents": registry.list() }),
+        "describe" => json!({ "agent": registry.get(arg("name")?) }),
+        "find" => json!({ "agents": registry.find_by_capability(arg("keyword")?) }),
+        other => return Err(anyhow!("unknown registry command {other:?}")),
+    };
+    content["command"] = json!(command);
+    Ok(content)
+}
+
+/// [`RegistrySource`] asking a registry service over the bus. Answers are
+/// reused for the cache TTL, so each distinct lookup costs at most one round
+/// trip per TTL. Replies come back on a stream of the client's own, which
+/// expires once the client has gone quiet.
+pub struct RemoteRegistry {
+    bus: Bus,
+    service_inbox: String,
+    reply_to: String,
+    reply_ttl: Duration,
+    goose_inbox: String,
+    cache_ttl: Duration,
+    cache: Mutex<HashMap<String, (Instant, Value)>>,
+}
+
+impl RemoteRegistry {
+    pub fn new(bus: Bus, service_inbox: impl Into<String>) -> Self {
+        Self {
+            bus,
+            service_inbox: service_inbox.into(),
+            reply_to: format!("AG1:edge:registry-client:{}:inbox", Uuid::new_v4()),
+            reply_ttl: REPLY_STREAM_TTL,
+            goose_inbox: crate::DEFAULT_GOOSE_INBOX.into(),
+            cache_ttl: DEFAULT_CACHE_TTL,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Stream delegations made through this registry ask for their replies on.
+    pub fn with_goose_inbox(mut self, inbox: impl Into<String>) -> Self {
+        self.goose_inbox = inbox.into();
+        self
+    }
+
+    pub fn with_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.cache_ttl = ttl;
+        self
+    }
+
+    /// The service's answer to the request with `content`, from the cache
+    /// when the same request was answered within the TTL.
+    async fn request(&self, content: Value) -> Result<Value> {
+        let key = content.to_string();
+        let cached = self.cache.lock().unwrap()
+            .get(&key)
+            .filter(|(at, _)| at.elapsed() < self.cache_ttl)
+            .map(|(_, answer)| answer.clone());
+        if let Some(answer) = cached {
+            return Ok(answer);
+        }
+
+        let env = crate::delegate_envelope(
+            &self.reply_to, SERVICE_NAME, crate::DEFAULT_AGENT_NAME, content, json!({}), "user",
+            EnvelopeKind::Control.as_str(),
+        );
+        let matcher = ReplyMatcher::default().with_accept_types(vec![EnvelopeKind::ControlReply, EnvelopeKind::Error]);
+        let reply = crate::send_and_await_reply(
+            &self.bus, &self.service_inbox, &self.reply_to, SERVICE_NAME, &env, REQUEST_TIMEOUT_MS, &matcher,
+        ).await;
+        // Nothing else reads the reply stream; have it go away with the client
+        if let Err(e) = self.bus.expire(&self.reply_to, self.reply_ttl).await {
+            tracing::warn!(stream = %self.reply_to, error = %e, "failed to set expiry on registry reply stream");
+        }
+        let reply = reply?;
+        if reply.kind() == Some(EnvelopeKind::Error) {
+            return Err(anyhow!("registry service: {}", reply.text_or_empty()));
+        }
+
+        self.cache.lock().unwrap().insert(key, (Instant::now(), reply.content.clone()));
+        Ok(reply.content)
+    }
+}
+
+#[async_trait::async_trait]
+impl RegistrySource for RemoteRegistry {
+    async fn list(&self) -> Result<Vec<AgentInfo>> {
+        let mut answer = self.request(json!({ "command": "list" })).await?;
+        Ok(serde_json::from_value(answer["agents"].take())?)
+    }
+
+    async fn get(&self, name: &str) -> Result<Option<AgentInfo>> {
+        let mut answer = self.request(json!({ "command": "describe", "name": name })).await?;
+        Ok(serde_json::from_value(answer["agent"].take())?)
+    }
+
+    async fn find_by_capability(&self, keyword: &str) -> Result<Vec<AgentInfo>> {
+        let mut answer = self.request(json!({ "command": "find", "keyword": keyword })).await?;
+        Ok(serde_json::from_value(answer["agents"].take())?)
+    }
+
+    fn goose_inbox(&self) -> &str {
+        &self.goose_inbox
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_redis_url;
+
+    fn fixture() -> Registry {
+        let agent = |name: &str, keywords: &[&str]| AgentInfo {
+            name: name.into(),
+            inbox: format!("AG1:agent:{name}:inbox"),
+            capabilities_keywords: keywords.iter().map(|k| k.to_string()).collect(),
+            ..Default::default()
+        };
+        Registry::from_agents(
+            vec![agent("Search", &["search", "web"]), agent("Echo", &["echo"])],
+            crate::DEFAULT_GOOSE_INBOX,
+        )
+    }
+
+    fn request(content: Value) -> Envelope {
+        crate::delegate_envelope(
+            "AG1:edge:tester:inbox", SERVICE_NAME, "tester", content, json!({}), "user",
+            EnvelopeKind::Control.as_str(),
+        )
+    }
+
+    #[test]
+    fn answer_covers_each_command() {
+        let reg = fixture();
+
+        let reply = answer(&reg, &request(json!({ "command": "list" })));
+        assert_eq!(reply.kind(), Some(EnvelopeKind::ControlReply));
+        assert_eq!(reply.agent_name.as_deref(), Some(SERVICE_NAME));
+        assert_eq!(reply.target.as_deref(), Some("tester"));
+        let names: Vec<_> = reply.content["agents"].as_array().unwrap().iter().map(|a| a["name"].clone()).collect();
+        assert_eq!(names, [json!("Echo"), json!("Search")]);
+
+        let reply = answer(&reg, &request(json!({ "command": "describe", "name": "Echo" })));
+        assert_eq!(reply.content["agent"]["inbox"], json!("AG1:agent:Echo:inbox"));
+        let reply = answer(&reg, &request(json!({ "command": "describe", "name": "Nobody" })));
+        assert_eq!(reply.content["agent"], Value::Null);
+
+        let reply = answer(&reg, &request(json!({ "command": "find", "keyword": "WEB" })));
+        assert_eq!(reply.content["agents"][0]["name"], json!("Search"));
+        assert_eq!(reply.content["agents"].as_array().unwrap().len(), 1);
+
+        for bad in [json!({ "command": "describe" }), json!({ "command": "reload" })] {
+            let reply = answer(&reg, &request(bad));
+            assert_eq!(reply.kind(), Some(EnvelopeKind::Error));
+        }
+        let mut message = request(json!({ "command": "list" }));
+        message.set_kind(EnvelopeKind::Message);
+        assert_eq!(answer(&reg, &message).kind(), Some(EnvelopeKind::Error));
+    }
+
+    #[tokio::test]
+    async fn remote_registry_reads_through_the_service() {
+        let Some(redis_url) = test_redis_url() else { return };
+        let bus = Bus::new(&redis_url).unwrap();
+        let inbox = format!("AG1:service:Registry:test-{}:inbox", Uuid::new_v4());
+        let service = tokio::spawn({
+            let (bus, inbox) = (bus.clone(), inbox.clone());
+            async move { run(&bus, &fixture(), &inbox).await }
+        });
+
+        let remote = RemoteRegistry::new(bus.clone(), &inbox).with_cache_ttl(Duration::from_millis(500));
+        let names: Vec<_> = remote.list().await.unwrap().into_iter().map(|a| a.name).collect();
+        assert_eq!(names, ["Echo", "Search"]);
+        assert_eq!(remote.get("Echo").await.unwrap().unwrap().inbox, "AG1:agent:Echo:inbox");
+        assert_eq!(remote.get("Nobody").await.unwrap(), None);
+        assert_eq!(remote.find_by_capability("search").await.unwrap()[0].name, "Search");
+        assert_eq!(bus.xlen(&inbox).await.unwrap(), 4);
+
+        // Repeats within the TTL don't reach the service; after it they do
+        remote.list().await.unwrap();
+        assert_eq!(bus.xlen(&inbox).await.unwrap(), 4);
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        remote.list().await.unwrap();
+        assert_eq!(bus.xlen(&inbox).await.unwrap(), 5);
+
+        let err = crate::delegate_to_name(&redis_url, &remote, "Nobody", json!("hi"), json!({}), 0)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("unknown agent: Nobody"), "{err}");
+
+        // The reply stream is gone once the client stops asking
+        let mut quiet = RemoteRegistry::new(bus.clone(), &inbox);
+        quiet.reply_ttl = Duration::from_millis(100);
+        quiet.list().await.unwrap();
+        assert!(bus.xlen(&quiet.reply_to).await.unwrap() > 0);
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(bus.xlen(&quiet.reply_to).await.unwrap(), 0);
+
+        service.abort();
+    }
+}
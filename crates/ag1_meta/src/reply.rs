@@ -0,0 +1,103 @@
+use bus::{Envelope, EnvelopeKind};
+
+/// Envelope types a strict [`ReplyMatcher`] accepts as the answer to a delegation.
+pub const DEFAULT_REPLY_TYPES: &[EnvelopeKind] = &[EnvelopeKind::MessageReply, EnvelopeKind::Error];
+
+/// Decides which envelope on the reply stream answers a delegation.
+///
+/// Some agents echo the inbound correlation id on notifications they
+/// broadcast, so by default a matching id is not enough: the envelope type
+/// must be an accepted reply type and the sender, when named, the target.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplyMatcher {
+    /// Envelope types accepted as the answer; `None` accepts any
+    pub accept_types: Option<Vec<EnvelopeKind>>,
+    /// Require a reply's `agent_name`, when it carries one, to be the delegation target
+    pub require_target: bool,
+}
+
+/// Outcome of checking one envelope against a [`ReplyMatcher`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplyMatch {
+    Accept,
+    /// Belongs to some other delegation
+    Unrelated,
+    /// Carries our correlation id but was rejected, for this reason
+    Mismatch(String),
+}
+
+impl Default for ReplyMatcher {
+    fn default() -> Self {
+        Self {
+            accept_types: Some(DEFAULT_REPLY_TYPES.to_vec()),
+            require_target: true,
+        }
+    }
+}
+
+impl ReplyMatcher {
+    /// Accept the first envelope with the right correlation id, whatever it is.
+    pub fn lenient() -> Self {
+        Self { accept_types: None, require_target: false }
+    }
+
+    pub fn with_accept_types(mut self, types: Vec<EnvelopeKind>) -> Self {
+        self.accept_types = Some(types);
+        self
+    }
+
+    /// Check `reply` against a delegation to `target` with correlation id `cid`.
+    pub fn check(&self, reply: &Envelope, cid: &str, target: &str) -> ReplyMatch {
+        if reply.correlation_id.as_deref() != Some(cid) {
+            return ReplyMatch::Unrelated;
+        }
+        if let Some(types) = &self.accept_types {
+            if !reply.kind().is_some_and(|kind| types.contains(&kind)) {
+                let envelope_type = reply.envelope_type.as_deref().unwrap_or("");
+                let types: Vec<&str> = types.iter().map(EnvelopeKind::as_str).collect();
+                return ReplyMatch::Mismatch(format!("envelope_type {envelope_type:?} is not one of {types:?}"));
+            }
+        }
+        if self.require_target {
+            if let Some(sender) = reply.agent_name.as_deref().filter(|s| *s != target) {
+                return ReplyMatch::Mismatch(format!("sent by {sender:?}, not {target:?}"));
+            }
+        }
+        ReplyMatch::Accept
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply(envelope_type: &str, agent_name: Option<&str>) -> Envelope {
+        let mut env = crate::delegate_envelope(
+            "AG1:test:replies", "tester", "Echo", serde_json::json!({}), serde_json::json!({}), "assistant", envelope_type,
+        );
+        env.correlation_id = Some("cid-1".into());
+        env.agent_name = agent_name.map(str::to_string);
+        env
+    }
+
+    #[test]
+    fn strict_matching_skips_correlated_notifications() {
+        let strict = ReplyMatcher::default();
+        let notification = reply("notification", Some("Echo"));
+        assert!(matches!(strict.check(&notification, "cid-1", "Echo"), ReplyMatch::Mismatch(_)));
+        assert_eq!(strict.check(&reply("message_reply", Some("Echo")), "cid-1", "Echo"), ReplyMatch::Accept);
+        assert_eq!(strict.check(&reply("error", None), "cid-1", "Echo"), ReplyMatch::Accept);
+        assert!(matches!(strict.check(&reply("message_reply", Some("Other")), "cid-1", "Echo"), ReplyMatch::Mismatch(_)));
+        assert_eq!(strict.check(&notification, "cid-2", "Echo"), ReplyMatch::Unrelated);
+
+        let custom = ReplyMatcher::default().with_accept_types(vec![EnvelopeKind::Notification]);
+        assert_eq!(custom.check(&notification, "cid-1", "Echo"), ReplyMatch::Accept);
+    }
+
+    #[test]
+    fn lenient_matching_takes_any_correlated_envelope() {
+        let lenient = ReplyMatcher::lenient();
+        assert_eq!(lenient.check(&reply("notification", Some("Other")), "cid-1", "Echo"), ReplyMatch::Accept);
+        assert_eq!(lenient.check(&reply("notification", None), "cid-2", "Echo"), ReplyMatch::Unrelated);
+    }
+}
@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use bus::{Bus, Envelope};
+
+use crate::{ReplyMatch, ReplyMatcher, RECV_SLICE_MS, REPLY_GROUP};
+
+/// Consumer name [`wait_replies`] reads with. Fixed, so replies it reads but
+/// wasn't asked for stay pending under one name, where the next call looks first.
+const WAIT_CONSUMER: &str = "ag1_meta-wait";
+
+/// When [`wait_replies`] returns, short of its deadline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitMode {
+    /// Once every correlation id has a reply
+    All,
+    /// Once this many correlation ids have a reply
+    Any(usize),
+}
+
+/// Gather the replies on `in_stream` to several envelopes sent earlier (e.g.
+/// with [`crate::send_to_name`]), with one reader and one deadline for all.
+///
+/// Returns each of `correlation_ids` in order with its reply, `None` where
+/// none arrived in time. Replies and correlated notifications for the
+/// requested ids are acked; other envelopes read along the way are left
+/// pending, and are looked at first by the next call.
+pub async fn wait_replies(
+    bus: &Bus,
+    correlation_ids: &[String],
+    in_stream: &str,
+    mode: WaitMode,
+    timeout_ms: u64,
+) -> Result<Vec<(String, Option<Envelope>)>> {
+    let wanted = match mode {
+        WaitMode::All => correlation_ids.len(),
+        WaitMode::Any(n) => n.min(correlation_ids.len()),
+    };
+    let mut replies: HashMap<&str, Option<Envelope>> = correlation_ids.iter().map(|c| (c.as_str(), None)).collect();
+    let mut got = 0;
+    if let Err(e) = bus.create_consumer_group(in_stream, REPLY_GROUP).await {
+        eprintln!("[AG1_meta] failed to create consumer group: {}", e);
+    }
+    let deadline = Instant::now() + Duration::from_millis(timeout_ms);
+
+    let mut after = "0".to_string();
+    while got < wanted {
+        let Some(reply) = bus.recv_pending(in_stream, REPLY_GROUP, WAIT_CONSUMER, &after).await? else {
+            break;
+        };
+        after = reply.entry_id;
+        if take_reply(bus, in_stream, &mut replies, reply.envelope).await? {
+            got += 1;
+        }
+    }
+
+    while got < wanted {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            break;
+        }
+        let block = RECV_SLICE_MS.min(left.as_millis() as u64).max(1);
+        if let Some(reply) = bus.recv_block_group(in_stream, REPLY_GROUP, WAIT_CONSUMER, block).await? {
+            if take_reply(bus, in_stream, &mut replies, reply.envelope).await? {
+                got += 1;
+            }
+        }
+    }
+
+    Ok(correlation_ids.iter().map(|c| (c.clone(), replies.get_mut(c.as_str()).and_then(Option::take))).collect())
+}
+
+/// Record `reply` if it is the first reply to one of the ids in `replies`.
+/// Anything correlated with a requested id is acked.
+async fn take_reply(
+    bus: &Bus,
+    in_stream: &str,
+    replies: &mut HashMap<&str, Option<Envelope>>,
+    reply: Envelope,
+) -> Result<bool> {
+    let Some((cid, slot)) = reply.correlation_id.as_deref().and_then(|c| replies.get_key_value(c)) else {
+        return Ok(false);
+    };
+    let (cid, unanswered) = (*cid, slot.is_none());
+    if let Some(id) = &reply.envelope_id {
+        bus.ack_message(in_stream, REPLY_GROUP, id).await?;
+    }
+    let matcher = ReplyMatcher { require_target: false, ..Default::default() };
+    match matcher.check(&reply, cid, "") {
+        ReplyMatch::Accept if unanswered => {
+            replies.insert(cid, Some(reply));
+            Ok(true)
+        }
+        ReplyMatch::Mismatch(reason) => {
+            eprintln!("[AG1_meta] Skipping {}: {}", reply, reason);
+            Ok(false)
+        }
+        _ => Ok(false),
+    }
+}
@@ -5,266 +5,1321 @@ use tokio::sync::Mutex;
 use serde_json::json;
 use uuid;
 use uuid::Uuid;
-use crate::{config::Config, session::GooseSession};
-use bus::{Bus, Envelope};
-use std::time::Instant;
+use crate::config::{ConfirmationDefault, Config};
+use crate::jsonl::ToolCall;
+use crate::middleware::{run_inbound, run_outbound, EnvelopeMiddleware, MiddlewareAction};
+use crate::session::{GooseSession, TurnEvent};
+use crate::util::{now_rfc3339, reply_content};
+use async_trait::async_trait;
+use bus::budget::budget_exceeded_content;
+use bus::journal::{Journal, JournalRecord, JsonlJournal};
+use bus::seed::seed_transcript;
+use bus::{Budget, Bus, Envelope, EnvelopeKind, SeedTurn, StartPos, TurnUsage};
+use bus_agent::{
+    normalized_text, BusAgentRuntime, CodedError, IncomingMessage, MessageHandler, OutgoingReply, RuntimeConfig,
+};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// How often idle warm-pool members are checked for a dead child process.
+const WARM_POOL_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// How long a warm-pool session may take to signal readiness.
+const WARM_READY_TIMEOUT: Duration = Duration::from_secs(30);
+/// Consumer group the bridge reads its inbox with; bridges sharing an inbox split its messages.
+const INBOX_GROUP: &str = "ag1goose-bridge";
+/// How long each inbox read blocks waiting for a message (ms).
+const INBOX_BLOCK_MS: u64 = 2000;
+/// Name the bridge answers as.
+const AGENT_NAME: &str = "GooseAgent";
+/// Where replies go for envelopes without a `reply_to`.
+const DEFAULT_REPLY_TO: &str = "AG1:agent:TestClient:inbox";
+/// How long Goose may take to reply, not counting time spent waiting on tool confirmations.
+const TURN_REPLY_TIMEOUT: Duration = Duration::from_secs(30);
+/// Messages a `transcript` control command returns when it gives no `max`.
+const DEFAULT_TRANSCRIPT_MAX: usize = 20;
+/// How often a draining bridge looks for sessions gone idle.
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// How often sessions are checked against `session_idle_ms`.
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Where a turn's reply goes, so mid-turn requests can follow the same path.
+struct TurnContext<'a> {
+    reply_to: &'a str,
+    correlation_id: &'a str,
+    /// Limits from the request's `meta.budget`
+    budget: Option<Budget>,
+    /// History to start a new session with, from the request's `meta.seed_transcript`
+    seed: Option<&'a [SeedTurn]>,
+    /// When the sender stops waiting, from the request's `x-deadline` header
+    reply_by: Option<tokio::time::Instant>,
+}
+
+struct TurnOutput {
+    /// The reply, or the text produced before the turn was cut off
+    text: String,
+    /// Tools denied because no confirmation response arrived in time
+    auto_denied: Vec<String>,
+    usage: TurnUsage,
+    /// Names of the tools Goose requested, in order
+    tools_used: Vec<String>,
+    /// The budget limit the turn was aborted on
+    exceeded: Option<&'static str>,
+}
 
 pub struct Bridge {
     cfg: Config,
     bus: Bus,
-    sessions: Mutex<HashMap<String, GooseSession>>, // key: session_code
-    reply_to_session: Mutex<HashMap<String, String>>, // key: reply_to, value: session_id
+    /// Reads the inbox and sends back what the bridge answers
+    runtime: BusAgentRuntime,
+    // key: session_code. A session adopted from the warm pool keeps its
+    // placeholder `sid` (and JSONL file); this map is the logical -> pooled mapping.
+    sessions: Mutex<HashMap<String, GooseSession>>,
+    /// `sessions.len()`, readable while a turn holds the map
+    live_sessions: AtomicUsize,
+    /// Sessions of conversations without a `session_code`, by (reply_to, correlation_id)
+    conversation_sessions: Mutex<HashMap<(String, String), String>>,
+    warm_pool: Mutex<Vec<GooseSession>>,
+    warm_pool_changed: Notify,
+    pool_hits: AtomicU64,
+    cold_starts: AtomicU64,
+    draining: AtomicBool,
+    drain_started: Notify,
+    /// Run around the answering of every inbox envelope, in order
+    middleware: Vec<Box<dyn EnvelopeMiddleware>>,
+    /// Where answered envelopes are recorded, when `journal_dir` is set
+    journal: Option<Arc<dyn Journal>>,
+    /// Envelopes being answered and when they arrived, by inbox entry id, for the journal
+    journal_pending: std::sync::Mutex<HashMap<String, (Envelope, Instant)>>,
 }
 
 impl Bridge {
-    pub async fn new(cfg: Config) -> Result<Self> {
+    pub async fn new(cfg: Config, middleware: Vec<Box<dyn EnvelopeMiddleware>>) -> Result<Self> {
         println!("[DEBUG] Creating new Bridge instance");
         println!("[DEBUG] Connecting to Redis at: {}", cfg.redis_url);
         
         let start = Instant::now();
-        let bus = Bus::new(&cfg.redis_url).map_err(|e| {
-            println!("[ERROR] Failed to connect to Redis: {}", e);
-            e
-        })?;
+        let mut bus = Bus::new(&cfg.redis_url)
+            .map_err(|e| {
+                println!("[ERROR] Failed to connect to Redis: {}", e);
+                e
+            })?
+            .with_max_envelope_bytes(cfg.max_envelope_bytes)
+            .with_max_entry_bytes(cfg.max_entry_bytes);
+        if cfg.visibility_timeout_ms > 0 {
+            // Inbox messages a turn never acked (a hung or crashed bridge) are answered again
+            bus = bus.with_visibility_timeout(cfg.visibility_timeout_ms);
+        }
         
         println!("[DEBUG] Successfully connected to Redis in {:?}", start.elapsed());
+        if let Err(e) = bus.ensure_stream_exists(&cfg.inbox).await {
+            // Not fatal: the receive loop creates it along with the consumer group
+            warn!(inbox = %cfg.inbox, error = %e, "Could not create the inbox stream");
+        }
+        let journal = match &cfg.journal_dir {
+            Some(dir) => {
+                info!(dir = %dir.display(), "Journaling answered envelopes");
+                let journal = JsonlJournal::new(dir)?.with_max_file_bytes(cfg.journal_max_file_bytes);
+                Some(Arc::new(journal) as Arc<dyn Journal>)
+            }
+            None => None,
+        };
         println!("[DEBUG] Bridge instance created successfully");
+
+        let runtime = BusAgentRuntime::new(
+            bus.clone(),
+            RuntimeConfig {
+                inbox: cfg.inbox.clone(),
+                group: INBOX_GROUP.to_string(),
+                consumer: cfg.consumer.clone(),
+                start: StartPos::Latest,
+                block_ms: INBOX_BLOCK_MS,
+                concurrency: 1,
+                agent_name: AGENT_NAME.to_string(),
+                version: env!("CARGO_PKG_VERSION").to_string(),
+                accept_kinds: Some(vec![EnvelopeKind::Message, EnvelopeKind::Task, EnvelopeKind::Control]),
+                accept_roles: vec!["user".to_string()],
+                default_reply_to: DEFAULT_REPLY_TO.to_string(),
+                description: cfg.description.clone(),
+                capabilities_keywords: cfg.capabilities_keywords.clone(),
+            },
+        );
         
         Ok(Self { 
             cfg, 
             bus, 
+            runtime,
             sessions: Mutex::new(HashMap::new()),
-            reply_to_session: Mutex::new(HashMap::new()),
+            live_sessions: AtomicUsize::new(0),
+            conversation_sessions: Mutex::new(HashMap::new()),
+            warm_pool: Mutex::new(Vec::new()),
+            warm_pool_changed: Notify::new(),
+            pool_hits: AtomicU64::new(0),
+            cold_starts: AtomicU64::new(0),
+            draining: AtomicBool::new(false),
+            drain_started: Notify::new(),
+            middleware,
+            journal,
+            journal_pending: std::sync::Mutex::new(HashMap::new()),
         })
     }
 
-    async fn get_or_start_session(&self, sid: &str) -> Result<()> {
+    /// Take a ready session from the warm pool for the logical session `sid`.
+    ///
+    /// Returns `None` when the pool is empty (or only held dead children), in
+    /// which case the caller falls back to a cold start. Either way the pool
+    /// task is woken to top the pool back up.
+    pub async fn checkout_warm_session(&self, sid: &str) -> Option<GooseSession> {
+        let mut pool = self.warm_pool.lock().await;
+        let mut adopted = None;
+        while let Some(mut sess) = pool.pop() {
+            if sess.is_running().await {
+                adopted = Some(sess);
+                break;
+            }
+            warn!(pooled_sid = %sess.sid, "Discarding dead warm session");
+        }
+        let remaining = pool.len();
+        drop(pool);
+        self.warm_pool_changed.notify_one();
+
+        if let Some(sess) = &adopted {
+            let hits = self.pool_hits.fetch_add(1, Ordering::Relaxed) + 1;
+            info!(
+                session_id = %sid,
+                pooled_sid = %sess.sid,
+                remaining,
+                pool_hits = hits,
+                cold_starts = self.cold_starts.load(Ordering::Relaxed),
+                "Warm pool hit"
+            );
+        }
+        adopted
+    }
+
+    /// Keep `warm_pool_size` started-and-ready sessions around. Never returns.
+    async fn maintain_warm_pool(&self) {
+        let target = self.cfg.warm_pool_size;
+        if target == 0 {
+            return std::future::pending().await;
+        }
+        info!(target, "Warm pool enabled");
+
+        loop {
+            self.prune_warm_pool().await;
+            self.fill_warm_pool(target).await;
+            tokio::select! {
+                _ = self.warm_pool_changed.notified() => {}
+                _ = tokio::time::sleep(WARM_POOL_CHECK_INTERVAL) => {}
+            }
+        }
+    }
+
+    /// Drop pool members whose goose child has exited.
+    async fn prune_warm_pool(&self) {
+        let mut pool = self.warm_pool.lock().await;
+        let mut alive = Vec::with_capacity(pool.len());
+        for mut sess in pool.drain(..) {
+            if sess.is_running().await {
+                alive.push(sess);
+            } else {
+                warn!(pooled_sid = %sess.sid, "Warm session exited, replacing");
+            }
+        }
+        *pool = alive;
+    }
+
+    async fn fill_warm_pool(&self, target: usize) {
+        while self.warm_pool.lock().await.len() < target {
+            let placeholder = format!("warm_{}", Uuid::new_v4().to_string().split('-').next().unwrap_or(""));
+            let start = Instant::now();
+            // Started without holding the pool lock so checkouts are never blocked on a spawn
+            let started = match GooseSession::start(&self.cfg, placeholder.clone()).await {
+                Ok(sess) => sess.wait_ready(WARM_READY_TIMEOUT).await.map(|_| sess),
+                Err(e) => Err(e),
+            };
+            match started {
+                Ok(sess) => {
+                    debug!(pooled_sid = %placeholder, elapsed = ?start.elapsed(), "Warm session ready");
+                    self.warm_pool.lock().await.push(sess);
+                }
+                Err(e) => {
+                    // Retry on the next health-check tick rather than spinning
+                    warn!(pooled_sid = %placeholder, error = %e, "Failed to start warm session");
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Make sure `sid` has a running session. A new one continuing `seed` is
+    /// always cold-started, as warm-pool sessions were started without it; a
+    /// running one ignores `seed`.
+    async fn get_or_start_session(&self, sid: &str, seed: Option<&[SeedTurn]>) -> Result<()> {
         println!("[DEBUG] Getting or starting session for ID: {}", sid);
         let start = Instant::now();
         
         let mut map = self.sessions.lock().await;
-        if !map.contains_key(sid) {
+        if map.contains_key(sid) {
+            println!("[DEBUG] Using existing session for ID: {}", sid);
+            if let Some(seed) = seed {
+                warn!(session_id = %sid, turns = seed.len(), "Ignoring seed_transcript for a session that is already running");
+            }
+        } else if let Some(seed) = seed {
+            let cold_starts = self.cold_starts.fetch_add(1, Ordering::Relaxed) + 1;
+            info!(session_id = %sid, turns = seed.len(), cold_starts, "Starting goose session from a seed transcript");
+            let sess = GooseSession::start_seeded(&self.cfg, sid.to_string(), seed).await?;
+            map.insert(sid.to_string(), sess);
+            self.live_sessions.store(map.len(), Ordering::Relaxed);
+        } else if let Some(sess) = self.checkout_warm_session(sid).await {
+            println!("[DEBUG] Adopted warm session {} for ID: {}", sess.sid, sid);
+            map.insert(sid.to_string(), sess);
+            self.live_sessions.store(map.len(), Ordering::Relaxed);
+        } else {
+            let cold_starts = self.cold_starts.fetch_add(1, Ordering::Relaxed) + 1;
+            info!(
+                session_id = %sid,
+                pool_hits = self.pool_hits.load(Ordering::Relaxed),
+                cold_starts,
+                "Cold-starting goose session"
+            );
             println!("[DEBUG] Creating new session for ID: {}", sid);
             match GooseSession::start(&self.cfg, sid.to_string()).await {
                 Ok(sess) => {
                     println!("[DEBUG] Successfully created new session for ID: {}", sid);
                     map.insert(sid.to_string(), sess);
+                    self.live_sessions.store(map.len(), Ordering::Relaxed);
                 }
                 Err(e) => {
                     println!("[ERROR] Failed to create session for ID {}: {}", sid, e);
                     return Err(e);
                 }
             }
-        } else {
-            println!("[DEBUG] Using existing session for ID: {}", sid);
         }
         
         println!("[DEBUG] Session operation completed in {:?}", start.elapsed());
         Ok(())
     }
 
+    /// Serve the inbox until it fails or, after [`Bridge::begin_drain`], the
+    /// last session has been stopped.
     pub async fn run(&self) -> Result<()> {
-        info!(inbox = %self.cfg.inbox, "bridge started");
-        println!("[DEBUG] Bridge starting to listen on inbox: {}", self.cfg.inbox);
-        
-        let mut last_id = "$".to_string();
-        let mut message_count = 0;
-        
-        loop {
-            println!("[DEBUG] Waiting for next message... (last_id: {})", last_id);
-            match self.bus.recv_block(&self.cfg.inbox, &last_id, 2000).await {
-                Ok(Some(env)) => {
-                    message_count += 1;
-                    println!("[DEBUG] Received message #{}", message_count);
-                    
-                    if let Some(id) = &env.envelope_id { 
-                        last_id = id.clone();
-                        println!("[DEBUG] Updated last_id to: {}", last_id);
-                    }
-                    
-                    let start = Instant::now();
-                    match self.handle_envelope(env).await {
-                        Ok(_) => {
-                            println!("[DEBUG] Successfully processed message #{} in {:?}", 
-                                    message_count, start.elapsed());
-                        }
-                        Err(e) => {
-                            error!(error=?e, "failed handling envelope");
-                            println!("[ERROR] Failed to handle message #{}: {}", message_count, e);
-                        }
-                    }
-                }
-                Ok(None) => {
-                    println!("[DEBUG] No messages received, continuing to poll...");
-                }
-                Err(e) => {
-                    error!(error=?e, "error receiving message");
-                    println!("[ERROR] Error receiving message: {}", e);
-                    // Add a small delay to prevent tight loop on errors
-                    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
-                }
+        tokio::select! {
+            res = self.runtime.run(self) => res,
+            _ = self.maintain_warm_pool() => Ok(()),
+            _ = self.reap_idle_sessions() => Ok(()),
+            _ = self.wait_drained() => {
+                let requeued = self.runtime.requeue_deferred().await;
+                info!(requeued, "Bridge drained");
+                Ok(())
             }
         }
     }
 
-    /// Get the reply-to address from an envelope or use a default
-    fn get_reply_to(&self, env: &Envelope) -> String {
-        match &env.reply_to {
-            Some(rt) => {
-                debug!(reply_to = %rt, "Using reply-to address from envelope");
-                rt.clone()
+    /// Stop taking on new conversations, for a rolling deploy. Envelopes that
+    /// would start a session are left unacked on the inbox, and put back on
+    /// it for other bridges once drained, while control commands and
+    /// messages for running sessions are still answered. Each session is
+    /// stopped once it has gone `drain_idle_ms` without a message.
+    pub fn begin_drain(&self) {
+        if !self.draining.swap(true, Ordering::SeqCst) {
+            info!(sessions_idle_after_ms = self.cfg.drain_idle_ms, "Draining, no new sessions will be started");
+            self.drain_started.notify_one();
+        }
+    }
+
+    /// Return once draining has begun and every session is gone.
+    async fn wait_drained(&self) {
+        while !self.draining.load(Ordering::SeqCst) {
+            self.drain_started.notified().await;
+        }
+        let idle_after = Duration::from_millis(self.cfg.drain_idle_ms);
+        loop {
+            let remaining = self.stop_idle_sessions(idle_after).await;
+            if remaining == 0 {
+                return;
             }
-            None => {
-                let default_reply = "AG1:agent:TestClient:inbox".to_string();
-                warn!(default_reply = %default_reply, "No reply-to address in envelope, using default");
-                default_reply
+            debug!(remaining, "Waiting for sessions to go idle");
+            tokio::time::sleep(DRAIN_CHECK_INTERVAL).await;
+        }
+    }
+
+    /// Stop sessions that have gone `session_idle_ms` without a message, so
+    /// one-off conversations don't keep a goose process each. Never returns
+    /// when enabled, and at once when `session_idle_ms` is 0.
+    async fn reap_idle_sessions(&self) {
+        if self.cfg.session_idle_ms == 0 {
+            return std::future::pending().await;
+        }
+        let idle_after = Duration::from_millis(self.cfg.session_idle_ms);
+        loop {
+            tokio::time::sleep(IDLE_CHECK_INTERVAL).await;
+            self.stop_idle_sessions(idle_after).await;
+        }
+    }
+
+    /// Stop every session unused for `idle_after`, dropping its conversation
+    /// mapping, and give how many sessions are left.
+    async fn stop_idle_sessions(&self, idle_after: Duration) -> usize {
+        // Turns hold the session lock, so a session is never taken mid-turn
+        let idle: Vec<String> = self
+            .sessions
+            .lock()
+            .await
+            .iter()
+            .filter(|(_, session)| session.last_used.elapsed().is_ok_and(|idle| idle >= idle_after))
+            .map(|(sid, _)| sid.clone())
+            .collect();
+        for sid in idle {
+            if self.stop_session(&sid).await.is_ok() {
+                let _ = self.cleanup_session_mapping(&sid).await;
             }
         }
+        self.sessions.lock().await.len()
     }
-    
-    async fn handle_envelope(&self, env: Envelope) -> Result<()> {
-        info!(correlation_id = ?env.correlation_id, "Handling envelope");
-        
-        // Skip non-user messages
-        if env.role != "user" {
-            debug!(role = %env.role, "Skipping non-user message");
-            return Ok(());
+
+    /// Whether a draining bridge still answers `msg`: anything but a turn, and
+    /// turns belonging to a session that is already running.
+    async fn accepted_while_draining(&self, msg: &IncomingMessage) -> bool {
+        if msg.kind == EnvelopeKind::Control {
+            return true;
         }
-        
-        // Get reply-to address
-        let reply_to = self.get_reply_to(&env);
-        
-        // Check if we have an existing session for this reply_to
-        let sid = if let Some(session_id) = self.get_session_for_reply_to(&reply_to).await? {
-            info!(session_id = %session_id, reply_to = %reply_to, "Reusing existing session");
+        let sid = match &msg.envelope.session_code {
+            Some(code) => Some(code.clone()),
+            None => self.get_session_for(&msg.reply_to, &msg.correlation_id).await.ok().flatten(),
+        };
+        match sid {
+            Some(sid) => self.sessions.lock().await.contains_key(&sid),
+            None => false,
+        }
+    }
+
+    /// Send `msg` to Goose, giving the reply.
+    async fn answer_turn(&self, msg: &IncomingMessage) -> Result<OutgoingReply> {
+        let env = &msg.envelope;
+        let (reply_to, cid) = (&msg.reply_to, &msg.correlation_id);
+
+        // Nobody is waiting for the reply any more
+        let time_left = env.time_left();
+        if time_left == Some(Duration::ZERO) {
+            warn!(correlation_id = %cid, "Skipping a turn past its deadline");
+            let message = format!("deadline {} passed before the turn started", env.headers[bus::DEADLINE_HEADER]);
+            return Err(CodedError::new("deadline_exceeded", message).into());
+        }
+
+        // The sender's session_code names the session; without one, each
+        // (reply_to, correlation_id) gets its own, so senders sharing a reply
+        // stream don't see each other's conversation
+        let sid = if let Some(code) = &env.session_code {
+            code.clone()
+        } else if let Some(session_id) = self.get_session_for(reply_to, cid).await? {
+            info!(session_id = %session_id, reply_to = %reply_to, correlation_id = %cid, "Reusing existing session");
             session_id
         } else {
-            // Generate new session ID if none provided
-            let sid = env.session_code.clone().unwrap_or_else(|| {
-                let new_sid = format!("sess_{}", Uuid::new_v4().to_string().split('-').next().unwrap_or(""));
-                info!(new_session_id = %new_sid, "Generated new session ID");
-                new_sid
-            });
-            
-            // Store the mapping from reply_to to session ID
-            self.map_reply_to_session(&reply_to, &sid).await?;
+            let sid = format!("sess_{}", Uuid::new_v4().to_string().split('-').next().unwrap_or(""));
+            info!(new_session_id = %sid, reply_to = %reply_to, correlation_id = %cid, "Generated new session ID");
+            self.map_session(reply_to, cid, &sid).await?;
             sid
         };
+
+        // Goose sees only the text of multi-part content
+        if msg.text.is_empty() {
+            return Err(anyhow!("No text content in message"));
+        }
+            
+        let queue_latency_ms = msg.queue_latency.map(|latency| latency.as_millis() as u64);
+        info!(queue_latency_ms, "[{}] Processing message ({} chars) with CID: {}", 
+             sid, msg.text.len(), cid);
         
-        // Get or create the session
-        self.get_or_start_session(&sid).await?;
+        // Only a new session takes a seed transcript; a bad one is refused before anything starts
+        let seed = if self.sessions.lock().await.contains_key(&sid) {
+            if env.meta.get("seed_transcript").is_some() {
+                warn!(session_id = %sid, "Ignoring seed_transcript for a session that is already running");
+            }
+            None
+        } else {
+            match seed_transcript(&env.meta) {
+                Ok(seed) => seed,
+                Err(e) => {
+                    self.cleanup_session_mapping(&sid).await?;
+                    return Err(e.into());
+                }
+            }
+        };
+        let ctx = TurnContext {
+            reply_to,
+            correlation_id: cid,
+            budget: Budget::from_meta(&env.meta),
+            seed: seed.as_deref(),
+            reply_by: time_left.map(|left| tokio::time::Instant::now() + left),
+        };
+        let output = match self.run_turn(&sid, &msg.text, &ctx).await {
+            Ok(output) => output,
+            Err(e) => {
+                // A session that failed to start leaves the mapping pointing at nothing
+                if !self.sessions.lock().await.contains_key(&sid) {
+                    self.cleanup_session_mapping(&sid).await?;
+                }
+                return Err(e);
+            }
+        };
+        let TurnOutput { text: response, auto_denied, usage, tools_used, exceeded } = output;
         
-        // Get or generate correlation ID
-        let cid = env.correlation_id.clone().unwrap_or_else(|| {
-            let new_cid = Uuid::new_v4().to_string();
-            debug!("Generated new correlation ID: {}", new_cid);
-            new_cid
-        });
-
-        // Get the message text
-        let message = env.content.get("text")
-            .and_then(|v| v.as_str())
-            .ok_or_else(|| anyhow!("No text content in message"))?;
-            
-        info!("[{}] Processing message ({} chars) with CID: {}", 
-             sid, message.len(), cid);
+        // Log the response details
+        info!("[{}] Sending response ({} chars) to {}", 
+             sid, response.len(), reply_to);
         
-        // Get session with lock scope
-        let response = {
-            let mut sessions = self.sessions.lock().await;
-            let session = sessions.get_mut(&sid).ok_or_else(|| {
-                error!("[{}] Session not found in session map", sid);
-                anyhow!("Session not found")
-            })?;
-            
-            // Get the current offset before sending input
-            let start_offset = session.get_last_offset();
-            debug!("[{}] Starting JSONL read from offset: {}", sid, start_offset);
-            
-            // Send the input to the session
-            if let Err(e) = session.send_user(message).await {
-                error!("[{}] Failed to send user input: {}", sid, e);
-                return Err(anyhow!("Failed to send input: {}", e));
+        let (content, kind) = match exceeded {
+            Some(limit) => {
+                let mut content = budget_exceeded_content(limit, &response);
+                content["session_id"] = json!(sid);
+                (content, EnvelopeKind::Error)
             }
-            
-            // Wait for the response with a timeout using JSONL file
-            // Using a 30 second timeout for the response
-            match session.wait_assistant_jsonl(30000, start_offset).await {
-                Ok((response, new_offset)) => {
+            None => {
+                let mut content = reply_content(&response);
+                content["session_id"] = json!(sid);
+                content["timestamp"] = json!(now_rfc3339());
+                (content, EnvelopeKind::MessageReply)
+            }
+        };
+        let mut reply = OutgoingReply::new(kind, content);
+        reply.session_code = Some(sid);
+        reply.usage = json!(usage);
+        reply.tools_used = tools_used;
+        if !auto_denied.is_empty() {
+            reply.meta["auto_denied_tools"] = json!(auto_denied);
+        }
+        if env.has_parts() {
+            reply.meta["request_parts"] = json!(env.parts());
+        }
+        Ok(reply)
+    }
+    
+    /// Run the session management command in a `control` envelope, giving its
+    /// `control_reply`.
+    ///
+    /// `content.command` is `list`, `reset`, `transcript` (with an optional
+    /// `max`) or `kill`; all but `list` take the logical `session_id`. With a
+    /// `signing_key` configured, envelopes without a valid signature are rejected.
+    async fn control_reply(&self, env: &Envelope) -> Result<OutgoingReply> {
+        if let Some(key) = &self.cfg.signing_key {
+            if !env.verify_signature(key.as_bytes()) {
+                return Err(anyhow!("control message rejected: missing or invalid signature"));
+            }
+        }
+        let command = env.content.get("command").and_then(|c| c.as_str()).unwrap_or_default();
+        let sid = || {
+            env.content
+                .get("session_id")
+                .and_then(|s| s.as_str())
+                .ok_or_else(|| anyhow!("control command {:?} needs a session_id", command))
+        };
+        info!(command, "Handling control command");
+
+        let mut content = match command {
+            "list" => json!({ "sessions": self.list_sessions().await }),
+            "reset" => {
+                let sid = sid()?;
+                self.stop_session(sid).await?;
+                self.get_or_start_session(sid, None).await?;
+                json!({ "session_id": sid })
+            }
+            "transcript" => {
+                let sid = sid()?;
+                let max = env.content.get("max").and_then(|m| m.as_u64()).map_or(DEFAULT_TRANSCRIPT_MAX, |m| m as usize);
+                let sessions = self.sessions.lock().await;
+                let session = sessions.get(sid).ok_or_else(|| anyhow!("no session {}", sid))?;
+                json!({ "session_id": sid, "messages": session.transcript(max).await? })
+            }
+            "kill" => {
+                let sid = sid()?;
+                self.stop_session(sid).await?;
+                self.cleanup_session_mapping(sid).await?;
+                json!({ "session_id": sid })
+            }
+            other => return Err(anyhow!("unknown control command {:?}", other)),
+        };
+        content["command"] = json!(command);
+        Ok(OutgoingReply::new(EnvelopeKind::ControlReply, content))
+    }
+
+    /// Every live session, by logical session id.
+    async fn list_sessions(&self) -> Vec<serde_json::Value> {
+        let mut sessions = self.sessions.lock().await;
+        let mut listed = Vec::with_capacity(sessions.len());
+        for (sid, session) in sessions.iter_mut() {
+            listed.push(json!({
+                "session_id": sid,
+                "goose_session": session.sid,
+                "last_used": chrono::DateTime::<chrono::Utc>::from(session.last_used).to_rfc3339(),
+                "is_running": session.is_running().await,
+            }));
+        }
+        listed.sort_by(|a, b| a["session_id"].as_str().cmp(&b["session_id"].as_str()));
+        listed
+    }
+
+    /// Remove the session for `sid` and terminate its goose child.
+    async fn stop_session(&self, sid: &str) -> Result<()> {
+        let mut session = {
+            let mut sessions = self.sessions.lock().await;
+            let session = sessions.remove(sid).ok_or_else(|| anyhow!("no session {}", sid))?;
+            self.live_sessions.store(sessions.len(), Ordering::Relaxed);
+            session
+        };
+        if let Err(e) = session.process.kill().await {
+            warn!(session_id = %sid, error = %e, "Failed to kill goose session");
+        }
+        info!(session_id = %sid, goose_session = %session.sid, "Stopped goose session");
+        Ok(())
+    }
+
+    /// Send one user message to the (possibly new) Goose session for `sid` and wait for its reply,
+    /// forwarding any tool confirmation prompts to `ctx.reply_to` along the way.
+    ///
+    /// A turn that goes over `ctx.budget` is aborted by killing its session; the
+    /// next message for `sid` starts a fresh one.
+    async fn run_turn(&self, sid: &str, message: &str, ctx: &TurnContext<'_>) -> Result<TurnOutput> {
+        // Get or create the session
+        self.get_or_start_session(sid, ctx.seed).await?;
+
+        // Get session with lock scope
+        let mut sessions = self.sessions.lock().await;
+        let session = sessions.get_mut(sid).ok_or_else(|| {
+            error!("[{}] Session not found in session map", sid);
+            anyhow!("Session not found")
+        })?;
+
+        // Get the current offset before sending input
+        let start_offset = session.get_last_offset();
+        debug!("[{}] Starting JSONL read from offset: {}", sid, start_offset);
+
+        // Send the input to the session
+        if let Err(e) = session.send_user(message).await {
+            error!("[{}] Failed to send user input: {}", sid, e);
+            return Err(anyhow!("Failed to send input: {}", e));
+        }
+
+        let mut turn = session.begin_turn(start_offset).with_budget(ctx.budget);
+        // Waiting past the sender's deadline is pointless
+        let reply_by = |deadline: tokio::time::Instant| ctx.reply_by.map_or(deadline, |by| by.min(deadline));
+        let mut deadline = reply_by(tokio::time::Instant::now() + TURN_REPLY_TIMEOUT);
+        let mut auto_denied = Vec::new();
+        loop {
+            match session.next_turn_event(&mut turn, deadline).await {
+                Ok(TurnEvent::Reply(text, new_offset)) => {
                     // Update the session's last_offset for the next read
                     session.update_offset(new_offset);
                     debug!("[{}] Updated session offset to: {}", sid, new_offset);
-                    response
-                },
+                    let tools_used = turn.tools_used().to_vec();
+                    return Ok(TurnOutput { text, auto_denied, usage: turn.usage(), tools_used, exceeded: None });
+                }
+                Ok(TurnEvent::BudgetExceeded(limit)) => {
+                    let usage = turn.usage();
+                    warn!(session_id = %sid, limit, ?usage, "Turn over budget, stopping goose session");
+                    let text = turn.partial_text().to_string();
+                    let tools_used = turn.tools_used().to_vec();
+                    // Dropping the session kills the child mid-turn
+                    sessions.remove(sid);
+                    self.live_sessions.store(sessions.len(), Ordering::Relaxed);
+                    drop(sessions);
+                    self.cleanup_session_mapping(sid).await?;
+                    return Ok(TurnOutput { text, auto_denied, usage, tools_used, exceeded: Some(limit) });
+                }
+                Ok(TurnEvent::Confirmation(tool)) => {
+                    let asked = tokio::time::Instant::now();
+                    let allow = match self.request_confirmation(sid, ctx, tool.as_ref()).await {
+                        Some(allow) => allow,
+                        None => {
+                            let allow = self.cfg.confirmation_default == ConfirmationDefault::Allow;
+                            let name = tool.as_ref().map_or("unknown", |t| t.name.as_str());
+                            warn!(session_id = %sid, tool = %name, allow, "No confirmation response, applying default");
+                            if !allow {
+                                auto_denied.push(name.to_string());
+                            }
+                            allow
+                        }
+                    };
+                    session.answer_confirmation(allow).await?;
+                    deadline = reply_by(deadline + asked.elapsed());
+                }
                 Err(e) => {
                     error!("[{}] Error getting response from Goose (JSONL): {}", sid, e);
-                    error!("[{}] Session state - is process running? {}", sid, 
+                    error!("[{}] Session state - is process running? {}", sid,
                           if session.is_running().await { "yes" } else { "no" });
-                    format!("Error getting response from Goose: {}", e)
+                    return Err(e.context("Error getting response from Goose"));
                 }
             }
+        }
+    }
+
+    /// Ask the delegating agent whether Goose may run `tool`.
+    ///
+    /// The request goes to `ctx.reply_to`; the answer is expected on a per-session
+    /// stream named in its `reply_to`. Returns `None` if the request could not be
+    /// sent or no answer arrived within `confirmation_timeout_ms`.
+    async fn request_confirmation(&self, sid: &str, ctx: &TurnContext<'_>, tool: Option<&ToolCall>) -> Option<bool> {
+        let answer_stream = format!("{}:confirm:{}", self.cfg.inbox, sid);
+        // Read from the current tail so an answer can't slip in before we listen
+        let mut last_id = match self.bus.tail_id(&answer_stream).await {
+            Ok(id) => id,
+            Err(e) => {
+                warn!(session_id = %sid, error = %e, "Cannot read confirmation stream");
+                return None;
+            }
         };
-        
-        // Log the response details
-        info!("[{}] Sending response ({} chars) to {}", 
-             sid, response.len(), reply_to);
-        
-        // Create and send the response envelope
-        let response_env = Envelope {
+
+        let request = Envelope {
             role: "assistant".to_string(),
-            content: json!({ 
-                "text": response,
-                "session_id": sid,
-                "timestamp": chrono::Utc::now().to_rfc3339()
+            content: json!({
+                "tool": tool.map(|t| t.name.as_str()),
+                "arguments": tool.map(|t| t.arguments.clone()),
+                "tool_call_id": tool.map(|t| t.id.as_str()),
             }),
-            session_code: Some(sid.clone()),
-            agent_name: Some("GooseAgent".to_string()),
+            content_type: None,
+            session_code: Some(sid.to_string()),
+            agent_name: Some(AGENT_NAME.to_string()),
             usage: json!({}),
             billing_hint: None,
             trace: vec![],
             user_id: None,
             task_id: None,
             target: None,
-            reply_to: Some(reply_to.clone()),
-            envelope_type: Some("message_reply".into()),
+            reply_to: Some(answer_stream.clone()),
+            envelope_type: Some(EnvelopeKind::ToolConfirmationRequest.into()),
             tools_used: vec![],
             auth_signature: None,
             timestamp: Some(chrono::Utc::now().to_rfc3339()),
             headers: Default::default(),
             meta: json!({ "x_stream_key": self.cfg.inbox }),
             envelope_id: Some(uuid::Uuid::new_v4().to_string()),
-            correlation_id: Some(cid),
+            correlation_id: Some(ctx.correlation_id.to_string()),
             consumer_group: None,
             consumer_id: None,
             delivery_count: None,
+            priority: None,
         };
-        
-        if let Err(e) = self.bus.send(&reply_to, &response_env).await {
-            println!("[ERROR][{}] Failed to send response: {}", sid, e);
-            return Err(e.into());
+        if let Err(e) = self.bus.send(ctx.reply_to, &request).await {
+            warn!(session_id = %sid, error = %e, "Failed to send tool confirmation request");
+            return None;
         }
-        
-        println!("[DEBUG][{}] Successfully sent response to {}", sid, reply_to);
-        Ok(())
+        info!(session_id = %sid, reply_to = %ctx.reply_to, "Sent tool confirmation request");
+
+        let deadline = Instant::now() + Duration::from_millis(self.cfg.confirmation_timeout_ms);
+        while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+            // BLOCK 0 would wait forever
+            let block_ms = (left.as_millis() as u64).max(1);
+            match self.bus.recv_block(&answer_stream, &last_id, block_ms).await {
+                Ok(Some(entry)) => {
+                    last_id = entry.id;
+                    if let Some(env) = entry.envelope.filter(|env| {
+                        env.kind() == Some(EnvelopeKind::ToolConfirmationResponse)
+                            && env.correlation_id.as_deref() == Some(ctx.correlation_id)
+                    }) {
+                        return Some(confirmation_allows(&env.content));
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    warn!(session_id = %sid, error = %e, "Failed waiting for tool confirmation response");
+                    return None;
+                }
+            }
+        }
+        None
     }
-    
-    /// Get the session ID associated with a reply_to address, if any
-    async fn get_session_for_reply_to(&self, reply_to: &str) -> Result<Option<String>> {
-        let map = self.reply_to_session.lock().await;
-        Ok(map.get(reply_to).cloned())
+
+    /// Get the session ID of the conversation on `reply_to` with `correlation_id`, if any
+    async fn get_session_for(&self, reply_to: &str, correlation_id: &str) -> Result<Option<String>> {
+        let map = self.conversation_sessions.lock().await;
+        Ok(map.get(&(reply_to.to_string(), correlation_id.to_string())).cloned())
     }
     
-    /// Map a reply_to address to a session ID
-    async fn map_reply_to_session(&self, reply_to: &str, session_id: &str) -> Result<()> {
-        let mut map = self.reply_to_session.lock().await;
-        map.insert(reply_to.to_string(), session_id.to_string());
+    /// Map the conversation on `reply_to` with `correlation_id` to a session ID
+    async fn map_session(&self, reply_to: &str, correlation_id: &str, session_id: &str) -> Result<()> {
+        let mut map = self.conversation_sessions.lock().await;
+        map.insert((reply_to.to_string(), correlation_id.to_string()), session_id.to_string());
         Ok(())
     }
     
     /// Clean up session mappings when a session ends
     async fn cleanup_session_mapping(&self, session_id: &str) -> Result<()> {
-        let mut map = self.reply_to_session.lock().await;
+        let mut map = self.conversation_sessions.lock().await;
         map.retain(|_, v| v != session_id);
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[async_trait]
+impl MessageHandler for Bridge {
+    /// Run `msg` through the inbound middleware, then Goose or the control
+    /// commands. A middleware rejection is answered with an `error`.
+    async fn handle(&self, mut msg: IncomingMessage) -> Result<Option<OutgoingReply>> {
+        if let (Some(_), Some(id)) = (&self.journal, &msg.envelope.envelope_id) {
+            let pending = (msg.envelope.clone(), Instant::now());
+            self.journal_pending.lock().unwrap().insert(id.clone(), pending);
+        }
+        match run_inbound(&self.middleware, &mut msg.envelope).await {
+            MiddlewareAction::Continue => msg.text = normalized_text(&msg.envelope),
+            MiddlewareAction::ShortCircuit(reply) => return Ok(Some((*reply).into())),
+            MiddlewareAction::Reject(reason) => {
+                info!(envelope = %msg.envelope, reason = %reason, "Envelope rejected by middleware");
+                let content = json!({ "text": format!("rejected: {}", reason), "rejected": true, "reason": reason });
+                return Ok(Some(OutgoingReply::new(EnvelopeKind::Error, content)));
+            }
+        }
+        let reply = match msg.kind {
+            EnvelopeKind::Control => self.control_reply(&msg.envelope).await?,
+            _ => self.answer_turn(&msg).await?,
+        };
+        Ok(Some(reply))
+    }
+
+    /// Envelopes that would start a session are deferred while draining.
+    async fn accepts(&self, msg: &IncomingMessage) -> bool {
+        !self.draining.load(Ordering::SeqCst) || self.accepted_while_draining(msg).await
+    }
+
+    async fn on_reply(&self, reply: &mut Envelope) {
+        run_outbound(&self.middleware, reply).await;
+    }
+
+    /// Journal the reply along with the request it answers. Pongs aren't journaled.
+    async fn on_sent(&self, request_id: &str, reply: &Envelope) {
+        let Some(journal) = &self.journal else { return };
+        if reply.kind() == Some(EnvelopeKind::Pong) {
+            return;
+        }
+        let cid = reply.correlation_id.as_deref().unwrap_or_default();
+        let pending = self.journal_pending.lock().unwrap().remove(request_id);
+        let record = match &pending {
+            Some((request, received)) => JournalRecord::new(Some(request), reply, received.elapsed()),
+            None => JournalRecord::new(None, reply, Duration::ZERO),
+        };
+        // Journal appends are blocking file writes
+        let journal = journal.clone();
+        match tokio::task::spawn_blocking(move || journal.record(&record)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => error!(correlation_id = %cid, error = %e, "Failed to journal reply"),
+            Err(e) => error!(correlation_id = %cid, error = %e, "Journal write panicked"),
+        }
+    }
+
+    async fn active_sessions(&self) -> usize {
+        self.live_sessions.load(Ordering::Relaxed)
+    }
+}
+/// Read a `tool_confirmation_response` body: `{"approved": bool}` or
+/// `{"decision": "allow" | "deny"}`. Anything else counts as a denial.
+fn confirmation_allows(content: &serde_json::Value) -> bool {
+    if let Some(approved) = content.get("approved").and_then(|a| a.as_bool()) {
+        return approved;
+    }
+    matches!(
+        content.get("decision").and_then(|d| d.as_str()).map(|d| d.to_lowercase()).as_deref(),
+        Some("allow" | "allow_once" | "always_allow" | "approve" | "yes" | "y")
+    )
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::session::test_support;
+    use bus_agent::Answer;
+
+    async fn wait_for_pool(bridge: &Bridge, len: usize) {
+        for _ in 0..200 {
+            if bridge.warm_pool.lock().await.len() == len {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("warm pool never reached {} sessions", len);
+    }
+
+    fn ctx() -> TurnContext<'static> {
+        TurnContext { reply_to: "AG1:test:bridge:replies", correlation_id: "cid-1", budget: None, seed: None, reply_by: None }
+    }
+
+    /// Run one turn against the endlessly working `loop` stub under `budget`.
+    async fn run_over_budget(budget: Budget) -> (Bridge, String, TurnOutput) {
+        let bridge = Bridge::new(test_support::config("loop"), vec![]).await.unwrap();
+        let sid = format!("sess_{}", Uuid::new_v4().simple());
+        let ctx = TurnContext { budget: Some(budget), ..ctx() };
+        let out = bridge.run_turn(&sid, "work forever", &ctx).await.unwrap();
+        (bridge, sid, out)
+    }
+
+    #[tokio::test]
+    async fn turn_uses_warm_session_and_pool_refills() {
+        let cfg = Config { warm_pool_size: 1, ..test_support::config("reply") };
+        let bridge = Bridge::new(cfg, vec![]).await.unwrap();
+
+        let scenario = async {
+            wait_for_pool(&bridge, 1).await;
+            let pooled_sid = bridge.warm_pool.lock().await[0].sid.clone();
+
+            let reply = bridge.run_turn("sess_logical", "ping", &ctx()).await.unwrap();
+            assert_eq!(reply.text, format!("reply from {}", pooled_sid));
+            assert_eq!(bridge.sessions.lock().await["sess_logical"].sid, pooled_sid);
+            assert_eq!(bridge.pool_hits.load(Ordering::Relaxed), 1);
+            assert_eq!(bridge.cold_starts.load(Ordering::Relaxed), 0);
+
+            wait_for_pool(&bridge, 1).await;
+            assert_ne!(bridge.warm_pool.lock().await[0].sid, pooled_sid);
+        };
+
+        tokio::select! {
+            _ = bridge.maintain_warm_pool() => unreachable!("pool task never returns"),
+            _ = scenario => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn unanswered_confirmation_is_denied_by_default() {
+        let bridge = Bridge::new(test_support::config("confirm"), vec![]).await.unwrap();
+        let sid = format!("sess_{}", Uuid::new_v4().simple());
+
+        let reply = bridge.run_turn(&sid, "clean up", &ctx()).await.unwrap();
+        assert_eq!(reply.text, "answer: n");
+        assert_eq!(reply.auto_denied, vec!["developer__shell".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn turn_is_cut_off_at_its_tool_call_limit() {
+        let (bridge, sid, out) = run_over_budget(Budget { max_tool_calls: Some(3), ..Default::default() }).await;
+        assert_eq!(out.exceeded, Some("max_tool_calls"));
+        assert_eq!(out.usage.tool_calls, 4);
+        assert_eq!(out.text, "step 1. step 2. step 3. step 4. ");
+        assert!(!bridge.sessions.lock().await.contains_key(&sid));
+    }
+
+    #[tokio::test]
+    async fn turn_is_cut_off_at_its_output_token_limit() {
+        // Each step is 8 characters, two estimated tokens
+        let (_, _, out) = run_over_budget(Budget { max_output_tokens: Some(5), ..Default::default() }).await;
+        assert_eq!(out.exceeded, Some("max_output_tokens"));
+        assert_eq!(out.usage.output_tokens, 6);
+        assert_eq!(out.text, "step 1. step 2. step 3. ");
+    }
+
+    #[tokio::test]
+    async fn turn_is_cut_off_at_its_duration_limit() {
+        let (_, _, out) = run_over_budget(Budget { max_duration_ms: Some(300), ..Default::default() }).await;
+        assert_eq!(out.exceeded, Some("max_duration_ms"));
+        assert!(out.usage.duration_ms >= 300);
+        assert!(out.usage.tool_calls >= 1);
+        assert!(out.text.starts_with("step 1. "));
+    }
+
+    /// Answer `env` the way the inbox loop would, without Redis.
+    async fn answered(bridge: &Bridge, env: Envelope) -> (String, Envelope) {
+        match bridge.runtime.answer(bridge, env).await {
+            Answer::Reply { reply_to, envelope } => (reply_to, *envelope),
+            other => panic!("expected a reply, got {:?}", other),
+        }
+    }
+
+    fn control(content: serde_json::Value) -> Envelope {
+        serde_json::from_value(json!({
+            "role": "user",
+            "content": content,
+            "agent_name": "operator",
+            "reply_to": "AG1:test:bridge:replies",
+            "envelope_type": "control",
+            "correlation_id": "cid-control",
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn control_commands_manage_sessions() {
+        let bridge = Bridge::new(test_support::config("chat"), vec![]).await.unwrap();
+        let sid = format!("sess_{}", Uuid::new_v4().simple());
+        bridge.map_session("AG1:test:bridge:replies", "cid-1", &sid).await.unwrap();
+        for message in ["one", "two"] {
+            bridge.run_turn(&sid, message, &ctx()).await.unwrap();
+        }
+
+        let (_, listed) = answered(&bridge, control(json!({ "command": "list" }))).await;
+        assert_eq!(listed.kind(), Some(EnvelopeKind::ControlReply));
+        assert_eq!(listed.correlation_id.as_deref(), Some("cid-control"));
+        assert_eq!(listed.target.as_deref(), Some("operator"));
+        let sessions = listed.content["sessions"].as_array().unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0]["session_id"], json!(sid));
+        assert_eq!(sessions[0]["is_running"], json!(true));
+        assert!(sessions[0]["last_used"].is_string());
+
+        let transcript = control(json!({ "command": "transcript", "session_id": sid, "max": 3 }));
+        let transcript = bridge.control_reply(&transcript).await.unwrap();
+        assert_eq!(
+            transcript.content["messages"],
+            json!([
+                { "role": "assistant", "text": "reply 1" },
+                { "role": "user", "text": "message 2" },
+                { "role": "assistant", "text": "reply 2" },
+            ])
+        );
+
+        let old = bridge.sessions.lock().await[&sid].process.id();
+        bridge.control_reply(&control(json!({ "command": "reset", "session_id": sid }))).await.unwrap();
+        assert_ne!(bridge.sessions.lock().await[&sid].process.id(), old);
+        let reply = bridge.run_turn(&sid, "again", &ctx()).await.unwrap();
+        assert_eq!(reply.text, "reply 1");
+
+        bridge.control_reply(&control(json!({ "command": "kill", "session_id": sid }))).await.unwrap();
+        assert!(bridge.sessions.lock().await.is_empty());
+        assert_eq!(bridge.get_session_for("AG1:test:bridge:replies", "cid-1").await.unwrap(), None);
+        assert!(bridge.control_reply(&control(json!({ "command": "kill", "session_id": sid }))).await.is_err());
+        assert!(bridge.control_reply(&control(json!({ "command": "reboot" }))).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn unsigned_control_is_rejected_when_a_key_is_set() {
+        let cfg = Config { signing_key: Some("secret".into()), ..test_support::config("chat") };
+        let bridge = Bridge::new(cfg, vec![]).await.unwrap();
+
+        let mut env = control(json!({ "command": "list" }));
+        assert!(bridge.control_reply(&env).await.is_err());
+        env.sign(b"wrong");
+        assert!(bridge.control_reply(&env).await.is_err());
+        env.sign(b"secret");
+        assert_eq!(bridge.control_reply(&env).await.unwrap().content["sessions"], json!([]));
+    }
+
+    /// Logs each hook it runs into a shared log, tagged with its name.
+    struct Recorder {
+        name: &'static str,
+        log: std::sync::Arc<std::sync::Mutex<Vec<String>>>,
+        action: MiddlewareAction,
+    }
+
+    #[async_trait::async_trait]
+    impl EnvelopeMiddleware for Recorder {
+        async fn on_inbound(&self, _env: &mut Envelope) -> MiddlewareAction {
+            self.log.lock().unwrap().push(format!("in:{}", self.name));
+            self.action.clone()
+        }
+
+        async fn on_outbound(&self, env: &mut Envelope) {
+            let seen = env.content.get("outbound").and_then(|v| v.as_str()).unwrap_or("").to_string();
+            self.log.lock().unwrap().push(format!("out:{}:{}", self.name, seen));
+            env.content["outbound"] = json!(format!("{}{}", seen, self.name));
+        }
+    }
+
+    async fn bridge_with(actions: Vec<(&'static str, MiddlewareAction)>) -> (Bridge, std::sync::Arc<std::sync::Mutex<Vec<String>>>) {
+        let log = std::sync::Arc::default();
+        let middleware = actions
+            .into_iter()
+            .map(|(name, action)| Box::new(Recorder { name, log: std::sync::Arc::clone(&log), action }) as Box<dyn EnvelopeMiddleware>)
+            .collect();
+        (Bridge::new(test_support::config("chat"), middleware).await.unwrap(), log)
+    }
+
+    #[tokio::test]
+    async fn middleware_runs_in_order_around_the_reply() {
+        let (bridge, log) = bridge_with(vec![("a", MiddlewareAction::Continue), ("b", MiddlewareAction::Continue)]).await;
+        let (reply_to, reply) = answered(&bridge, control(json!({ "command": "list" }))).await;
+        assert_eq!(reply_to, "AG1:test:bridge:replies");
+        assert_eq!(reply.kind(), Some(EnvelopeKind::ControlReply));
+        // Outbound hooks run last-first, each seeing what the later ones did
+        assert_eq!(reply.content["outbound"], json!("ba"));
+        assert_eq!(*log.lock().unwrap(), ["in:a", "in:b", "out:b:", "out:a:b"]);
+    }
+
+    #[tokio::test]
+    async fn rejecting_middleware_answers_with_an_error() {
+        let (bridge, log) = bridge_with(vec![
+            ("a", MiddlewareAction::Reject("too spicy".into())),
+            ("b", MiddlewareAction::Continue),
+        ])
+        .await;
+        let (_, reply) = answered(&bridge, control(json!({ "command": "list" }))).await;
+        assert_eq!(reply.kind(), Some(EnvelopeKind::Error));
+        assert_eq!(reply.correlation_id.as_deref(), Some("cid-control"));
+        assert_eq!(reply.content["rejected"], json!(true));
+        assert_eq!(reply.content["reason"], json!("too spicy"));
+        assert_eq!(reply.content["outbound"], json!("ba"));
+        assert_eq!(*log.lock().unwrap(), ["in:a", "out:b:", "out:a:b"]);
+    }
+
+    #[tokio::test]
+    async fn short_circuit_replies_without_goose() {
+        let canned = Envelope { role: "assistant".into(), content: json!({ "text": "canned" }), ..Default::default() };
+        let (bridge, log) = bridge_with(vec![("a", MiddlewareAction::ShortCircuit(Box::new(canned))), ("b", MiddlewareAction::Continue)]).await;
+        let mut env = control(json!({}));
+        env.set_kind(EnvelopeKind::Message);
+        let (_, reply) = answered(&bridge, env).await;
+        assert_eq!(reply.content["text"], json!("canned"));
+        assert_eq!(reply.content["outbound"], json!("ba"));
+        assert!(bridge.sessions.lock().await.is_empty());
+        assert_eq!(*log.lock().unwrap(), ["in:a", "out:b:", "out:a:b"]);
+    }
+
+    fn user_message(reply_to: &str) -> Envelope {
+        serde_json::from_value(json!({
+            "role": "user",
+            "content": { "text": "hello" },
+            "reply_to": reply_to,
+            "correlation_id": "cid-drain",
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn draining_defers_new_conversations_and_keeps_existing_ones() {
+        let cfg = Config { drain_idle_ms: 0, ..test_support::config("chat") };
+        let bridge = Bridge::new(cfg, vec![]).await.unwrap();
+        let sid = format!("sess_{}", Uuid::new_v4().simple());
+        bridge.map_session("AG1:test:bridge:existing", "cid-drain", &sid).await.unwrap();
+        bridge.run_turn(&sid, "one", &ctx()).await.unwrap();
+
+        bridge.begin_drain();
+        // Deferred before anything is started or sent, so it stays unacked on the inbox
+        let new = user_message("AG1:test:bridge:new");
+        assert!(matches!(bridge.runtime.answer(&bridge, new).await, Answer::Deferred));
+        assert_eq!(bridge.get_session_for("AG1:test:bridge:new", "cid-drain").await.unwrap(), None);
+        assert_eq!(bridge.sessions.lock().await.len(), 1);
+        let existing = bridge.runtime.incoming(user_message("AG1:test:bridge:existing"));
+        assert!(bridge.accepted_while_draining(&existing).await);
+        assert!(bridge.accepted_while_draining(&bridge.runtime.incoming(control(json!({ "command": "list" })))).await);
+
+        // With nothing left running, the drain finishes
+        tokio::time::timeout(Duration::from_secs(5), bridge.wait_drained()).await.unwrap();
+        assert!(bridge.sessions.lock().await.is_empty());
+        assert_eq!(bridge.get_session_for("AG1:test:bridge:existing", "cid-drain").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn conversations_sharing_a_reply_stream_get_their_own_sessions() {
+        let bridge = Bridge::new(test_support::config("chat"), vec![]).await.unwrap();
+        let ask = |cid: &str, session_code: Option<&str>| {
+            let mut env = user_message("AG1:test:bridge:shared");
+            env.correlation_id = Some(cid.into());
+            env.session_code = session_code.map(str::to_string);
+            env
+        };
+        let bridge = &bridge;
+        let answer = |env: Envelope| async move { answered(bridge, env).await.1 };
+
+        let a1 = answer(ask("cid-a", None)).await;
+        let b1 = answer(ask("cid-b", None)).await;
+        let a2 = answer(ask("cid-a", None)).await;
+        // The chat stub counts the messages its session has seen
+        assert_eq!((a1.text_or_empty(), b1.text_or_empty(), a2.text_or_empty()), ("reply 1", "reply 1", "reply 2"));
+        assert_eq!(a1.session_code, a2.session_code);
+        assert_ne!(a1.session_code, b1.session_code);
+        assert_eq!(a2.correlation_id.as_deref(), Some("cid-a"));
+
+        // A session_code holds a conversation together across correlation ids
+        let code = format!("sess_{}", Uuid::new_v4().simple());
+        answer(ask("cid-c", Some(&code))).await;
+        let c2 = answer(ask("cid-d", Some(&code))).await;
+        assert_eq!(c2.text_or_empty(), "reply 2");
+        assert_eq!(c2.session_code.as_deref(), Some(code.as_str()));
+        assert_eq!(bridge.sessions.lock().await.len(), 3);
+        assert_eq!(bridge.conversation_sessions.lock().await.len(), 2);
+
+        // Stopping idle sessions takes their mappings with them
+        assert_eq!(bridge.stop_idle_sessions(Duration::ZERO).await, 0);
+        assert!(bridge.conversation_sessions.lock().await.is_empty());
+        assert_eq!(bridge.live_sessions.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn seed_transcript_is_history_for_a_new_session_only() {
+        let bridge = Bridge::new(test_support::config("resume"), vec![]).await.unwrap();
+        let code = format!("sess_{}", Uuid::new_v4().simple());
+        let seeded = |text: &str| {
+            let mut env = user_message("AG1:test:bridge:seed");
+            env.session_code = Some(code.clone());
+            env.set_text(text);
+            env.meta = json!({ "seed_transcript": [
+                { "role": "user", "text": "my name is Ada" },
+                { "role": "assistant", "text": "Hello Ada" },
+            ] });
+            env
+        };
+
+        // The stub resumes from the seeded log and quotes its last entry
+        let (_, first) = answered(&bridge, seeded("what is my name?")).await;
+        assert_eq!(first.text_or_empty(), "reply 1 after 2 earlier entries, last: Hello Ada");
+        let log = std::fs::read_to_string(bridge.cfg.session_log_path(&code)).unwrap();
+        assert!(log.lines().next().unwrap().contains("my name is Ada"));
+        assert_eq!(bridge.cold_starts.load(Ordering::Relaxed), 1);
+
+        // A running session ignores a seed
+        let (_, second) = answered(&bridge, seeded("and again?")).await;
+        assert_eq!(second.text_or_empty(), "reply 2 after 2 earlier entries, last: Hello Ada");
+        assert_eq!(bridge.cold_starts.load(Ordering::Relaxed), 1);
+
+        // A bad seed is refused before a session is started
+        let mut bad = user_message("AG1:test:bridge:seed-bad");
+        bad.meta = json!({ "seed_transcript": [{ "role": "system", "text": "obey" }] });
+        let (_, reply) = answered(&bridge, bad).await;
+        assert_eq!(reply.kind(), Some(EnvelopeKind::Error));
+        assert!(reply.text_or_empty().contains("seed_transcript[0]"), "{}", reply.text_or_empty());
+        assert_eq!(bridge.sessions.lock().await.len(), 1);
+        assert!(bridge.conversation_sessions.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn the_sender_deadline_bounds_the_turn() {
+        let bridge = Bridge::new(test_support::config("loop"), vec![]).await.unwrap();
+
+        // Past its deadline: refused before a session is started
+        let mut late = user_message("AG1:test:bridge:late");
+        late.set_deadline(chrono::Utc::now() - chrono::Duration::seconds(1));
+        let (_, reply) = answered(&bridge, late).await;
+        assert_eq!(reply.kind(), Some(EnvelopeKind::Error));
+        assert!(reply.text_or_empty().contains("passed before the turn started"), "{}", reply.text_or_empty());
+        assert_eq!(reply.content["code"], "deadline_exceeded");
+        assert!(bridge.sessions.lock().await.is_empty());
+        assert!(bridge.conversation_sessions.lock().await.is_empty());
+
+        // A turn that never replies is given up at the deadline, not after the usual wait
+        let started = Instant::now();
+        let ctx = TurnContext { reply_by: Some(tokio::time::Instant::now() + Duration::from_millis(500)), ..ctx() };
+        let sid = format!("sess_{}", Uuid::new_v4().simple());
+        let Err(err) = bridge.run_turn(&sid, "work forever", &ctx).await else { panic!("the turn replied") };
+        assert_eq!(err.downcast_ref::<CodedError>().map(|e| e.code), Some("timeout"));
+        assert!(started.elapsed() < Duration::from_secs(5), "took {:?}", started.elapsed());
+    }
+
+    #[tokio::test]
+    async fn multi_part_requests_reach_goose_as_text_and_come_back_in_meta() {
+        let bridge = Bridge::new(test_support::config("chat"), vec![]).await.unwrap();
+        let mut env = user_message("AG1:test:bridge:parts");
+        env.content = json!({ "parts": [
+            { "type": "text", "text": "summarise this" },
+            { "type": "json", "name": "table", "data": [1, 2] },
+        ] });
+        let (_, reply) = answered(&bridge, env.clone()).await;
+        assert_eq!(reply.text_or_empty(), "reply 1");
+        assert_eq!(reply.meta["request_parts"], env.content["parts"]);
+
+        let (_, plain) = answered(&bridge, user_message("AG1:test:bridge:parts")).await;
+        assert!(plain.meta.get("request_parts").is_none());
+    }
+
+    #[tokio::test]
+    async fn ping_is_answered_at_once_while_a_turn_runs() {
+        let bridge = Bridge::new(test_support::config("loop"), vec![]).await.unwrap();
+        let mut turn = user_message("AG1:test:bridge:busy");
+        Budget { max_duration_ms: Some(1500), ..Default::default() }.insert_into(&mut turn.meta);
+        let mut ping = Envelope::ping("AG1:test:bridge:pinger");
+        ping.agent_name = Some("pinger".into());
+
+        let pinging = async {
+            while bridge.live_sessions.load(Ordering::Relaxed) == 0 {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            let asked = Instant::now();
+            let pong = answered(&bridge, ping.clone()).await;
+            (asked.elapsed(), pong)
+        };
+        let ((took, (reply_to, pong)), (_, turn_reply)) = tokio::join!(pinging, answered(&bridge, turn));
+        assert!(took < Duration::from_millis(100), "pong took {took:?}");
+        assert_eq!(reply_to, "AG1:test:bridge:pinger");
+        assert_eq!(pong.kind(), Some(EnvelopeKind::Pong));
+        assert_eq!(pong.correlation_id, ping.correlation_id);
+        assert_eq!(pong.target.as_deref(), Some("pinger"));
+        let info = pong.pong_info().unwrap();
+        assert_eq!(info.agent_name, "GooseAgent");
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(info.active_sessions, 1);
+        // No Redis to read the lag from
+        assert_eq!(info.inbox_lag, None);
+
+        // The turn ran its course, and the ping started no session of its own
+        assert_eq!(turn_reply.kind(), Some(EnvelopeKind::Error));
+        assert!(bridge.conversation_sessions.lock().await.is_empty());
+        assert!(bridge.sessions.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn pings_skip_the_inbound_middleware() {
+        let (bridge, log) = bridge_with(vec![("a", MiddlewareAction::Reject("rate limited".into()))]).await;
+        let (_, pong) = answered(&bridge, Envelope::ping("AG1:test:bridge:pinger")).await;
+        assert_eq!(pong.kind(), Some(EnvelopeKind::Pong));
+        assert_eq!(pong.pong_info().unwrap().active_sessions, 0);
+        assert_eq!(*log.lock().unwrap(), ["out:a:"]);
+    }
+
+    #[tokio::test]
+    async fn answered_envelopes_are_journaled() {
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = Config { journal_dir: Some(dir.path().into()), ..test_support::config("chat") };
+        let bridge = Bridge::new(cfg, vec![]).await.unwrap();
+        let journaled = |id: &'static str, mut env: Envelope| async {
+            env.envelope_id = Some(id.into());
+            let (_, reply) = answered(&bridge, env).await;
+            bridge.on_sent(id, &reply).await;
+        };
+
+        // Both answered before either is journaled, as with concurrent turns sharing a correlation id
+        let mut ok = user_message("AG1:test:bridge:journal");
+        ok.user_id = Some("ada".into());
+        ok.envelope_id = Some("1-0".into());
+        let (_, ok_reply) = answered(&bridge, ok).await;
+        let mut again = user_message("AG1:test:bridge:journal");
+        again.user_id = Some("bob".into());
+        again.envelope_id = Some("2-0".into());
+        let (_, again_reply) = answered(&bridge, again).await;
+        bridge.on_sent("1-0", &ok_reply).await;
+        bridge.on_sent("2-0", &again_reply).await;
+
+        let mut empty = user_message("AG1:test:bridge:journal");
+        empty.correlation_id = Some("cid-empty".into());
+        empty.set_text("");
+        journaled("3-0", empty).await;
+        let mut unknown = control(json!({ "text": "hi" }));
+        unknown.envelope_type = Some("message_repy".into());
+        journaled("4-0", unknown).await;
+        journaled("5-0", Envelope::ping("AG1:test:bridge:pinger")).await;
+
+        let records = bus::journal::query_journal(dir.path(), &Default::default()).unwrap();
+        let outcomes: Vec<_> = records.iter().map(|r| (r.correlation_id.as_str(), r.outcome)).collect();
+        use bus::journal::Outcome;
+        assert_eq!(
+            outcomes,
+            [
+                ("cid-drain", Outcome::Ok),
+                ("cid-drain", Outcome::Ok),
+                ("cid-empty", Outcome::Error),
+                ("cid-control", Outcome::Rejected)
+            ]
+        );
+        assert_eq!(records[0].user_id.as_deref(), Some("ada"));
+        assert_eq!(records[1].user_id.as_deref(), Some("bob"));
+        assert_eq!(records[0].request["text"], "hello");
+        assert_eq!(records[0].reply["text"], "reply 1");
+        assert_eq!(records[3].agent_name.as_deref(), Some("operator"));
+        assert!(bridge.journal_pending.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn unknown_envelope_kinds_are_rejected() {
+        let bridge = Bridge::new(test_support::config("chat"), vec![]).await.unwrap();
+        let mut env = control(json!({ "text": "hi" }));
+        env.envelope_type = Some("message_repy".into());
+        let (_, err) = answered(&bridge, env.clone()).await;
+        assert_eq!(err.kind(), Some(EnvelopeKind::Error));
+        assert!(err.text_or_empty().contains("unsupported envelope_type \"message_repy\""), "{}", err.text_or_empty());
+
+        env.set_kind(EnvelopeKind::Heartbeat);
+        assert!(matches!(bridge.runtime.answer(&bridge, env).await, Answer::Ignored));
+        assert!(bridge.sessions.lock().await.is_empty());
+    }
+
+    #[test]
+    fn confirmation_response_bodies() {
+        assert!(confirmation_allows(&json!({ "approved": true })));
+        assert!(confirmation_allows(&json!({ "decision": "Allow" })));
+        assert!(!confirmation_allows(&json!({ "decision": "deny" })));
+        assert!(!confirmation_allows(&json!({})));
+    }
+}
@@ -0,0 +1,411 @@
+//! Incremental reader over a Goose session JSONL log.
+//!
+//! One `JsonlTail` follows the log for a whole turn, so the same reader can
+//! look for the assistant reply and for the tool request behind a
+//! confirmation prompt.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use bus::SeedTurn;
+use serde_json::{json, Value};
+use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt, AsyncSeekExt, BufReader};
+use tokio::sync::Notify;
+use tokio::time::Instant;
+use tracing::{debug, error, warn};
+
+const MAX_CONSECUTIVE_ERRORS: u32 = 5;
+/// How often a polling tail checks the log for growth.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// How often a watching tail checks anyway, in case a notification was missed.
+const WATCH_FALLBACK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A tool call Goose asked to make, as recorded in a `toolRequest` content item.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    pub arguments: Value,
+}
+
+pub struct JsonlTail {
+    sid: String,
+    path: PathBuf,
+    /// Kept open across appends; dropped only to start over after an error or truncation
+    reader: Option<BufReader<File>>,
+    offset: u64,
+    /// Start of a line whose newline hasn't been written yet
+    partial: Vec<u8>,
+    buffer: String,
+    consecutive_errors: u32,
+    poll: bool,
+    watch: Option<LogWatch>,
+}
+
+/// Filesystem notifications for one log file.
+struct LogWatch {
+    _watcher: RecommendedWatcher,
+    changed: Arc<Notify>,
+}
+
+impl LogWatch {
+    /// Watches the log's directory rather than the file, so the watch survives
+    /// the file being replaced and can be set up before it exists.
+    fn new(path: &Path) -> notify::Result<Self> {
+        let dir = path.parent().filter(|d| !d.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        let name = path.file_name().map(|n| n.to_os_string());
+        let changed = Arc::new(Notify::new());
+        let notifier = changed.clone();
+        let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+            // The directory holds every session's log; wake only for ours
+            if res.is_ok_and(|event| event.paths.iter().any(|p| p.file_name() == name.as_deref())) {
+                notifier.notify_one();
+            }
+        })?;
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+        Ok(Self { _watcher: watcher, changed })
+    }
+}
+
+impl JsonlTail {
+    pub fn new(sid: &str, path: PathBuf, start_offset: u64) -> Self {
+        Self {
+            sid: sid.to_string(),
+            path,
+            reader: None,
+            offset: start_offset,
+            partial: Vec::new(),
+            buffer: String::new(),
+            consecutive_errors: 0,
+            poll: false,
+            watch: None,
+        }
+    }
+
+    /// Check the log for growth on a timer instead of waiting for filesystem
+    /// notifications, which some filesystems (e.g. NFS) never deliver.
+    pub fn polling(mut self, poll: bool) -> Self {
+        self.poll = poll;
+        self
+    }
+
+    /// Byte offset just past the last line consumed.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    async fn open(&mut self) -> Result<()> {
+        let mut file = File::open(&self.path).await.map_err(|e| {
+            error!(session_id = %self.sid, path = %self.path.display(), error = %e, "Failed to open JSONL file");
+            anyhow!("Failed to open JSONL file: {}", e)
+        })?;
+        file.seek(std::io::SeekFrom::Start(self.offset)).await.map_err(|e| {
+            error!(session_id = %self.sid, offset = self.offset, error = %e, "Failed to seek in JSONL file");
+            anyhow!("Failed to seek in JSONL file: {}", e)
+        })?;
+        self.partial.clear();
+        self.reader = Some(BufReader::new(file));
+        Ok(())
+    }
+
+    /// Start watching the log unless polling, falling back to polling if the
+    /// watch can't be set up.
+    fn start_watching(&mut self) {
+        if self.poll || self.watch.is_some() {
+            return;
+        }
+        match LogWatch::new(&self.path) {
+            Ok(watch) => self.watch = Some(watch),
+            Err(e) => {
+                warn!(session_id = %self.sid, path = %self.path.display(), error = %e, "Can't watch session log, polling it instead");
+                self.poll = true;
+            }
+        }
+    }
+
+    /// Wait until the log may have changed, or until `until`.
+    async fn wait_for_change(&self, until: Instant) {
+        let interval = if self.poll { POLL_INTERVAL } else { WATCH_FALLBACK_INTERVAL };
+        let wake = until.min(Instant::now() + interval);
+        match &self.watch {
+            Some(watch) => {
+                let _ = tokio::time::timeout_at(wake, watch.changed.notified()).await;
+            }
+            None => tokio::time::sleep_until(wake).await,
+        }
+    }
+
+    /// The next complete line, or `None` at the end of what has been written so far.
+    async fn read_line(&mut self) -> std::io::Result<Option<String>> {
+        let Some(reader) = self.reader.as_mut() else { return Ok(None) };
+        reader.read_until(b'\n', &mut self.partial).await?;
+        if self.partial.last() != Some(&b'\n') {
+            return Ok(None);
+        }
+        let mut line = std::mem::take(&mut self.partial);
+        self.offset += line.len() as u64;
+        line.pop();
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        Ok(Some(String::from_utf8_lossy(&line).into_owned()))
+    }
+
+    /// Whether the log is now shorter than what has been read of it: it was
+    /// truncated, or replaced by a new file.
+    async fn shrank(&self) -> bool {
+        let read = self.offset + self.partial.len() as u64;
+        tokio::fs::metadata(&self.path).await.is_ok_and(|m| m.len() < read)
+    }
+
+    /// Next complete JSON entry in the log, or `Ok(None)` once `deadline` passes.
+    ///
+    /// Entries may span several lines; MCP client warnings and invalid JSON are
+    /// skipped. If the log shrinks it is read again from the start.
+    pub async fn next_entry(&mut self, deadline: Instant) -> Result<Option<Value>> {
+        self.start_watching();
+        loop {
+            let Some(left) = deadline.checked_duration_since(Instant::now()) else {
+                return Ok(None);
+            };
+
+            if self.reader.is_none() {
+                if !self.path.exists() {
+                    if left <= Duration::from_millis(100) {
+                        return Err(anyhow!("Timeout waiting for session log file to appear: {}", self.path.display()));
+                    }
+                    self.wait_for_change(deadline - Duration::from_millis(100)).await;
+                    continue;
+                }
+                self.open().await?;
+            }
+
+            let line = match self.read_line().await {
+                Ok(Some(line)) => line,
+                Ok(None) => {
+                    if self.shrank().await {
+                        warn!(
+                            session_id = %self.sid,
+                            path = %self.path.display(),
+                            offset = self.offset,
+                            "Session log shrank (truncated or rotated), reading it again from the start"
+                        );
+                        self.reader = None;
+                        self.offset = 0;
+                        self.buffer.clear();
+                    } else {
+                        self.wait_for_change(deadline).await;
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    self.consecutive_errors += 1;
+                    error!(
+                        session_id = %self.sid,
+                        error = %e,
+                        consecutive_errors = self.consecutive_errors,
+                        "Failed to read line from JSONL"
+                    );
+                    if self.consecutive_errors >= MAX_CONSECUTIVE_ERRORS {
+                        return Err(anyhow!("Too many consecutive read errors: {}", e));
+                    }
+                    // Reopen at the last complete line
+                    self.reader = None;
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            self.consecutive_errors = 0;
+            debug!(session_id = %self.sid, line_content = line, "Read line from JSONL");
+
+            // Filter out MCP client warnings
+            if line.contains("mcp_client::transport::stdio") {
+                debug!(session_id = %self.sid, "Skipping MCP client warning message");
+                continue;
+            }
+
+            self.buffer.push_str(&line);
+            match serde_json::from_str::<Value>(&self.buffer) {
+                Ok(json) => {
+                    self.buffer.clear();
+                    return Ok(Some(json));
+                }
+                // Continue reading if JSON appears incomplete
+                Err(e) if e.is_eof() => {
+                    debug!(session_id = %self.sid, "Waiting for rest of JSON");
+                }
+                Err(e) => {
+                    debug!(session_id = %self.sid, error = %e, "Discarding invalid JSON line");
+                    self.buffer.clear();
+                }
+            }
+        }
+    }
+}
+
+/// Reply text of an assistant entry whose first content item is text.
+///
+/// Entries that also request a tool are not replies: the turn continues once
+/// the tool has run.
+pub fn assistant_text(entry: &Value) -> Option<&str> {
+    if entry.get("role").and_then(|r| r.as_str()) != Some("assistant") || tool_request(entry).is_some() {
+        return None;
+    }
+    entry.get("content")?.as_array()?.first()?.get("text")?.as_str()
+}
+
+/// All text items of an assistant entry joined, or `None` if it has no text.
+pub fn entry_text(entry: &Value) -> Option<String> {
+    if entry.get("role").and_then(|r| r.as_str()) != Some("assistant") {
+        return None;
+    }
+    let text: String = entry
+        .get("content")?
+        .as_array()?
+        .iter()
+        .filter_map(|item| item.get("text")?.as_str())
+        .collect();
+    (!text.is_empty()).then_some(text)
+}
+
+/// The last `toolRequest` in an assistant entry, if any.
+pub fn tool_request(entry: &Value) -> Option<ToolCall> {
+    if entry.get("role").and_then(|r| r.as_str()) != Some("assistant") {
+        return None;
+    }
+    entry.get("content")?.as_array()?.iter().rev().find_map(|item| {
+        if item.get("type").and_then(|t| t.as_str()) != Some("toolRequest") {
+            return None;
+        }
+        let call = item.get("toolCall")?.get("value")?;
+        Some(ToolCall {
+            id: item.get("id").and_then(|i| i.as_str()).unwrap_or_default().to_string(),
+            name: call.get("name")?.as_str()?.to_string(),
+            arguments: call.get("arguments").cloned().unwrap_or(Value::Null),
+        })
+    })
+}
+
+/// A session log holding `seed` as goose messages, one JSON line per turn,
+/// which [`assistant_text`] and a resumed goose both read back.
+pub fn seed_log(seed: &[SeedTurn]) -> String {
+    let created = chrono::Utc::now().timestamp();
+    seed.iter()
+        .map(|turn| {
+            let entry = json!({
+                "role": turn.role,
+                "created": created,
+                "content": [{ "type": "text", "text": turn.text }],
+            });
+            format!("{}\n", entry)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    #[test]
+    fn seed_log_is_goose_messages() {
+        let seed = [
+            SeedTurn { role: "user".into(), text: "my name is Ada".into() },
+            SeedTurn { role: "assistant".into(), text: "Hello \"Ada\"\nhow can I help?".into() },
+        ];
+        let log = seed_log(&seed);
+        let entries: Vec<Value> = log.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0]["role"], "user");
+        assert_eq!(entries[0]["content"][0]["text"], "my name is Ada");
+        assert_eq!(assistant_text(&entries[1]), Some("Hello \"Ada\"\nhow can I help?"));
+        assert!(log.ends_with('\n'));
+    }
+
+    #[test]
+    fn extracts_text_and_tool_requests() {
+        let reply = json!({ "role": "assistant", "content": [{ "type": "text", "text": "done" }] });
+        assert_eq!(assistant_text(&reply), Some("done"));
+        assert_eq!(tool_request(&reply), None);
+
+        let call = json!({
+            "role": "assistant",
+            "content": [
+                { "type": "text", "text": "" },
+                { "type": "toolRequest", "id": "t1", "toolCall": {
+                    "status": "success",
+                    "value": { "name": "developer__shell", "arguments": { "command": "ls" } }
+                } }
+            ]
+        });
+        assert_eq!(assistant_text(&call), None);
+        assert_eq!(entry_text(&call), None);
+        assert_eq!(entry_text(&reply).as_deref(), Some("done"));
+        assert_eq!(
+            tool_request(&call),
+            Some(ToolCall {
+                id: "t1".into(),
+                name: "developer__shell".into(),
+                arguments: json!({ "command": "ls" }),
+            })
+        );
+        assert_eq!(tool_request(&json!({ "role": "user", "content": [] })), None);
+    }
+
+    async fn follow_appends(poll: bool) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("appended.jsonl");
+        std::fs::write(&path, "").unwrap();
+
+        let writer = tokio::spawn({
+            let path = path.clone();
+            async move {
+                let mut file = tokio::fs::OpenOptions::new().append(true).open(&path).await.unwrap();
+                // The first entry arrives without its newline at first
+                for chunk in ["{\"n\":", "1}\n{\"n\":", "2}\n"] {
+                    tokio::time::sleep(Duration::from_millis(30)).await;
+                    file.write_all(chunk.as_bytes()).await.unwrap();
+                    file.flush().await.unwrap();
+                }
+            }
+        });
+
+        let mut tail = JsonlTail::new("appended", path.clone(), 0).polling(poll);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        assert_eq!(tail.next_entry(deadline).await.unwrap(), Some(json!({ "n": 1 })));
+        assert_eq!(tail.watch.is_some(), !poll);
+        assert_eq!(tail.next_entry(deadline).await.unwrap(), Some(json!({ "n": 2 })));
+        writer.await.unwrap();
+        assert_eq!(tail.offset(), std::fs::metadata(&path).unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn follows_lines_appended_by_another_task() {
+        follow_appends(false).await;
+    }
+
+    #[tokio::test]
+    async fn follows_lines_appended_by_another_task_when_polling() {
+        follow_appends(true).await;
+    }
+
+    #[tokio::test]
+    async fn restarts_from_the_top_when_the_log_shrinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rotated.jsonl");
+        std::fs::write(&path, "{\"n\":1}\n{\"n\":2}\n").unwrap();
+
+        let mut tail = JsonlTail::new("rotated", path.clone(), 0);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        assert_eq!(tail.next_entry(deadline).await.unwrap(), Some(json!({ "n": 1 })));
+        assert_eq!(tail.next_entry(deadline).await.unwrap(), Some(json!({ "n": 2 })));
+
+        std::fs::write(&path, "{\"n\":3}\n").unwrap();
+        assert_eq!(tail.next_entry(deadline).await.unwrap(), Some(json!({ "n": 3 })));
+        assert_eq!(tail.offset(), 8);
+    }
+}
@@ -1,6 +1,8 @@
 mod config;
 mod bridge;
 mod session;
+mod jsonl;
+mod middleware;
 mod util;
 
 use anyhow::Result;
@@ -10,9 +12,19 @@ use tracing_subscriber::{EnvFilter, fmt};
 use tracing_subscriber::prelude::*;
 use config::Config;
 use bridge::Bridge;
+use middleware::{EnvelopeMiddleware, MaxLengthMiddleware, MetadataStampMiddleware};
+
+/// Bridges Redis bus envelopes to goose sessions; configured through `GOOSE_*` variables.
+#[derive(Parser)]
+struct Cli {
+    /// Also check at startup that goose can reach its LLM provider
+    #[arg(long)]
+    probe_llm: bool,
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let cli = Cli::parse();
     info!("Starting ag1goose-bridge...");
     
     // Initialize tracing
@@ -26,19 +38,62 @@ async fn main() -> Result<()> {
         inbox = cfg.inbox, 
         redis_url = cfg.redis_url, 
         goose_bin = cfg.goose_bin, 
+        warm_pool_size = cfg.warm_pool_size,
         "Loaded config"
     );
 
+    // Fail now rather than on every envelope when goose can't run
+    session::probe_goose(&cfg.goose_bin, cli.probe_llm).await?;
+
+    let mut middleware: Vec<Box<dyn EnvelopeMiddleware>> = vec![Box::new(MetadataStampMiddleware)];
+    if let Some(max_chars) = cfg.max_message_chars {
+        middleware.push(Box::new(MaxLengthMiddleware { max_chars }));
+    }
+
     // Create and run bridge
     debug!("Creating bridge instance...");
-    let bridge = Bridge::new(cfg).await?;
+    let bridge = Bridge::new(cfg, middleware).await?;
     info!("Starting bridge run loop...");
     
-    if let Err(e) = bridge.run().await {
+    let res = tokio::select! {
+        res = bridge.run() => res,
+        res = stop_signals(&bridge) => {
+            warn!("Stopping without draining");
+            res
+        }
+    };
+    if let Err(e) = res {
         error!(error = %e, "Bridge error");
         return Err(e);
     }
 
     info!("Bridge exited cleanly");
     Ok(())
-}
\ No newline at end of file
+}
+/// Soft stop on SIGTERM: begin draining and let `run` finish once sessions
+/// are done. Returns (for a hard stop) on a second SIGTERM or Ctrl-C.
+async fn stop_signals(bridge: &Bridge) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut term = signal(SignalKind::terminate())?;
+        tokio::select! {
+            _ = term.recv() => {
+                info!("SIGTERM received, draining; send it again to stop at once");
+                bridge.begin_drain();
+            }
+            res = tokio::signal::ctrl_c() => return Ok(res?),
+        }
+        tokio::select! {
+            _ = term.recv() => {}
+            res = tokio::signal::ctrl_c() => res?,
+        }
+        Ok(())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = bridge;
+        tokio::signal::ctrl_c().await?;
+        Ok(())
+    }
+}
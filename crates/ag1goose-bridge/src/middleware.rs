@@ -0,0 +1,121 @@
+use async_trait::async_trait;
+use bus::Envelope;
+use serde_json::json;
+
+/// What the bridge does with an inbound envelope once a middleware has seen it.
+#[derive(Debug, Clone)]
+pub enum MiddlewareAction {
+    /// Hand it to the next middleware, then to Goose
+    Continue,
+    /// Answer with an `error` envelope carrying this reason, without invoking Goose
+    Reject(String),
+    /// Answer with this envelope, without invoking Goose. None of the
+    /// built-ins do; it's there for deployment-specific middleware.
+    #[cfg_attr(not(test), allow(dead_code))]
+    ShortCircuit(Box<Envelope>),
+}
+
+/// Hook run around the bridge's handling of each inbox envelope.
+///
+/// Inbound hooks run in chain order and stop at the first one that doesn't
+/// `Continue`. Outbound hooks run in reverse order on every reply the bridge
+/// sends back (including error, rejection and short-circuit replies), so the
+/// first middleware sees the reply as it leaves. Tool confirmation requests
+/// sent mid-turn don't pass through them.
+#[async_trait]
+pub trait EnvelopeMiddleware: Send + Sync {
+    async fn on_inbound(&self, _env: &mut Envelope) -> MiddlewareAction {
+        MiddlewareAction::Continue
+    }
+
+    async fn on_outbound(&self, _env: &mut Envelope) {}
+}
+
+pub async fn run_inbound(chain: &[Box<dyn EnvelopeMiddleware>], env: &mut Envelope) -> MiddlewareAction {
+    for middleware in chain {
+        match middleware.on_inbound(env).await {
+            MiddlewareAction::Continue => {}
+            action => return action,
+        }
+    }
+    MiddlewareAction::Continue
+}
+
+pub async fn run_outbound(chain: &[Box<dyn EnvelopeMiddleware>], env: &mut Envelope) {
+    for middleware in chain.iter().rev() {
+        middleware.on_outbound(env).await;
+    }
+}
+
+/// Rejects messages whose `content.text` is longer than `max_chars` characters.
+pub struct MaxLengthMiddleware {
+    pub max_chars: usize,
+}
+
+#[async_trait]
+impl EnvelopeMiddleware for MaxLengthMiddleware {
+    async fn on_inbound(&self, env: &mut Envelope) -> MiddlewareAction {
+        let len = env.text_or_empty().chars().count();
+        if len > self.max_chars {
+            return MiddlewareAction::Reject(format!(
+                "message is {} characters, over the limit of {}",
+                len, self.max_chars
+            ));
+        }
+        MiddlewareAction::Continue
+    }
+}
+
+/// Stamps `meta.received_at` and `meta.bridge_version` on inbound envelopes.
+/// `meta` that is neither an object nor `null` is left alone.
+pub struct MetadataStampMiddleware;
+
+#[async_trait]
+impl EnvelopeMiddleware for MetadataStampMiddleware {
+    async fn on_inbound(&self, env: &mut Envelope) -> MiddlewareAction {
+        if env.meta.is_null() {
+            env.meta = json!({});
+        }
+        if let Some(meta) = env.meta.as_object_mut() {
+            meta.insert("received_at".into(), json!(chrono::Utc::now().to_rfc3339()));
+            meta.insert("bridge_version".into(), json!(env!("CARGO_PKG_VERSION")));
+        }
+        MiddlewareAction::Continue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(text: &str) -> Envelope {
+        let mut env = Envelope::default();
+        env.set_text(text);
+        env
+    }
+
+    #[tokio::test]
+    async fn max_length_rejects_long_text() {
+        let chain: Vec<Box<dyn EnvelopeMiddleware>> = vec![Box::new(MaxLengthMiddleware { max_chars: 5 })];
+        assert!(matches!(run_inbound(&chain, &mut message("héllo")).await, MiddlewareAction::Continue));
+        match run_inbound(&chain, &mut message("héllo!")).await {
+            MiddlewareAction::Reject(reason) => assert!(reason.contains("over the limit of 5"), "{reason}"),
+            other => panic!("expected a rejection, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn metadata_stamp_keeps_existing_meta() {
+        let mut env = message("hi");
+        env.meta = json!({ "budget": { "max_tool_calls": 3 } });
+        MetadataStampMiddleware.on_inbound(&mut env).await;
+        assert_eq!(env.meta["budget"]["max_tool_calls"], json!(3));
+        assert_eq!(env.meta["bridge_version"], json!(env!("CARGO_PKG_VERSION")));
+        assert!(env.meta["received_at"].is_string());
+
+        let mut env = message("hi");
+        env.meta = serde_json::Value::Null;
+        MetadataStampMiddleware.on_inbound(&mut env).await;
+        assert!(env.meta["received_at"].is_string());
+    }
+}
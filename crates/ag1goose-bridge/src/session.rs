@@ -1,17 +1,104 @@
-use std::path::PathBuf;
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
 use anyhow::{anyhow, Result};
-use tokio::fs::File;
-use tokio::io::{AsyncBufReadExt, AsyncSeekExt, BufReader};
-use tokio::process::{Child, ChildStdin, Command};
-use tokio::time::{timeout as tokio_timeout, Instant};
-use tokio_stream::StreamExt;
-use tokio_util::codec::{FramedRead, LinesCodec};
+use bus::{Budget, EnvelopeKind, SeedTurn, TurnUsage};
+use bus_agent::CodedError;
+use serde_json::Value;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStderr, ChildStdin, Command};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
 use tracing::{debug, error, info, warn};
 
 use crate::config::Config;
+use crate::jsonl::{assistant_text, entry_text, seed_log, tool_request, JsonlTail, ToolCall};
+
+/// Printed by goose-cli when a tool call needs interactive approval.
+const CONFIRMATION_PROMPT: &str = "do you allow?";
+/// How long to keep reading the log for the tool request once a prompt is seen.
+const CONFIRMATION_LOOKUP_GRACE: Duration = Duration::from_millis(500);
+/// Lines of goose's stderr kept for the error of a session that fails to start.
+const STDERR_TAIL_LINES: usize = 20;
+/// How long to wait for the last stderr lines of a goose that exited.
+const STDERR_DRAIN_TIMEOUT: Duration = Duration::from_millis(500);
+/// How long to keep reading the log of a goose that exited mid-turn, for a
+/// reply it wrote just before.
+const EXIT_LOG_GRACE: Duration = Duration::from_millis(200);
+/// How long `goose --version` may take in the startup probe.
+const VERSION_PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long the `--probe-llm` round trip through goose's provider may take.
+const LLM_PROBE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Log position, last tool request and usage of an in-flight turn.
+pub struct Turn {
+    tail: JsonlTail,
+    last_tool: Option<ToolCall>,
+    budget: Option<Budget>,
+    started: Instant,
+    usage: TurnUsage,
+    /// Assistant text logged so far, including text alongside tool requests
+    partial: String,
+    /// Names of the tools requested so far, in order
+    tools: Vec<String>,
+}
+
+impl Turn {
+    /// Stop the turn with [`TurnEvent::BudgetExceeded`] once `budget` is used up.
+    pub fn with_budget(mut self, budget: Option<Budget>) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    /// Usage so far, with the duration measured up to now.
+    pub fn usage(&self) -> TurnUsage {
+        TurnUsage { duration_ms: self.started.elapsed().as_millis() as u64, ..self.usage }
+    }
+
+    pub fn partial_text(&self) -> &str {
+        &self.partial
+    }
+
+    pub fn tools_used(&self) -> &[String] {
+        &self.tools
+    }
+
+    /// Account for one log entry; returns the tool it requests, if any.
+    fn record(&mut self, entry: &Value) -> Option<ToolCall> {
+        let call = tool_request(entry);
+        if let Some(call) = &call {
+            self.usage.tool_calls += 1;
+            self.tools.push(call.name.clone());
+        }
+        if let Some(text) = entry_text(entry) {
+            self.usage.add_output(&text);
+            self.partial.push_str(&text);
+        }
+        call
+    }
+
+    fn exceeded(&self) -> Option<&'static str> {
+        self.budget?.exceeded(&self.usage())
+    }
+
+    /// When the budget's duration limit runs out, if it has one.
+    fn budget_deadline(&self) -> Option<Instant> {
+        let max = self.budget?.max_duration_ms?;
+        Some(self.started + Duration::from_millis(max))
+    }
+}
+
+pub enum TurnEvent {
+    /// Goose's reply and the log offset just past it.
+    Reply(String, u64),
+    /// Goose is blocked on a confirmation prompt for this tool call
+    /// (`None` if the request never showed up in the log).
+    Confirmation(Option<ToolCall>),
+    /// The turn went over its budget on this limit; Goose may still be working.
+    BudgetExceeded(&'static str),
+}
 
 /// Represents a live Goose CLI session process.
 pub struct GooseSession {
@@ -20,31 +107,164 @@ pub struct GooseSession {
     pub stdin: Option<ChildStdin>,
     pub is_ready: Arc<tokio::sync::Notify>,
     pub last_offset: u64,
+    /// When the session was started or last sent a message
+    pub last_used: SystemTime,
     jsonl_path: PathBuf,
+    /// Poll the log instead of watching it, see [`Config::poll_session_logs`]
+    poll_log: bool,
+    confirmations: mpsc::UnboundedReceiver<()>,
+    /// For the error of a turn goose exits in the middle of
+    stderr: StderrTail,
 }
 
-/// Get the path to a session's JSONL log file
-fn session_log_path(sid: &str) -> PathBuf {
-    // ~/.local/share/goose/sessions/<sid>.jsonl  (Unix)
-    // Lowercase filename is typical; we use lowercase for safety.
-    let home_dir = std::env::var("HOME")
-        .unwrap_or_else(|_| ".".to_string());
-    
-    let mut p = PathBuf::from(home_dir);
-    p.push(".local");
-    p.push("share");
-    p.push("goose");
-    p.push("sessions");
-    
-    // Create the directory if it doesn't exist
-    if !p.exists() {
-        if let Err(e) = std::fs::create_dir_all(&p) {
-            error!("Failed to create sessions directory at {}: {}", p.display(), e);
+/// The last [`STDERR_TAIL_LINES`] lines a goose child wrote to stderr.
+struct StderrTail {
+    lines: Arc<std::sync::Mutex<VecDeque<String>>>,
+    reader: tokio::task::JoinHandle<()>,
+}
+
+impl StderrTail {
+    /// Read `stderr` until it closes, logging each line and keeping the last few.
+    fn spawn(sid: String, stderr: ChildStderr) -> Self {
+        let lines = Arc::new(std::sync::Mutex::new(VecDeque::new()));
+        let kept = lines.clone();
+        let reader = tokio::spawn(async move {
+            let mut stderr = BufReader::new(stderr).lines();
+            while let Ok(Some(line)) = stderr.next_line().await {
+                // Filter out non-critical extension errors
+                if line.contains("failed to load extension") && line.contains("goose_agent") {
+                    debug!(session_id = %sid, "Non-critical extension error (suppressed): {}", line);
+                    continue;
+                }
+                // Log other stderr lines as warnings
+                warn!(session_id = %sid, "{}", line);
+                let mut kept = kept.lock().unwrap();
+                if kept.len() == STDERR_TAIL_LINES {
+                    kept.pop_front();
+                }
+                kept.push_back(line);
+            }
+        });
+        Self { lines, reader }
+    }
+
+    fn text(&self) -> String {
+        self.lines.lock().unwrap().iter().map(String::as_str).collect::<Vec<_>>().join("\n")
+    }
+
+    /// The lines kept once stderr has closed, or after [`STDERR_DRAIN_TIMEOUT`]
+    /// if something else still holds it open.
+    async fn drained(&mut self) -> String {
+        let _ = tokio::time::timeout(STDERR_DRAIN_TIMEOUT, &mut self.reader).await;
+        self.text()
+    }
+}
+
+/// An exit status as its code, or as the signal that ended the process.
+fn exit_status(status: std::process::ExitStatus) -> String {
+    status.code().map_or_else(|| status.to_string(), |code| code.to_string())
+}
+
+/// `message`, followed by goose's `stderr` when it wrote any.
+fn with_stderr(message: String, stderr: &str) -> anyhow::Error {
+    match stderr {
+        "" => anyhow!(message),
+        stderr => anyhow!("{}: {}", message, stderr),
+    }
+}
+
+/// Wait up to `timeout` for the session log at `expected`. If it never appears
+/// (goose named it differently), adopt the newest `.jsonl` in the same directory
+/// modified since the child was spawned at `spawned_at`. Fails at once if
+/// `child` exits first, with what it wrote to stderr.
+async fn discover_session_log(
+    sid: &str,
+    expected: &Path,
+    spawned_at: SystemTime,
+    timeout: Duration,
+    child: &mut Child,
+    stderr: &mut StderrTail,
+) -> Result<PathBuf> {
+    let start = std::time::Instant::now();
+    while !expected.exists() {
+        if let Some(status) = child.try_wait()? {
+            return Err(with_stderr(format!("goose exited with status {}", exit_status(status)), &stderr.drained().await));
+        }
+        if start.elapsed() > timeout {
+            let adopted = expected.parent().and_then(|dir| newest_log_since(dir, spawned_at));
+            let Some(path) = adopted else {
+                let message = format!("Timeout waiting for JSONL file to be created at {}", expected.display());
+                return Err(with_stderr(message, &stderr.text()));
+            };
+            warn!(
+                session_id = %sid,
+                expected = %expected.display(),
+                adopted = %path.display(),
+                "Session log never appeared under its name, adopting the newest log"
+            );
+            return Ok(path);
         }
+        tokio::time::sleep(Duration::from_millis(100)).await;
     }
-    
-    p.push(format!("{}.jsonl", sid.to_lowercase()));
-    p
+    Ok(expected.to_path_buf())
+}
+
+/// The most recently modified `.jsonl` file in `dir` changed at or after `since`.
+fn newest_log_since(dir: &Path, since: SystemTime) -> Option<PathBuf> {
+    std::fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let path = entry.path();
+            if path.extension()? != "jsonl" {
+                return None;
+            }
+            let modified = entry.metadata().ok()?.modified().ok()?;
+            (modified >= since).then_some((modified, path))
+        })
+        .max_by_key(|(modified, _)| *modified)
+        .map(|(_, path)| path)
+}
+
+/// Check before serving that `goose_bin` runs at all (`goose --version`)
+/// and, with `llm`, that it can reach its provider (a one-off `goose run`
+/// without a session). The error says what to fix.
+pub async fn probe_goose(goose_bin: &str, llm: bool) -> Result<()> {
+    let version = run_probe(goose_bin, &["--version"], VERSION_PROBE_TIMEOUT).await?;
+    info!(goose_bin, version = %version.trim(), "goose is runnable");
+    if llm {
+        run_probe(goose_bin, &["run", "--no-session", "-t", "Reply with the word pong."], LLM_PROBE_TIMEOUT)
+            .await
+            .map_err(|e| anyhow!("{:#}; run `goose configure` to set up a provider", e))?;
+        info!(goose_bin, "goose reached its LLM provider");
+    }
+    Ok(())
+}
+
+/// Run `goose_bin` with `args` to completion, giving its stdout.
+async fn run_probe(goose_bin: &str, args: &[&str], timeout: Duration) -> Result<String> {
+    let shown = format!("`{} {}`", goose_bin, args.join(" "));
+    let child = Command::new(goose_bin)
+        .args(args)
+        .stdin(std::process::Stdio::null())
+        .kill_on_drop(true)
+        .output();
+    let output = match tokio::time::timeout(timeout, child).await {
+        Err(_) => return Err(anyhow!("{} did not finish within {:?}", shown, timeout)),
+        Ok(Err(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(anyhow!("goose binary not found: {}; set GOOSE_BIN to the goose executable", goose_bin))
+        }
+        Ok(Err(e)) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+            return Err(anyhow!("{} is not executable; check GOOSE_BIN and the file's permissions", goose_bin))
+        }
+        Ok(Err(e)) => return Err(anyhow!("Failed to run {}: {}", shown, e)),
+        Ok(Ok(output)) => output,
+    };
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(with_stderr(format!("{} exited with status {}", shown, exit_status(output.status)), stderr.trim()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
 }
 
 impl GooseSession {
@@ -89,7 +309,7 @@ impl GooseSession {
             "task_id": null,
             "target": null,
             "reply_to": format!("AG1:agent:GooseAgent:inbox"),
-            "envelope_type": "message",
+            "envelope_type": EnvelopeKind::Message.as_str(),
             "tools_used": [],
             "auth_signature": null,
             "timestamp": chrono::Utc::now().to_rfc3339(),
@@ -115,12 +335,38 @@ impl GooseSession {
         // Flush to ensure the input is sent
         stdin.flush().await
             .map_err(|e| anyhow!("Failed to flush stdin: {}", e))?;
+        self.last_used = SystemTime::now();
             
         info!("[{}] Input sent successfully", self.sid);
         Ok(())
     }
     pub async fn start(cfg: &Config, sid: String) -> Result<Self> {
-        debug!(session_id = %sid, "Starting new Goose session");
+        Self::launch(cfg, sid, false).await
+    }
+
+    /// Start a session that continues the conversation in `seed`.
+    ///
+    /// The turns are written as the session's log, in goose's own message
+    /// format and replacing any earlier log under that name, and goose is
+    /// started with `--resume` so it loads them as history the way it would
+    /// a session of its own. Replies are read from past the seeded entries.
+    pub async fn start_seeded(cfg: &Config, sid: String, seed: &[SeedTurn]) -> Result<Self> {
+        let path = cfg.session_log_path(&sid);
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        let log = seed_log(seed);
+        tokio::fs::write(&path, &log).await
+            .map_err(|e| anyhow!("Failed to write seed transcript to {}: {}", path.display(), e))?;
+        debug!(session_id = %sid, turns = seed.len(), path = %path.display(), "Wrote seed transcript");
+
+        let mut session = Self::launch(cfg, sid, true).await?;
+        session.last_offset = log.len() as u64;
+        Ok(session)
+    }
+
+    async fn launch(cfg: &Config, sid: String, resume: bool) -> Result<Self> {
+        debug!(session_id = %sid, resume, "Starting new Goose session");
         let start_time = Instant::now();
         
         // Ensure goose binary is available
@@ -139,6 +385,9 @@ impl GooseSession {
         // Start an interactive session with the given session ID
         cmd.arg("session")
            .arg("--name").arg(&sid);
+        if resume {
+            cmd.arg("--resume");
+        }
            
         // Enable developer builtins by default
         cmd.arg("--with-builtin").arg("developer");
@@ -198,7 +447,12 @@ impl GooseSession {
             }
         }
         
+        let session_dir = cfg.session_dir();
+        std::fs::create_dir_all(&session_dir)
+            .map_err(|e| anyhow!("Failed to create sessions directory at {}: {}", session_dir.display(), e))?;
+
         // Spawn the child process with enhanced error handling
+        let spawned_at = SystemTime::now();
         let mut child = match cmd.spawn() {
             Ok(child) => {
                 if let Some(pid) = child.id() {
@@ -228,13 +482,6 @@ impl GooseSession {
             }
         };
         
-        // Check if the process is still running
-        if let Some(exit_status) = child.try_wait()? {
-            let err = anyhow!("goose process exited immediately with status: {:?}", exit_status);
-            error!(%err, "Process exited immediately");
-            return Err(err);
-        }
-        
         // Get handles to stdin/stdout/stderr
         let stdin = child.stdin.take()
             .ok_or_else(|| anyhow!("Failed to get stdin handle from goose process"))?;
@@ -249,25 +496,12 @@ impl GooseSession {
         let is_ready = Arc::new(tokio::sync::Notify::new());
         
         // Spawn stderr reader task
-        let stderr_sid = sid.clone();
-        tokio::spawn(async move {
-            let reader = BufReader::new(stderr);
-            let mut lines = reader.lines();
-            
-            while let Ok(Some(line)) = lines.next_line().await {
-                // Filter out non-critical extension errors
-                if line.contains("failed to load extension") && line.contains("goose_agent") {
-                    debug!(session_id = %stderr_sid, "Non-critical extension error (suppressed): {}", line);
-                    continue;
-                }
-                // Log other stderr lines as warnings
-                warn!(session_id = %stderr_sid, "{}", line);
-            }
-        });
+        let mut stderr = StderrTail::spawn(sid.clone(), stderr);
         
         // Spawn stdout reader task
         let stdout_sid = sid.clone();
         let ready_notifier = is_ready.clone();
+        let (confirm_tx, confirmations) = mpsc::unbounded_channel();
         tokio::spawn(async move {
             let reader = BufReader::new(stdout);
             let mut lines = reader.lines();
@@ -279,6 +513,10 @@ impl GooseSession {
                     ready_notifier.notify_one();
                 }
                 
+                if line.contains(CONFIRMATION_PROMPT) {
+                    let _ = confirm_tx.send(());
+                }
+
                 // Log other stdout lines as debug
                 debug!(session_id = %stdout_sid, "{}", line);
                 // Check for readiness signal (Goose prints "logging to <path>" when ready)
@@ -292,17 +530,12 @@ impl GooseSession {
             }
         });
         
-        // Wait for the JSONL file to be created
-        let jsonl_path = session_log_path(&sid);
-        let timeout = std::time::Duration::from_secs(10);
-        let start = std::time::Instant::now();
-        
-        while !jsonl_path.exists() {
-            if start.elapsed() > timeout {
-                return Err(anyhow!("Timeout waiting for JSONL file to be created"));
-            }
-            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
-        }
+        // Wait for the JSONL file to be created, or for goose to give up
+        let timeout = Duration::from_millis(cfg.session_log_timeout_ms);
+        let expected = cfg.session_log_path(&sid);
+        let jsonl_path = discover_session_log(&sid, &expected, spawned_at, timeout, &mut child, &mut stderr)
+            .await
+            .inspect_err(|e| error!(session_id = %sid, error = %e, "Goose session failed to start"))?;
         
         info!("[{}] Session created and JSONL file found at {:?}", sid, jsonl_path);
         
@@ -313,7 +546,11 @@ impl GooseSession {
             stdin: Some(stdin),
             is_ready,
             last_offset: 0,
-            jsonl_path: session_log_path(&sid),
+            last_used: SystemTime::now(),
+            jsonl_path,
+            poll_log: cfg.poll_session_logs,
+            confirmations,
+            stderr,
         };
         
         // Start monitoring the child process
@@ -351,244 +588,89 @@ impl GooseSession {
         }
     }
     
-    /// Wait for an assistant response from the JSONL log file
-    /// Returns the assistant's message and the new file offset
-    pub async fn wait_assistant_jsonl(
-        &self,
-        timeout_ms: u64,
-        start_offset: u64,
-    ) -> Result<(String, u64)> {
-        let path = &self.jsonl_path;
-        let start_time = Instant::now();
-        let timeout_duration = Duration::from_millis(timeout_ms);
-        let mut current_offset = start_offset;
-        let mut consecutive_errors = 0;
-        const MAX_CONSECUTIVE_ERRORS: u32 = 5;
-        
-        debug!(
-            session_id = %self.sid,
-            path = %path.display(),
-            start_offset,
-            timeout_ms,
-            "Waiting for assistant response in JSONL file"
-        );
-
-        // Wait for the file to exist with a timeout
-        while !path.exists() {
-            if start_time.elapsed() > timeout_duration {
-                return Err(anyhow!("Timeout waiting for session log file to appear: {}", path.display()));
-            }
-            tokio::time::sleep(Duration::from_millis(100)).await;
-        }
-
-        // Open the file with retry logic
-        let mut file = match File::open(&path).await {
-            Ok(file) => file,
-            Err(e) => {
-                error!(
-                    session_id = %self.sid,
-                    path = %path.display(),
-                    error = %e,
-                    "Failed to open JSONL file"
-                );
-                return Err(anyhow!("Failed to open JSONL file: {}", e));
-            }
-        };
+    fn tail(&self, start_offset: u64) -> JsonlTail {
+        JsonlTail::new(&self.sid, self.jsonl_path.clone(), start_offset).polling(self.poll_log)
+    }
 
-        // Seek to the start offset
-        if let Err(e) = file.seek(std::io::SeekFrom::Start(start_offset)).await {
-            error!(
-                session_id = %self.sid,
-                offset = start_offset,
-                error = %e,
-                "Failed to seek in JSONL file"
-            );
-            return Err(anyhow!("Failed to seek in JSONL file: {}", e));
+    /// Start following the JSONL log for one turn from `start_offset`.
+    ///
+    /// A prompt still unanswered from an earlier turn is reported by the first
+    /// [`Self::next_turn_event`], since Goose is still blocked on it.
+    pub fn begin_turn(&self, start_offset: u64) -> Turn {
+        Turn {
+            tail: self.tail(start_offset),
+            last_tool: None,
+            budget: None,
+            started: Instant::now(),
+            usage: TurnUsage::default(),
+            partial: String::new(),
+            tools: Vec::new(),
         }
+    }
 
-        let mut reader = FramedRead::new(file, LinesCodec::new());
-        let mut last_file_size = start_offset;
-
-        // Buffer to hold partial JSON objects read from the log
-        let mut buffer = String::new();
-
-        // Read lines until we find an assistant message or timeout
+    /// Wait for the turn's reply, or for Goose to block on a tool confirmation prompt.
+    ///
+    /// After a [`TurnEvent::Confirmation`], answer with [`Self::answer_confirmation`]
+    /// and call this again to keep waiting on the same turn. A turn with a budget
+    /// ends in [`TurnEvent::BudgetExceeded`] as soon as a limit is passed, even
+    /// by its final reply.
+    pub async fn next_turn_event(&mut self, turn: &mut Turn, deadline: Instant) -> Result<TurnEvent> {
+        let budget_deadline = turn.budget_deadline().filter(|d| *d < deadline);
         loop {
-            // Check for timeout
-            let elapsed = start_time.elapsed();
-            if elapsed > timeout_duration {
-                debug!(
-                    session_id = %self.sid,
-                    elapsed_ms = elapsed.as_millis(),
-                    "Timeout waiting for assistant response"
-                );
-                break;
-            }
-
-            match tokio_timeout(
-                timeout_duration.saturating_sub(elapsed),
-                reader.next()
-            ).await {
-                Ok(Some(Ok(line))) => {
-                    consecutive_errors = 0; // Reset error counter on successful read
-                    current_offset += line.len() as u64 + 1; // +1 for newline
-                    
-                    debug!(
-                        session_id = %self.sid,
-                        line_content = line,
-                        "Read line from JSONL"
-                    );
-                    
-                    // Filter out MCP client warnings
-                    if line.contains("mcp_client::transport::stdio") {
-                        debug!(
-                            session_id = %self.sid,
-                            "Skipping MCP client warning message"
-                        );
-                        continue;
-                    }
-
-                    
-                    buffer.push_str(&line);
-                    
-                    // Try to parse the buffer
-                    match serde_json::from_str::<serde_json::Value>(&buffer) {
-                        Ok(json) => {
-                            // Clear buffer if we got a complete JSON object
-                            buffer.clear();
-                            
-                            // Handle tool responses specially
-                            if let Some(content) = json.get("content").and_then(|c| c.as_array()) {
-                                for item in content {
-                                    if let Some(text) = item.get("text").and_then(|t| t.as_str()) {
-                                        debug!(
-                                            session_id = %self.sid,
-                                            text = text,
-                                            "Processing tool response text"
-                                        );
-                                    }
-                                }
-                            }
-                            
-                            // Handle regular assistant responses
-                            if let (Some("assistant"), Some(content)) = (
-                                json.get("role").and_then(|r| r.as_str()),
-                                json.get("content").and_then(|c| c.as_array()).and_then(|a| a.get(0))
-                            ) {
-                                if let Some(text) = content.get("text").and_then(|t| t.as_str()) {
-                                    debug!(
-                                        session_id = %self.sid,
-                                        content_length = text.len(),
-                                        "Found assistant response"
-                                    );
-                                    return Ok((text.to_string(), current_offset));
-                                }
-                            }
-                        },
-                        Err(e) => {
-                            // If parsing fails, check if it's a MCP client warning
-                            if line.contains("mcp_client::transport::stdio") {
-                                debug!(
-                                    session_id = %self.sid,
-                                    "Skipping MCP client warning message"
-                                );
-                                continue;
-                            }
-                            
-                            // Otherwise, keep buffering
-                            // Continue reading if JSON appears incomplete
-                            if e.is_eof() {
-                                debug!(session_id = %self.sid, "Waiting for rest of JSON" );
-                                continue;
-                            }
-
-                            // Log and clear buffer on other errors
-                            debug!(session_id = %self.sid, error = %e, "Discarding invalid JSON line");
-                            buffer.clear();
-                            continue;
+            tokio::select! {
+                entry = turn.tail.next_entry(budget_deadline.unwrap_or(deadline)) => {
+                    let Some(entry) = entry? else {
+                        if budget_deadline.is_some() {
+                            return Ok(TurnEvent::BudgetExceeded("max_duration_ms"));
                         }
+                        return Err(CodedError::new("timeout", "Timeout waiting for assistant response").into());
+                    };
+                    if let Some(call) = turn.record(&entry) {
+                        turn.last_tool = Some(call);
                     }
-                }
-                
-                Ok(Some(Err(e))) => {
-                    consecutive_errors += 1;
-                    error!(
-                        session_id = %self.sid,
-                        error = %e,
-                        consecutive_errors,
-                        "Failed to read line from JSONL"
-                    );
-                    
-                    if consecutive_errors >= MAX_CONSECUTIVE_ERRORS {
-                        return Err(anyhow!("Too many consecutive read errors: {}", e));
+                    if let Some(limit) = turn.exceeded() {
+                        info!(session_id = %self.sid, limit, usage = ?turn.usage(), "Turn is over budget");
+                        return Ok(TurnEvent::BudgetExceeded(limit));
+                    }
+                    if let Some(text) = assistant_text(&entry) {
+                        return Ok(TurnEvent::Reply(text.to_string(), turn.tail.offset()));
                     }
-                    
-                    // Wait a bit before retrying after an error
-                    tokio::time::sleep(Duration::from_millis(100)).await;
                 }
-                
-                Ok(None) => {
-                    // No more lines available, check if file has grown
-                    let metadata = match tokio::fs::metadata(&path).await {
-                        Ok(m) => m,
-                        Err(e) => {
-                            error!(
-                                session_id = %self.sid,
-                                error = %e,
-                                "Failed to get file metadata"
-                            );
-                            tokio::time::sleep(Duration::from_millis(100)).await;
-                            continue;
+                Some(()) = self.confirmations.recv() => {
+                    // The prompt can reach stdout before the tool request reaches the log
+                    let grace = Instant::now() + CONFIRMATION_LOOKUP_GRACE;
+                    while turn.last_tool.is_none() {
+                        match turn.tail.next_entry(grace).await? {
+                            Some(entry) => turn.last_tool = turn.record(&entry),
+                            None => break,
                         }
-                    };
-                    
-                    let current_size = metadata.len();
-                    
-                    // If file hasn't grown, wait a bit before checking again
-                    if current_size <= last_file_size {
-                        tokio::time::sleep(Duration::from_millis(50)).await;
-                        continue;
                     }
-                    
-                    // File has grown, reopen it and seek to the last position
-                    match File::open(&path).await {
-                        Ok(mut new_file) => {
-                            if let Err(e) = new_file.seek(std::io::SeekFrom::Start(current_offset)).await {
-                                error!(
-                                    session_id = %self.sid,
-                                    error = %e,
-                                    offset = current_offset,
-                                    "Failed to seek in reopened file"
-                                );
-                                tokio::time::sleep(Duration::from_millis(100)).await;
-                                continue;
-                            }
-                            
-                            reader = FramedRead::new(new_file, LinesCodec::new());
-                            last_file_size = current_size;
-                        }
-                        Err(e) => {
-                            error!(
-                                session_id = %self.sid,
-                                error = %e,
-                                "Failed to reopen file"
-                            );
-                            tokio::time::sleep(Duration::from_millis(100)).await;
+                    info!(session_id = %self.sid, tool = ?turn.last_tool.as_ref().map(|t| &t.name), "Goose is waiting for tool confirmation");
+                    return Ok(TurnEvent::Confirmation(turn.last_tool.take()));
+                }
+                status = self.process.wait() => {
+                    let status = exit_status(status?);
+                    // It may have logged its reply just before exiting
+                    while let Some(entry) = turn.tail.next_entry(Instant::now() + EXIT_LOG_GRACE).await? {
+                        turn.record(&entry);
+                        if let Some(text) = assistant_text(&entry) {
+                            return Ok(TurnEvent::Reply(text.to_string(), turn.tail.offset()));
                         }
                     }
-                }
-                
-                Err(_) => {
-                    // Timeout occurred
-                    break;
+                    error!(session_id = %self.sid, status = %status, "Goose exited mid-turn");
+                    let message = format!("goose exited with status {} before replying", status);
+                    return Err(with_stderr(message, &self.stderr.drained().await));
                 }
             }
         }
-        
-        Err(anyhow!(
-            "Timeout waiting for assistant response after {}ms",
-            timeout_ms
-        ))
+    }
+
+    /// Answer a pending confirmation prompt on the child's stdin.
+    pub async fn answer_confirmation(&mut self, allow: bool) -> Result<()> {
+        let stdin = self.stdin.as_mut().ok_or_else(|| anyhow!("goose stdin is closed"))?;
+        stdin.write_all(if allow { b"y\n" } else { b"n\n" }).await?;
+        stdin.flush().await?;
+        Ok(())
     }
 
     /// Wait for a reply from the Goose CLI by monitoring the JSONL session file
@@ -604,6 +686,27 @@ impl GooseSession {
         }
     }
     
+    /// The last `max` messages in the session log, oldest first, as
+    /// `{"role", "text"}`. Entries without text (bare tool calls and results) are left out.
+    pub async fn transcript(&self, max: usize) -> Result<Vec<Value>> {
+        let log = tokio::fs::read_to_string(&self.jsonl_path).await?;
+        let messages: Vec<Value> = log
+            .lines()
+            .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+            .filter_map(|entry| {
+                let role = entry.get("role")?.as_str()?;
+                let text: String = entry
+                    .get("content")?
+                    .as_array()?
+                    .iter()
+                    .filter_map(|item| item.get("text")?.as_str())
+                    .collect();
+                (!text.is_empty()).then(|| serde_json::json!({ "role": role, "text": text }))
+            })
+            .collect();
+        Ok(messages[messages.len().saturating_sub(max)..].to_vec())
+    }
+
     /// Monitor the child process and clean up when it exits
     pub async fn monitor(&mut self) -> Result<()> {
         let sid = self.sid.clone();
@@ -614,45 +717,270 @@ impl GooseSession {
         
         Ok(())
     }
-    
-    pub async fn wait_reply_raw(&mut self, timeout_ms: u64) -> Result<String> {
-        info!("[{}] Waiting for JSONL response (timeout: {}ms)", self.sid, timeout_ms);
-        let start_time = Instant::now();
-        
-        // First, check if we already have a response in the log file
-        if let Ok((response, new_offset)) = self.wait_assistant_jsonl(timeout_ms, self.last_offset).await {
-            info!("[{}] Found response in JSONL log", self.sid);
-            self.update_offset(new_offset);
-            return Ok(response);
+}
+
+
+#[cfg(all(test, unix))]
+pub(crate) mod test_support {
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+    use std::sync::OnceLock;
+
+    use crate::config::{ConfirmationDefault, Config};
+
+    /// Stand-ins for `goose session --name <sid> ...`. Each creates the session log,
+    /// announces readiness and stays alive until stdin closes.
+    const STUBS: &[(&str, &str)] = &[
+        // Logs one reply naming the sid it was started with.
+        ("reply", r#"#!/bin/sh
+log="$HOME/.local/share/goose/sessions/$3.jsonl"
+mkdir -p "$(dirname "$log")"
+echo '{"role":"assistant","content":[{"type":"text","text":"reply from '"$3"'"}]}' > "$log"
+echo "logging to $log"
+exec cat > /dev/null
+"#),
+        // On the first message: requests a shell call, asks for confirmation and
+        // replies with the answer it read.
+        ("confirm", r#"#!/bin/sh
+log="$HOME/.local/share/goose/sessions/$3.jsonl"
+mkdir -p "$(dirname "$log")"
+: > "$log"
+echo "logging to $log"
+read -r _message
+echo '{"role":"assistant","content":[{"type":"text","text":""},{"type":"toolRequest","id":"call_1","toolCall":{"status":"success","value":{"name":"developer__shell","arguments":{"command":"rm -rf build"}}}}]}' >> "$log"
+echo "Goose would like to call the above tool, do you allow?"
+read -r answer
+echo '{"role":"assistant","content":[{"type":"text","text":"answer: '"$answer"'"}]}' >> "$log"
+exec cat > /dev/null
+"#),
+        // Fails the way a misconfigured goose does, before creating a log.
+        ("fail", r#"#!/bin/sh
+echo "Loading config..." >&2
+echo "Error: No provider configured. Run 'goose configure' first" >&2
+exit 3
+"#),
+        // Starts fine, then on the first message loses its provider and exits.
+        ("dies", r#"#!/bin/sh
+log="$HOME/.local/share/goose/sessions/$3.jsonl"
+mkdir -p "$(dirname "$log")"
+: > "$log"
+echo "logging to $log"
+read -r _message
+echo "Error: request to provider failed: 401 Unauthorized" >&2
+exit 2
+"#),
+        // Logs one reply under a name of its own, in a directory named after the sid.
+        // The sleep keeps the log's mtime, from the kernel's coarse clock, after the spawn.
+        ("renamed", r#"#!/bin/sh
+dir="$HOME/.local/share/goose/sessions/$3"
+mkdir -p "$dir"
+sleep 0.1
+echo '{"role":"assistant","content":[{"type":"text","text":"reply from elsewhere"}]}' > "$dir/goose-chose-this.jsonl"
+echo "logging to $dir/goose-chose-this.jsonl"
+exec cat > /dev/null
+"#),
+        // Logs each message it reads as a numbered user entry and its reply.
+        // Like chat, but with --resume keeps the log, and each reply names the
+        // entries it found there and the text of the last.
+        ("resume", r#"#!/bin/sh
+log="$HOME/.local/share/goose/sessions/$3.jsonl"
+mkdir -p "$(dirname "$log")"
+seeded="0 earlier entries"
+if [ "$4" = "--resume" ]; then
+  last=$(tail -n 1 "$log" | sed 's/.*"text":"\([^"]*\)".*/\1/')
+  seeded="$(wc -l < "$log" | tr -d ' ') earlier entries, last: $last"
+else
+  : > "$log"
+fi
+echo "logging to $log"
+i=0
+while read -r _message; do
+  i=$((i + 1))
+  echo '{"role":"user","content":[{"type":"text","text":"message '"$i"'"}]}' >> "$log"
+  echo '{"role":"assistant","content":[{"type":"text","text":"reply '"$i"' after '"$seeded"'"}]}' >> "$log"
+done
+"#),
+        ("chat", r#"#!/bin/sh
+log="$HOME/.local/share/goose/sessions/$3.jsonl"
+mkdir -p "$(dirname "$log")"
+: > "$log"
+echo "logging to $log"
+i=0
+while read -r _message; do
+  i=$((i + 1))
+  echo '{"role":"user","content":[{"type":"text","text":"message '"$i"'"}]}' >> "$log"
+  echo '{"role":"assistant","content":[{"type":"text","text":"reply '"$i"'"}]}' >> "$log"
+done
+"#),
+        // On the first message: logs a step of text and a tool call every 50ms, forever.
+        ("loop", r#"#!/bin/sh
+log="$HOME/.local/share/goose/sessions/$3.jsonl"
+mkdir -p "$(dirname "$log")"
+: > "$log"
+echo "logging to $log"
+read -r _message
+i=0
+while :; do
+  i=$((i + 1))
+  echo '{"role":"assistant","content":[{"type":"text","text":"step '"$i"'. "},{"type":"toolRequest","id":"call_'"$i"'","toolCall":{"status":"success","value":{"name":"developer__shell","arguments":{"command":"true"}}}}]}' >> "$log"
+  sleep 0.05
+done
+"#),
+    ];
+
+    /// Shared fake HOME holding every stub. Written once, before any test spawns a
+    /// child, so parallel tests neither race on `HOME` nor exec a script that
+    /// another fork still holds open for writing.
+    fn home() -> &'static PathBuf {
+        static HOME: OnceLock<PathBuf> = OnceLock::new();
+        HOME.get_or_init(|| {
+            let home = tempfile::tempdir().unwrap().keep();
+            for (name, script) in STUBS {
+                let path = home.join(format!("goose-{}", name));
+                std::fs::write(&path, script).unwrap();
+                std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+            }
+            std::env::set_var("HOME", &home);
+            home
+        })
+    }
+
+    /// Where the stubs write session logs.
+    pub fn session_dir() -> PathBuf {
+        home().join(".local/share/goose/sessions")
+    }
+
+    /// Config running the named stub; the bus points at a closed port.
+    pub fn config(stub: &str) -> Config {
+        Config {
+            inbox: "AG1:test:bridge:inbox".into(),
+            consumer: "bridge-test".into(),
+            redis_url: "redis://127.0.0.1:1".into(),
+            goose_bin: home().join(format!("goose-{}", stub)).display().to_string(),
+            turn_timeout_ms: 5_000,
+            warm_pool_size: 0,
+            confirmation_timeout_ms: 200,
+            confirmation_default: ConfirmationDefault::Deny,
+            session_dir: Some(session_dir()),
+            session_log_timeout_ms: 10_000,
+            poll_session_logs: false,
+            signing_key: None,
+            max_message_chars: None,
+            drain_idle_ms: 30_000,
+            session_idle_ms: 0,
+            visibility_timeout_ms: 0,
+            max_envelope_bytes: bus::DEFAULT_MAX_ENVELOPE_BYTES,
+            max_entry_bytes: bus::DEFAULT_MAX_ENTRY_BYTES,
+            journal_dir: None,
+            journal_max_file_bytes: bus::journal::DEFAULT_MAX_JOURNAL_FILE_BYTES,
+            description: None,
+            capabilities_keywords: Vec::new(),
         }
-        
-        // If no response found in existing log, wait for a new one
-        info!("[{}] No existing response found in log, waiting for new one...", self.sid);
-        
-        // Use the current offset to only read new content
-        let (response, new_offset) = self.wait_assistant_jsonl(timeout_ms, self.last_offset).await?;
-        let elapsed = start_time.elapsed();
-        
-        // Update the offset for the next read
-        self.update_offset(new_offset);
-        
-        // Log the response (truncate if too long)
-        let response_preview = if response.len() > 100 { 
-            format!("{}... (truncated)", &response[..100])
-        } else {
-            response.clone()
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn confirmation_prompt_is_surfaced_and_answered() {
+        let cfg = test_support::config("confirm");
+        let sid = format!("confirm_{}", uuid::Uuid::new_v4().simple());
+        let mut session = GooseSession::start(&cfg, sid).await.unwrap();
+        session.send_user("clean up").await.unwrap();
+        let mut turn = session.begin_turn(0);
+        let deadline = Instant::now() + Duration::from_secs(10);
+
+        let TurnEvent::Confirmation(Some(tool)) = session.next_turn_event(&mut turn, deadline).await.unwrap() else {
+            panic!("expected a confirmation request with its tool call");
         };
-        
-        info!(
-            "[{}] Received response after {:.2?} ({} chars): {}",
-            self.sid, elapsed, response.len(), response_preview
-        );
-        
-        if response.is_empty() {
-            error!("[{}] Empty response from Goose CLI", self.sid);
-            return Err(anyhow!("Empty response from Goose CLI"));
-        }
-        
-        Ok(response)
+        assert_eq!(tool.name, "developer__shell");
+        assert_eq!(tool.arguments, json!({ "command": "rm -rf build" }));
+
+        session.answer_confirmation(true).await.unwrap();
+        let TurnEvent::Reply(text, _) = session.next_turn_event(&mut turn, deadline).await.unwrap() else {
+            panic!("expected the turn's reply");
+        };
+        assert_eq!(text, "answer: y");
+    }
+
+    #[tokio::test]
+    async fn goose_exiting_early_is_reported_with_its_stderr() {
+        let cfg = test_support::config("fail");
+        let sid = format!("fail_{}", uuid::Uuid::new_v4().simple());
+        let started = Instant::now();
+        let Err(err) = GooseSession::start(&cfg, sid).await else { panic!("a failing goose started") };
+        assert!(started.elapsed() < Duration::from_secs(5), "took {:?}", started.elapsed());
+        let err = err.to_string();
+        assert!(err.starts_with("goose exited with status 3: "), "{err}");
+        assert!(err.contains("Loading config...\nError: No provider configured"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn goose_exiting_mid_turn_is_reported_with_its_stderr() {
+        let cfg = test_support::config("dies");
+        let sid = format!("dies_{}", uuid::Uuid::new_v4().simple());
+        let mut session = GooseSession::start(&cfg, sid).await.unwrap();
+        session.send_user("hello").await.unwrap();
+        let mut turn = session.begin_turn(0);
+        let started = Instant::now();
+
+        let Err(err) = session.next_turn_event(&mut turn, started + Duration::from_secs(30)).await else {
+            panic!("expected the turn to fail");
+        };
+        assert!(started.elapsed() < Duration::from_secs(5), "took {:?}", started.elapsed());
+        let err = err.to_string();
+        assert!(err.starts_with("goose exited with status 2 before replying: "), "{err}");
+        assert!(err.contains("401 Unauthorized"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn probe_names_what_is_wrong_with_goose() {
+        let missing = test_support::session_dir().join("no-such-goose");
+        let err = probe_goose(&missing.display().to_string(), false).await.unwrap_err().to_string();
+        assert!(err.starts_with("goose binary not found: "), "{err}");
+
+        let dir = tempfile::tempdir().unwrap();
+        let unexecutable = dir.path().join("goose");
+        std::fs::write(&unexecutable, "#!/bin/sh\necho 1.0.0\n").unwrap();
+        let err = probe_goose(&unexecutable.display().to_string(), false).await.unwrap_err().to_string();
+        assert!(err.ends_with("is not executable; check GOOSE_BIN and the file's permissions"), "{err}");
+
+        // The fail stub exits whatever it's asked, as an unconfigured goose does
+        let err = probe_goose(&test_support::config("fail").goose_bin, false).await.unwrap_err().to_string();
+        assert!(err.contains("--version` exited with status 3: "), "{err}");
+        assert!(err.contains("No provider configured"), "{err}");
+
+        probe_goose("true", true).await.unwrap();
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn an_unusable_session_dir_fails_the_start() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let cfg = Config { session_dir: Some(file.path().join("sessions")), ..test_support::config("reply") };
+        let Err(err) = GooseSession::start(&cfg, "unusable".into()).await else { panic!("started without a session dir") };
+        assert!(err.to_string().starts_with("Failed to create sessions directory at "), "{err}");
+    }
+
+    #[tokio::test]
+    async fn newest_log_is_adopted_when_the_sid_named_one_never_appears() {
+        let sid = format!("renamed_{}", uuid::Uuid::new_v4().simple());
+        let cfg = Config {
+            session_dir: Some(test_support::session_dir().join(&sid)),
+            session_log_timeout_ms: 300,
+            ..test_support::config("renamed")
+        };
+        let mut session = GooseSession::start(&cfg, sid.clone()).await.unwrap();
+        assert_eq!(session.jsonl_path, cfg.session_dir().join("goose-chose-this.jsonl"));
+        assert!(!cfg.session_log_path(&sid).exists());
+
+        let mut turn = session.begin_turn(0);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let TurnEvent::Reply(text, _) = session.next_turn_event(&mut turn, deadline).await.unwrap() else {
+            panic!("expected the reply from the adopted log");
+        };
+        assert_eq!(text, "reply from elsewhere");
+    }
+}
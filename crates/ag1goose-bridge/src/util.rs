@@ -1,16 +1,117 @@
-
+use bus::ContentBuilder;
 use chrono::Utc;
-use serde_json::Value;
+use serde_json::{json, Value};
 
 pub fn now_rfc3339() -> String {
     Utc::now().to_rfc3339()
 }
 
+/// What a piece of content carries, so structured data isn't flattened to a string.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExtractedContent {
+    Text(String),
+    Json(Value),
+    Empty,
+}
+
+pub fn extract_content(content: &Value) -> ExtractedContent {
+    match content {
+        Value::Null => ExtractedContent::Empty,
+        Value::String(s) if s.is_empty() => ExtractedContent::Empty,
+        Value::String(s) => ExtractedContent::Text(s.clone()),
+        Value::Object(map) if map.is_empty() => ExtractedContent::Empty,
+        Value::Array(items) if items.is_empty() => ExtractedContent::Empty,
+        // Primary convention: { "text": "..." }
+        Value::Object(map) => match map.get("text").and_then(Value::as_str) {
+            Some(t) if !t.is_empty() => ExtractedContent::Text(t.to_string()),
+            _ => ExtractedContent::Json(content.clone()),
+        },
+        other => ExtractedContent::Json(other.clone()),
+    }
+}
+
+/// The text of `content`: `text`, else where other agents put it, tried in
+/// order: `message.text`, `choices[0].text` (OpenAI-compatible completions)
+/// and `response`. Anything else is stringified compactly.
 pub fn extract_text(content: &Value) -> Option<String> {
-    // Primary convention: { "text": "..." }
-    if let Some(t) = content.get("text").and_then(|v| v.as_str()) {
-        return Some(t.to_string());
+    let nested: [fn(&Value) -> Option<&str>; 3] = [
+        |c| c.get("message")?.get("text")?.as_str(),
+        |c| c.get("choices")?.as_array()?.first()?.get("text")?.as_str(),
+        |c| c.get("response")?.as_str(),
+    ];
+    match extract_content(content) {
+        ExtractedContent::Text(t) => Some(t),
+        ExtractedContent::Json(v) => match nested.iter().find_map(|path| path(&v)) {
+            Some(text) => Some(text.to_string()),
+            None => Some(v.to_string()),
+        },
+        ExtractedContent::Empty => None,
     }
-    // Otherwise stringify JSON compactly
-    Some(content.to_string())
-}
\ No newline at end of file
+}
+
+/// Reply `content` for a turn's text. A reply that is a JSON object or array
+/// (e.g. a tool result passed through) is forwarded as a `json` part too, so
+/// agents downstream get the value rather than an escaped string.
+pub fn reply_content(response: &str) -> Value {
+    let structured = serde_json::from_str::<Value>(response.trim())
+        .ok()
+        .filter(|v| v.is_object() || v.is_array());
+    match structured.as_ref().map(extract_content) {
+        Some(ExtractedContent::Json(value)) => {
+            let mut content = ContentBuilder::new().json("result", value).build();
+            content["text"] = json!(response);
+            content
+        }
+        _ => json!({ "text": response }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extract_content_tells_each_shape_apart() {
+        assert_eq!(extract_content(&json!("hi")), ExtractedContent::Text("hi".into()));
+        assert_eq!(extract_content(&json!({ "text": "hi", "n": 1 })), ExtractedContent::Text("hi".into()));
+        let rows = json!({ "rows": [[1, "a"]], "text": "" });
+        assert_eq!(extract_content(&rows), ExtractedContent::Json(rows.clone()));
+        assert_eq!(extract_content(&json!([1, 2])), ExtractedContent::Json(json!([1, 2])));
+        assert_eq!(extract_content(&json!(42)), ExtractedContent::Json(json!(42)));
+        for empty in [json!(null), json!(""), json!({}), json!([])] {
+            assert_eq!(extract_content(&empty), ExtractedContent::Empty, "{empty}");
+        }
+
+        assert_eq!(extract_text(&json!({ "rows": [1] })).as_deref(), Some(r#"{"rows":[1]}"#));
+        assert_eq!(extract_text(&Value::Null), None);
+    }
+
+    #[test]
+    fn extract_text_finds_text_nested_by_other_agents() {
+        assert_eq!(extract_text(&json!({ "message": { "role": "assistant", "text": "hi" } })).as_deref(), Some("hi"));
+        let completion = json!({ "choices": [{ "index": 0, "text": "first" }, { "index": 1, "text": "second" }] });
+        assert_eq!(extract_text(&completion).as_deref(), Some("first"));
+        assert_eq!(extract_text(&json!({ "response": "done", "model": "m" })).as_deref(), Some("done"));
+
+        // `text` wins, and the paths are tried in order
+        assert_eq!(extract_text(&json!({ "text": "top", "response": "r" })).as_deref(), Some("top"));
+        assert_eq!(extract_text(&json!({ "response": "r", "message": { "text": "m" } })).as_deref(), Some("m"));
+        // Shapes that only look alike stay JSON
+        let no_text = json!({ "choices": [], "message": "plain" });
+        assert_eq!(extract_text(&no_text), Some(no_text.to_string()));
+    }
+
+    #[test]
+    fn structured_replies_keep_their_json() {
+        assert_eq!(reply_content("all done"), json!({ "text": "all done" }));
+        assert_eq!(reply_content("42"), json!({ "text": "42" }));
+
+        let response = "{\"status\": \"ok\", \"files\": [\"a.rs\"]}\n";
+        let content = reply_content(response);
+        assert_eq!(content["text"], json!(response));
+        assert_eq!(
+            content["parts"],
+            json!([{ "type": "json", "name": "result", "data": { "status": "ok", "files": ["a.rs"] } }])
+        );
+    }
+}
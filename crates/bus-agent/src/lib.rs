@@ -0,0 +1,1028 @@
+//! crates/bus-agent/src/lib.rs
+//!
+//! The inbox side of a bus agent, shared by the Goose web server and the
+//! ag1goose bridge. [`BusAgentRuntime`] reads an inbox through a consumer
+//! group, answers pings, filters and normalizes what it reads, and sends back
+//! (and acks) whatever its [`MessageHandler`] answers; the handler only turns
+//! an [`IncomingMessage`] into an [`OutgoingReply`].
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use bus::{
+    payload_too_large_content, AckMode, Bus, BusError, Capabilities, Delivery, Envelope, EnvelopeKind, OversizedEntry,
+    PongInfo, RedactionPolicy, StartPos, SubscribeOptions, ANNOUNCE_INTERVAL, CAPABILITIES_SCHEMA_VERSION,
+    CAPABILITIES_STREAM,
+};
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use serde_json::{json, Value};
+use tracing::{debug, error, info, warn};
+
+/// Deliveries read past a running message, looking for pings.
+const MAX_READ_AHEAD: usize = 32;
+/// How long a `pong` waits on the inbox lag before going out without it.
+const PING_LAG_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// Where a runtime reads, and what it hands to its handler.
+#[derive(Debug, Clone)]
+pub struct RuntimeConfig {
+    pub inbox: String,
+    /// Consumer group; runtimes sharing an inbox and group split its messages
+    pub group: String,
+    /// Consumer name. Keep it stable across restarts so messages left
+    /// unacked are picked up again.
+    pub consumer: String,
+    /// Where the group starts when it has to be created
+    pub start: StartPos,
+    /// How long each inbox read blocks (ms)
+    pub block_ms: u64,
+    /// Most messages answered at once; the rest wait, read ahead or still
+    /// in the inbox. 1 answers them one at a time, in order.
+    pub concurrency: usize,
+    /// Name replies and pongs are sent as
+    pub agent_name: String,
+    /// Version pongs report (the binary's `CARGO_PKG_VERSION`)
+    pub version: String,
+    /// Kinds handed to the handler, envelopes without a type counting as
+    /// `message`. Other known kinds are dropped and unknown ones answered with
+    /// an `error`. `None` hands over everything but pings, pongs and capabilities.
+    pub accept_kinds: Option<Vec<EnvelopeKind>>,
+    /// Roles whose messages and tasks are handed to the handler; turns from any
+    /// other are dropped. Other kinds get through whatever their role
+    pub accept_roles: Vec<String>,
+    /// Where replies go for envelopes without a `reply_to`
+    pub default_reply_to: String,
+    /// What the agent is for, announced in its [`Capabilities`]
+    pub description: Option<String>,
+    /// What the agent is good at, announced in its [`Capabilities`]
+    pub capabilities_keywords: Vec<String>,
+}
+
+/// Keywords for [`RuntimeConfig::capabilities_keywords`] from
+/// `AG1_AGENT_CAPABILITIES`, comma separated; none when it isn't set.
+pub fn capabilities_keywords_from_env() -> Vec<String> {
+    std::env::var("AG1_AGENT_CAPABILITIES").map(|v| split_keywords(&v)).unwrap_or_default()
+}
+
+/// The comma-separated keywords in `list`, trimmed, blanks dropped.
+fn split_keywords(list: &str) -> Vec<String> {
+    list.split(',').map(str::trim).filter(|k| !k.is_empty()).map(String::from).collect()
+}
+
+/// A handler error whose `error` reply carries `code` as `content.code`, so
+/// callers and journals can tell failures apart without reading the text.
+/// Found under any context added to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodedError {
+    /// E.g. `timeout` or `deadline_exceeded`
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl CodedError {
+    pub fn new(code: &'static str, message: impl Into<String>) -> Self {
+        Self { code, message: message.into() }
+    }
+}
+
+impl fmt::Display for CodedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for CodedError {}
+
+/// An inbox envelope on its way to the handler.
+#[derive(Debug, Clone)]
+pub struct IncomingMessage {
+    pub envelope: Envelope,
+    /// The envelope's kind, `message` for envelopes without one
+    pub kind: EnvelopeKind,
+    /// The content as text, see [`normalized_text`]
+    pub text: String,
+    /// Where the reply goes: the envelope's `reply_to` or the configured default
+    pub reply_to: String,
+    /// The envelope's correlation id, or a fresh one the reply carries
+    pub correlation_id: String,
+    /// How long the envelope waited on the inbox before it was read; `None`
+    /// when it wasn't read by [`BusAgentRuntime::run`]. Replies carry it as
+    /// `meta.queue_latency_ms`.
+    pub queue_latency: Option<Duration>,
+}
+
+/// What a handler answers with. The runtime addresses it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutgoingReply {
+    pub kind: EnvelopeKind,
+    pub content: Value,
+    /// Session the reply belongs to; the request's `session_code` when `None`
+    pub session_code: Option<String>,
+    pub usage: Value,
+    /// The reply's `meta`; the runtime adds `x_stream_key`
+    pub meta: Value,
+    /// Tools the agent called while answering
+    pub tools_used: Vec<String>,
+}
+
+impl OutgoingReply {
+    pub fn new(kind: EnvelopeKind, content: Value) -> Self {
+        Self { kind, content, session_code: None, usage: json!({}), meta: json!({}), tools_used: vec![] }
+    }
+
+    /// A `message_reply` with `text` as its content.
+    pub fn text(text: &str) -> Self {
+        Self::new(EnvelopeKind::MessageReply, json!({ "text": text }))
+    }
+}
+
+/// A ready-made reply envelope, such as a middleware's canned answer. Its
+/// addressing is replaced; a missing kind makes it a `message_reply`.
+impl From<Envelope> for OutgoingReply {
+    fn from(env: Envelope) -> Self {
+        Self {
+            kind: env.kind().unwrap_or(EnvelopeKind::MessageReply),
+            content: env.content,
+            session_code: env.session_code,
+            usage: env.usage,
+            meta: env.meta,
+            tools_used: env.tools_used,
+        }
+    }
+}
+
+/// Answers the messages a [`BusAgentRuntime`] reads. Any
+/// `Fn(IncomingMessage) -> impl Future<Output = Result<Option<OutgoingReply>>>`
+/// is one.
+#[async_trait]
+pub trait MessageHandler: Send + Sync {
+    /// Answer `msg`, or give `None` to send nothing. An error is answered
+    /// with an `error` envelope; either way the message is acked.
+    async fn handle(&self, msg: IncomingMessage) -> Result<Option<OutgoingReply>>;
+
+    /// Whether to take `msg` now. Declined messages are left unacked on the
+    /// inbox, where a restart with the same consumer name finds them again.
+    async fn accepts(&self, _msg: &IncomingMessage) -> bool {
+        true
+    }
+
+    /// Last look at every envelope the runtime sends, pongs and errors included.
+    async fn on_reply(&self, _reply: &mut Envelope) {}
+
+    /// Called with what [`MessageHandler::on_reply`] saw once the runtime has
+    /// tried to send it, whether or not the send went through. `request_id`
+    /// is the inbox entry the reply answers, which is also the request's
+    /// `envelope_id`.
+    async fn on_sent(&self, _request_id: &str, _reply: &Envelope) {}
+
+    /// Sessions the agent has open, for pongs. Called while a message is being handled.
+    async fn active_sessions(&self) -> usize {
+        0
+    }
+}
+
+#[async_trait]
+impl<F, Fut> MessageHandler for F
+where
+    F: Fn(IncomingMessage) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<Option<OutgoingReply>>> + Send + 'static,
+{
+    async fn handle(&self, msg: IncomingMessage) -> Result<Option<OutgoingReply>> {
+        self(msg).await
+    }
+}
+
+/// What became of an inbox envelope.
+#[derive(Debug)]
+pub enum Answer {
+    /// Send `envelope` to `reply_to`, then ack
+    Reply { reply_to: String, envelope: Box<Envelope> },
+    /// Nothing to send; ack
+    Ignored,
+    /// Declined by [`MessageHandler::accepts`]; left unacked
+    Deferred,
+}
+
+/// What the runtime does with an inbox envelope, by its kind.
+#[derive(Debug, PartialEq, Eq)]
+enum Dispatch {
+    Handle,
+    /// Answered with a `pong` by the runtime itself
+    Ping,
+    Ignore,
+    /// Answered with an `error`
+    Reject,
+}
+
+pub struct BusAgentRuntime {
+    bus: Bus,
+    cfg: RuntimeConfig,
+    started: Instant,
+    /// Deliveries left unacked as [`Answer::Deferred`], by entry id
+    deferred: Mutex<HashMap<String, Delivery>>,
+}
+
+impl BusAgentRuntime {
+    pub fn new(bus: Bus, cfg: RuntimeConfig) -> Self {
+        Self { bus, cfg, started: Instant::now(), deferred: Mutex::default() }
+    }
+
+    pub fn config(&self) -> &RuntimeConfig {
+        &self.cfg
+    }
+
+    /// Answer the inbox with `handler`, up to [`RuntimeConfig::concurrency`]
+    /// messages at a time in the order they were read, until the
+    /// subscription ends (which it only does if Redis stops answering for good).
+    ///
+    /// The group is created as needed and reads back off while Redis is
+    /// unreachable. Messages this consumer was handed before a restart but
+    /// never acked come first. Each message is acked once answered, even if
+    /// answering failed, so one bad message can't wedge the inbox; only
+    /// deferred ones stay unacked, until [`Self::requeue_deferred`]. Pings
+    /// arriving while messages are being
+    /// answered are answered right away. When the bus has a
+    /// [visibility timeout](Bus::with_visibility_timeout), messages left
+    /// unacked that long, by this consumer or another, are answered again.
+    /// Messages too large to read, and replies too large to send, are
+    /// answered with a `payload_too_large` error instead.
+    ///
+    /// The agent first [announces](Self::announce) its capabilities, and
+    /// again every [`ANNOUNCE_INTERVAL`] so discovery knows it is still up.
+    pub async fn run<H: MessageHandler>(&self, handler: &H) -> Result<()> {
+        info!(inbox = %self.cfg.inbox, group = %self.cfg.group, consumer = %self.cfg.consumer, "Bus agent started");
+        self.announce().await;
+        let mut opts = SubscribeOptions::new(&self.cfg.inbox, &self.cfg.group, &self.cfg.consumer);
+        opts.block_ms = self.cfg.block_ms;
+        opts.auto_ack = AckMode::Manual;
+        opts.start = self.cfg.start.clone();
+        let reclaimed = self.bus.reclaim_loop(&self.cfg.inbox, &self.cfg.group, &self.cfg.consumer);
+        let mut deliveries = std::pin::pin!(futures::stream::select(self.bus.subscribe(opts), reclaimed));
+        let concurrency = self.cfg.concurrency.max(1);
+        let mut in_flight = FuturesUnordered::new();
+        // Read on while messages are answered so pings are; the rest wait here, in order.
+        // Handlers run as futures of this one task, not spawned workers: while a select!
+        // arm below awaits (a pong, an oversized refusal, settling an answer), the handlers
+        // in flight are not polled and resume once it is done.
+        let mut read_ahead: VecDeque<Delivery> = VecDeque::new();
+        let mut ended = false;
+        let mut announce = tokio::time::interval_at(tokio::time::Instant::now() + ANNOUNCE_INTERVAL, ANNOUNCE_INTERVAL);
+        announce.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            while in_flight.len() < concurrency {
+                let Some(delivery) = read_ahead.pop_front() else { break };
+                in_flight.push(self.work(handler, delivery));
+            }
+            if ended && in_flight.is_empty() {
+                return Ok(());
+            }
+            tokio::select! {
+                Some((delivery, answer)) = in_flight.next() => self.settle(handler, answer, delivery).await,
+                next = deliveries.next(), if !ended && read_ahead.len() < MAX_READ_AHEAD => match next {
+                    Some(Ok(next)) if next.envelope.is_ping() => {
+                        let pong = self.pong(handler, &next.envelope).await;
+                        self.settle(handler, pong, next).await;
+                    }
+                    Some(Ok(next)) => read_ahead.push_back(next),
+                    Some(Err(BusError::Oversized(entry))) => self.refuse_oversized(handler, &entry).await,
+                    Some(Err(e)) => error!(error = %e, "Error receiving from the inbox"),
+                    None => ended = true,
+                },
+                _ = announce.tick(), if !ended => self.announce().await,
+            }
+        }
+    }
+
+    /// Answer `delivery` with `handler`, handing it back with the answer to settle.
+    async fn work<H: MessageHandler>(&self, handler: &H, delivery: Delivery) -> (Delivery, Answer) {
+        let start = Instant::now();
+        let queue_latency = delivery.queue_latency();
+        info!(
+            id = %delivery.entry_id,
+            queue_latency_ms = queue_latency.as_millis() as u64,
+            redelivered = delivery.redelivered,
+            "Read inbox message"
+        );
+        let answer = self.answer_queued(handler, delivery.envelope.clone(), Some(queue_latency)).await;
+        debug!(id = %delivery.entry_id, elapsed = ?start.elapsed(), "Answered inbox message");
+        (delivery, answer)
+    }
+
+    /// Send what `answer` says to, and ack `delivery` unless it was deferred.
+    async fn settle<H: MessageHandler>(&self, handler: &H, answer: Answer, delivery: Delivery) {
+        if let Answer::Deferred = answer {
+            info!(id = %delivery.entry_id, "Left message unacked for later");
+            self.deferred.lock().unwrap().insert(delivery.entry_id.clone(), delivery);
+            return;
+        }
+        // Answered after all, e.g. reclaimed once accepted again
+        self.deferred.lock().unwrap().remove(&delivery.entry_id);
+        if let Answer::Reply { reply_to, envelope } = answer {
+            self.send_reply(&reply_to, &envelope).await;
+            handler.on_sent(&delivery.entry_id, &envelope).await;
+        }
+        if let Err(e) = delivery.ack().await {
+            error!(id = %delivery.entry_id, error = %e, "Failed to ack inbox message");
+        }
+    }
+
+    /// Hand the messages deferred so far back to the inbox as new entries,
+    /// for any consumer of the group to answer, and give how many were. For
+    /// once [`Self::run`] has stopped, e.g. after a drain: left unacked, they
+    /// would wait for this consumer to come back.
+    pub async fn requeue_deferred(&self) -> usize {
+        let deferred: Vec<Delivery> = self.deferred.lock().unwrap().drain().map(|(_, d)| d).collect();
+        let mut requeued = 0;
+        for delivery in deferred {
+            match delivery.requeue().await {
+                Ok(id) => {
+                    debug!(id = %delivery.entry_id, new_id = %id, "Requeued deferred message");
+                    requeued += 1;
+                }
+                Err(e) => error!(id = %delivery.entry_id, error = %e, "Failed to requeue deferred message"),
+            }
+        }
+        requeued
+    }
+
+    /// Send `envelope` to `reply_to`. A reply over the bus's size limit is
+    /// replaced with a `payload_too_large` error, so its sender isn't left waiting.
+    async fn send_reply(&self, reply_to: &str, envelope: &Envelope) {
+        let err = match self.bus.send(reply_to, envelope).await {
+            Ok(_) => return,
+            Err(BusError::TooLarge { bytes, limit }) => {
+                warn!(reply_to = %reply_to, bytes, limit, "Reply too large to send, sending an error instead");
+                match self.bus.send(reply_to, &self.too_large_reply(envelope, bytes, limit)).await {
+                    Ok(_) => return,
+                    Err(e) => e,
+                }
+            }
+            Err(e) => e,
+        };
+        error!(reply_to = %reply_to, error = %err, "Failed to send reply");
+    }
+
+    /// Answer an inbox entry too large to read with a `payload_too_large`
+    /// error, then ack it.
+    async fn refuse_oversized<H: MessageHandler>(&self, handler: &H, entry: &OversizedEntry) {
+        warn!(id = %entry.id, bytes = entry.bytes, limit = entry.limit, "Refusing oversized inbox entry");
+        if let Answer::Reply { reply_to, envelope } = self.oversized_answer(handler, entry).await {
+            self.send_reply(&reply_to, &envelope).await;
+            handler.on_sent(&entry.id, &envelope).await;
+        }
+        if let Err(e) = self.bus.ack_message(&entry.stream, &self.cfg.group, &entry.id).await {
+            error!(id = %entry.id, error = %e, "Failed to ack oversized inbox message");
+        }
+    }
+
+    /// The `payload_too_large` error for an inbox entry too large to read,
+    /// addressed from what could be read of it.
+    pub async fn oversized_answer<H: MessageHandler>(&self, handler: &H, entry: &OversizedEntry) -> Answer {
+        let msg = self.incoming(entry.head.clone());
+        let mut reply = self.error_envelope(&msg, "");
+        reply.content = payload_too_large_content(entry.bytes, entry.limit);
+        self.finish(handler, msg.reply_to, reply).await
+    }
+
+    /// The `payload_too_large` error sent in place of `reply`, addressed like it.
+    fn too_large_reply(&self, reply: &Envelope, bytes: usize, limit: usize) -> Envelope {
+        Envelope {
+            role: "error".into(),
+            content: payload_too_large_content(bytes, limit),
+            session_code: reply.session_code.clone(),
+            agent_name: reply.agent_name.clone(),
+            target: reply.target.clone(),
+            reply_to: reply.reply_to.clone(),
+            envelope_type: Some(EnvelopeKind::Error.into()),
+            timestamp: Some(chrono::Utc::now().to_rfc3339()),
+            meta: json!({ "x_stream_key": self.cfg.inbox }),
+            envelope_id: Some(uuid::Uuid::new_v4().to_string()),
+            correlation_id: reply.correlation_id.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// Answer one inbox envelope with `handler`, without sending anything.
+    pub async fn answer<H: MessageHandler>(&self, handler: &H, env: Envelope) -> Answer {
+        self.answer_queued(handler, env, None).await
+    }
+
+    /// [`BusAgentRuntime::answer`], for an envelope that waited `queue_latency` on the inbox.
+    async fn answer_queued<H: MessageHandler>(&self, handler: &H, env: Envelope, queue_latency: Option<Duration>) -> Answer {
+        info!(envelope = %env, "Handling envelope");
+        debug!(envelope = %env.redacted(RedactionPolicy::global()), "Envelope received");
+
+        match self.dispatch(env.kind().as_ref()) {
+            Dispatch::Handle => {}
+            Dispatch::Ping => return self.pong(handler, &env).await,
+            Dispatch::Ignore => {
+                debug!(envelope_type = ?env.envelope_type, "Ignoring envelope type the agent doesn't answer");
+                return Answer::Ignored;
+            }
+            Dispatch::Reject => {
+                let envelope_type = env.envelope_type.clone().unwrap_or_default();
+                warn!(envelope_type, envelope = %env, "Rejecting envelope of unknown type");
+                let answered: Vec<&str> = self.cfg.accept_kinds.iter().flatten().map(EnvelopeKind::as_str).collect();
+                let mut msg = self.incoming(env);
+                msg.queue_latency = queue_latency;
+                let error = format!("unsupported envelope_type {:?}: {} answers {:?}", envelope_type, self.cfg.agent_name, answered);
+                let reply = self.error_envelope(&msg, &error);
+                return self.finish(handler, msg.reply_to, reply).await;
+            }
+        }
+        // Roles filter conversation turns; control envelopes come from whoever runs the agent
+        let turn = matches!(env.kind(), None | Some(EnvelopeKind::Message | EnvelopeKind::Task));
+        if turn && !self.cfg.accept_roles.contains(&env.role) {
+            debug!(role = %env.role, "Skipping message from a role the agent doesn't answer");
+            return Answer::Ignored;
+        }
+
+        let mut msg = self.incoming(env);
+        msg.queue_latency = queue_latency;
+        if !handler.accepts(&msg).await {
+            return Answer::Deferred;
+        }
+        let request = msg.clone();
+        let reply = match handler.handle(msg).await {
+            Ok(Some(reply)) => self.reply_envelope(&request, reply),
+            Ok(None) => return Answer::Ignored,
+            Err(e) => {
+                error!(correlation_id = %request.correlation_id, error = %e, "Failed answering envelope");
+                let mut reply = self.error_envelope(&request, &format!("{:#}", e));
+                if let Some(coded) = e.downcast_ref::<CodedError>() {
+                    reply.content["code"] = json!(coded.code);
+                }
+                reply
+            }
+        };
+        self.finish(handler, request.reply_to, reply).await
+    }
+
+    /// How the agent describes itself: its name, inbox, description and
+    /// keywords, the kinds it answers (pings included) and the largest
+    /// envelope its bus reads.
+    pub fn capabilities(&self) -> Capabilities {
+        let handled = self.cfg.accept_kinds.as_deref().unwrap_or(EnvelopeKind::KNOWN);
+        let mut envelope_types = vec![EnvelopeKind::Ping.as_str().to_string()];
+        for kind in handled {
+            if self.dispatch(Some(kind)) == Dispatch::Handle && !envelope_types.iter().any(|t| t == kind.as_str()) {
+                envelope_types.push(kind.as_str().to_string());
+            }
+        }
+        Capabilities {
+            name: self.cfg.agent_name.clone(),
+            inbox: self.cfg.inbox.clone(),
+            description: self.cfg.description.clone(),
+            capabilities_keywords: self.cfg.capabilities_keywords.clone(),
+            envelope_types,
+            schema_versions: BTreeMap::from([("capabilities".to_string(), CAPABILITIES_SCHEMA_VERSION)]),
+            max_payload_bytes: Some(self.bus.max_entry_bytes()),
+        }
+    }
+
+    /// Publish [`Self::capabilities`] to [`CAPABILITIES_STREAM`] so registries
+    /// in discovery mode find the agent. Failing to is logged, not fatal.
+    pub async fn announce(&self) {
+        match self.bus.announce_capabilities(CAPABILITIES_STREAM, &self.capabilities()).await {
+            Ok(id) => info!(stream = CAPABILITIES_STREAM, id, "Announced capabilities"),
+            Err(e) => warn!(stream = CAPABILITIES_STREAM, error = %e, "Failed announcing capabilities"),
+        }
+    }
+
+    /// `env` as the handler sees it.
+    pub fn incoming(&self, env: Envelope) -> IncomingMessage {
+        let reply_to = env.reply_to.clone().unwrap_or_else(|| {
+            warn!(default_reply_to = %self.cfg.default_reply_to, "No reply-to address in envelope, using default");
+            self.cfg.default_reply_to.clone()
+        });
+        IncomingMessage {
+            kind: env.kind().unwrap_or(EnvelopeKind::Message),
+            text: normalized_text(&env),
+            correlation_id: env.correlation_id.clone().unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
+            reply_to,
+            envelope: env,
+            queue_latency: None,
+        }
+    }
+
+    fn dispatch(&self, kind: Option<&EnvelopeKind>) -> Dispatch {
+        let kind = match kind {
+            Some(EnvelopeKind::Ping) => return Dispatch::Ping,
+            Some(EnvelopeKind::Pong | EnvelopeKind::Capabilities) => return Dispatch::Ignore,
+            Some(kind) => kind,
+            None => &EnvelopeKind::Message,
+        };
+        match &self.cfg.accept_kinds {
+            None => Dispatch::Handle,
+            Some(kinds) if kinds.contains(kind) => Dispatch::Handle,
+            Some(_) if matches!(kind, EnvelopeKind::Other(_)) => Dispatch::Reject,
+            Some(_) => Dispatch::Ignore,
+        }
+    }
+
+    /// The `pong` for a ping, built without the handler's help but for its
+    /// session count. The inbox lag is left out when Redis doesn't answer
+    /// within [`PING_LAG_TIMEOUT`].
+    async fn pong<H: MessageHandler>(&self, handler: &H, ping: &Envelope) -> Answer {
+        // Defaulting like messages do would only ping-pong with ourselves
+        let Some(reply_to) = ping.reply_to.clone() else {
+            warn!("Dropping ping without a reply_to");
+            return Answer::Ignored;
+        };
+        let inbox_lag = match tokio::time::timeout(PING_LAG_TIMEOUT, self.bus.group_lag(&self.cfg.inbox, &self.cfg.group)).await {
+            Ok(Ok(lag)) => Some(lag),
+            _ => None,
+        };
+        let info = PongInfo {
+            agent_name: self.cfg.agent_name.clone(),
+            version: self.cfg.version.clone(),
+            uptime_secs: self.started.elapsed().as_secs(),
+            active_sessions: handler.active_sessions().await,
+            inbox_lag,
+        };
+        let mut pong = ping.pong_reply(&info);
+        pong.meta = json!({ "x_stream_key": self.cfg.inbox });
+        self.finish(handler, reply_to, pong).await
+    }
+
+    async fn finish<H: MessageHandler>(&self, handler: &H, reply_to: String, mut envelope: Envelope) -> Answer {
+        handler.on_reply(&mut envelope).await;
+        Answer::Reply { reply_to, envelope: Box::new(envelope) }
+    }
+
+    /// `reply` addressed back to the sender of `msg`, on its correlation id and session.
+    fn reply_envelope(&self, msg: &IncomingMessage, reply: OutgoingReply) -> Envelope {
+        let meta = self.reply_meta(msg, if reply.meta.is_object() { reply.meta } else { json!({}) });
+        let mut envelope = Envelope {
+            role: "assistant".into(),
+            content: reply.content,
+            session_code: reply.session_code.or_else(|| msg.envelope.session_code.clone()),
+            agent_name: Some(self.cfg.agent_name.clone()),
+            usage: reply.usage,
+            target: msg.envelope.agent_name.clone(),
+            reply_to: Some(msg.reply_to.clone()),
+            envelope_type: Some(reply.kind.into()),
+            timestamp: Some(chrono::Utc::now().to_rfc3339()),
+            meta,
+            tools_used: reply.tools_used,
+            envelope_id: Some(uuid::Uuid::new_v4().to_string()),
+            correlation_id: Some(msg.correlation_id.clone()),
+            ..Default::default()
+        };
+        // Multi-part replies still reach readers of `content.text`
+        envelope.ensure_text_fallback();
+        envelope
+    }
+
+    /// The `error` reply to `msg`, addressed like any other reply.
+    fn error_envelope(&self, msg: &IncomingMessage, error: &str) -> Envelope {
+        let mut reply = msg.envelope.clone().into_error_reply(error);
+        reply.agent_name = Some(self.cfg.agent_name.clone());
+        reply.reply_to = Some(msg.reply_to.clone());
+        reply.correlation_id = Some(msg.correlation_id.clone());
+        reply.meta = self.reply_meta(msg, json!({}));
+        reply
+    }
+
+    /// `meta` with the inbox as `x_stream_key` and, for messages read off
+    /// it, how long they waited there as `queue_latency_ms`.
+    fn reply_meta(&self, msg: &IncomingMessage, mut meta: Value) -> Value {
+        meta["x_stream_key"] = json!(self.cfg.inbox);
+        if let Some(latency) = msg.queue_latency {
+            meta["queue_latency_ms"] = json!(latency.as_millis() as u64);
+        }
+        meta
+    }
+}
+
+/// The text an agent is given for `env`: `content.text` (or the parts'
+/// fallback), else string content as is, else an object's first string
+/// field, else the content as JSON. Empty for `null`.
+pub fn normalized_text(env: &Envelope) -> String {
+    if let Some(text) = env.text_or_fallback() {
+        return text;
+    }
+    match &env.content {
+        Value::String(s) => s.clone(),
+        Value::Object(map) => map.values().find_map(Value::as_str).unwrap_or_default().to_string(),
+        Value::Null => String::new(),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// For tests that answer envelopes directly and never connect.
+    const OFFLINE_REDIS_URL: &str = "redis://127.0.0.1:1";
+
+    /// The Redis the end-to-end tests run against, from `AG1_TEST_REDIS_URL`.
+    /// Without it those tests return early.
+    fn test_redis_url() -> Option<String> {
+        let url = std::env::var("AG1_TEST_REDIS_URL").ok().filter(|url| !url.is_empty());
+        if url.is_none() {
+            eprintln!("AG1_TEST_REDIS_URL is not set, skipping");
+        }
+        url
+    }
+
+    fn config(inbox: &str) -> RuntimeConfig {
+        RuntimeConfig {
+            inbox: inbox.into(),
+            group: "bus-agent-test".into(),
+            consumer: "tester".into(),
+            start: StartPos::Latest,
+            block_ms: 200,
+            concurrency: 1,
+            agent_name: "EchoAgent".into(),
+            version: "1.2.3".into(),
+            accept_kinds: Some(vec![EnvelopeKind::Message, EnvelopeKind::Task]),
+            accept_roles: vec!["user".into()],
+            default_reply_to: "AG1:test:agent:default".into(),
+            description: None,
+            capabilities_keywords: Vec::new(),
+        }
+    }
+
+    fn runtime(cfg: RuntimeConfig) -> BusAgentRuntime {
+        BusAgentRuntime::new(Bus::new(OFFLINE_REDIS_URL).unwrap(), cfg)
+    }
+
+    fn message(text: &str) -> Envelope {
+        Envelope {
+            role: "user".into(),
+            content: json!({ "text": text }),
+            agent_name: Some("tester".into()),
+            reply_to: Some("AG1:test:agent:replies".into()),
+            correlation_id: Some("cid-1".into()),
+            ..Default::default()
+        }
+    }
+
+    async fn echo(msg: IncomingMessage) -> Result<Option<OutgoingReply>> {
+        match msg.text.as_str() {
+            "fail" => Err(anyhow::anyhow!("echo failed")),
+            "quiet" => Ok(None),
+            text => Ok(Some(OutgoingReply::text(&format!("echo: {}", text)))),
+        }
+    }
+
+    fn replied(answer: Answer) -> (String, Envelope) {
+        match answer {
+            Answer::Reply { reply_to, envelope } => (reply_to, *envelope),
+            other => panic!("expected a reply, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn replies_are_addressed_back_on_the_request_correlation_and_session() {
+        let runtime = runtime(config("AG1:test:agent:inbox"));
+        let mut env = message("hi");
+        env.session_code = Some("sess-1".into());
+        let (reply_to, reply) = replied(runtime.answer(&echo, env).await);
+        assert_eq!(reply_to, "AG1:test:agent:replies");
+        assert_eq!(reply.kind(), Some(EnvelopeKind::MessageReply));
+        assert_eq!(reply.text_or_empty(), "echo: hi");
+        assert_eq!(reply.role, "assistant");
+        assert_eq!(reply.agent_name.as_deref(), Some("EchoAgent"));
+        assert_eq!(reply.target.as_deref(), Some("tester"));
+        assert_eq!(reply.correlation_id.as_deref(), Some("cid-1"));
+        assert_eq!(reply.session_code.as_deref(), Some("sess-1"));
+        assert_eq!(reply.meta["x_stream_key"], json!("AG1:test:agent:inbox"));
+        assert_eq!(reply.meta.get("queue_latency_ms"), None);
+
+        // Read off the inbox, replies and errors alike say how long the request waited there
+        for text in ["hi", "fail"] {
+            let latency = Some(Duration::from_millis(1500));
+            let (_, reply) = replied(runtime.answer_queued(&echo, message(text), latency).await);
+            assert_eq!(reply.meta["queue_latency_ms"], json!(1500), "{text}");
+        }
+
+        // A handler's session wins, and requests without addressing get defaults
+        let handler = |_msg: IncomingMessage| async {
+            let mut reply = OutgoingReply::text("ok");
+            reply.session_code = Some("sess-2".into());
+            reply.tools_used = vec!["developer__shell".into()];
+            Ok(Some(reply))
+        };
+        let mut env = message("hi");
+        env.reply_to = None;
+        env.correlation_id = None;
+        let (reply_to, reply) = replied(runtime.answer(&handler, env).await);
+        assert_eq!(reply_to, "AG1:test:agent:default");
+        assert_eq!(reply.session_code.as_deref(), Some("sess-2"));
+        assert_eq!(reply.tools_used, ["developer__shell"]);
+        assert!(reply.correlation_id.is_some_and(|cid| !cid.is_empty()));
+
+        let parts = |_msg: IncomingMessage| async {
+            Ok(Some(OutgoingReply { content: json!({ "parts": [{ "type": "text", "text": "from parts" }] }), ..OutgoingReply::text("") }))
+        };
+        let (_, reply) = replied(runtime.answer(&parts, message("hi")).await);
+        assert_eq!(reply.text_or_empty(), "from parts");
+    }
+
+    #[tokio::test]
+    async fn failures_are_answered_with_an_error() {
+        let runtime = runtime(config("AG1:test:agent:inbox"));
+        let (reply_to, reply) = replied(runtime.answer(&echo, message("fail")).await);
+        assert_eq!(reply_to, "AG1:test:agent:replies");
+        assert_eq!(reply.kind(), Some(EnvelopeKind::Error));
+        assert_eq!(reply.text_or_empty(), "echo failed");
+        assert_eq!(reply.correlation_id.as_deref(), Some("cid-1"));
+        assert_eq!(reply.target.as_deref(), Some("tester"));
+
+        assert!(matches!(runtime.answer(&echo, message("quiet")).await, Answer::Ignored));
+        assert_eq!(reply.content.get("code"), None);
+
+        let timing_out = |_msg: IncomingMessage| async {
+            let e = anyhow::Error::new(CodedError::new("timeout", "no reply in time"));
+            Err(e.context("asking the model"))
+        };
+        let (_, reply) = replied(runtime.answer(&timing_out, message("hi")).await);
+        assert_eq!(reply.text_or_empty(), "asking the model: no reply in time");
+        assert_eq!(reply.content["code"], "timeout");
+    }
+
+    #[tokio::test]
+    async fn oversized_messages_and_replies_are_answered_with_payload_too_large() {
+        let runtime = runtime(config("AG1:test:agent:inbox"));
+        let mut head = message("");
+        head.content = Value::Null;
+        head.session_code = Some("sess-1".into());
+        let entry = OversizedEntry { stream: "AG1:test:agent:inbox".into(), id: "1-0".into(), bytes: 12_000_000, limit: 16 << 20, head };
+
+        let (reply_to, reply) = replied(runtime.oversized_answer(&echo, &entry).await);
+        assert_eq!(reply_to, "AG1:test:agent:replies");
+        assert_eq!(reply.kind(), Some(EnvelopeKind::Error));
+        assert_eq!(reply.content["code"], "payload_too_large");
+        assert_eq!(reply.content["bytes"], 12_000_000);
+        assert_eq!(reply.correlation_id.as_deref(), Some("cid-1"));
+        assert_eq!(reply.session_code.as_deref(), Some("sess-1"));
+
+        let (_, too_large) = replied(runtime.answer(&echo, message("hi")).await);
+        let error = runtime.too_large_reply(&too_large, 2_000_000, 1 << 20);
+        assert_eq!(error.kind(), Some(EnvelopeKind::Error));
+        assert_eq!(error.content["code"], "payload_too_large");
+        assert_eq!(error.content["limit"], 1 << 20);
+        assert_eq!((error.correlation_id, error.target), (too_large.correlation_id, too_large.target));
+    }
+
+    #[tokio::test]
+    async fn kinds_and_roles_are_filtered_before_the_handler() {
+        let calls = AtomicUsize::new(0);
+        let counting = |msg: IncomingMessage| {
+            calls.fetch_add(1, Ordering::SeqCst);
+            echo(msg)
+        };
+        let runtime = runtime(config("AG1:test:agent:inbox"));
+
+        let mut env = message("hi");
+        env.envelope_type = Some("message_repy".into());
+        let (_, reply) = replied(runtime.answer(&counting, env.clone()).await);
+        assert_eq!(reply.kind(), Some(EnvelopeKind::Error));
+        assert!(reply.text_or_empty().contains("unsupported envelope_type \"message_repy\""), "{}", reply.text_or_empty());
+
+        for kind in [EnvelopeKind::Heartbeat, EnvelopeKind::MessageReply, EnvelopeKind::Pong] {
+            env.set_kind(kind);
+            assert!(matches!(runtime.answer(&counting, env.clone()).await, Answer::Ignored));
+        }
+        let mut assistant = message("hi");
+        assistant.role = "assistant".into();
+        assert!(matches!(runtime.answer(&counting, assistant.clone()).await, Answer::Ignored));
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+
+        // Without a kind list, everything but pings, pongs and capabilities reaches the handler
+        let runtime = BusAgentRuntime::new(
+            Bus::new(OFFLINE_REDIS_URL).unwrap(),
+            RuntimeConfig { accept_kinds: None, accept_roles: vec!["user".into(), "assistant".into()], ..config("AG1:test:agent:inbox") },
+        );
+        let announcement = Envelope::capabilities(&runtime.capabilities());
+        assert!(matches!(runtime.answer(&counting, announcement).await, Answer::Ignored));
+        assistant.set_kind(EnvelopeKind::MessageReply);
+        replied(runtime.answer(&counting, assistant).await);
+        env.envelope_type = Some("message_repy".into());
+        replied(runtime.answer(&counting, env).await);
+        // Roles only filter messages and tasks
+        let mut control = message("status");
+        control.role = "system".into();
+        control.set_kind(EnvelopeKind::Control);
+        replied(runtime.answer(&counting, control).await);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    /// Defers everything and counts what it sends.
+    #[derive(Default)]
+    struct Draining {
+        sent: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl MessageHandler for Draining {
+        async fn handle(&self, msg: IncomingMessage) -> Result<Option<OutgoingReply>> {
+            echo(msg).await
+        }
+
+        async fn accepts(&self, _msg: &IncomingMessage) -> bool {
+            false
+        }
+
+        async fn on_reply(&self, reply: &mut Envelope) {
+            self.sent.fetch_add(1, Ordering::SeqCst);
+            reply.meta["seen"] = json!(true);
+        }
+
+        async fn active_sessions(&self) -> usize {
+            3
+        }
+    }
+
+    #[tokio::test]
+    async fn pings_are_answered_by_the_runtime() {
+        let runtime = runtime(config("AG1:test:agent:inbox"));
+        let handler = Draining::default();
+        assert!(matches!(runtime.answer(&handler, message("hi")).await, Answer::Deferred));
+
+        let mut ping = Envelope::ping("AG1:test:agent:pinger");
+        ping.agent_name = Some("pinger".into());
+        let (reply_to, pong) = replied(runtime.answer(&handler, ping.clone()).await);
+        assert_eq!(reply_to, "AG1:test:agent:pinger");
+        assert_eq!(pong.kind(), Some(EnvelopeKind::Pong));
+        assert_eq!(pong.correlation_id, ping.correlation_id);
+        assert_eq!(pong.target.as_deref(), Some("pinger"));
+        assert_eq!(pong.meta["seen"], json!(true));
+        let info = pong.pong_info().unwrap();
+        assert_eq!(info.agent_name, "EchoAgent");
+        assert_eq!(info.version, "1.2.3");
+        assert_eq!(info.active_sessions, 3);
+        // No Redis to read the lag from
+        assert_eq!(info.inbox_lag, None);
+        assert_eq!(handler.sent.load(Ordering::SeqCst), 1);
+
+        ping.reply_to = None;
+        assert!(matches!(runtime.answer(&handler, ping).await, Answer::Ignored));
+    }
+
+    #[test]
+    fn capabilities_describe_the_runtime() {
+        let cfg = RuntimeConfig {
+            description: Some("Echoes".into()),
+            capabilities_keywords: vec!["echo".into()],
+            ..config("AG1:test:agent:inbox")
+        };
+        let caps = runtime(cfg).capabilities();
+        assert_eq!((caps.name.as_str(), caps.inbox.as_str()), ("EchoAgent", "AG1:test:agent:inbox"));
+        assert_eq!(caps.description.as_deref(), Some("Echoes"));
+        assert_eq!(caps.capabilities_keywords, ["echo"]);
+        assert_eq!(caps.envelope_types, ["ping", "message", "task"]);
+        assert_eq!(caps.schema_versions["capabilities"], CAPABILITIES_SCHEMA_VERSION);
+        assert_eq!(caps.max_payload_bytes, Some(Bus::new(OFFLINE_REDIS_URL).unwrap().max_entry_bytes()));
+
+        let everything = runtime(RuntimeConfig { accept_kinds: None, ..config("AG1:test:agent:inbox") }).capabilities();
+        assert!(everything.envelope_types.iter().any(|t| t == "heartbeat"));
+        assert!(!everything.envelope_types.iter().any(|t| t == "pong" || t == "capabilities"));
+    }
+
+    #[test]
+    fn capabilities_keywords_are_split_on_commas() {
+        assert_eq!(split_keywords(" code, review ,,search "), ["code", "review", "search"]);
+        assert!(split_keywords("").is_empty());
+    }
+
+    #[test]
+    fn content_is_normalized_to_text() {
+        let text = |content: Value| normalized_text(&Envelope { content, ..Default::default() });
+        assert_eq!(text(json!({ "text": "hi", "other": "x" })), "hi");
+        assert_eq!(text(json!({ "parts": [{ "type": "text", "text": "from parts" }] })), "from parts");
+        assert_eq!(text(json!("plain")), "plain");
+        assert_eq!(text(json!({ "prompt": "first string", "n": 1 })), "first string");
+        assert_eq!(text(json!(42)), "42");
+        assert_eq!(text(Value::Null), "");
+    }
+
+    #[tokio::test]
+    async fn runtime_answers_the_inbox_and_pings_during_a_turn() {
+        let Some(redis_url) = test_redis_url() else { return };
+        let bus = Bus::new(&redis_url).unwrap();
+        let inbox = format!("AG1:test:agent:{}:inbox", uuid::Uuid::new_v4());
+        let replies = format!("AG1:test:agent:{}:replies", uuid::Uuid::new_v4());
+        let runtime = BusAgentRuntime::new(bus.clone(), RuntimeConfig { start: StartPos::Earliest, ..config(&inbox) });
+        let slow = |msg: IncomingMessage| async move {
+            tokio::time::sleep(Duration::from_millis(1500)).await;
+            echo(msg).await
+        };
+
+        let scenario = async {
+            let mut failing = message("fail");
+            failing.reply_to = Some(replies.clone());
+            bus.send(&inbox, &failing).await.unwrap();
+            let mut turn = message("hi");
+            turn.reply_to = Some(replies.clone());
+            turn.correlation_id = Some("cid-2".into());
+            bus.send(&inbox, &turn).await.unwrap();
+
+            let started = Instant::now();
+            let pong = bus.ping(&inbox, &format!("{}:pongs", replies), 1000).await.unwrap();
+            assert!(started.elapsed() < Duration::from_millis(1000));
+            assert_eq!(pong.agent_name, "EchoAgent");
+
+            let mut last_id = "0".to_string();
+            let mut got = Vec::new();
+            while got.len() < 2 {
+                let entry = bus.recv_block(&replies, &last_id, 5000).await.unwrap().expect("reply in time");
+                last_id = entry.id;
+                got.push(entry.envelope.unwrap());
+            }
+            assert_eq!(got[0].kind(), Some(EnvelopeKind::Error));
+            assert_eq!(got[1].text_or_empty(), "echo: hi");
+            assert_eq!(got[1].correlation_id.as_deref(), Some("cid-2"));
+            // The failed message was acked along with the rest
+            assert_eq!(bus.pending_messages(&inbox, "bus-agent-test").await.unwrap(), 0);
+        };
+        tokio::select! {
+            res = runtime.run(&slow) => panic!("runtime stopped: {:?}", res),
+            _ = scenario => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn deferred_messages_are_requeued_for_other_consumers() {
+        let Some(redis_url) = test_redis_url() else { return };
+        let bus = Bus::new(&redis_url).unwrap();
+        let inbox = format!("AG1:test:agent:{}:inbox", uuid::Uuid::new_v4());
+        let replies = format!("AG1:test:agent:{}:replies", uuid::Uuid::new_v4());
+        let draining = BusAgentRuntime::new(bus.clone(), RuntimeConfig { start: StartPos::Earliest, ..config(&inbox) });
+        let handler = Draining::default();
+        let mut turn = message("hi");
+        turn.reply_to = Some(replies.clone());
+        bus.send(&inbox, &turn).await.unwrap();
+
+        let deferred = async {
+            while bus.pending_messages(&inbox, "bus-agent-test").await.unwrap_or(0) == 0 {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        };
+        tokio::select! {
+            res = draining.run(&handler) => panic!("runtime stopped: {:?}", res),
+            _ = tokio::time::timeout(Duration::from_secs(5), deferred) => {}
+        }
+        assert_eq!(draining.requeue_deferred().await, 1);
+        assert_eq!(bus.pending_messages(&inbox, "bus-agent-test").await.unwrap(), 0);
+
+        let other = BusAgentRuntime::new(bus.clone(), RuntimeConfig { consumer: "other".into(), ..config(&inbox) });
+        let answered = async { bus.recv_block(&replies, "0", 5000).await.unwrap().expect("reply in time") };
+        tokio::select! {
+            res = other.run(&echo) => panic!("runtime stopped: {:?}", res),
+            entry = answered => assert_eq!(entry.envelope.unwrap().text_or_empty(), "echo: hi"),
+        }
+        assert_eq!(draining.requeue_deferred().await, 0);
+    }
+
+    #[tokio::test]
+    async fn runtime_answers_up_to_its_concurrency_at_once() {
+        let Some(redis_url) = test_redis_url() else { return };
+        let bus = Bus::new(&redis_url).unwrap();
+        let inbox = format!("AG1:test:agent:{}:inbox", uuid::Uuid::new_v4());
+        let replies = format!("AG1:test:agent:{}:replies", uuid::Uuid::new_v4());
+        let cfg = RuntimeConfig { start: StartPos::Earliest, concurrency: 2, ..config(&inbox) };
+        let runtime = BusAgentRuntime::new(bus.clone(), cfg);
+        let (active, most) = (Arc::new(AtomicUsize::new(0)), AtomicUsize::new(0));
+        let slow = |msg: IncomingMessage| {
+            let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+            most.fetch_max(now, Ordering::SeqCst);
+            let active = active.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                active.fetch_sub(1, Ordering::SeqCst);
+                echo(msg).await
+            }
+        };
+
+        let scenario = async {
+            for i in 0..5 {
+                let mut turn = message(&format!("hi {i}"));
+                turn.reply_to = Some(replies.clone());
+                bus.send(&inbox, &turn).await.unwrap();
+            }
+            let mut last_id = "0".to_string();
+            for _ in 0..5 {
+                last_id = bus.recv_block(&replies, &last_id, 5000).await.unwrap().expect("reply in time").id;
+            }
+        };
+        tokio::select! {
+            res = runtime.run(&slow) => panic!("runtime stopped: {:?}", res),
+            _ = scenario => {}
+        }
+        // Two at a time, never more
+        assert_eq!(most.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,111 @@
+//! crates/bus/src/audit.rs
+//!
+//! The audit stream: a redacted copy of every envelope a bus sends or
+//! receives, for operators who need a record of the traffic without keeping
+//! its content.
+
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::{Bus, Envelope, RedactionPolicy};
+
+/// Environment variable [`Bus::new`] takes the audit stream from.
+pub const AUDIT_STREAM_ENV: &str = "AG1_AUDIT_STREAM";
+
+/// Which way an audited envelope went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+impl Direction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Direction::Sent => "sent",
+            Direction::Received => "received",
+        }
+    }
+}
+
+/// The audit stream named by [`AUDIT_STREAM_ENV`], if set.
+pub(crate) fn audit_stream_from_env() -> Option<String> {
+    std::env::var(AUDIT_STREAM_ENV).ok().filter(|s| !s.is_empty())
+}
+
+/// What the audit stream keeps of `env`: the envelope redacted with the
+/// global [`RedactionPolicy`], its content replaced with the SHA-256 and
+/// size of its JSON, and its `auth_signature` dropped.
+pub fn audit_record(env: &Envelope) -> Value {
+    let content = serde_json::to_vec(&env.content).unwrap_or_default();
+    let hash: String = Sha256::digest(&content).iter().map(|b| format!("{:02x}", b)).collect();
+    let mut env = env.clone();
+    env.content = json!({ "sha256": hash, "bytes": content.len() });
+    env.auth_signature = None;
+    env.redacted(RedactionPolicy::global())
+}
+
+impl Bus {
+    /// Also XADD an [`audit_record`] of every envelope this bus (or a later
+    /// clone) sends or receives to `audit_stream`, with `direction` (`sent`
+    /// or `received`) and `stream` fields. Off unless set here or through
+    /// [`AUDIT_STREAM_ENV`].
+    ///
+    /// The bus never trims the audit stream. Retention is up to operators:
+    /// `XTRIM <audit_stream> MAXLEN ~ <n>` keeps about the last `n` records,
+    /// `XTRIM <audit_stream> MINID <ms>-0` drops those older than a time.
+    pub fn with_audit(mut self, audit_stream: impl Into<String>) -> Self {
+        self.audit_stream = Some(audit_stream.into());
+        self
+    }
+
+    /// The stream set with [`Bus::with_audit`], if any.
+    pub fn audit_stream(&self) -> Option<&str> {
+        self.audit_stream.as_deref()
+    }
+
+    /// Record `env`, sent to or received from `stream`, on the audit stream.
+    /// A failure is logged, never returned: auditing doesn't hold up traffic.
+    pub(crate) async fn audit(&self, direction: Direction, stream: &str, env: &Envelope) {
+        let Some(audit_stream) = &self.audit_stream else { return };
+        let res: Result<String, redis::RedisError> = async {
+            let mut conn = self.client.get_async_connection().await?;
+            redis::cmd("XADD")
+                .arg(audit_stream)
+                .arg("*")
+                .arg("direction").arg(direction.as_str())
+                .arg("stream").arg(stream)
+                .arg("env").arg(audit_record(env).to_string())
+                .query_async(&mut conn)
+                .await
+        }
+        .await;
+        if let Err(e) = res {
+            eprintln!("[BUS_ERROR] ❌ Failed to audit {} envelope on {}: {}", direction.as_str(), stream, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_env;
+
+    #[test]
+    fn audit_records_keep_a_hash_of_the_content_and_no_signature() {
+        let mut env = test_env();
+        env.auth_signature = Some("sig-abcdef".into());
+        env.headers.insert("authorization".into(), "Bearer sk-live-123".into());
+
+        let record = audit_record(&env);
+        let expected: String = Sha256::digest(serde_json::to_vec(&env.content).unwrap())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        assert_eq!(record["content"]["sha256"], expected);
+        assert!(record["content"].get("text").is_none());
+        assert!(record["auth_signature"].is_null());
+        assert_eq!(record["correlation_id"], "test-cid");
+        assert!(!record.to_string().contains("sk-live-123"));
+    }
+}
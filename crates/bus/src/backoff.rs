@@ -0,0 +1,53 @@
+//! crates/bus/src/backoff.rs
+//!
+//! Jittered exponential backoff for reconnect loops, so replicas that lost
+//! Redis at the same moment don't all retry at the same moment too.
+
+use std::time::Duration;
+
+use rand::Rng;
+
+/// Retry delays that double from `initial` up to `max`, each drawn at random
+/// from the upper half of its step (`step/2 + rand(0..step/2)`).
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    step: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self { initial, max, step: initial.min(max) }
+    }
+
+    /// Delay before the next retry; the step after it is twice as long, up to `max`.
+    pub fn next_delay(&mut self) -> Duration {
+        let step = self.step;
+        self.step = (step * 2).min(self.max);
+        let half = step / 2;
+        half + half.mul_f64(rand::thread_rng().gen::<f64>())
+    }
+
+    /// Start again from `initial`, after a successful attempt.
+    pub fn reset(&mut self) {
+        self.step = self.initial.min(self.max);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_double_up_to_the_cap_within_the_upper_half_of_each_step() {
+        let secs = Duration::from_secs;
+        let mut backoff = Backoff::new(secs(1), secs(30));
+        for step in [1, 2, 4, 8, 16, 30, 30] {
+            let delay = backoff.next_delay();
+            assert!(delay >= secs(step) / 2 && delay <= secs(step), "{delay:?} outside step {step}s");
+        }
+        backoff.reset();
+        assert!(backoff.next_delay() <= secs(1));
+    }
+}
@@ -0,0 +1,111 @@
+//! crates/bus/src/budget.rs
+//!
+//! Per-delegation spending limits, carried in `meta.budget`, and the running
+//! account a worker checks them against while a turn is in progress.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// Limits a delegating agent puts on one task. Unset fields are unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Budget {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_output_tokens: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_duration_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tool_calls: Option<u64>,
+}
+
+/// What a turn has used so far. Sent as the reply's `usage`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TurnUsage {
+    /// Estimated from the text produced, see [`estimate_tokens`]
+    pub output_tokens: u64,
+    pub duration_ms: u64,
+    pub tool_calls: u64,
+}
+
+impl Budget {
+    /// The budget in `meta.budget`, if it sets any limit. A malformed one is ignored.
+    pub fn from_meta(meta: &Value) -> Option<Self> {
+        let budget: Self = serde_json::from_value(meta.get("budget")?.clone()).ok()?;
+        (!budget.is_unlimited()).then_some(budget)
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Store this budget as `meta.budget`, replacing a non-object `meta`.
+    pub fn insert_into(&self, meta: &mut Value) {
+        if !meta.is_object() {
+            *meta = json!({});
+        }
+        meta["budget"] = json!(self);
+    }
+
+    /// Name of the first limit `usage` has gone over, if any.
+    pub fn exceeded(&self, usage: &TurnUsage) -> Option<&'static str> {
+        let over = |limit: Option<u64>, used: u64| limit.is_some_and(|max| used > max);
+        if over(self.max_tool_calls, usage.tool_calls) {
+            Some("max_tool_calls")
+        } else if over(self.max_output_tokens, usage.output_tokens) {
+            Some("max_output_tokens")
+        } else if self.max_duration_ms.is_some_and(|max| usage.duration_ms >= max) {
+            Some("max_duration_ms")
+        } else {
+            None
+        }
+    }
+}
+
+impl TurnUsage {
+    pub fn add_output(&mut self, text: &str) {
+        self.output_tokens += estimate_tokens(text);
+    }
+}
+
+/// Rough token count for `text` (four characters per token), for workers
+/// that see only the text a model produced.
+pub fn estimate_tokens(text: &str) -> u64 {
+    (text.chars().count() as u64).div_ceil(4)
+}
+
+/// `content` of the `error` reply sent when a turn is cut off at `limit`,
+/// carrying the text produced before that.
+pub fn budget_exceeded_content(limit: &str, partial: &str) -> Value {
+    json!({
+        "code": "budget_exceeded",
+        "error": format!("budget exceeded: {limit}"),
+        "limit": limit,
+        "text": partial,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn budget_round_trips_through_meta_and_reports_the_limit_hit() {
+        let budget = Budget { max_tool_calls: Some(2), max_duration_ms: Some(1000), ..Default::default() };
+        let mut meta = json!({ "trace_id": "t" });
+        budget.insert_into(&mut meta);
+        assert_eq!(meta["budget"], json!({ "max_duration_ms": 1000, "max_tool_calls": 2 }));
+        assert_eq!(Budget::from_meta(&meta), Some(budget));
+        assert_eq!(Budget::from_meta(&json!({ "budget": {} })), None);
+        assert_eq!(Budget::from_meta(&json!({ "budget": "lots" })), None);
+
+        let mut usage = TurnUsage { tool_calls: 2, duration_ms: 999, ..Default::default() };
+        assert_eq!(budget.exceeded(&usage), None);
+        usage.tool_calls = 3;
+        assert_eq!(budget.exceeded(&usage), Some("max_tool_calls"));
+        usage.tool_calls = 0;
+        usage.duration_ms = 1000;
+        assert_eq!(budget.exceeded(&usage), Some("max_duration_ms"));
+
+        usage.add_output("twelve chars");
+        assert_eq!(usage.output_tokens, 3);
+    }
+}
@@ -0,0 +1,189 @@
+//! crates/bus/src/capabilities.rs
+//!
+//! Self-description: on startup, and every [`ANNOUNCE_INTERVAL`] after, an
+//! agent publishes an `envelope_type: "capabilities"` envelope to
+//! [`CAPABILITIES_STREAM`] whose content is a [`Capabilities`], so registries
+//! can pick up agents nobody has written into the registry file yet.
+//! [`Bus::discover_capabilities`] reads back those still announcing.
+
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::delivery::entry_time;
+use crate::{Bus, BusError, Envelope, EnvelopeKind};
+
+/// Where agents announce themselves.
+pub const CAPABILITIES_STREAM: &str = "AG1:discovery:capabilities";
+/// Version of the [`Capabilities`] record, under `capabilities` in its `schema_versions`.
+pub const CAPABILITIES_SCHEMA_VERSION: u32 = 1;
+/// Newest entries of the stream [`Bus::discover_capabilities`] looks at,
+/// and about how many [`Bus::announce_capabilities`] keeps.
+const DISCOVERY_SCAN: usize = 1000;
+/// How often a running agent announces itself again.
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+/// Announcements in a row an agent may miss before discovery takes it for gone.
+const MISSED_ANNOUNCEMENTS: u32 = 3;
+
+/// What an agent says about itself in a `capabilities` envelope.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Capabilities {
+    pub name: String,
+    pub inbox: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub capabilities_keywords: Vec<String>,
+    /// `envelope_type`s the agent answers
+    #[serde(default)]
+    pub envelope_types: Vec<String>,
+    /// Version of each schema the agent speaks, e.g. `{"capabilities": 1}`
+    #[serde(default)]
+    pub schema_versions: BTreeMap<String, u32>,
+    /// Largest envelope the agent reads in full, in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_payload_bytes: Option<usize>,
+}
+
+impl Envelope {
+    /// The `capabilities` announcement of `info`, with `"text": "capabilities"`
+    /// alongside it in the content.
+    pub fn capabilities(info: &Capabilities) -> Envelope {
+        let mut content = json!(info);
+        content["text"] = json!("capabilities");
+        Envelope {
+            role: "assistant".into(),
+            content,
+            agent_name: Some(info.name.clone()),
+            envelope_type: Some(EnvelopeKind::Capabilities.into()),
+            timestamp: Some(chrono::Utc::now().to_rfc3339()),
+            envelope_id: Some(uuid::Uuid::new_v4().to_string()),
+            ..Default::default()
+        }
+    }
+
+    /// The [`Capabilities`] in a `capabilities` envelope's content.
+    pub fn capabilities_info(&self) -> Result<Capabilities, BusError> {
+        Ok(serde_json::from_value(self.content.clone())?)
+    }
+}
+
+impl Bus {
+    /// Publish `info` as a `capabilities` envelope on `stream`, normally
+    /// [`CAPABILITIES_STREAM`], trimming the stream to about the entries
+    /// discovery looks at. A failed trim is logged, not returned.
+    pub async fn announce_capabilities(&self, stream: &str, info: &Capabilities) -> Result<String, BusError> {
+        let id = self.send(stream, &Envelope::capabilities(info)).await?;
+        let trimmed: Result<u64, redis::RedisError> = async {
+            let mut conn = self.client.get_async_connection().await?;
+            redis::cmd("XTRIM").arg(stream).arg("MAXLEN").arg("~").arg(DISCOVERY_SCAN).query_async(&mut conn).await
+        }
+        .await;
+        if let Err(e) = trimmed {
+            eprintln!("[BUS_ERROR] ❌ Failed to trim {}: {}", stream, e);
+        }
+        Ok(id)
+    }
+
+    /// The latest announcement of each agent among the newest entries of
+    /// `stream`, sorted by name; names match ignoring case. Agents that have
+    /// missed their last few [`ANNOUNCE_INTERVAL`]s are taken for gone and
+    /// left out. Entries that aren't a readable `capabilities` envelope are skipped.
+    pub async fn discover_capabilities(&self, stream: &str) -> Result<Vec<Capabilities>, BusError> {
+        let live_since = Utc::now() - ANNOUNCE_INTERVAL * MISSED_ANNOUNCEMENTS;
+        Ok(latest_announcements(stream, self.xrevrange(stream, DISCOVERY_SCAN).await?, live_since))
+    }
+}
+
+/// The latest announcement of each agent among `envs`, newest first, made
+/// at or after `live_since`; sorted by name.
+fn latest_announcements(stream: &str, envs: Vec<Envelope>, live_since: DateTime<Utc>) -> Vec<Capabilities> {
+    let mut latest: HashMap<String, Capabilities> = HashMap::new();
+    for env in envs {
+        let announced = env.envelope_id.as_deref().and_then(entry_time);
+        if announced.is_some_and(|at| at < live_since) {
+            // Newest first, so the rest are older still
+            break;
+        }
+        if env.kind() != Some(EnvelopeKind::Capabilities) {
+            continue;
+        }
+        match env.capabilities_info() {
+            // Newest first, so the first one seen is the latest
+            Ok(info) if !info.name.is_empty() => {
+                latest.entry(info.name.to_lowercase()).or_insert(info);
+            }
+            Ok(_) => eprintln!("[BUS_ERROR] ❌ Skipping capabilities without a name on {}", stream),
+            Err(e) => eprintln!("[BUS_ERROR] ❌ Skipping unreadable capabilities on {}: {}", stream, e),
+        }
+    }
+    let mut found: Vec<Capabilities> = latest.into_values().collect();
+    found.sort_by(|a, b| a.name.cmp(&b.name));
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_redis_url;
+
+    fn goose(inbox: &str) -> Capabilities {
+        Capabilities {
+            name: "GooseAgent".into(),
+            inbox: inbox.into(),
+            description: Some("General coding agent".into()),
+            capabilities_keywords: vec!["code".into()],
+            envelope_types: vec!["message".into(), "ping".into()],
+            schema_versions: BTreeMap::from([("capabilities".into(), CAPABILITIES_SCHEMA_VERSION)]),
+            max_payload_bytes: Some(1024),
+        }
+    }
+
+    #[test]
+    fn capabilities_round_trip_through_an_envelope() {
+        let info = goose("AG1:agent:GooseAgent:inbox");
+        let env = Envelope::capabilities(&info);
+        assert_eq!(env.kind(), Some(EnvelopeKind::Capabilities));
+        assert_eq!(env.agent_name.as_deref(), Some("GooseAgent"));
+        assert_eq!(env.text_or_empty(), "capabilities");
+        assert_eq!(env.capabilities_info().unwrap(), info);
+    }
+
+    #[tokio::test]
+    async fn discovery_keeps_the_latest_announcement_per_agent() {
+        let Some(redis_url) = test_redis_url() else { return };
+        let bus = Bus::new(&redis_url).unwrap();
+        let stream = format!("ag1:bus:test:capabilities:{}", uuid::Uuid::new_v4());
+        bus.announce_capabilities(&stream, &goose("AG1:agent:GooseAgent:old")).await.unwrap();
+        bus.send(&stream, &crate::tests::test_env()).await.unwrap();
+        let other = Capabilities { name: "Echo".into(), inbox: "AG1:agent:Echo:inbox".into(), ..Default::default() };
+        bus.announce_capabilities(&stream, &other).await.unwrap();
+        let restarted = Capabilities { name: "gooseagent".into(), ..goose("AG1:agent:GooseAgent:inbox") };
+        bus.announce_capabilities(&stream, &restarted).await.unwrap();
+
+        let found = bus.discover_capabilities(&stream).await.unwrap();
+        assert_eq!(found, [other, restarted]);
+    }
+
+    #[test]
+    fn agents_that_stopped_announcing_are_left_out() {
+        let now = Utc::now();
+        let announced = |info: &Capabilities, ago: Duration| {
+            let mut env = Envelope::capabilities(info);
+            env.envelope_id = Some(format!("{}-0", (now - ago).timestamp_millis()));
+            env
+        };
+        let echo = Capabilities { name: "Echo".into(), inbox: "AG1:agent:Echo:inbox".into(), ..Default::default() };
+        let gone = Capabilities { name: "Gone".into(), inbox: "AG1:agent:Gone:inbox".into(), ..Default::default() };
+        let envs = vec![
+            announced(&echo, Duration::from_secs(30)),
+            announced(&gone, Duration::from_secs(200)),
+            announced(&echo, Duration::from_secs(300)),
+        ];
+        let live_since = now - ANNOUNCE_INTERVAL * MISSED_ANNOUNCEMENTS;
+        assert_eq!(latest_announcements("s", envs, live_since), [echo]);
+    }
+}
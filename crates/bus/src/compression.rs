@@ -0,0 +1,91 @@
+//! crates/bus/src/compression.rs
+//!
+//! Gzip-compressed envelopes, for payloads (e.g. file contents in `meta`)
+//! large enough to strain the Redis memory budget. Enabled by the
+//! `compression` feature.
+
+use std::io::{Read, Write};
+use std::time::Instant;
+
+use base64::Engine;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use tracing::Instrument;
+
+use crate::{hop, Bus, BusError, Envelope};
+
+/// Gzip `json` and base64-encode the result, as stored under the `gzip` field.
+pub(crate) fn gzip_base64(json: &[u8]) -> std::io::Result<String> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(json)?;
+    Ok(base64::engine::general_purpose::STANDARD.encode(encoder.finish()?))
+}
+
+/// Undo [`gzip_base64`], giving back the envelope JSON.
+pub(crate) fn gunzip_base64(encoded: &[u8]) -> std::io::Result<String> {
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(encoded)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    let mut json = String::new();
+    GzDecoder::new(bytes.as_slice()).read_to_string(&mut json)?;
+    Ok(json)
+}
+
+impl Bus {
+    /// [`Bus::send`], but XADD the envelope JSON gzipped and base64-encoded
+    /// under the `gzip` field instead of `data`. Readers need the
+    /// `compression` feature too.
+    pub async fn send_compressed(&self, stream: &str, env: &Envelope) -> Result<String, BusError> {
+        let span = tracing::info_span!(
+            "bus.send",
+            stream,
+            correlation_id = env.correlation_id.as_deref(),
+            target = env.target.as_deref(),
+        );
+        let mut env = env.clone();
+        env.trace.push(hop("send", stream));
+
+        let started = Instant::now();
+        let res = async {
+            let encoded = gzip_base64(&serde_json::to_vec(&env)?)?;
+            let mut conn = self.client.get_async_connection().await?;
+            let id: String = redis::cmd("XADD")
+                .arg(stream)
+                .arg("*")
+                .arg("gzip")
+                .arg(encoded)
+                .query_async(&mut conn)
+                .await?;
+            Ok(id)
+        }
+        .instrument(span)
+        .await;
+        self.counters.record_send(stream, started.elapsed(), &res);
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::gzip_base64;
+    use crate::entry_env;
+    use crate::tests::test_env;
+    use crate::Envelope;
+
+    #[test]
+    fn gzip_entries_decode_like_json_ones() {
+        let mut env = test_env();
+        let file: String = (0..50 * 1024).map(|i| (b'a' + (i % 26) as u8) as char).collect();
+        env.meta = serde_json::json!({ "file": file });
+        let json = serde_json::to_vec(&env).unwrap();
+        let encoded = gzip_base64(&json).unwrap();
+        assert!(encoded.len() < json.len() / 10, "{} bytes compressed to {}", json.len(), encoded.len());
+
+        use redis::Value::*;
+        let entry = Bulk(vec![Data(b"1-0".to_vec()), Bulk(vec![Data(b"gzip".to_vec()), Data(encoded.into_bytes())])]);
+        let (id, read) = entry_env(&entry).unwrap();
+        assert_eq!(id, "1-0");
+        let read: Envelope = serde_json::from_str(&read).unwrap();
+        assert_eq!(read.meta, env.meta);
+        assert_eq!(read.correlation_id.as_deref(), Some("test-cid"));
+    }
+}
@@ -0,0 +1,56 @@
+//! crates/bus/src/deadline.rs
+//!
+//! Reply deadlines. A delegating agent stamps its request with the time it
+//! stops waiting for the reply, so the agent answering it, and whoever that
+//! agent delegates to in turn, can give up rather than work on an answer
+//! nobody will read.
+
+use std::time::Duration;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+
+use crate::Envelope;
+
+/// Header holding the RFC 3339 time the sender stops waiting for a reply.
+pub const DEADLINE_HEADER: &str = "x-deadline";
+
+impl Envelope {
+    /// When the sender stops waiting for a reply, from the [`DEADLINE_HEADER`]
+    /// header. `None` without one or when it isn't an RFC 3339 time.
+    pub fn deadline(&self) -> Option<DateTime<Utc>> {
+        let deadline = self.headers.get(DEADLINE_HEADER)?;
+        DateTime::parse_from_rfc3339(deadline).ok().map(|d| d.with_timezone(&Utc))
+    }
+
+    pub fn set_deadline(&mut self, deadline: DateTime<Utc>) {
+        self.headers.insert(DEADLINE_HEADER.into(), deadline.to_rfc3339_opts(SecondsFormat::Millis, true));
+    }
+
+    /// Time left until [`Envelope::deadline`]: `None` without a deadline,
+    /// zero once it has passed.
+    pub fn time_left(&self) -> Option<Duration> {
+        Some((self.deadline()? - Utc::now()).to_std().unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_env;
+
+    #[test]
+    fn deadlines_round_trip_through_the_header() {
+        let mut env = test_env();
+        assert_eq!((env.deadline(), env.time_left()), (None, None));
+
+        let deadline = Utc::now() + chrono::Duration::seconds(30);
+        env.set_deadline(deadline);
+        assert_eq!(env.deadline().unwrap().timestamp_millis(), deadline.timestamp_millis());
+        assert!(env.time_left().unwrap() > Duration::from_secs(29));
+
+        env.set_deadline(Utc::now() - chrono::Duration::seconds(1));
+        assert_eq!(env.time_left(), Some(Duration::ZERO));
+        env.headers.insert(DEADLINE_HEADER.into(), "soon".into());
+        assert_eq!(env.deadline(), None);
+    }
+}
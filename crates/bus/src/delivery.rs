@@ -0,0 +1,193 @@
+//! crates/bus/src/delivery.rs
+//!
+//! [`Delivery`]: an envelope as a consumer group handed it out, with when it
+//! was queued, when it was read and whether it had been handed out before.
+//! The group receive APIs, [`Bus::subscribe`] and [`Bus::reclaim_loop`] all
+//! give these, so consumers can measure queue latency and spot redeliveries
+//! without looking at fields the bus writes into the envelope.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+
+use chrono::{DateTime, TimeZone, Utc};
+
+use crate::{Bus, BusError, Envelope};
+
+/// One envelope read through a consumer group.
+pub struct Delivery {
+    pub envelope: Envelope,
+    /// Stream the envelope was read from
+    pub stream: String,
+    /// Stream entry id the envelope was read from
+    pub entry_id: String,
+    /// When the entry was added to the stream, from its id's millisecond part
+    pub enqueued_at: DateTime<Utc>,
+    pub received_at: DateTime<Utc>,
+    /// Handed out before, to this consumer or another, and never acked
+    pub redelivered: bool,
+    /// Times the group has handed the entry out, this one included
+    pub delivery_count: u32,
+    bus: Bus,
+    group: String,
+    acked: bool,
+}
+
+impl fmt::Debug for Delivery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Delivery")
+            .field("envelope", &self.envelope)
+            .field("stream", &self.stream)
+            .field("entry_id", &self.entry_id)
+            .field("enqueued_at", &self.enqueued_at)
+            .field("received_at", &self.received_at)
+            .field("redelivered", &self.redelivered)
+            .field("delivery_count", &self.delivery_count)
+            .field("group", &self.group)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Delivery {
+    /// `envelope`, read from entry `entry_id` on `stream` by `consumer` in
+    /// `group` and now handed out for the `delivery_count`th time. `acked`
+    /// when the entry was acked as it was read.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        bus: &Bus,
+        stream: &str,
+        group: &str,
+        consumer: &str,
+        entry_id: String,
+        mut envelope: Envelope,
+        delivery_count: u32,
+        acked: bool,
+    ) -> Self {
+        let received_at = Utc::now();
+        envelope.envelope_id = Some(entry_id.clone());
+        stamp_envelope(&mut envelope, group, consumer, delivery_count);
+        Self {
+            envelope,
+            stream: stream.to_string(),
+            enqueued_at: entry_time(&entry_id).unwrap_or(received_at),
+            entry_id,
+            received_at,
+            redelivered: delivery_count > 1,
+            delivery_count,
+            bus: bus.clone(),
+            group: group.to_string(),
+            acked,
+        }
+    }
+
+    /// How long the entry sat in the stream before this read. Zero if the
+    /// clock of the Redis server ran ahead of ours.
+    pub fn queue_latency(&self) -> Duration {
+        (self.received_at - self.enqueued_at).to_std().unwrap_or_default()
+    }
+
+    /// XACK the entry. A no-op for entries acked as they were read, such as
+    /// those of [`AckMode::Auto`](crate::AckMode::Auto) subscriptions.
+    pub async fn ack(&self) -> Result<(), BusError> {
+        if self.acked {
+            return Ok(());
+        }
+        self.bus.ack_message(&self.stream, &self.group, &self.entry_id).await
+    }
+
+    /// Add the envelope back to the stream as a new entry, then ack this
+    /// one, so any consumer of the group can take it up; for entries left
+    /// unacked by a consumer that is going away. Gives the new entry's id.
+    pub async fn requeue(&self) -> Result<String, BusError> {
+        let mut envelope = self.envelope.clone();
+        envelope.envelope_id = None;
+        envelope.consumer_group = None;
+        envelope.consumer_id = None;
+        envelope.delivery_count = None;
+        let id = self.bus.send(&self.stream, &envelope).await?;
+        self.ack().await?;
+        Ok(id)
+    }
+}
+
+/// When entry `id` was added: the millisecond part of a `<ms>-<seq>` stream id.
+pub(crate) fn entry_time(id: &str) -> Option<DateTime<Utc>> {
+    let (ms, seq) = id.split_once('-').unwrap_or((id, "0"));
+    seq.parse::<u64>().ok()?;
+    Utc.timestamp_millis_opt(ms.parse().ok()?).single()
+}
+
+/// How often `group` has handed out each of `ids`, from its pending list
+/// (XPENDING, pipelined). Entries no longer pending count as delivered once.
+pub(crate) async fn delivery_counts(
+    conn: &mut redis::aio::Connection,
+    stream: &str,
+    group: &str,
+    ids: &[String],
+) -> Result<HashMap<String, u32>, BusError> {
+    if ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let mut pipe = redis::pipe();
+    for id in ids {
+        pipe.cmd("XPENDING").arg(stream).arg(group).arg(id).arg(id).arg(1);
+    }
+    let replies: Vec<Vec<(String, String, u64, u64)>> = pipe.query_async(conn).await?;
+    Ok(replies
+        .into_iter()
+        .flatten()
+        .map(|(id, _, _, count)| (id, u32::try_from(count).unwrap_or(u32::MAX)))
+        .collect())
+}
+
+/// With the `envelope-delivery-fields` feature, also record the group,
+/// consumer and delivery count on the envelope, as reads did before
+/// [`Delivery`] carried them. On by default for one more release.
+fn stamp_envelope(env: &mut Envelope, group: &str, consumer: &str, delivery_count: u32) {
+    if cfg!(feature = "envelope-delivery-fields") {
+        env.consumer_group = Some(group.to_string());
+        env.consumer_id = Some(consumer.to_string());
+        env.delivery_count = Some(delivery_count);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{test_env, OFFLINE_REDIS_URL};
+
+    #[test]
+    fn entry_ids_give_the_time_they_were_added() {
+        let at = entry_time("1700000000123-4").unwrap();
+        assert_eq!(at.timestamp_millis(), 1_700_000_000_123);
+        assert_eq!(entry_time("1700000000123"), Some(at));
+        assert_eq!(entry_time("0-0").unwrap().timestamp_millis(), 0);
+        for bad in ["", "-1", "abc-0", "1-x", "$", ">"] {
+            assert_eq!(entry_time(bad), None, "{bad}");
+        }
+    }
+
+    #[test]
+    fn deliveries_carry_their_timing_and_the_envelope_fields_only_for_compat() {
+        let bus = Bus::new(OFFLINE_REDIS_URL).unwrap();
+        let delivery = Delivery::new(&bus, "s", "workers", "w1", "1000-0".into(), test_env(), 2, false);
+        assert_eq!(delivery.envelope.envelope_id.as_deref(), Some("1000-0"));
+        assert_eq!(delivery.enqueued_at.timestamp_millis(), 1000);
+        assert!(delivery.queue_latency() > Duration::from_secs(3600));
+        assert!(delivery.redelivered);
+
+        let env = &delivery.envelope;
+        let stamped = (env.consumer_group.as_deref(), env.consumer_id.as_deref(), env.delivery_count);
+        if cfg!(feature = "envelope-delivery-fields") {
+            assert_eq!(stamped, (Some("workers"), Some("w1"), Some(2)));
+        } else {
+            assert_eq!(stamped, (None, None, None));
+        }
+
+        // An id from a clock ahead of ours is no latency rather than a negative one
+        let future = format!("{}-0", Utc::now().timestamp_millis() + 60_000);
+        let delivery = Delivery::new(&bus, "s", "workers", "w1", future, test_env(), 1, true);
+        assert_eq!(delivery.queue_latency(), Duration::ZERO);
+        assert!(!delivery.redelivered);
+    }
+}
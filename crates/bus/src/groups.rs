@@ -0,0 +1,187 @@
+//! crates/bus/src/groups.rs
+//!
+//! Consumer group upkeep: creating a group on a consumer's first read, and
+//! for operators, moving a group's cursor and removing groups and consumers
+//! left behind by a bad deployment.
+
+use crate::{Bus, BusError, Delivery, StartPos};
+
+/// Pending entries looked up and claimed per round trip by [`Bus::delete_consumer`].
+const CLAIM_BATCH: usize = 100;
+
+impl Bus {
+    /// [`Bus::recv_block_group`], creating the group as
+    /// [`Bus::create_consumer_group`] does on the first call for `stream` and
+    /// `group` on this bus or its clones, so a consumer's read loop makes one
+    /// round trip per read. A read that finds the group gone (NOGROUP) fails,
+    /// and the next call creates it again.
+    pub async fn ensure_group_and_recv(
+        &self,
+        stream: &str,
+        group: &str,
+        consumer: &str,
+        block_ms: u64,
+    ) -> Result<Option<Delivery>, BusError> {
+        let key = (stream.to_string(), group.to_string());
+        if !self.known_groups.lock().unwrap().contains(&key) {
+            self.create_consumer_group(stream, group).await?;
+            self.known_groups.lock().unwrap().insert(key.clone());
+        }
+        let res = self.recv_block_group(stream, group, consumer, block_ms).await;
+        if matches!(&res, Err(BusError::Redis(e)) if e.code() == Some("NOGROUP")) {
+            self.known_groups.lock().unwrap().remove(&key);
+        }
+        res
+    }
+
+    /// Remove `group` from `stream` (XGROUP DESTROY), pending list and all.
+    /// Gives whether there was such a group.
+    pub async fn delete_consumer_group(&self, stream: &str, group: &str) -> Result<bool, BusError> {
+        let mut conn = self.client.get_async_connection().await?;
+        let destroyed: u64 = redis::cmd("XGROUP").arg("DESTROY").arg(stream).arg(group).query_async(&mut conn).await?;
+        self.known_groups.lock().unwrap().remove(&(stream.to_string(), group.to_string()));
+        Ok(destroyed > 0)
+    }
+
+    /// Move `group`'s cursor to `id` (XGROUP SETID): the group is next handed
+    /// the entries after it. Pending entries are left as they are. Errors if
+    /// the stream or group doesn't exist.
+    pub async fn set_group_id(&self, stream: &str, group: &str, id: &StartPos) -> Result<(), BusError> {
+        let mut conn = self.client.get_async_connection().await?;
+        redis::cmd("XGROUP")
+            .arg("SETID")
+            .arg(stream)
+            .arg(group)
+            .arg(id.as_id())
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Remove `consumer` from `group` (XGROUP DELCONSUMER). Its pending
+    /// entries are first claimed for `transfer_to` when given, so they can be
+    /// handled again; otherwise they are dropped unacked. Gives the number of
+    /// pending entries dropped with the consumer.
+    pub async fn delete_consumer(
+        &self,
+        stream: &str,
+        group: &str,
+        consumer: &str,
+        transfer_to: Option<&str>,
+    ) -> Result<u64, BusError> {
+        let mut conn = self.client.get_async_connection().await?;
+        if let Some(heir) = transfer_to {
+            loop {
+                let pending: Vec<(String, String, u64, u64)> = redis::cmd("XPENDING")
+                    .arg(stream)
+                    .arg(group)
+                    .arg("-")
+                    .arg("+")
+                    .arg(CLAIM_BATCH)
+                    .arg(consumer)
+                    .query_async(&mut conn)
+                    .await?;
+                if pending.is_empty() {
+                    break;
+                }
+                // Claiming takes them off the consumer's pending list, so each round gets the next batch
+                let mut claim = redis::cmd("XCLAIM");
+                claim.arg(stream).arg(group).arg(heir).arg(0);
+                for (id, ..) in &pending {
+                    claim.arg(id);
+                }
+                claim.arg("JUSTID").query_async::<_, Vec<String>>(&mut conn).await?;
+            }
+        }
+        let dropped: u64 = redis::cmd("XGROUP")
+            .arg("DELCONSUMER")
+            .arg(stream)
+            .arg(group)
+            .arg(consumer)
+            .query_async(&mut conn)
+            .await?;
+        Ok(dropped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{test_env, test_redis_url};
+
+    /// A stream with three envelopes and a `workers` group created at `start`.
+    async fn stream_with_group(bus: &Bus, start: StartPos) -> (String, Vec<String>) {
+        let stream = format!("ag1:bus:test:groups:{}", uuid::Uuid::new_v4());
+        let mut ids = Vec::new();
+        for _ in 0..3 {
+            ids.push(bus.send(&stream, &test_env()).await.unwrap());
+        }
+        bus.create_consumer_group_at(&stream, "workers", &start).await.unwrap();
+        (stream, ids)
+    }
+
+    #[tokio::test]
+    async fn groups_start_where_asked_and_can_be_moved() {
+        let Some(redis_url) = test_redis_url() else { return };
+        let bus = Bus::new(&redis_url).unwrap();
+        let (stream, ids) = stream_with_group(&bus, StartPos::Latest).await;
+        // Created at the end, the group sees none of the history
+        assert!(bus.recv_block_group(&stream, "workers", "w1", 100).await.unwrap().is_none());
+
+        bus.set_group_id(&stream, "workers", &StartPos::Id(ids[0].clone())).await.unwrap();
+        let next = bus.recv_block_group(&stream, "workers", "w1", 100).await.unwrap().unwrap();
+        assert_eq!(next.entry_id, ids[1]);
+
+        bus.set_group_id(&stream, "workers", &StartPos::Earliest).await.unwrap();
+        let first = bus.recv_block_group(&stream, "workers", "w1", 100).await.unwrap().unwrap();
+        assert_eq!(first.entry_id, ids[0]);
+        assert!(bus.set_group_id(&stream, "nobody", &StartPos::Latest).await.is_err());
+
+        assert!(bus.delete_consumer_group(&stream, "workers").await.unwrap());
+        assert!(!bus.delete_consumer_group(&stream, "workers").await.unwrap());
+        assert!(bus.stream_info(&stream).await.unwrap().groups.is_empty());
+    }
+
+    #[tokio::test]
+    async fn groups_are_created_on_the_first_read_only() {
+        let Some(redis_url) = test_redis_url() else { return };
+        let bus = Bus::new(&redis_url).unwrap();
+        let stream = format!("ag1:bus:test:groups:{}", uuid::Uuid::new_v4());
+        let sent = bus.send(&stream, &test_env()).await.unwrap();
+
+        let first = bus.ensure_group_and_recv(&stream, "workers", "w1", 100).await.unwrap().unwrap();
+        assert_eq!(first.entry_id, sent);
+        assert!(bus.known_groups.lock().unwrap().contains(&(stream.clone(), "workers".to_string())));
+        assert!(bus.clone().ensure_group_and_recv(&stream, "workers", "w1", 100).await.unwrap().is_none());
+
+        // Dropped behind the bus's back: the read fails, and the next one recreates it
+        Bus::new(&redis_url).unwrap().delete_consumer_group(&stream, "workers").await.unwrap();
+        assert!(bus.ensure_group_and_recv(&stream, "workers", "w1", 100).await.is_err());
+        let again = bus.ensure_group_and_recv(&stream, "workers", "w1", 100).await.unwrap().unwrap();
+        assert_eq!(again.entry_id, sent);
+    }
+
+    #[tokio::test]
+    async fn deleted_consumers_hand_their_pending_entries_on_or_drop_them() {
+        let Some(redis_url) = test_redis_url() else { return };
+        let bus = Bus::new(&redis_url).unwrap();
+        let (stream, ids) = stream_with_group(&bus, StartPos::Earliest).await;
+        for _ in 0..2 {
+            bus.recv_block_group(&stream, "workers", "dead", 100).await.unwrap().unwrap();
+        }
+        bus.recv_block_group(&stream, "workers", "doomed", 100).await.unwrap().unwrap();
+
+        // Transferred: the heir now holds what the dead consumer never acked
+        assert_eq!(bus.delete_consumer(&stream, "workers", "dead", Some("heir")).await.unwrap(), 0);
+        let consumers = bus.consumer_list(&stream, "workers").await.unwrap();
+        let pending: Vec<_> = consumers.iter().map(|c| (c.name.as_str(), c.pending)).collect();
+        assert_eq!(pending, [("doomed", 1), ("heir", 2)]);
+        let claimed = bus.recv_pending(&stream, "workers", "heir", "0").await.unwrap().unwrap();
+        assert_eq!(claimed.entry_id, ids[0]);
+
+        // Not transferred: its entry leaves the pending list unacked
+        assert_eq!(bus.delete_consumer(&stream, "workers", "doomed", None).await.unwrap(), 1);
+        assert_eq!(bus.pending_messages(&stream, "workers").await.unwrap(), 2);
+        assert_eq!(bus.consumer_info(&stream, "workers", "doomed").await.unwrap(), None);
+    }
+}
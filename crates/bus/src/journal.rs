@@ -0,0 +1,366 @@
+//! crates/bus/src/journal.rs
+//!
+//! Append-only record of every envelope an agent answered, kept on disk so it
+//! outlives Redis retention: who asked, when, how it went and how long it took.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{Envelope, EnvelopeKind, RedactionPolicy};
+
+/// Bumped whenever a [`JournalRecord`] field changes meaning or goes away.
+pub const JOURNAL_SCHEMA_VERSION: u32 = 1;
+
+/// Size a journal file may reach before the day's records go on in a new one.
+pub const DEFAULT_MAX_JOURNAL_FILE_BYTES: u64 = 64 * 1024 * 1024;
+
+/// How answering an envelope went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Ok,
+    Error,
+    Timeout,
+    /// Refused unanswered: by middleware (e.g. a rate limit), for its type or its size
+    Rejected,
+}
+
+impl Outcome {
+    /// The outcome `reply` reports. Error replies are a [`Outcome::Timeout`]
+    /// when their `code` is `timeout` or `deadline_exceeded`, and
+    /// [`Outcome::Rejected`] when marked `rejected` or too large to read;
+    /// what their text says doesn't count.
+    pub fn of_reply(reply: &Envelope) -> Self {
+        if reply.kind() != Some(EnvelopeKind::Error) {
+            return Outcome::Ok;
+        }
+        let content = &reply.content;
+        match content.get("code").and_then(Value::as_str) {
+            Some("timeout" | "deadline_exceeded") => Outcome::Timeout,
+            Some("payload_too_large") => Outcome::Rejected,
+            _ if content.get("rejected").and_then(Value::as_bool) == Some(true) => Outcome::Rejected,
+            _ => Outcome::Error,
+        }
+    }
+}
+
+/// One answered envelope. `request` and `reply` are the envelopes' `content`
+/// after the global [`RedactionPolicy`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JournalRecord {
+    pub schema_version: u32,
+    /// When the reply went out
+    pub at: DateTime<Utc>,
+    pub correlation_id: String,
+    pub user_id: Option<String>,
+    /// The sender's `agent_name`
+    pub agent_name: Option<String>,
+    pub session_code: Option<String>,
+    /// The request's `envelope_type`; `None` when only the reply is known
+    pub kind: Option<String>,
+    pub outcome: Outcome,
+    /// From receiving the request to sending the reply
+    pub latency_ms: u64,
+    pub tools_used: Vec<String>,
+    pub request: Value,
+    pub reply: Value,
+}
+
+impl JournalRecord {
+    /// The record of answering `request` with `reply` after `latency`. Without
+    /// the request (an envelope refused before the agent saw it), who asked is
+    /// taken from the reply's `target`.
+    pub fn new(request: Option<&Envelope>, reply: &Envelope, latency: Duration) -> Self {
+        let policy = RedactionPolicy::global();
+        let content = |env: &Envelope| env.redacted(policy).get("content").cloned().unwrap_or(Value::Null);
+        JournalRecord {
+            schema_version: JOURNAL_SCHEMA_VERSION,
+            at: Utc::now(),
+            correlation_id: reply.correlation_id.clone().unwrap_or_default(),
+            user_id: request.and_then(|r| r.user_id.clone()).or_else(|| reply.user_id.clone()),
+            agent_name: request.map_or_else(|| reply.target.clone(), |r| r.agent_name.clone()),
+            session_code: reply.session_code.clone().or_else(|| request.and_then(|r| r.session_code.clone())),
+            kind: request.map(|r| r.envelope_type.clone().unwrap_or_else(|| EnvelopeKind::Message.into())),
+            outcome: match request {
+                Some(_) => Outcome::of_reply(reply),
+                None => Outcome::Rejected,
+            },
+            latency_ms: latency.as_millis() as u64,
+            tools_used: reply.tools_used.clone(),
+            request: request.map_or(Value::Null, content),
+            reply: content(reply),
+        }
+    }
+}
+
+/// Where [`JournalRecord`]s are kept.
+pub trait Journal: Send + Sync {
+    /// Append `record`. Appends are small and done before returning, but may
+    /// block on disk: async callers run them with `spawn_blocking`.
+    fn record(&self, record: &JournalRecord) -> io::Result<()>;
+}
+
+/// A [`Journal`] of JSONL files in one directory, one compact record per
+/// line. Each UTC day gets `YYYY-MM-DD.jsonl`; once a file would go past
+/// its size limit the day goes on in `YYYY-MM-DD.1.jsonl`, `.2`, and so on.
+/// Files are never rewritten or removed.
+pub struct JsonlJournal {
+    dir: PathBuf,
+    max_file_bytes: u64,
+    /// Held while picking a file and appending, so lines never interleave
+    write: Mutex<()>,
+}
+
+impl JsonlJournal {
+    /// Journal into `dir`, creating it if needed.
+    pub fn new(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir, max_file_bytes: DEFAULT_MAX_JOURNAL_FILE_BYTES, write: Mutex::new(()) })
+    }
+
+    pub fn with_max_file_bytes(mut self, bytes: u64) -> Self {
+        self.max_file_bytes = bytes;
+        self
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The file a line of `len` bytes for `day` goes in: the day's first that
+    /// is empty or has room for it.
+    fn file_for(&self, day: NaiveDate, len: u64) -> PathBuf {
+        (0..)
+            .map(|n| self.dir.join(journal_file_name(day, n)))
+            .find(|path| match fs::metadata(path) {
+                Ok(meta) => meta.len() == 0 || meta.len() + len <= self.max_file_bytes,
+                Err(_) => true,
+            })
+            .expect("some journal file has room")
+    }
+}
+
+impl Journal for JsonlJournal {
+    fn record(&self, record: &JournalRecord) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        let _guard = self.write.lock().unwrap_or_else(|e| e.into_inner());
+        let path = self.file_for(record.at.date_naive(), line.len() as u64);
+        OpenOptions::new().create(true).append(true).open(path)?.write_all(&line)
+    }
+}
+
+/// `YYYY-MM-DD.jsonl` for the day's first file, `YYYY-MM-DD.<n>.jsonl` after.
+fn journal_file_name(day: NaiveDate, n: u32) -> String {
+    match n {
+        0 => format!("{day}.jsonl"),
+        n => format!("{day}.{n}.jsonl"),
+    }
+}
+
+/// The day and sequence number of a file [`JsonlJournal`] wrote.
+fn parse_journal_file_name(name: &str) -> Option<(NaiveDate, u32)> {
+    let stem = name.strip_suffix(".jsonl")?;
+    let (day, n) = match stem.split_once('.') {
+        Some((day, n)) => (day, n.parse().ok()?),
+        None => (stem, 0),
+    };
+    Some((day.parse().ok()?, n))
+}
+
+/// Which [`JournalRecord`]s [`query_journal`] returns; unset filters match everything.
+#[derive(Debug, Clone, Default)]
+pub struct JournalQuery {
+    /// Records at or after this time
+    pub since: Option<DateTime<Utc>>,
+    /// Records before this time
+    pub until: Option<DateTime<Utc>>,
+    pub correlation_id: Option<String>,
+    /// Matches the record's `user_id` or `agent_name`
+    pub user: Option<String>,
+}
+
+impl JournalQuery {
+    pub fn matches(&self, record: &JournalRecord) -> bool {
+        self.since.is_none_or(|since| record.at >= since)
+            && self.until.is_none_or(|until| record.at < until)
+            && self.correlation_id.as_ref().is_none_or(|cid| &record.correlation_id == cid)
+            && self.user.as_ref().is_none_or(|user| {
+                record.user_id.as_ref() == Some(user) || record.agent_name.as_ref() == Some(user)
+            })
+    }
+
+    /// Whether a file of `day`'s records can hold any match.
+    fn covers(&self, day: NaiveDate) -> bool {
+        self.since.is_none_or(|since| day >= since.date_naive())
+            && self.until.is_none_or(|until| day <= until.date_naive())
+    }
+}
+
+/// Every record in the journal directory `dir` that `query` matches, in the
+/// order written. Lines that aren't records are skipped.
+pub fn query_journal(dir: &Path, query: &JournalQuery) -> io::Result<Vec<JournalRecord>> {
+    let mut files: Vec<((NaiveDate, u32), PathBuf)> = fs::read_dir(dir)?
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let key = parse_journal_file_name(path.file_name()?.to_str()?)?;
+            query.covers(key.0).then_some((key, path))
+        })
+        .collect();
+    files.sort();
+
+    let mut records = Vec::new();
+    for (_, path) in files {
+        for line in BufReader::new(File::open(&path)?).lines() {
+            match serde_json::from_str::<JournalRecord>(&line?) {
+                Ok(record) if query.matches(&record) => records.push(record),
+                Ok(_) => {}
+                Err(e) => eprintln!("[BUS_DEBUG] ⚠️ Skipping unreadable line in {}: {}", path.display(), e),
+            }
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn request(user: &str) -> Envelope {
+        Envelope {
+            role: "user".into(),
+            content: json!({ "text": "hello" }),
+            agent_name: Some("Planner".into()),
+            user_id: Some(user.into()),
+            envelope_type: Some("message".into()),
+            correlation_id: Some(format!("cid-{user}")),
+            ..Default::default()
+        }
+    }
+
+    fn reply(kind: EnvelopeKind, content: Value) -> Envelope {
+        let mut reply = Envelope { role: "assistant".into(), content, correlation_id: Some("cid-u1".into()), ..Default::default() };
+        reply.set_kind(kind);
+        reply
+    }
+
+    fn record_at(at: &str, cid: &str, user: &str) -> JournalRecord {
+        let mut record = JournalRecord::new(Some(&request(user)), &reply(EnvelopeKind::MessageReply, json!({ "text": "hi" })), Duration::ZERO);
+        record.at = at.parse().unwrap();
+        record.correlation_id = cid.into();
+        record
+    }
+
+    #[test]
+    fn outcomes_come_from_the_reply() {
+        let req = request("u1");
+        let outcome = |kind, content| JournalRecord::new(Some(&req), &reply(kind, content), Duration::from_millis(40)).outcome;
+        assert_eq!(outcome(EnvelopeKind::MessageReply, json!({ "text": "hi" })), Outcome::Ok);
+        assert_eq!(outcome(EnvelopeKind::ControlReply, json!({ "sessions": [] })), Outcome::Ok);
+        assert_eq!(outcome(EnvelopeKind::Error, json!({ "text": "goose crashed" })), Outcome::Error);
+        assert_eq!(
+            outcome(EnvelopeKind::Error, json!({ "text": "Timeout waiting for assistant response", "code": "timeout" })),
+            Outcome::Timeout
+        );
+        assert_eq!(outcome(EnvelopeKind::Error, json!({ "text": "too late", "code": "deadline_exceeded" })), Outcome::Timeout);
+        // Text alone doesn't make a timeout
+        assert_eq!(outcome(EnvelopeKind::Error, json!({ "text": "tool failed: timeout=30 is invalid" })), Outcome::Error);
+        assert_eq!(
+            outcome(EnvelopeKind::Error, json!({ "text": "rejected: rate limited", "rejected": true })),
+            Outcome::Rejected
+        );
+        assert_eq!(outcome(EnvelopeKind::Error, crate::payload_too_large_content(10, 5)), Outcome::Rejected);
+
+        let record = JournalRecord::new(Some(&req), &reply(EnvelopeKind::MessageReply, json!({ "text": "hi" })), Duration::from_millis(40));
+        assert_eq!(record.schema_version, JOURNAL_SCHEMA_VERSION);
+        assert_eq!(record.user_id.as_deref(), Some("u1"));
+        assert_eq!(record.agent_name.as_deref(), Some("Planner"));
+        assert_eq!(record.kind.as_deref(), Some("message"));
+        assert_eq!(record.latency_ms, 40);
+        assert_eq!(record.request["text"], "hello");
+
+        // Refused before the agent saw it: only the reply is known
+        let mut refused = reply(EnvelopeKind::Error, json!({ "text": "unsupported envelope_type" }));
+        refused.target = Some("Planner".into());
+        let record = JournalRecord::new(None, &refused, Duration::ZERO);
+        assert_eq!(record.outcome, Outcome::Rejected);
+        assert_eq!(record.agent_name.as_deref(), Some("Planner"));
+        assert!(record.request.is_null());
+    }
+
+    #[test]
+    fn records_keep_only_redacted_content() {
+        let mut req = request("u1");
+        req.content = json!({ "text": "x".repeat(5000) });
+        let record = JournalRecord::new(Some(&req), &reply(EnvelopeKind::MessageReply, json!({ "text": "ok" })), Duration::ZERO);
+        let text = record.request["text"].as_str().unwrap();
+        assert!(text.len() < 5000 && text.contains("chars truncated"), "{text}");
+    }
+
+    #[test]
+    fn files_rotate_by_day_and_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let line_len = serde_json::to_vec(&record_at("2024-05-01T10:00:00Z", "a", "u1")).unwrap().len() as u64 + 1;
+        let journal = JsonlJournal::new(dir.path()).unwrap().with_max_file_bytes(line_len * 2);
+
+        for (at, cid) in [
+            ("2024-05-01T10:00:00Z", "a"),
+            ("2024-05-01T11:00:00Z", "b"),
+            ("2024-05-01T12:00:00Z", "c"),
+            ("2024-05-02T00:00:00Z", "d"),
+        ] {
+            journal.record(&record_at(at, cid, "u1")).unwrap();
+        }
+
+        let mut names: Vec<String> =
+            fs::read_dir(dir.path()).unwrap().map(|e| e.unwrap().file_name().into_string().unwrap()).collect();
+        names.sort();
+        assert_eq!(names, ["2024-05-01.1.jsonl", "2024-05-01.jsonl", "2024-05-02.jsonl"]);
+        let lines = |name: &str| fs::read_to_string(dir.path().join(name)).unwrap().lines().count();
+        assert_eq!((lines("2024-05-01.jsonl"), lines("2024-05-01.1.jsonl"), lines("2024-05-02.jsonl")), (2, 1, 1));
+
+        let all = query_journal(dir.path(), &JournalQuery::default()).unwrap();
+        let cids: Vec<&str> = all.iter().map(|r| r.correlation_id.as_str()).collect();
+        assert_eq!(cids, ["a", "b", "c", "d"]);
+    }
+
+    #[test]
+    fn queries_filter_by_time_correlation_and_user() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = JsonlJournal::new(dir.path()).unwrap();
+        for (at, cid, user) in [
+            ("2024-04-30T23:59:00Z", "a", "u1"),
+            ("2024-05-01T09:00:00Z", "b", "u2"),
+            ("2024-05-01T18:00:00Z", "c", "u1"),
+            ("2024-05-03T08:00:00Z", "d", "u1"),
+        ] {
+            journal.record(&record_at(at, cid, user)).unwrap();
+        }
+        fs::write(dir.path().join("notes.txt"), "not a journal").unwrap();
+        let mut f = OpenOptions::new().append(true).open(dir.path().join("2024-05-01.jsonl")).unwrap();
+        writeln!(f, "not json").unwrap();
+
+        let cids = |query: JournalQuery| -> Vec<String> {
+            query_journal(dir.path(), &query).unwrap().into_iter().map(|r| r.correlation_id).collect()
+        };
+        let at = |s: &str| Some(s.parse::<DateTime<Utc>>().unwrap());
+        assert_eq!(cids(JournalQuery { since: at("2024-05-01T00:00:00Z"), ..Default::default() }), ["b", "c", "d"]);
+        assert_eq!(
+            cids(JournalQuery { since: at("2024-05-01T00:00:00Z"), until: at("2024-05-01T18:00:00Z"), ..Default::default() }),
+            ["b"]
+        );
+        assert_eq!(cids(JournalQuery { correlation_id: Some("c".into()), ..Default::default() }), ["c"]);
+        assert_eq!(cids(JournalQuery { user: Some("u1".into()), ..Default::default() }), ["a", "c", "d"]);
+        assert_eq!(cids(JournalQuery { user: Some("Planner".into()), ..Default::default() }).len(), 4);
+        assert_eq!(cids(JournalQuery { user: Some("u3".into()), ..Default::default() }), Vec::<String>::new());
+    }
+}
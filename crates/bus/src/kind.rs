@@ -0,0 +1,156 @@
+//! crates/bus/src/kind.rs
+//!
+//! [`EnvelopeKind`]: the `envelope_type` values agents exchange, so matching
+//! code compares variants rather than string literals that typo silently.
+//! The field itself stays a string on the wire and in [`Envelope`].
+
+use std::fmt;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::Envelope;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum EnvelopeKind {
+    Message,
+    MessageReply,
+    Task,
+    Control,
+    ControlReply,
+    Heartbeat,
+    ToolConfirmationRequest,
+    ToolConfirmationResponse,
+    Error,
+    Cancelled,
+    Transcript,
+    StreamChunk,
+    StreamEnd,
+    Thinking,
+    Notification,
+    Ping,
+    Pong,
+    /// An agent describing itself, see [`crate::capabilities`]
+    Capabilities,
+    /// Any other value, kept as sent
+    Other(String),
+}
+
+impl EnvelopeKind {
+    /// Every kind but `Other`.
+    pub const KNOWN: &'static [EnvelopeKind] = &[
+        EnvelopeKind::Message,
+        EnvelopeKind::MessageReply,
+        EnvelopeKind::Task,
+        EnvelopeKind::Control,
+        EnvelopeKind::ControlReply,
+        EnvelopeKind::Heartbeat,
+        EnvelopeKind::ToolConfirmationRequest,
+        EnvelopeKind::ToolConfirmationResponse,
+        EnvelopeKind::Error,
+        EnvelopeKind::Cancelled,
+        EnvelopeKind::Transcript,
+        EnvelopeKind::StreamChunk,
+        EnvelopeKind::StreamEnd,
+        EnvelopeKind::Thinking,
+        EnvelopeKind::Notification,
+        EnvelopeKind::Ping,
+        EnvelopeKind::Pong,
+        EnvelopeKind::Capabilities,
+    ];
+
+    /// The `envelope_type` string.
+    pub fn as_str(&self) -> &str {
+        match self {
+            EnvelopeKind::Message => "message",
+            EnvelopeKind::MessageReply => "message_reply",
+            EnvelopeKind::Task => "task",
+            EnvelopeKind::Control => "control",
+            EnvelopeKind::ControlReply => "control_reply",
+            EnvelopeKind::Heartbeat => "heartbeat",
+            EnvelopeKind::ToolConfirmationRequest => "tool_confirmation_request",
+            EnvelopeKind::ToolConfirmationResponse => "tool_confirmation_response",
+            EnvelopeKind::Error => "error",
+            EnvelopeKind::Cancelled => "cancelled",
+            EnvelopeKind::Transcript => "transcript",
+            EnvelopeKind::StreamChunk => "stream_chunk",
+            EnvelopeKind::StreamEnd => "stream_end",
+            EnvelopeKind::Thinking => "thinking",
+            EnvelopeKind::Notification => "notification",
+            EnvelopeKind::Ping => "ping",
+            EnvelopeKind::Pong => "pong",
+            EnvelopeKind::Capabilities => "capabilities",
+            EnvelopeKind::Other(other) => other,
+        }
+    }
+}
+
+impl From<&str> for EnvelopeKind {
+    fn from(s: &str) -> Self {
+        EnvelopeKind::KNOWN
+            .iter()
+            .find(|kind| kind.as_str() == s)
+            .cloned()
+            .unwrap_or_else(|| EnvelopeKind::Other(s.to_string()))
+    }
+}
+
+impl From<EnvelopeKind> for String {
+    fn from(kind: EnvelopeKind) -> Self {
+        match kind {
+            EnvelopeKind::Other(other) => other,
+            kind => kind.as_str().to_string(),
+        }
+    }
+}
+
+impl fmt::Display for EnvelopeKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for EnvelopeKind {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for EnvelopeKind {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(EnvelopeKind::from(String::deserialize(deserializer)?.as_str()))
+    }
+}
+
+impl Envelope {
+    /// `envelope_type` as an [`EnvelopeKind`]; `None` when it isn't set.
+    pub fn kind(&self) -> Option<EnvelopeKind> {
+        self.envelope_type.as_deref().map(EnvelopeKind::from)
+    }
+
+    pub fn set_kind(&mut self, kind: EnvelopeKind) {
+        self.envelope_type = Some(kind.into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_env;
+
+    #[test]
+    fn kinds_round_trip_through_json() {
+        for kind in EnvelopeKind::KNOWN.iter().cloned().chain([EnvelopeKind::Other("message_repy".into())]) {
+            let json = serde_json::to_value(&kind).unwrap();
+            assert_eq!(json, serde_json::json!(kind.as_str()));
+            assert_eq!(serde_json::from_value::<EnvelopeKind>(json).unwrap(), kind);
+
+            let mut env = test_env();
+            env.set_kind(kind.clone());
+            let env: Envelope = serde_json::from_str(&serde_json::to_string(&env).unwrap()).unwrap();
+            assert_eq!(env.kind(), Some(kind));
+        }
+        // Unknown strings aren't mistaken for a near miss
+        assert_eq!(EnvelopeKind::from("message_repy"), EnvelopeKind::Other("message_repy".into()));
+        assert_eq!(Envelope::default().kind(), None);
+    }
+}
@@ -0,0 +1,95 @@
+//! crates/bus/src/kv.rs
+//!
+//! Plain Redis keys and hashes, for small records kept beside the streams
+//! (e.g. agents registering themselves) rather than sent over them.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::{Bus, BusError};
+
+/// Keys asked for per SCAN round trip.
+const SCAN_COUNT: usize = 100;
+
+/// `literal` with the glob metacharacters `* ? [ ] \` escaped, so it can
+/// be put in a [`Bus::scan_keys`] pattern and match only itself.
+pub fn escape_glob(literal: &str) -> String {
+    let mut escaped = String::with_capacity(literal.len());
+    for c in literal.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+impl Bus {
+    /// Every key matching the glob `pattern`, sorted. Uses SCAN, so a large
+    /// keyspace is walked without blocking Redis; keys added or removed
+    /// meanwhile may or may not be included.
+    pub async fn scan_keys(&self, pattern: &str) -> Result<Vec<String>, BusError> {
+        let mut conn = self.client.get_async_connection().await?;
+        let mut keys = Vec::new();
+        let mut cursor: u64 = 0;
+        loop {
+            let (next, batch): (u64, Vec<String>) = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH").arg(pattern)
+                .arg("COUNT").arg(SCAN_COUNT)
+                .query_async(&mut conn)
+                .await?;
+            keys.extend(batch);
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        // SCAN may return a key more than once
+        keys.sort();
+        keys.dedup();
+        Ok(keys)
+    }
+
+    /// The string at `key` (GET); `None` if there is no such key.
+    pub async fn get_value(&self, key: &str) -> Result<Option<String>, BusError> {
+        let mut conn = self.client.get_async_connection().await?;
+        Ok(redis::cmd("GET").arg(key).query_async(&mut conn).await?)
+    }
+
+    /// Set `key` to `value`, expiring after `ttl` (PSETEX, so to the millisecond).
+    pub async fn set_with_ttl(&self, key: &str, value: &str, ttl: Duration) -> Result<(), BusError> {
+        let mut conn = self.client.get_async_connection().await?;
+        let ttl_ms = (ttl.as_millis() as u64).max(1);
+        redis::cmd("PSETEX").arg(key).arg(ttl_ms).arg(value).query_async::<_, ()>(&mut conn).await?;
+        Ok(())
+    }
+
+    /// Have `key`, of any type, expire after `ttl` (PEXPIRE), replacing any
+    /// expiry it had. `false` if there is no such key.
+    pub async fn expire(&self, key: &str, ttl: Duration) -> Result<bool, BusError> {
+        let mut conn = self.client.get_async_connection().await?;
+        let ttl_ms = (ttl.as_millis() as u64).max(1);
+        Ok(redis::cmd("PEXPIRE").arg(key).arg(ttl_ms).query_async(&mut conn).await?)
+    }
+
+    /// The fields of the hash at `key` (HGETALL); empty if there is no such key.
+    pub async fn hash_get_all(&self, key: &str) -> Result<HashMap<String, String>, BusError> {
+        let mut conn = self.client.get_async_connection().await?;
+        Ok(redis::cmd("HGETALL").arg(key).query_async(&mut conn).await?)
+    }
+
+    /// Make the hash at `key` hold exactly `fields`, dropping any others, in
+    /// one transaction. No `fields` deletes the key.
+    pub async fn hash_replace(&self, key: &str, fields: &[(String, String)]) -> Result<(), BusError> {
+        let mut conn = self.client.get_async_connection().await?;
+        let mut pipe = redis::pipe();
+        pipe.atomic().del(key).ignore();
+        if !fields.is_empty() {
+            pipe.hset_multiple(key, fields).ignore();
+        }
+        pipe.query_async::<_, ()>(&mut conn).await?;
+        Ok(())
+    }
+}
+
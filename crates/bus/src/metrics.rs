@@ -0,0 +1,326 @@
+//! crates/bus/src/metrics.rs
+//!
+//! Lock-free message counters for a [`Bus`](crate::Bus), plus an optional
+//! Prometheus exporter (`prometheus` feature).
+//!
+//! With the `metrics` feature, [`Bus::with_metrics`](crate::Bus::with_metrics)
+//! also records through the [`metrics`](https://docs.rs/metrics) facade, to
+//! whatever recorder the application installed, all labelled by `stream`:
+//!
+//! - `bus.send.duration_ms` (histogram): how long each send took
+//! - `bus.messages.sent` (counter): envelopes sent
+//! - `bus.message.latency_ms` (histogram): how long envelopes read through a
+//!   consumer group waited in their stream, from the time in the entry id
+//!
+//! To scrape them, install `metrics-exporter-prometheus` once at startup:
+//!
+//! ```ignore
+//! metrics_exporter_prometheus::PrometheusBuilder::new()
+//!     .with_http_listener(([0, 0, 0, 0], 9000))
+//!     .install()?;
+//! let bus = Bus::new(&redis_url)?.with_metrics();
+//! ```
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{BusError, Delivery};
+
+/// Point-in-time snapshot of a [`Bus`](crate::Bus)'s message counters.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BusMetrics {
+    pub sent: u64,
+    pub received: u64,
+    pub acked: u64,
+    pub send_errors: u64,
+    pub recv_errors: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct Counters {
+    sent: AtomicU64,
+    received: AtomicU64,
+    acked: AtomicU64,
+    send_errors: AtomicU64,
+    recv_errors: AtomicU64,
+    #[cfg(feature = "prometheus")]
+    pub(crate) exporter: std::sync::OnceLock<std::sync::Arc<PrometheusExporter>>,
+    /// Also record through the `metrics` facade
+    #[cfg(feature = "metrics")]
+    pub(crate) facade: std::sync::atomic::AtomicBool,
+}
+
+impl Counters {
+    #[cfg_attr(not(feature = "prometheus"), allow(unused_variables))]
+    pub(crate) fn record_send<T>(&self, stream: &str, elapsed: Duration, res: &Result<T, BusError>) {
+        match res {
+            Ok(_) => self.sent.fetch_add(1, Ordering::Relaxed),
+            Err(_) => self.send_errors.fetch_add(1, Ordering::Relaxed),
+        };
+        #[cfg(feature = "prometheus")]
+        if let Some(exporter) = self.exporter.get() {
+            exporter.observe_send(stream, elapsed, res.is_ok());
+        }
+        #[cfg(feature = "metrics")]
+        if self.facade.load(Ordering::Relaxed) {
+            let stream = stream.to_string();
+            ::metrics::histogram!("bus.send.duration_ms", "stream" => stream.clone()).record(elapsed.as_secs_f64() * 1000.0);
+            if res.is_ok() {
+                ::metrics::counter!("bus.messages.sent", "stream" => stream).increment(1);
+            }
+        }
+    }
+
+    /// How long `delivery` waited in its stream, for the `metrics` facade.
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+    pub(crate) fn record_latency(&self, delivery: &Delivery) {
+        #[cfg(feature = "metrics")]
+        if self.facade.load(Ordering::Relaxed) {
+            let latency = delivery.queue_latency().as_secs_f64() * 1000.0;
+            ::metrics::histogram!("bus.message.latency_ms", "stream" => delivery.stream.clone()).record(latency);
+        }
+    }
+
+    #[cfg_attr(not(feature = "prometheus"), allow(unused_variables))]
+    pub(crate) fn record_recv<T>(
+        &self,
+        stream: &str,
+        elapsed: Duration,
+        res: &Result<Option<T>, BusError>,
+    ) {
+        match res {
+            Ok(Some(_)) => {
+                self.received.fetch_add(1, Ordering::Relaxed);
+            }
+            Ok(None) => {}
+            Err(_) => {
+                self.recv_errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        #[cfg(feature = "prometheus")]
+        if let Some(exporter) = self.exporter.get() {
+            exporter.observe_recv(stream, elapsed, res);
+        }
+    }
+
+    #[cfg_attr(not(feature = "prometheus"), allow(unused_variables))]
+    pub(crate) fn record_ack(&self, stream: &str) {
+        self.acked.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "prometheus")]
+        if let Some(exporter) = self.exporter.get() {
+            exporter.observe_ack(stream);
+        }
+    }
+
+    pub(crate) fn snapshot(&self) -> BusMetrics {
+        BusMetrics {
+            sent: self.sent.load(Ordering::Relaxed),
+            received: self.received.load(Ordering::Relaxed),
+            acked: self.acked.load(Ordering::Relaxed),
+            send_errors: self.send_errors.load(Ordering::Relaxed),
+            recv_errors: self.recv_errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(feature = "prometheus")]
+pub use exporter::PrometheusExporter;
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use super::*;
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+    use crate::tests::{test_env, OFFLINE_REDIS_URL};
+
+    #[test]
+    fn sends_and_queue_latency_are_recorded_through_the_facade() {
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        ::metrics::with_local_recorder(&recorder, || {
+            let bus = crate::Bus::new(OFFLINE_REDIS_URL).unwrap();
+            // Nothing is recorded until the bus asks for it
+            bus.counters.record_send("AG1:test:inbox", Duration::from_millis(3), &Ok::<_, BusError>(()));
+            let bus = bus.with_metrics();
+            bus.counters.record_send("AG1:test:inbox", Duration::from_millis(3), &Ok::<_, BusError>(()));
+            bus.counters.record_send("AG1:test:inbox", Duration::from_millis(5), &Err::<(), _>(BusError::Timeout("XADD".into())));
+            let delivery = Delivery::new(&bus, "AG1:test:inbox", "g", "c", "1000-0".into(), test_env(), 1, false);
+            bus.counters.record_latency(&delivery);
+        });
+
+        let metrics: Vec<(String, DebugValue)> = snapshotter
+            .snapshot()
+            .into_vec()
+            .into_iter()
+            .map(|(key, _, _, value)| (key.key().name().to_string(), value))
+            .collect();
+        let value = |name: &str| metrics.iter().find(|(n, _)| n == name).map(|(_, v)| v);
+        assert_eq!(value("bus.messages.sent"), Some(&DebugValue::Counter(1)));
+        let Some(DebugValue::Histogram(sends)) = value("bus.send.duration_ms") else { panic!("{metrics:?}") };
+        assert_eq!(sends.len(), 2);
+        let Some(DebugValue::Histogram(latencies)) = value("bus.message.latency_ms") else { panic!("{metrics:?}") };
+        assert!(latencies[0].into_inner() > 3_600_000.0);
+    }
+}
+
+#[cfg(feature = "prometheus")]
+mod exporter {
+    use std::collections::BTreeSet;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use prometheus::{
+        HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder,
+    };
+
+    use crate::{BusError, StreamInfo};
+
+    /// Latency buckets in seconds; recv includes the XREAD BLOCK wait.
+    const LATENCY_BUCKETS: &[f64] = &[
+        0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+    ];
+
+    /// Prometheus view of bus traffic, labelled by stream.
+    ///
+    /// Attach it with [`Bus::with_exporter`](crate::Bus::with_exporter) and serve
+    /// [`PrometheusExporter::render`] from a `/metrics` endpoint.
+    pub struct PrometheusExporter {
+        registry: Registry,
+        sent: IntCounterVec,
+        received: IntCounterVec,
+        acked: IntCounterVec,
+        send_errors: IntCounterVec,
+        recv_errors: IntCounterVec,
+        send_latency: HistogramVec,
+        recv_latency: HistogramVec,
+        stream_length: IntGaugeVec,
+        stream_groups: IntGaugeVec,
+        stream_pending: IntGaugeVec,
+        streams: Mutex<BTreeSet<String>>,
+    }
+
+    fn counter(registry: &Registry, name: &str, help: &str) -> prometheus::Result<IntCounterVec> {
+        let c = IntCounterVec::new(Opts::new(name, help), &["stream"])?;
+        registry.register(Box::new(c.clone()))?;
+        Ok(c)
+    }
+
+    fn gauge(registry: &Registry, name: &str, help: &str) -> prometheus::Result<IntGaugeVec> {
+        let g = IntGaugeVec::new(Opts::new(name, help), &["stream"])?;
+        registry.register(Box::new(g.clone()))?;
+        Ok(g)
+    }
+
+    fn histogram(registry: &Registry, name: &str, help: &str) -> prometheus::Result<HistogramVec> {
+        let h = HistogramVec::new(
+            HistogramOpts::new(name, help).buckets(LATENCY_BUCKETS.to_vec()),
+            &["stream"],
+        )?;
+        registry.register(Box::new(h.clone()))?;
+        Ok(h)
+    }
+
+    impl PrometheusExporter {
+        pub fn new() -> prometheus::Result<Self> {
+            let registry = Registry::new();
+            Ok(Self {
+                sent: counter(&registry, "ag1_bus_sent_total", "Envelopes sent")?,
+                received: counter(&registry, "ag1_bus_received_total", "Envelopes received")?,
+                acked: counter(&registry, "ag1_bus_acked_total", "Messages acknowledged")?,
+                send_errors: counter(&registry, "ag1_bus_send_errors_total", "Failed sends")?,
+                recv_errors: counter(&registry, "ag1_bus_recv_errors_total", "Failed reads")?,
+                send_latency: histogram(&registry, "ag1_bus_send_seconds", "XADD latency")?,
+                recv_latency: histogram(&registry, "ag1_bus_recv_seconds", "Blocking read latency")?,
+                stream_length: gauge(&registry, "ag1_bus_stream_length", "XLEN of the stream")?,
+                stream_groups: gauge(&registry, "ag1_bus_stream_groups", "Consumer groups on the stream")?,
+                stream_pending: gauge(&registry, "ag1_bus_stream_pending", "Pending messages across groups")?,
+                streams: Mutex::new(BTreeSet::new()),
+                registry,
+            })
+        }
+
+        fn seen(&self, stream: &str) {
+            if let Ok(mut streams) = self.streams.lock() {
+                if !streams.contains(stream) {
+                    streams.insert(stream.to_string());
+                }
+            }
+        }
+
+        /// Streams this exporter has seen traffic on, for refreshing gauges.
+        pub fn streams(&self) -> Vec<String> {
+            self.streams.lock().map(|s| s.iter().cloned().collect()).unwrap_or_default()
+        }
+
+        pub fn observe_send(&self, stream: &str, elapsed: Duration, ok: bool) {
+            self.seen(stream);
+            self.send_latency.with_label_values(&[stream]).observe(elapsed.as_secs_f64());
+            if ok {
+                self.sent.with_label_values(&[stream]).inc();
+            } else {
+                self.send_errors.with_label_values(&[stream]).inc();
+            }
+        }
+
+        pub fn observe_recv<T>(
+            &self,
+            stream: &str,
+            elapsed: Duration,
+            res: &Result<Option<T>, BusError>,
+        ) {
+            self.seen(stream);
+            self.recv_latency.with_label_values(&[stream]).observe(elapsed.as_secs_f64());
+            match res {
+                Ok(Some(_)) => self.received.with_label_values(&[stream]).inc(),
+                Ok(None) => {}
+                Err(_) => self.recv_errors.with_label_values(&[stream]).inc(),
+            }
+        }
+
+        pub fn observe_ack(&self, stream: &str) {
+            self.seen(stream);
+            self.acked.with_label_values(&[stream]).inc();
+        }
+
+        /// Update the per-stream gauges from a [`Bus::stream_info`](crate::Bus::stream_info) result.
+        pub fn set_stream_info(&self, stream: &str, info: &StreamInfo) {
+            self.seen(stream);
+            let pending: u64 = info.groups.iter().map(|g| g.pending).sum();
+            self.stream_length.with_label_values(&[stream]).set(info.length as i64);
+            self.stream_groups.with_label_values(&[stream]).set(info.groups.len() as i64);
+            self.stream_pending.with_label_values(&[stream]).set(pending as i64);
+        }
+
+        /// Prometheus text-format scrape body.
+        pub fn render(&self) -> String {
+            TextEncoder::new()
+                .encode_to_string(&self.registry.gather())
+                .unwrap_or_default()
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn render_includes_counters_histograms_and_gauges() {
+            let exporter = PrometheusExporter::new().unwrap();
+            exporter.observe_send("AG1:test:inbox", Duration::from_millis(3), true);
+            exporter.observe_recv("AG1:test:inbox", Duration::from_millis(40), &Ok(None::<crate::Envelope>));
+            exporter.set_stream_info(
+                "AG1:test:inbox",
+                &StreamInfo { length: 7, groups: vec![] },
+            );
+
+            let body = exporter.render();
+            assert!(body.contains(r#"ag1_bus_sent_total{stream="AG1:test:inbox"} 1"#));
+            assert!(body.contains(r#"ag1_bus_send_seconds_bucket{stream="AG1:test:inbox",le="0.005"} 1"#));
+            assert!(body.contains(r#"ag1_bus_recv_seconds_count{stream="AG1:test:inbox"} 1"#));
+            assert!(body.contains(r#"ag1_bus_stream_length{stream="AG1:test:inbox"} 7"#));
+            assert_eq!(exporter.streams(), vec!["AG1:test:inbox".to_string()]);
+        }
+    }
+}
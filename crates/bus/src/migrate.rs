@@ -0,0 +1,267 @@
+//! crates/bus/src/migrate.rs
+//!
+//! [`Bus::migrate_stream`]: copy a stream's entries into another in the
+//! current layout, for history written by older producers. Each envelope is
+//! read from whichever field it was written under (`env`, `data`, ...) and
+//! written under `data` with the fields of today's [`Envelope`] filled in and
+//! `meta.schema_version` set, keeping its entry id where the destination allows.
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{entry_env, entry_id, since_id, Bus, BusError, Envelope};
+
+/// Version of the envelope layout migrated entries are written in, as their
+/// `meta.schema_version`.
+pub const ENVELOPE_SCHEMA_VERSION: u32 = 1;
+
+/// What [`Bus::migrate_stream`] copies, and how.
+#[derive(Debug, Clone)]
+pub struct MigrateOptions {
+    /// Envelope keys of the old layout and the keys they have now, e.g.
+    /// `("body", "content")`. Envelopes that already have the new key keep it.
+    pub field_mapping: Vec<(String, String)>,
+    /// Entries read per XRANGE
+    pub batch_size: usize,
+    /// Count what would be migrated without writing anything
+    pub dry_run: bool,
+    /// Only entries added at or after this time
+    pub since: Option<DateTime<Utc>>,
+    /// Only entries added before this time
+    pub until: Option<DateTime<Utc>>,
+}
+
+impl Default for MigrateOptions {
+    fn default() -> Self {
+        Self { field_mapping: Vec::new(), batch_size: 500, dry_run: false, since: None, until: None }
+    }
+}
+
+/// What [`Bus::migrate_stream`] did, or would do on a dry run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct MigrationReport {
+    /// Entries written to the destination
+    pub migrated: u64,
+    /// Entries without an envelope that reads, left behind
+    pub skipped_malformed: u64,
+    /// Migrated entries that couldn't keep their id, as the destination was
+    /// already past it; they carry it as `meta.original_id`. Once one entry
+    /// can't, none after it can, so that they stay in order.
+    pub id_conflicts: u64,
+}
+
+impl Bus {
+    /// Copy the entries of `src` into `dst`, oldest first and
+    /// `opts.batch_size` at a time, upgraded to the current envelope layout
+    /// (see [`upgrade_envelope`]). Entries keep their id, and so the time they
+    /// were added, unless `dst` already has one at or past it: then they are
+    /// added under a new id, still in order, with the old one in
+    /// `meta.original_id`. Entries without a readable envelope are skipped.
+    /// `src` is left as it is, and must not be `dst`. Without `opts.until`,
+    /// the migration stops at what `src` held when it started.
+    pub async fn migrate_stream(&self, src: &str, dst: &str, opts: &MigrateOptions) -> Result<MigrationReport, BusError> {
+        if src == dst {
+            return Err(BusError::Validation(format!("cannot migrate {} onto itself", src)));
+        }
+        let mut conn = self.client.get_async_connection().await?;
+        let mut report = MigrationReport::default();
+        let mut last = id_key(&self.tail_id(dst).await?).unwrap_or_default();
+        let mut start = opts.since.map_or_else(|| "-".to_string(), since_id);
+        // Fixed up front, so entries added while migrating can't keep it going
+        let end = match opts.until {
+            Some(until) => (until.timestamp_millis() - 1).max(0).to_string(),
+            None => self.tail_id(src).await?,
+        };
+        let batch_size = opts.batch_size.max(1);
+
+        loop {
+            let reply: redis::Value = redis::cmd("XRANGE")
+                .arg(src)
+                .arg(&start).arg(&end)
+                .arg("COUNT").arg(batch_size)
+                .query_async(&mut conn)
+                .await?;
+            let redis::Value::Bulk(entries) = reply else { break };
+
+            for entry in &entries {
+                let Some(id) = entry_id(entry) else { continue };
+                let upgraded = match entry_env(entry) {
+                    Some((_, json)) => upgrade_envelope(&json, &opts.field_mapping),
+                    None => Err(BusError::Validation("no envelope field".into())),
+                };
+                let mut env = match upgraded {
+                    Ok(env) => env,
+                    Err(e) => {
+                        eprintln!("[BUS_ERROR] ❌ Not migrating entry {} on {}: {}", id, src, e);
+                        report.skipped_malformed += 1;
+                        continue;
+                    }
+                };
+
+                let mut added = None;
+                if id_key(&id).is_some_and(|key| key > last) {
+                    added = if opts.dry_run {
+                        Some(id.clone())
+                    } else {
+                        match xadd_data(&mut conn, dst, &id, &env).await {
+                            Ok(added) => Some(added),
+                            // Written to since we looked; a conflict like any other
+                            Err(BusError::Redis(e)) if e.detail().is_some_and(|d| d.contains("equal or smaller")) => None,
+                            Err(e) => return Err(e),
+                        }
+                    };
+                }
+                let added = match added {
+                    Some(added) => added,
+                    None => {
+                        report.id_conflicts += 1;
+                        env.meta["original_id"] = json!(id);
+                        if opts.dry_run {
+                            // Where `*` would land
+                            format!("{}-0", Utc::now().timestamp_millis())
+                        } else {
+                            xadd_data(&mut conn, dst, "*", &env).await?
+                        }
+                    }
+                };
+                last = last.max(id_key(&added).unwrap_or_default());
+                report.migrated += 1;
+            }
+
+            match entries.last().and_then(entry_id) {
+                Some(last_read) if entries.len() == batch_size => start = format!("({}", last_read),
+                _ => break,
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// `json` in the current envelope layout: keys of an older one renamed per
+/// `field_mapping`, the fields it lacks at their defaults, and
+/// `meta.schema_version` set to [`ENVELOPE_SCHEMA_VERSION`].
+pub fn upgrade_envelope(json: &str, field_mapping: &[(String, String)]) -> Result<Envelope, BusError> {
+    let mut value: serde_json::Value = serde_json::from_str(json)?;
+    let Some(fields) = value.as_object_mut() else {
+        return Err(BusError::Validation("envelope is not a JSON object".into()));
+    };
+    for (old, new) in field_mapping {
+        if !fields.contains_key(new) {
+            if let Some(v) = fields.remove(old) {
+                fields.insert(new.clone(), v);
+            }
+        }
+    }
+    let mut env: Envelope = serde_json::from_value(value)?;
+    let defaults = Envelope::default();
+    if !env.usage.is_object() {
+        env.usage = defaults.usage;
+    }
+    if !env.meta.is_object() {
+        env.meta = defaults.meta;
+    }
+    env.meta["schema_version"] = json!(ENVELOPE_SCHEMA_VERSION);
+    Ok(env)
+}
+
+/// XADD `env` to `stream` under `data`, as entry `id` (`*` for a new one).
+async fn xadd_data(
+    conn: &mut redis::aio::Connection,
+    stream: &str,
+    id: &str,
+    env: &Envelope,
+) -> Result<String, BusError> {
+    Ok(redis::cmd("XADD")
+        .arg(stream)
+        .arg(id)
+        .arg("data")
+        .arg(serde_json::to_string(env)?)
+        .query_async(conn)
+        .await?)
+}
+
+/// A `<ms>-<seq>` stream id as numbers, ordered as Redis orders them.
+fn id_key(id: &str) -> Option<(u64, u64)> {
+    let (ms, seq) = id.split_once('-')?;
+    Some((ms.parse().ok()?, seq.parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{test_env, test_redis_url, OFFLINE_REDIS_URL};
+
+    #[test]
+    fn legacy_envelopes_are_upgraded_to_the_current_layout() {
+        let legacy = r#"{"role":"user","body":{"text":"hi"},"meta":null,"correlation_id":"c1"}"#;
+        let mapping = [("body".to_string(), "content".to_string())];
+        let env = upgrade_envelope(legacy, &mapping).unwrap();
+        assert_eq!(env.text_or_empty(), "hi");
+        assert_eq!(env.correlation_id.as_deref(), Some("c1"));
+        assert_eq!(env.meta, json!({ "schema_version": ENVELOPE_SCHEMA_VERSION }));
+        assert_eq!(env.usage, json!({}));
+        assert_eq!(env.consumer_group, None);
+
+        // A key the envelope already has in the new layout wins
+        let both = r#"{"role":"user","content":{"text":"new"},"body":{"text":"old"}}"#;
+        assert_eq!(upgrade_envelope(both, &mapping).unwrap().text_or_empty(), "new");
+        for bad in ["{not json", "[1, 2]", r#"{"content": {}}"#] {
+            assert!(upgrade_envelope(bad, &mapping).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn stream_ids_order_like_redis() {
+        assert!(id_key("1000-2") > id_key("1000-1"));
+        assert!(id_key("1001-0") > id_key("1000-9"));
+        assert_eq!(id_key("0-0"), Some((0, 0)));
+        assert_eq!(id_key("*"), None);
+    }
+
+    #[tokio::test]
+    async fn a_stream_is_not_migrated_onto_itself() {
+        let bus = Bus::new(OFFLINE_REDIS_URL).unwrap();
+        let err = bus.migrate_stream("AG1:s", "AG1:s", &MigrateOptions::default()).await.unwrap_err();
+        assert!(matches!(err, BusError::Validation(_)), "{err}");
+    }
+
+    #[tokio::test]
+    async fn mixed_entries_migrate_in_order_with_their_ids() {
+        let Some(redis_url) = test_redis_url() else { return };
+        let bus = Bus::new(&redis_url).unwrap();
+        let run = uuid::Uuid::new_v4();
+        let (src, dst) = (format!("ag1:bus:test:migrate:{run}:src"), format!("ag1:bus:test:migrate:{run}:dst"));
+        let mut conn = bus.client.get_async_connection().await.unwrap();
+        let entries: [(&str, &str, String); 5] = [
+            ("1000-0", "env", serde_json::to_string(&Envelope { correlation_id: Some("a".into()), ..test_env() }).unwrap()),
+            ("1000-1", "data", serde_json::to_string(&Envelope { correlation_id: Some("b".into()), ..test_env() }).unwrap()),
+            ("1001-0", "env", "{not json".into()),
+            ("1002-0", "other", "no envelope here".into()),
+            ("1003-0", "env", serde_json::to_string(&Envelope { correlation_id: Some("c".into()), ..test_env() }).unwrap()),
+        ];
+        for (id, field, value) in &entries {
+            let _: String = redis::cmd("XADD").arg(&src).arg(*id).arg(*field).arg(value).query_async(&mut conn).await.unwrap();
+        }
+
+        let opts = MigrateOptions { batch_size: 2, ..Default::default() };
+        let expected = MigrationReport { migrated: 3, skipped_malformed: 2, id_conflicts: 0 };
+        let dry = bus.migrate_stream(&src, &dst, &MigrateOptions { dry_run: true, ..opts.clone() }).await.unwrap();
+        assert_eq!(dry, expected);
+        assert!(bus.xrange(&dst, "-", "+", 10).await.unwrap().is_empty());
+
+        assert_eq!(bus.migrate_stream(&src, &dst, &opts).await.unwrap(), expected);
+        let migrated = bus.xrange(&dst, "-", "+", 10).await.unwrap();
+        let ids: Vec<_> = migrated.iter().map(|env| env.envelope_id.as_deref().unwrap()).collect();
+        assert_eq!(ids, ["1000-0", "1000-1", "1003-0"]);
+        assert!(migrated.iter().all(|env| env.meta["schema_version"] == json!(ENVELOPE_SCHEMA_VERSION)));
+
+        // Again: the destination is past every id now, so all are added after, in order
+        let again = bus.migrate_stream(&src, &dst, &opts).await.unwrap();
+        assert_eq!(again, MigrationReport { id_conflicts: 3, ..expected });
+        let appended = bus.xrange(&dst, "(1003-0", "+", 10).await.unwrap();
+        let order: Vec<_> = appended.iter().map(|env| env.correlation_id.as_deref().unwrap()).collect();
+        assert_eq!(order, ["a", "b", "c"]);
+        assert_eq!(appended[0].meta["original_id"], json!("1000-0"));
+    }
+}
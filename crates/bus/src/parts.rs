@@ -0,0 +1,208 @@
+//! crates/bus/src/parts.rs
+//!
+//! Multi-part content: `content.parts` holds typed parts next to
+//! `content.text`, which stays a plain-text rendering (the text parts joined)
+//! for consumers that only read the text.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::Envelope;
+
+/// One entry of `content.parts`, tagged by `type`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ContentPart {
+    Text {
+        text: String,
+    },
+    /// Structured data, `name` saying what it is (`table`, `result`, ...)
+    Json {
+        name: String,
+        data: Value,
+    },
+    Link {
+        url: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        title: Option<String>,
+    },
+    /// Data stored elsewhere, e.g. the `blob_id` of an attachment
+    Ref {
+        id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mime: Option<String>,
+    },
+}
+
+/// The `content.text` fallback for `parts`: its text parts, blank-line separated.
+pub fn fallback_text(parts: &[ContentPart]) -> String {
+    parts
+        .iter()
+        .filter_map(|p| match p {
+            ContentPart::Text { text } => Some(text.as_str()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// Builds multi-part `content`, e.g.
+/// `ContentBuilder::text("Top results").json("table", rows).link(url, "Source").build()`.
+#[derive(Debug, Clone, Default)]
+pub struct ContentBuilder {
+    parts: Vec<ContentPart>,
+}
+
+impl ContentBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A builder starting with one text part.
+    pub fn text(text: impl Into<String>) -> Self {
+        Self::new().with_text(text)
+    }
+
+    pub fn with_text(mut self, text: impl Into<String>) -> Self {
+        self.parts.push(ContentPart::Text { text: text.into() });
+        self
+    }
+
+    pub fn json(mut self, name: impl Into<String>, data: Value) -> Self {
+        self.parts.push(ContentPart::Json { name: name.into(), data });
+        self
+    }
+
+    pub fn link(mut self, url: impl Into<String>, title: impl Into<String>) -> Self {
+        self.parts.push(ContentPart::Link { url: url.into(), title: Some(title.into()) });
+        self
+    }
+
+    pub fn reference(mut self, id: impl Into<String>, mime: Option<&str>) -> Self {
+        self.parts.push(ContentPart::Ref { id: id.into(), mime: mime.map(str::to_string) });
+        self
+    }
+
+    /// `{ "text": <fallback>, "parts": [...] }`
+    pub fn build(self) -> Value {
+        json!({ "text": fallback_text(&self.parts), "parts": self.parts })
+    }
+}
+
+impl Envelope {
+    /// The content's parts. Legacy `{ text }` content reads as a single text
+    /// part; parts of a type this version doesn't know are skipped.
+    pub fn parts(&self) -> Vec<ContentPart> {
+        match self.content.get("parts").and_then(Value::as_array) {
+            Some(parts) => parts
+                .iter()
+                .filter_map(|p| serde_json::from_value(p.clone()).ok())
+                .collect(),
+            None => match self.try_get_text() {
+                Some(text) if !text.is_empty() => vec![ContentPart::Text { text: text.into() }],
+                _ => vec![],
+            },
+        }
+    }
+
+    /// Whether the content carries a `parts` array.
+    pub fn has_parts(&self) -> bool {
+        self.content.get("parts").is_some_and(Value::is_array)
+    }
+
+    /// Replace the content's parts, keeping the other fields and setting
+    /// `content.text` to their fallback.
+    pub fn set_parts(&mut self, parts: Vec<ContentPart>) {
+        self.set_text(&fallback_text(&parts));
+        self.content["parts"] = json!(parts);
+    }
+
+    /// `content.text`, or the parts' fallback when multi-part content leaves
+    /// it missing or empty. `None` for content with neither.
+    pub fn text_or_fallback(&self) -> Option<String> {
+        match self.try_get_text() {
+            Some(text) if !text.is_empty() || !self.has_parts() => Some(text.to_string()),
+            _ if self.has_parts() => Some(fallback_text(&self.parts())),
+            _ => None,
+        }
+    }
+
+    /// Fill in a missing or empty `content.text` from the parts, so readers
+    /// of the text alone still get something.
+    pub fn ensure_text_fallback(&mut self) {
+        if self.has_parts() && self.text_or_empty().is_empty() {
+            let text = fallback_text(&self.parts());
+            self.set_text(&text);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builder_round_trips_through_an_envelope() {
+        let content = ContentBuilder::text("Top results")
+            .json("table", json!([{ "name": "a", "score": 1 }]))
+            .link("https://example.com/a", "Source")
+            .with_text("That's all.")
+            .reference("blob-1", Some("image/png"))
+            .build();
+        assert_eq!(content["text"], json!("Top results\n\nThat's all."));
+        assert_eq!(content["parts"][1]["type"], json!("json"));
+        assert_eq!(content["parts"][1]["name"], json!("table"));
+        assert_eq!(content["parts"][2], json!({ "type": "link", "url": "https://example.com/a", "title": "Source" }));
+
+        let env: Envelope = serde_json::from_str(
+            &serde_json::to_string(&Envelope { content: content.clone(), ..Default::default() }).unwrap(),
+        )
+        .unwrap();
+        let parts = env.parts();
+        assert_eq!(parts.len(), 5);
+        assert_eq!(parts[4], ContentPart::Ref { id: "blob-1".into(), mime: Some("image/png".into()) });
+
+        let mut rebuilt = Envelope::default();
+        rebuilt.set_parts(parts);
+        assert_eq!(rebuilt.content, content);
+    }
+
+    #[test]
+    fn legacy_text_content_interoperates() {
+        // Old sender, new reader
+        let legacy = Envelope { content: json!({ "text": "hello" }), ..Default::default() };
+        assert!(!legacy.has_parts());
+        assert_eq!(legacy.parts(), vec![ContentPart::Text { text: "hello".into() }]);
+        assert!(Envelope::default().parts().is_empty());
+        assert_eq!(Envelope::default().text_or_fallback(), None);
+
+        // New sender, old reader: only `text` is looked at
+        let mut env = Envelope { content: ContentBuilder::text("hello").json("result", json!(42)).build(), ..Default::default() };
+        assert_eq!(env.text_or_empty(), "hello");
+
+        // Unknown part types are skipped rather than failing the read
+        env.content["parts"].as_array_mut().unwrap().push(json!({ "type": "audio", "id": "x" }));
+        assert_eq!(env.parts().len(), 2);
+    }
+
+    #[test]
+    fn missing_text_is_synthesized_from_the_text_parts() {
+        let mut env = Envelope {
+            content: json!({ "parts": [
+                { "type": "text", "text": "one" },
+                { "type": "link", "url": "https://example.com" },
+                { "type": "text", "text": "two" },
+            ] }),
+            ..Default::default()
+        };
+        assert_eq!(env.text_or_empty(), "");
+        assert_eq!(env.text_or_fallback().as_deref(), Some("one\n\ntwo"));
+        env.ensure_text_fallback();
+        assert_eq!(env.text_or_empty(), "one\n\ntwo");
+
+        // A text the sender set is left alone
+        env.set_text("summary");
+        env.ensure_text_fallback();
+        assert_eq!(env.text_or_empty(), "summary");
+    }
+}
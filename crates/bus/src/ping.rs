@@ -0,0 +1,133 @@
+//! crates/bus/src/ping.rs
+//!
+//! Liveness checks: bus consumers answer an `envelope_type: "ping"` envelope
+//! straight away, without invoking their agent, with a `pong` whose content
+//! is a [`PongInfo`]. [`Bus::ping`] sends one and waits for the answer.
+
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{Bus, BusError, Envelope, EnvelopeKind};
+
+/// What a consumer reports about itself in a `pong`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PongInfo {
+    pub agent_name: String,
+    /// Version of the answering binary (its `CARGO_PKG_VERSION`)
+    pub version: String,
+    pub uptime_secs: u64,
+    pub active_sessions: usize,
+    /// Entries on the consumer's inbox not yet delivered to its group, when
+    /// it could be read quickly enough
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inbox_lag: Option<i64>,
+}
+
+impl Envelope {
+    /// A `ping` asking for a `pong` on `reply_to`, with a fresh correlation id.
+    pub fn ping(reply_to: &str) -> Envelope {
+        Envelope {
+            content: json!({ "text": "ping" }),
+            reply_to: Some(reply_to.to_string()),
+            envelope_type: Some(EnvelopeKind::Ping.into()),
+            timestamp: Some(chrono::Utc::now().to_rfc3339()),
+            envelope_id: Some(uuid::Uuid::new_v4().to_string()),
+            correlation_id: Some(uuid::Uuid::new_v4().to_string()),
+            ..Default::default()
+        }
+    }
+
+    pub fn is_ping(&self) -> bool {
+        self.kind() == Some(EnvelopeKind::Ping)
+    }
+
+    /// The `pong` answering this ping: addressed back to its sender on the
+    /// same correlation id, with `info` (and `"text": "pong"`) as the content.
+    pub fn pong_reply(&self, info: &PongInfo) -> Envelope {
+        let mut content = json!(info);
+        content["text"] = json!("pong");
+        Envelope {
+            role: "assistant".into(),
+            content,
+            agent_name: Some(info.agent_name.clone()),
+            target: self.agent_name.clone(),
+            reply_to: self.reply_to.clone(),
+            envelope_type: Some(EnvelopeKind::Pong.into()),
+            timestamp: Some(chrono::Utc::now().to_rfc3339()),
+            envelope_id: Some(uuid::Uuid::new_v4().to_string()),
+            correlation_id: self.correlation_id.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// The [`PongInfo`] in a `pong`'s content.
+    pub fn pong_info(&self) -> Result<PongInfo, BusError> {
+        Ok(serde_json::from_value(self.content.clone())?)
+    }
+}
+
+impl Bus {
+    /// Ping whoever consumes `target_inbox` and wait up to `timeout_ms` for
+    /// the `pong` on `reply_inbox`. Other entries arriving on `reply_inbox`
+    /// meanwhile are skipped over, not consumed. Errors with
+    /// [`BusError::Timeout`] when nothing answers in time.
+    pub async fn ping(&self, target_inbox: &str, reply_inbox: &str, timeout_ms: u64) -> Result<PongInfo, BusError> {
+        let deadline = Instant::now() + Duration::from_millis(timeout_ms);
+        let mut last_id = self.tail_id(reply_inbox).await?;
+        let ping = Envelope::ping(reply_inbox);
+        self.send(target_inbox, &ping).await?;
+
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(BusError::Timeout(format!("no pong from {} within {}ms", target_inbox, timeout_ms)));
+            }
+            // BLOCK 0 would wait forever
+            let block_ms = (left.as_millis() as u64).max(1);
+            let Some(entry) = self.recv_block(reply_inbox, &last_id, block_ms).await? else {
+                continue;
+            };
+            last_id = entry.id;
+            if let Some(env) = entry.envelope {
+                if env.kind() == Some(EnvelopeKind::Pong) && env.correlation_id == ping.correlation_id {
+                    return env.pong_info();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pong_answers_the_ping_it_was_built_from() {
+        let mut ping = Envelope::ping("AG1:agent:tester:inbox");
+        ping.agent_name = Some("tester".into());
+        assert!(ping.is_ping());
+        assert!(ping.correlation_id.is_some());
+
+        let info = PongInfo {
+            agent_name: "GooseAgent".into(),
+            version: "1.2.3".into(),
+            uptime_secs: 42,
+            active_sessions: 2,
+            inbox_lag: None,
+        };
+        let pong = ping.pong_reply(&info);
+        assert_eq!(pong.kind(), Some(EnvelopeKind::Pong));
+        assert!(!pong.is_ping());
+        assert_eq!(pong.correlation_id, ping.correlation_id);
+        assert_eq!(pong.reply_to.as_deref(), Some("AG1:agent:tester:inbox"));
+        assert_eq!(pong.target.as_deref(), Some("tester"));
+        assert_eq!(pong.text_or_empty(), "pong");
+        assert!(pong.content.get("inbox_lag").is_none());
+        assert_eq!(pong.pong_info().unwrap(), info);
+
+        let lagging = ping.pong_reply(&PongInfo { inbox_lag: Some(7), ..info });
+        assert_eq!(lagging.pong_info().unwrap().inbox_lag, Some(7));
+    }
+}
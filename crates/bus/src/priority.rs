@@ -0,0 +1,325 @@
+//! crates/bus/src/priority.rs
+//!
+//! Reading a consumer group most urgent envelope first. Redis streams are
+//! FIFO, so priority can only reorder what has already arrived: each read
+//! takes a small batch and hands out its most urgent envelope, leaving the
+//! rest pending for the consumer until its next read. A high-priority
+//! envelope overtakes the others in the same batch, not ones read before it.
+
+use std::cmp::Reverse;
+use std::time::Instant;
+
+use crate::audit::Direction;
+use crate::{entry_env, hop, parse_entry_env, Bus, BusError, Delivery, Envelope, RedactionPolicy, SecretMasker};
+
+/// Priority of an envelope that sets none: the middle of the range, so
+/// senders can mark messages as more or less urgent than the rest.
+pub const DEFAULT_PRIORITY: u8 = 128;
+
+impl Envelope {
+    /// How urgent this envelope is, higher first: its `priority`, else a
+    /// `meta.priority` of `"low"`, `"normal"` or `"high"`, else [`DEFAULT_PRIORITY`].
+    pub fn effective_priority(&self) -> u8 {
+        if let Some(priority) = self.priority {
+            return priority;
+        }
+        match self.meta.get("priority").and_then(|p| p.as_str()) {
+            Some("low") => DEFAULT_PRIORITY / 2,
+            Some("high") => DEFAULT_PRIORITY + DEFAULT_PRIORITY / 2,
+            _ => DEFAULT_PRIORITY,
+        }
+    }
+}
+
+impl Bus {
+    /// [`Bus::recv_block_group`], most urgent first: of up to `batch`
+    /// envelopes, `consumer`'s pending ones and then new ones, hand back the
+    /// one with the highest [`Envelope::effective_priority`], the oldest among
+    /// equals. The rest stay pending for `consumer` and are weighed again on
+    /// the next call, with whatever has arrived since, so ack each envelope
+    /// once handled or it comes back. Blocks for up to `block_ms` only when
+    /// nothing is pending. Passing an envelope over doesn't count as
+    /// delivering it: its delivery count and idle time are left alone until
+    /// it is handed out.
+    pub async fn recv_block_group_priority(
+        &self,
+        stream: &str,
+        group: &str,
+        consumer: &str,
+        block_ms: u64,
+        batch: usize,
+    ) -> Result<Option<Delivery>, BusError> {
+        let started = Instant::now();
+        let mut res = self.most_urgent(stream, group, consumer, block_ms, batch.max(1)).await;
+        self.counters.record_recv(stream, started.elapsed(), &res);
+        if let Ok(Some(Delivery { envelope: env, .. })) = &mut res {
+            self.restore_read(env).await;
+            env.trace.push(hop("recv", stream));
+            self.audit(Direction::Received, stream, env).await;
+        }
+        res
+    }
+
+    async fn most_urgent(
+        &self,
+        stream: &str,
+        group: &str,
+        consumer: &str,
+        block_ms: u64,
+        batch: usize,
+    ) -> Result<Option<Delivery>, BusError> {
+        let mut conn = self.client.get_async_connection().await?;
+        let mut candidates = self.read_pending_batch(&mut conn, stream, group, consumer, batch).await?;
+        let pending = candidates.len();
+        if pending < batch {
+            // Only wait for new ones when there is nothing to hand out already
+            let block = (pending == 0).then_some(block_ms);
+            candidates.extend(self.read_group_batch(&mut conn, stream, group, consumer, batch - pending, block).await?);
+        }
+        eprintln!("[BUS_DEBUG] Weighing {} envelopes on {} by priority", candidates.len(), stream);
+        // Pending entries come first and stream ids only grow, so index order is arrival order
+        let Some(chosen) = candidates
+            .iter()
+            .enumerate()
+            .max_by_key(|(i, candidate)| (candidate.env.effective_priority(), Reverse(*i)))
+            .map(|(i, _)| i)
+        else {
+            return Ok(None);
+        };
+
+        let key = |id: &str| (stream.to_string(), group.to_string(), id.to_string());
+        let was_passed_over = {
+            let mut passed_over = self.passed_over.lock().unwrap();
+            for candidate in &candidates[pending..] {
+                passed_over.insert(key(&candidate.id));
+            }
+            passed_over.remove(&key(&candidates[chosen].id))
+        };
+        let Candidate { id, env, mut count } = candidates.swap_remove(chosen);
+        if chosen < pending {
+            // Handed out again: the claim restarts its idle time, and counts a
+            // delivery unless it was only ever passed over
+            let mut cmd = redis::cmd("XCLAIM");
+            cmd.arg(stream).arg(group).arg(consumer).arg(0).arg(&id);
+            if was_passed_over {
+                cmd.arg("JUSTID");
+            } else {
+                count += 1;
+            }
+            cmd.query_async::<_, redis::Value>(&mut conn).await?;
+        }
+
+        let env_json = env.redacted(RedactionPolicy::global()).to_string();
+        eprintln!("[BUS_DEBUG] Most urgent {}: {}", id, SecretMasker::global().mask(&env_json));
+        Ok(Some(Delivery::new(self, stream, group, consumer, id, env, count, false)))
+    }
+
+    /// Up to `count` of `consumer`'s pending entries, oldest first, read with
+    /// XPENDING and XRANGE so those passed over again keep their delivery
+    /// count and idle time.
+    async fn read_pending_batch(
+        &self,
+        conn: &mut redis::aio::Connection,
+        stream: &str,
+        group: &str,
+        consumer: &str,
+        count: usize,
+    ) -> Result<Vec<Candidate>, BusError> {
+        let pending: Vec<(String, String, u64, u64)> = redis::cmd("XPENDING")
+            .arg(stream).arg(group).arg("-").arg("+").arg(count).arg(consumer)
+            .query_async(conn)
+            .await?;
+        if pending.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut pipe = redis::pipe();
+        for (id, ..) in &pending {
+            pipe.cmd("XRANGE").arg(stream).arg(id).arg(id);
+        }
+        let ranges: Vec<redis::Value> = pipe.query_async(conn).await?;
+
+        let mut candidates = Vec::new();
+        for ((id, _, _, delivered), range) in pending.into_iter().zip(&ranges) {
+            let entry = match range {
+                redis::Value::Bulk(entries) => entries.first(),
+                _ => None,
+            };
+            let delivered = u32::try_from(delivered).unwrap_or(u32::MAX);
+            candidates.extend(self.candidate(conn, stream, group, id, entry, delivered).await?);
+        }
+        Ok(candidates)
+    }
+
+    /// XREADGROUP up to `count` new entries, blocking for `block_ms` when given.
+    async fn read_group_batch(
+        &self,
+        conn: &mut redis::aio::Connection,
+        stream: &str,
+        group: &str,
+        consumer: &str,
+        count: usize,
+        block_ms: Option<u64>,
+    ) -> Result<Vec<Candidate>, BusError> {
+        let mut cmd = redis::cmd("XREADGROUP");
+        cmd.arg("GROUP").arg(group).arg(consumer).arg("COUNT").arg(count);
+        if let Some(block_ms) = block_ms {
+            cmd.arg("BLOCK").arg(block_ms);
+        }
+        let reply: redis::Value = cmd.arg("STREAMS").arg(stream).arg(">").query_async(conn).await?;
+
+        let mut candidates = Vec::new();
+        for entry in stream_entries(&reply) {
+            let Some(id) = entry_id(entry) else { continue };
+            candidates.extend(self.candidate(conn, stream, group, id, Some(entry), 1).await?);
+        }
+        Ok(candidates)
+    }
+
+    /// The entry `id` as a candidate delivered `count` times. One holding no
+    /// envelope (or gone from the stream) is acked and skipped; one that
+    /// doesn't parse is acked and fails the read, as in [`Bus::recv_block_group`].
+    async fn candidate(
+        &self,
+        conn: &mut redis::aio::Connection,
+        stream: &str,
+        group: &str,
+        id: String,
+        entry: Option<&redis::Value>,
+        count: u32,
+    ) -> Result<Option<Candidate>, BusError> {
+        let parsed = entry
+            .and_then(entry_env)
+            .map(|(_, json)| parse_entry_env(stream, &id, &json, self.max_entry_bytes, &self.content_keys));
+        match parsed {
+            Some(Ok(env)) => Ok(Some(Candidate { id, env, count })),
+            // Deleted from the stream since, or never an envelope: nothing to hand out
+            None => {
+                redis::cmd("XACK").arg(stream).arg(group).arg(&id).query_async::<_, i64>(conn).await?;
+                Ok(None)
+            }
+            Some(Err(e)) => {
+                // serde quotes the offending content, so mask it like the envelopes themselves
+                eprintln!("[BUS_ERROR] ❌ Failed to parse envelope {}: {}", id, SecretMasker::global().mask(&e.to_string()));
+                redis::cmd("XACK").arg(stream).arg(group).arg(&id).query_async::<_, i64>(conn).await?;
+                Err(e)
+            }
+        }
+    }
+}
+
+/// An entry weighed by [`Bus::recv_block_group_priority`], with how often it was delivered.
+struct Candidate {
+    id: String,
+    env: Envelope,
+    count: u32,
+}
+
+/// The `[id, fields]` entries of a single-stream XREAD/XREADGROUP reply.
+fn stream_entries(v: &redis::Value) -> &[redis::Value] {
+    use redis::Value::Bulk;
+    let Bulk(streams) = v else { return &[] };
+    let Some(Bulk(stream)) = streams.first() else { return &[] };
+    match stream.get(1) {
+        Some(Bulk(entries)) => entries,
+        _ => &[],
+    }
+}
+
+fn entry_id(entry: &redis::Value) -> Option<String> {
+    use redis::Value::{Bulk, Data};
+    let Bulk(entry) = entry else { return None };
+    match entry.first()? {
+        Data(id) => Some(String::from_utf8_lossy(id).into_owned()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::tests::{test_env, test_redis_url};
+
+    fn with_priority(text: &str, priority: Option<u8>) -> Envelope {
+        let mut env = test_env();
+        env.content = json!({ "text": text });
+        env.priority = priority;
+        env
+    }
+
+    #[test]
+    fn priority_falls_back_to_meta_then_the_default() {
+        let mut env = test_env();
+        assert_eq!(env.effective_priority(), DEFAULT_PRIORITY);
+        env.meta = json!({ "priority": "high" });
+        assert!(env.effective_priority() > DEFAULT_PRIORITY);
+        env.meta = json!({ "priority": "low" });
+        assert!(env.effective_priority() < DEFAULT_PRIORITY);
+        env.meta = json!({ "priority": "normal" });
+        assert_eq!(env.effective_priority(), DEFAULT_PRIORITY);
+        env.priority = Some(3);
+        assert_eq!(env.effective_priority(), 3);
+    }
+
+    #[tokio::test]
+    async fn high_priority_overtakes_earlier_messages_in_the_batch() {
+        let Some(redis_url) = test_redis_url() else { return };
+        let bus = Bus::new(&redis_url).unwrap();
+        let stream = format!("ag1:bus:test:priority:{}", uuid::Uuid::new_v4());
+        bus.create_consumer_group(&stream, "workers").await.unwrap();
+        for env in [with_priority("low", Some(10)), with_priority("unset", None), with_priority("urgent", Some(250))] {
+            bus.send(&stream, &env).await.unwrap();
+        }
+
+        let mut order = Vec::new();
+        while let Some(delivery) = bus.recv_block_group_priority(&stream, "workers", "w1", 50, 10).await.unwrap() {
+            order.push(delivery.envelope.text_or_empty().to_string());
+            delivery.ack().await.unwrap();
+        }
+        assert_eq!(order, ["urgent", "unset", "low"]);
+
+        // A batch of one is plain FIFO
+        for env in [with_priority("first", Some(10)), with_priority("second", Some(250))] {
+            bus.send(&stream, &env).await.unwrap();
+        }
+        let next = bus.recv_block_group_priority(&stream, "workers", "w1", 50, 1).await.unwrap().unwrap();
+        assert_eq!(next.envelope.text_or_empty(), "first");
+    }
+
+    #[tokio::test]
+    async fn passed_over_entries_keep_their_delivery_count() {
+        let Some(redis_url) = test_redis_url() else { return };
+        let bus = Bus::new(&redis_url).unwrap();
+        let stream = format!("ag1:bus:test:priority:{}", uuid::Uuid::new_v4());
+        bus.create_consumer_group(&stream, "workers").await.unwrap();
+        for env in [with_priority("low", Some(10)), with_priority("mid", Some(100)), with_priority("high", Some(250))] {
+            bus.send(&stream, &env).await.unwrap();
+        }
+
+        let mut conn = bus.client.get_async_connection().await.unwrap();
+        for expected in ["high", "mid", "low"] {
+            let delivery = bus.recv_block_group_priority(&stream, "workers", "w1", 50, 10).await.unwrap().unwrap();
+            assert_eq!(delivery.envelope.text_or_empty(), expected);
+            assert_eq!(delivery.delivery_count, 1, "{expected}");
+            assert!(!delivery.redelivered);
+            delivery.ack().await.unwrap();
+            // The ones skipped so far were delivered once, when first read
+            let pending: Vec<(String, String, u64, u64)> = redis::cmd("XPENDING")
+                .arg(&stream).arg("workers").arg("-").arg("+").arg(10)
+                .query_async(&mut conn)
+                .await
+                .unwrap();
+            assert!(pending.iter().all(|(.., count)| *count == 1), "{pending:?}");
+        }
+
+        // One handed out and left unacked is delivered again
+        bus.send(&stream, &with_priority("again", None)).await.unwrap();
+        let first = bus.recv_block_group_priority(&stream, "workers", "w1", 50, 10).await.unwrap().unwrap();
+        let second = bus.recv_block_group_priority(&stream, "workers", "w1", 50, 10).await.unwrap().unwrap();
+        assert_eq!(first.entry_id, second.entry_id);
+        assert_eq!(second.delivery_count, 2);
+        let counts = crate::delivery::delivery_counts(&mut conn, &stream, "workers", std::slice::from_ref(&second.entry_id)).await.unwrap();
+        assert_eq!(counts[&second.entry_id], 2);
+    }
+}
@@ -0,0 +1,33 @@
+//! crates/bus/src/pubsub.rs
+//!
+//! Redis Pub/Sub, for fan-out that doesn't need stream persistence (live
+//! dashboards, ephemeral notifications): a message published while nobody is
+//! subscribed is simply gone, and there are no groups, acks or replays.
+
+use futures::{Stream, StreamExt};
+
+use crate::{Bus, BusError};
+
+impl Bus {
+    /// PUBLISH `message` on `channel`, giving how many subscribers got it.
+    pub async fn publish(&self, channel: &str, message: &str) -> Result<u64, BusError> {
+        let mut conn = self.client.get_async_connection().await?;
+        Ok(redis::cmd("PUBLISH").arg(channel).arg(message).query_async(&mut conn).await?)
+    }
+
+    /// Messages published on `channel` from now on.
+    ///
+    /// A connection in subscribed mode can't run any other command, so this
+    /// opens one of its own instead of sharing the stream commands'
+    /// connections; it is closed when the returned stream is dropped. The
+    /// stream ends if the connection is lost; unlike [`Bus::subscribe`] there
+    /// is no reconnect. Payloads that aren't UTF-8 are yielded as errors.
+    pub async fn subscribe_channel(
+        &self,
+        channel: &str,
+    ) -> Result<impl Stream<Item = Result<String, BusError>> + Send + 'static, BusError> {
+        let mut pubsub = self.client.get_async_connection().await?.into_pubsub();
+        pubsub.subscribe(channel).await?;
+        Ok(pubsub.into_on_message().map(|msg| Ok(msg.get_payload::<String>()?)))
+    }
+}
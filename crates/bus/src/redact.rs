@@ -0,0 +1,299 @@
+//! crates/bus/src/redact.rs
+//!
+//! Envelope redaction for logs and traces.
+
+use std::sync::OnceLock;
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::Envelope;
+
+const REDACTED: &str = "[REDACTED]";
+
+/// What to strip from an envelope before it is logged.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RedactionPolicy {
+    /// Dotted paths into the serialized envelope, `*` matches any key
+    /// (e.g. `meta.user.email`, `content.*.password`).
+    pub redact_fields: Vec<String>,
+    /// `content.text` is truncated to this many characters.
+    pub max_text_len: usize,
+    /// Replace secrets with a stable digest instead of masking/dropping them.
+    pub hash_instead: bool,
+}
+
+impl Default for RedactionPolicy {
+    fn default() -> Self {
+        Self {
+            redact_fields: Vec::new(),
+            max_text_len: 200,
+            hash_instead: false,
+        }
+    }
+}
+
+impl RedactionPolicy {
+    /// Load from `AG1_REDACT_FIELDS` (comma separated), `AG1_REDACT_MAX_TEXT_LEN`
+    /// and `AG1_REDACT_HASH`, falling back to the defaults.
+    pub fn from_env() -> Self {
+        let mut policy = Self::default();
+        if let Ok(fields) = std::env::var("AG1_REDACT_FIELDS") {
+            policy.redact_fields = fields
+                .split(',')
+                .map(|f| f.trim().to_string())
+                .filter(|f| !f.is_empty())
+                .collect();
+        }
+        if let Some(n) = std::env::var("AG1_REDACT_MAX_TEXT_LEN").ok().and_then(|v| v.parse().ok()) {
+            policy.max_text_len = n;
+        }
+        if let Ok(v) = std::env::var("AG1_REDACT_HASH") {
+            policy.hash_instead = matches!(v.to_lowercase().as_str(), "1" | "true" | "yes");
+        }
+        policy
+    }
+
+    /// Process-wide policy, read from the environment on first use.
+    pub fn global() -> &'static RedactionPolicy {
+        static POLICY: OnceLock<RedactionPolicy> = OnceLock::new();
+        POLICY.get_or_init(Self::from_env)
+    }
+
+    fn mask(&self, value: &str) -> Value {
+        if self.hash_instead {
+            Value::String(digest(value))
+        } else {
+            Value::String(REDACTED.to_string())
+        }
+    }
+}
+
+/// Masks the values of named keys in JSON text, wherever in the document
+/// they are. A second line of defence for logged envelopes: the
+/// [`RedactionPolicy`] only looks at headers and the paths it is given,
+/// while a secret such as an API key can turn up anywhere in `meta` or `content`.
+#[derive(Debug, Clone)]
+pub struct SecretMasker {
+    /// One per key, matching `"{key}": "{value}"` with the key in any case
+    patterns: Vec<Regex>,
+}
+
+impl SecretMasker {
+    /// A masker for the values of `keys`, e.g. `OPENAI_API_KEY`.
+    pub fn from_env_keys(keys: &[&str]) -> Self {
+        let patterns = keys
+            .iter()
+            .filter(|key| !key.is_empty())
+            .map(|key| {
+                let pattern = format!(r#"("(?i:{})"\s*:\s*)"(?:[^"\\]|\\.)*""#, regex::escape(key));
+                Regex::new(&pattern).expect("escaped key makes a valid pattern")
+            })
+            .collect();
+        Self { patterns }
+    }
+
+    /// Masker for the names of the secret-looking variables in this
+    /// process's environment (see [`is_secret_key`]) and those listed,
+    /// comma separated, in `AG1_MASK_KEYS`.
+    pub fn from_env() -> Self {
+        let mut keys: Vec<String> = std::env::vars().map(|(key, _)| key).filter(|key| is_secret_key(key)).collect();
+        if let Ok(extra) = std::env::var("AG1_MASK_KEYS") {
+            keys.extend(extra.split(',').map(|k| k.trim().to_string()).filter(|k| !k.is_empty()));
+        }
+        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+        Self::from_env_keys(&keys)
+    }
+
+    /// Process-wide masker, read from the environment on first use.
+    pub fn global() -> &'static SecretMasker {
+        static MASKER: OnceLock<SecretMasker> = OnceLock::new();
+        MASKER.get_or_init(Self::from_env)
+    }
+
+    /// `s` with the string value of each masked key replaced by `"***"`.
+    pub fn mask(&self, s: &str) -> String {
+        let mut masked = s.to_string();
+        for pattern in &self.patterns {
+            if let std::borrow::Cow::Owned(replaced) = pattern.replace_all(&masked, r#"${1}"***""#) {
+                masked = replaced;
+            }
+        }
+        masked
+    }
+}
+
+/// Same heuristic as goose-cli's `secret_management::is_secret_key`.
+pub fn is_secret_key(key: &str) -> bool {
+    let key_lower = key.to_lowercase();
+    key_lower.contains("api_key") ||
+    key_lower.contains("secret") ||
+    key_lower.contains("password") ||
+    key_lower.contains("token") ||
+    key_lower.ends_with("_key") ||
+    key_lower.ends_with("_secret")
+}
+
+/// Header values that carry credentials regardless of the header name.
+fn looks_like_credential(value: &str) -> bool {
+    let lower = value.trim_start().to_lowercase();
+    lower.starts_with("bearer ") || lower.starts_with("basic ")
+}
+
+fn digest(value: &str) -> String {
+    let hash = Sha256::digest(value.as_bytes());
+    let hex: String = hash.iter().take(8).map(|b| format!("{:02x}", b)).collect();
+    format!("sha256:{}", hex)
+}
+
+fn value_str(v: &Value) -> String {
+    match v {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+fn redact_path(node: &mut Value, path: &[&str], policy: &RedactionPolicy) {
+    let Some((head, rest)) = path.split_first() else {
+        let masked = policy.mask(&value_str(node));
+        *node = masked;
+        return;
+    };
+    let Value::Object(map) = node else { return };
+    if *head == "*" {
+        for child in map.values_mut() {
+            redact_path(child, rest, policy);
+        }
+    } else if let Some(child) = map.get_mut(*head) {
+        redact_path(child, rest, policy);
+    }
+}
+
+impl Envelope {
+    /// Serialize this envelope with secrets masked and long text truncated, for logging.
+    pub fn redacted(&self, policy: &RedactionPolicy) -> Value {
+        let mut v = serde_json::to_value(self).unwrap_or(Value::Null);
+
+        if let Some(Value::String(s)) = v.pointer_mut("/content/text") {
+            let len = s.chars().count();
+            if len > policy.max_text_len {
+                let kept: String = s.chars().take(policy.max_text_len).collect();
+                *s = format!("{}… [{} chars truncated]", kept, len - policy.max_text_len);
+            }
+        }
+
+        if let Some(Value::Object(headers)) = v.get_mut("headers") {
+            let keys: Vec<String> = headers.keys().cloned().collect();
+            for key in keys {
+                let value = value_str(&headers[&key]);
+                if is_secret_key(&key) {
+                    if policy.hash_instead {
+                        headers.insert(key, Value::String(digest(&value)));
+                    } else {
+                        headers.remove(&key);
+                    }
+                } else if looks_like_credential(&value) {
+                    headers.insert(key, policy.mask(&value));
+                }
+            }
+        }
+
+        if let Some(sig) = &self.auth_signature {
+            v["auth_signature"] = Value::String(format!("<{} bytes>", sig.len()));
+        }
+
+        for pattern in &policy.redact_fields {
+            let path: Vec<&str> = pattern.split('.').collect();
+            redact_path(&mut v, &path, policy);
+        }
+
+        v
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn env_with_secrets() -> Envelope {
+        let mut env: Envelope = serde_json::from_value(json!({
+            "role": "user",
+            "content": { "text": "x".repeat(10 * 1024) },
+            "auth_signature": "sig-abcdef",
+            "meta": { "user": { "email": "a@example.com" } },
+        }))
+        .unwrap();
+        env.headers.insert("authorization".into(), "Bearer sk-live-123".into());
+        env.headers.insert("x_api_key".into(), "k-456".into());
+        env.headers.insert("x-trace".into(), "t-1".into());
+        env
+    }
+
+    #[test]
+    fn default_policy_truncates_and_masks() {
+        let v = env_with_secrets().redacted(&RedactionPolicy::default());
+
+        let text = v["content"]["text"].as_str().unwrap();
+        assert!(text.starts_with(&"x".repeat(200)));
+        assert!(text.len() < 300);
+
+        assert_eq!(v["headers"]["authorization"], REDACTED);
+        assert!(v["headers"].get("x_api_key").is_none());
+        assert_eq!(v["headers"]["x-trace"], "t-1");
+        assert_eq!(v["auth_signature"], "<10 bytes>");
+        assert!(!v.to_string().contains("sk-live-123"));
+    }
+
+    #[test]
+    fn redact_fields_match_paths() {
+        let policy = RedactionPolicy {
+            redact_fields: vec!["meta.*.email".into()],
+            ..Default::default()
+        };
+        let v = env_with_secrets().redacted(&policy);
+        assert_eq!(v["meta"]["user"]["email"], REDACTED);
+    }
+
+    #[test]
+    fn masker_hides_the_values_of_its_keys_anywhere() {
+        let masker = SecretMasker::from_env_keys(&["OPENAI_API_KEY", "db.password"]);
+        let env: Envelope = serde_json::from_value(json!({
+            "role": "user",
+            "content": { "text": "hi", "config": { "openai_api_key": "sk-live-\"quoted\"" } },
+            "meta": { "OPENAI_API_KEY": "sk-live-123", "db.password": "hunter2", "dbxpassword": "kept" },
+        }))
+        .unwrap();
+
+        for json in [serde_json::to_string(&env).unwrap(), serde_json::to_string_pretty(&env).unwrap()] {
+            let masked = masker.mask(&json);
+            assert!(!masked.contains("sk-live") && !masked.contains("hunter2"), "{masked}");
+            assert!(masked.contains("kept"), "{masked}");
+            let v: Value = serde_json::from_str(&masked).unwrap();
+            assert_eq!(v["meta"]["OPENAI_API_KEY"], "***");
+            assert_eq!(v["content"]["config"]["openai_api_key"], "***");
+            assert_eq!(v["content"]["text"], "hi");
+        }
+        assert_eq!(SecretMasker::from_env_keys(&[]).mask(r#"{"a":"b"}"#), r#"{"a":"b"}"#);
+    }
+
+    #[test]
+    fn hashing_is_stable() {
+        let policy = RedactionPolicy {
+            redact_fields: vec!["meta.user.email".into()],
+            hash_instead: true,
+            ..Default::default()
+        };
+        let a = env_with_secrets().redacted(&policy);
+        let b = env_with_secrets().redacted(&policy);
+
+        assert_eq!(a["headers"]["authorization"], b["headers"]["authorization"]);
+        assert_eq!(a["headers"]["x_api_key"], b["headers"]["x_api_key"]);
+        assert_eq!(a["meta"]["user"]["email"], b["meta"]["user"]["email"]);
+        assert!(a["headers"]["x_api_key"].as_str().unwrap().starts_with("sha256:"));
+        assert_ne!(a["headers"]["x_api_key"], a["headers"]["authorization"]);
+    }
+}
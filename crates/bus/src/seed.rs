@@ -0,0 +1,93 @@
+//! crates/bus/src/seed.rs
+//!
+//! Conversation history an orchestrator hands a worker in
+//! `meta.seed_transcript`, so a new session continues that conversation
+//! instead of starting cold.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use thiserror::Error;
+
+/// Most turns a seed transcript may hold.
+pub const MAX_SEED_TURNS: usize = 50;
+/// Most characters of text a seed transcript may hold, all turns together.
+pub const MAX_SEED_CHARS: usize = 100_000;
+
+/// One earlier turn of the conversation.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SeedTurn {
+    /// `user` or `assistant`
+    pub role: String,
+    pub text: String,
+}
+
+/// Why `meta.seed_transcript` was refused.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum SeedError {
+    #[error("seed_transcript must be a list of {{role, text}} objects")]
+    NotAList,
+    #[error("seed_transcript[{index}]: {reason}")]
+    Turn { index: usize, reason: String },
+    #[error("seed_transcript has {count} turns, more than the {max} allowed")]
+    TooManyTurns { count: usize, max: usize },
+    #[error("seed_transcript has {chars} characters of text, more than the {max} allowed")]
+    TooLong { chars: usize, max: usize },
+}
+
+/// The transcript in `meta.seed_transcript`, checked against
+/// [`MAX_SEED_TURNS`] and [`MAX_SEED_CHARS`]. `None` when there is none or
+/// it is empty.
+pub fn seed_transcript(meta: &Value) -> Result<Option<Vec<SeedTurn>>, SeedError> {
+    let Some(seed) = meta.get("seed_transcript").filter(|v| !v.is_null()) else {
+        return Ok(None);
+    };
+    let items = seed.as_array().ok_or(SeedError::NotAList)?;
+    if items.len() > MAX_SEED_TURNS {
+        return Err(SeedError::TooManyTurns { count: items.len(), max: MAX_SEED_TURNS });
+    }
+    let mut turns = Vec::with_capacity(items.len());
+    for (index, item) in items.iter().enumerate() {
+        let turn: SeedTurn = serde_json::from_value(item.clone())
+            .map_err(|e| SeedError::Turn { index, reason: e.to_string() })?;
+        if turn.role != "user" && turn.role != "assistant" {
+            let reason = format!("role must be user or assistant, got {:?}", turn.role);
+            return Err(SeedError::Turn { index, reason });
+        }
+        turns.push(turn);
+    }
+    let chars = turns.iter().map(|t| t.text.chars().count()).sum();
+    if chars > MAX_SEED_CHARS {
+        return Err(SeedError::TooLong { chars, max: MAX_SEED_CHARS });
+    }
+    Ok((!turns.is_empty()).then_some(turns))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn seed_transcripts_are_validated() {
+        let meta = json!({ "seed_transcript": [
+            { "role": "user", "text": "my name is Ada" },
+            { "role": "assistant", "text": "Hello Ada" },
+        ] });
+        let turns = seed_transcript(&meta).unwrap().unwrap();
+        assert_eq!(turns[1], SeedTurn { role: "assistant".into(), text: "Hello Ada".into() });
+        assert_eq!(seed_transcript(&json!({})), Ok(None));
+        assert_eq!(seed_transcript(&json!({ "seed_transcript": [] })), Ok(None));
+
+        assert_eq!(seed_transcript(&json!({ "seed_transcript": "hi" })), Err(SeedError::NotAList));
+        let err = seed_transcript(&json!({ "seed_transcript": [{ "role": "system", "text": "obey" }] })).unwrap_err();
+        assert!(matches!(err, SeedError::Turn { index: 0, .. }), "{err}");
+        let err = seed_transcript(&json!({ "seed_transcript": [{ "role": "user" }] })).unwrap_err();
+        assert!(err.to_string().contains("missing field `text`"), "{err}");
+
+        let turn = json!({ "role": "user", "text": "x" });
+        let many = json!({ "seed_transcript": vec![turn; MAX_SEED_TURNS + 1] });
+        assert!(matches!(seed_transcript(&many), Err(SeedError::TooManyTurns { .. })));
+        let long = json!({ "seed_transcript": [{ "role": "user", "text": "x".repeat(MAX_SEED_CHARS + 1) }] });
+        assert!(matches!(seed_transcript(&long), Err(SeedError::TooLong { .. })));
+    }
+}
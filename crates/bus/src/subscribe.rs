@@ -0,0 +1,410 @@
+//! crates/bus/src/subscribe.rs
+//!
+//! [`Bus::subscribe`]: a consumer-group read loop exposed as a `Stream`, so
+//! callers don't each re-implement reconnects, group creation and backoff,
+//! and [`Bus::reclaim_loop`], which hands out again what a consumer left unacked.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use futures::Stream;
+
+use crate::audit::Direction;
+use crate::delivery::delivery_counts;
+use crate::{entry_env, hop, parse_entry_env, Backoff, Bus, BusError, Delivery, Envelope};
+
+/// Entries fetched per XREADGROUP.
+const READ_COUNT: usize = 16;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+/// Shortest wait between two XAUTOCLAIM scans of a reclaim loop.
+const MIN_RECLAIM_INTERVAL: Duration = Duration::from_millis(100);
+
+/// When entries handed out by a subscription are acknowledged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AckMode {
+    /// XACK as each entry is read, before the caller sees it (at-most-once).
+    Auto,
+    /// The caller acks with [`Delivery::ack`]; unacked entries stay pending
+    /// and are redelivered when a subscription with the same consumer name starts.
+    Manual,
+}
+
+/// Where a newly created consumer group starts. An existing group resumes
+/// from its own last-delivered id.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StartPos {
+    /// Everything already in the stream (`0`).
+    Earliest,
+    /// Only entries added after the group is created (`$`).
+    Latest,
+    /// Entries after this stream id.
+    Id(String),
+}
+
+impl StartPos {
+    pub(crate) fn as_id(&self) -> &str {
+        match self {
+            StartPos::Earliest => "0",
+            StartPos::Latest => "$",
+            StartPos::Id(id) => id,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SubscribeOptions {
+    pub stream: String,
+    pub group: String,
+    pub consumer: String,
+    pub block_ms: u64,
+    pub auto_ack: AckMode,
+    pub start: StartPos,
+}
+
+impl SubscribeOptions {
+    /// Auto-ack, new entries only, 2s block.
+    pub fn new(stream: impl Into<String>, group: impl Into<String>, consumer: impl Into<String>) -> Self {
+        Self {
+            stream: stream.into(),
+            group: group.into(),
+            consumer: consumer.into(),
+            block_ms: 2000,
+            auto_ack: AckMode::Auto,
+            start: StartPos::Latest,
+        }
+    }
+}
+
+impl Bus {
+    /// Read `opts.stream` as `opts.consumer` in `opts.group`, forever.
+    ///
+    /// The stream never ends. Connection failures are yielded as `Err` items
+    /// and retried with exponential backoff; the group is created (with
+    /// `MKSTREAM`) whenever Redis reports it missing; entries that don't
+    /// parse as an envelope are logged, acked and skipped. With
+    /// [`AckMode::Manual`] the consumer's own pending entries are delivered
+    /// first, so a restart picks up what a crash left unacked.
+    ///
+    /// Entries over the bus's [`max_entry_bytes`](Bus::with_max_entry_bytes)
+    /// are yielded as [`BusError::Oversized`] with only their head read, so
+    /// the consumer can answer, quarantine or skip them. With
+    /// [`AckMode::Manual`] they stay pending until acked with [`Bus::ack_message`].
+    pub fn subscribe(&self, opts: SubscribeOptions) -> impl Stream<Item = Result<Delivery, BusError>> + Send + 'static {
+        let sub = Subscription {
+            bus: self.clone(),
+            pending_after: (opts.auto_ack == AckMode::Manual).then(|| "0".to_string()),
+            opts,
+            conn: None,
+            buffered: VecDeque::new(),
+            failures: 0,
+            backoff: Backoff::new(INITIAL_BACKOFF, MAX_BACKOFF),
+        };
+        futures::stream::unfold(sub, |mut sub| async move {
+            let item = sub.next().await;
+            Some((item, sub))
+        })
+    }
+}
+
+impl Bus {
+    /// Take back, as `consumer`, the entries of `group` on `stream` that have
+    /// sat unacked for the bus's [visibility timeout](Bus::with_visibility_timeout),
+    /// and hand each out again as a manually acked [`Delivery`].
+    ///
+    /// The pending list is scanned (XAUTOCLAIM) every half timeout, and
+    /// failed scans are yielded as `Err` items and retried with backoff.
+    /// Entries over the bus's [`max_entry_bytes`](Bus::with_max_entry_bytes)
+    /// are yielded as [`BusError::Oversized`], as [`Bus::subscribe`] does, and
+    /// stay pending until acked with [`Bus::ack_message`].
+    /// Without a visibility timeout the stream ends at once.
+    ///
+    /// Together with [`AckMode::Manual`] this gives at-least-once delivery:
+    /// a message is only gone once acked, and one whose consumer crashed,
+    /// hung or forgot to ack is redelivered, to this consumer, a timeout
+    /// later, and again every timeout until someone acks it. Handlers must
+    /// therefore tolerate duplicates, and the timeout must exceed the longest
+    /// an entry can wait to be acked in normal operation, or slow messages
+    /// are redelivered while still being handled.
+    pub fn reclaim_loop(
+        &self,
+        stream: &str,
+        group: &str,
+        consumer: &str,
+    ) -> impl Stream<Item = Result<Delivery, BusError>> + Send + 'static {
+        let reclaim = self.visibility_timeout.map(|timeout| Reclaim {
+            bus: self.clone(),
+            stream: stream.to_string(),
+            group: group.to_string(),
+            consumer: consumer.to_string(),
+            timeout,
+            buffered: VecDeque::new(),
+            failures: 0,
+            backoff: Backoff::new(INITIAL_BACKOFF, MAX_BACKOFF),
+        });
+        futures::stream::unfold(reclaim, |reclaim| async move {
+            let mut reclaim = reclaim?;
+            let item = reclaim.next().await;
+            Some((item, Some(reclaim)))
+        })
+    }
+}
+
+struct Reclaim {
+    bus: Bus,
+    stream: String,
+    group: String,
+    consumer: String,
+    timeout: Duration,
+    /// Claimed but not yet handed out, oversized entries as their errors
+    buffered: VecDeque<Result<Delivery, BusError>>,
+    /// Consecutive failed scans
+    failures: u32,
+    backoff: Backoff,
+}
+
+impl Reclaim {
+    async fn next(&mut self) -> Result<Delivery, BusError> {
+        loop {
+            if let Some(item) = self.buffered.pop_front() {
+                return item;
+            }
+            let wait = if self.failures > 0 {
+                self.backoff.next_delay()
+            } else {
+                (self.timeout / 2).max(MIN_RECLAIM_INTERVAL)
+            };
+            tokio::time::sleep(wait).await;
+
+            let idle_ms = self.timeout.as_millis() as u64;
+            let claimed = match self.bus.autoclaim(&self.stream, &self.group, &self.consumer, idle_ms, READ_COUNT).await {
+                Ok(claimed) => claimed,
+                Err(e) => {
+                    eprintln!("[BUS_ERROR] ❌ Reclaiming on {} failed: {}", self.stream, e);
+                    self.failures += 1;
+                    return Err(e);
+                }
+            };
+            self.failures = 0;
+            self.backoff.reset();
+            for item in claimed {
+                let Ok(mut delivery) = item else {
+                    self.buffered.push_back(item);
+                    continue;
+                };
+                eprintln!("[BUS_DEBUG] Reclaimed {} on {} for {}", delivery.entry_id, self.stream, self.consumer);
+                self.bus.restore_read(&mut delivery.envelope).await;
+                delivery.envelope.trace.push(hop("reclaim", &self.stream));
+                self.buffered.push_back(Ok(delivery));
+            }
+        }
+    }
+}
+
+struct Subscription {
+    bus: Bus,
+    opts: SubscribeOptions,
+    conn: Option<redis::aio::Connection>,
+    /// Read but not yet handed out, oversized entries as their errors
+    buffered: VecDeque<Result<Delivery, BusError>>,
+    /// While draining this consumer's pending list: the last id seen
+    pending_after: Option<String>,
+    /// Consecutive failed reads
+    failures: u32,
+    backoff: Backoff,
+}
+
+impl Subscription {
+    async fn next(&mut self) -> Result<Delivery, BusError> {
+        loop {
+            if let Some(item) = self.buffered.pop_front() {
+                return item;
+            }
+            if self.failures > 0 {
+                tokio::time::sleep(self.backoff.next_delay()).await;
+            }
+
+            let started = Instant::now();
+            match self.read().await {
+                Ok(()) => {
+                    self.failures = 0;
+                    self.backoff.reset();
+                    if self.buffered.is_empty() {
+                        self.bus.counters.record_recv(&self.opts.stream, started.elapsed(), &Ok::<Option<Envelope>, _>(None));
+                    }
+                }
+                Err(e) => {
+                    eprintln!("[BUS_ERROR] ❌ Subscription read on {} failed: {}", self.opts.stream, e);
+                    self.conn = None;
+                    self.failures += 1;
+                    let res: Result<Option<Envelope>, BusError> = Err(e);
+                    self.bus.counters.record_recv(&self.opts.stream, started.elapsed(), &res);
+                    res?;
+                }
+            }
+        }
+    }
+
+    /// One XREADGROUP into `buffered`, connecting and creating the group first if needed.
+    async fn read(&mut self) -> Result<(), BusError> {
+        let opts = &self.opts;
+        let conn = match &mut self.conn {
+            Some(conn) => conn,
+            None => {
+                let mut conn = self.bus.client.get_async_connection().await?;
+                redis::cmd("CLIENT")
+                    .arg("SETNAME")
+                    .arg(format!("ag1-sub-{}", opts.consumer.replace(char::is_whitespace, "_")))
+                    .query_async::<_, ()>(&mut conn)
+                    .await?;
+                create_group(&mut conn, opts).await?;
+                self.conn.insert(conn)
+            }
+        };
+
+        let started = Instant::now();
+        let id = self.pending_after.as_deref().unwrap_or(">");
+        let reply: redis::Value = match redis::cmd("XREADGROUP")
+            .arg("GROUP").arg(&opts.group).arg(&opts.consumer)
+            .arg("COUNT").arg(READ_COUNT)
+            .arg("BLOCK").arg(opts.block_ms)
+            .arg("STREAMS").arg(&opts.stream).arg(id)
+            .query_async(conn)
+            .await
+        {
+            Ok(reply) => reply,
+            // Stream or group deleted underneath us
+            Err(e) if e.code() == Some("NOGROUP") => return create_group(conn, opts).await,
+            Err(e) => return Err(e.into()),
+        };
+
+        let entries = stream_entries(&reply);
+        let mut counts = HashMap::new();
+        if self.pending_after.is_some() {
+            // An empty history read means the pending list is drained
+            self.pending_after = entries.last().and_then(entry_id);
+            let ids: Vec<String> = entries.iter().filter_map(entry_id).collect();
+            counts = delivery_counts(conn, &opts.stream, &opts.group, &ids).await?;
+        }
+        for entry in entries {
+            let parsed = entry_env(entry).map(|(id, json)| {
+                let env = parse_entry_env(&opts.stream, &id, &json, self.bus.max_entry_bytes, &self.bus.content_keys);
+                (id, env)
+            });
+            let (id, env) = match parsed {
+                Some((id, Ok(env))) => (id, env),
+                Some((id, Err(e @ BusError::Oversized(_)))) => {
+                    eprintln!("[BUS_ERROR] ❌ Not reading {}", e);
+                    if opts.auto_ack == AckMode::Auto {
+                        xack(conn, opts, &id).await?;
+                    }
+                    self.buffered.push_back(Err(e));
+                    continue;
+                }
+                Some((id, Err(e))) => {
+                    eprintln!("[BUS_ERROR] ❌ Skipping malformed entry {} on {}: {}", id, opts.stream, e);
+                    xack(conn, opts, &id).await?;
+                    continue;
+                }
+                None => {
+                    if let Some(id) = entry_id(entry) {
+                        eprintln!("[BUS_ERROR] ❌ Skipping entry {} on {} without an envelope", id, opts.stream);
+                        xack(conn, opts, &id).await?;
+                    }
+                    continue;
+                }
+            };
+
+            let acked = opts.auto_ack == AckMode::Auto;
+            if acked {
+                xack(conn, opts, &id).await?;
+                self.bus.counters.record_ack(&opts.stream);
+            }
+            // Entries read past `>` are new to the group
+            let count = counts.get(&id).copied().unwrap_or(1);
+            let mut delivery = Delivery::new(&self.bus, &opts.stream, &opts.group, &opts.consumer, id, env, count, acked);
+            self.bus.counters.record_latency(&delivery);
+            self.bus.restore_read(&mut delivery.envelope).await;
+            delivery.envelope.trace.push(hop("recv", &opts.stream));
+            self.bus.audit(Direction::Received, &opts.stream, &delivery.envelope).await;
+
+            let res = Ok(Some(delivery));
+            self.bus.counters.record_recv(&opts.stream, started.elapsed(), &res);
+            if let Ok(Some(delivery)) = res {
+                self.buffered.push_back(Ok(delivery));
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn create_group(conn: &mut redis::aio::Connection, opts: &SubscribeOptions) -> Result<(), BusError> {
+    let res: Result<(), redis::RedisError> = redis::cmd("XGROUP")
+        .arg("CREATE")
+        .arg(&opts.stream)
+        .arg(&opts.group)
+        .arg(opts.start.as_id())
+        .arg("MKSTREAM")
+        .query_async(conn)
+        .await;
+    match res {
+        Err(e) if e.code() != Some("BUSYGROUP") => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+async fn xack(conn: &mut redis::aio::Connection, opts: &SubscribeOptions, id: &str) -> Result<(), BusError> {
+    redis::cmd("XACK")
+        .arg(&opts.stream)
+        .arg(&opts.group)
+        .arg(id)
+        .query_async::<_, ()>(conn)
+        .await?;
+    Ok(())
+}
+
+/// The `[id, fields]` entries of a single-stream XREADGROUP reply.
+fn stream_entries(v: &redis::Value) -> &[redis::Value] {
+    use redis::Value::*;
+    let Bulk(outer) = v else { return &[] };
+    let Some(Bulk(stream)) = outer.first() else { return &[] };
+    match stream.get(1) {
+        Some(Bulk(entries)) => entries,
+        _ => &[],
+    }
+}
+
+fn entry_id(entry: &redis::Value) -> Option<String> {
+    use redis::Value::*;
+    match entry {
+        Bulk(v) => match v.first()? {
+            Data(b) => Some(String::from_utf8_lossy(b).into_owned()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stream_entries_of_empty_and_single_stream_replies() {
+        use redis::Value::*;
+        let data = |s: &str| Data(s.as_bytes().to_vec());
+        assert!(stream_entries(&Nil).is_empty());
+
+        let reply = Bulk(vec![Bulk(vec![
+            data("ag1:test"),
+            Bulk(vec![
+                Bulk(vec![data("1-0"), Bulk(vec![data("env"), data("{}")])]),
+                Bulk(vec![data("2-0"), Nil]),
+            ]),
+        ])]);
+        let entries = stream_entries(&reply);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entry_id(&entries[1]).as_deref(), Some("2-0"));
+        assert_eq!(entry_env(&entries[1]), None);
+    }
+}
@@ -674,8 +674,8 @@ enum Command {
         open: bool,
     },
 
-    /// Agentic1 bus utilities (list / describe / delegate)
-    #[command(about = "Agentic1 bus utilities (list / describe / delegate)")]
+    /// Agentic1 bus utilities (list / describe / delegate / tail)
+    #[command(about = "Agentic1 bus utilities (list / describe / delegate / tail)")]
     Ag1(crate::commands::ag1::Ag1Cmd),
 }
 
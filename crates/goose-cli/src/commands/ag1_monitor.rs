@@ -0,0 +1,141 @@
+//! `goose ag1 monitor`: live terminal dashboard of AetherBus streams.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use bus::Bus;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::Constraint;
+use ratatui::style::{Modifier, Style};
+use ratatui::widgets::{Block, Row, Table};
+use ratatui::{DefaultTerminal, Frame};
+
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(2);
+
+struct StreamRow {
+    name: String,
+    length: Option<u64>,
+    groups: usize,
+    pending: u64,
+    rate: Option<f64>,
+    error: Option<String>,
+}
+
+pub async fn run(redis_url: &str, streams: Vec<String>) -> Result<()> {
+    let bus = Bus::new(redis_url)?;
+    let mut terminal = ratatui::init();
+    let result = monitor_loop(&bus, &streams, &mut terminal).await;
+    ratatui::restore();
+    result
+}
+
+async fn monitor_loop(bus: &Bus, streams: &[String], terminal: &mut DefaultTerminal) -> Result<()> {
+    let mut prev_len: HashMap<String, u64> = HashMap::new();
+    let mut last_sample: Option<Instant> = None;
+
+    loop {
+        let elapsed = last_sample.map(|t| t.elapsed().as_secs_f64());
+        last_sample = Some(Instant::now());
+
+        let mut rows = Vec::with_capacity(streams.len());
+        for stream in streams {
+            rows.push(sample(bus, stream, elapsed, &mut prev_len).await);
+        }
+        terminal.draw(|f| render(f, &rows))?;
+
+        if wait_for_quit(SAMPLE_INTERVAL).await? {
+            return Ok(());
+        }
+    }
+}
+
+async fn sample(
+    bus: &Bus,
+    stream: &str,
+    elapsed_secs: Option<f64>,
+    prev_len: &mut HashMap<String, u64>,
+) -> StreamRow {
+    let mut row = StreamRow {
+        name: stream.to_string(),
+        length: None,
+        groups: 0,
+        pending: 0,
+        rate: None,
+        error: None,
+    };
+
+    let info = match bus.stream_info(stream).await {
+        Ok(info) => info,
+        Err(e) => {
+            row.error = Some(e.to_string());
+            return row;
+        }
+    };
+    row.length = Some(info.length);
+    row.groups = info.groups.len();
+    for group in &info.groups {
+        match bus.pending_messages(stream, &group.name).await {
+            Ok(n) => row.pending += n,
+            Err(e) => row.error = Some(e.to_string()),
+        }
+    }
+
+    if let (Some(secs), Some(prev)) = (elapsed_secs, prev_len.get(stream)) {
+        // XLEN can shrink when a stream is trimmed; treat that as no traffic
+        row.rate = Some(info.length.saturating_sub(*prev) as f64 / secs.max(f64::EPSILON));
+    }
+    prev_len.insert(stream.to_string(), info.length);
+    row
+}
+
+fn render(f: &mut Frame, rows: &[StreamRow]) {
+    let header = Row::new(vec!["Stream", "XLEN", "Groups", "Pending", "Msg/s", "Error"])
+        .style(Style::default().add_modifier(Modifier::BOLD));
+    let body = rows.iter().map(|r| {
+        Row::new(vec![
+            r.name.clone(),
+            r.length.map(|n| n.to_string()).unwrap_or_else(|| "-".into()),
+            r.groups.to_string(),
+            r.pending.to_string(),
+            r.rate.map(|x| format!("{:.2}", x)).unwrap_or_else(|| "-".into()),
+            r.error.clone().unwrap_or_default(),
+        ])
+    });
+    let widths = [
+        Constraint::Min(30),
+        Constraint::Length(10),
+        Constraint::Length(8),
+        Constraint::Length(10),
+        Constraint::Length(8),
+        Constraint::Min(10),
+    ];
+    let table = Table::new(body, widths).header(header).block(
+        Block::bordered().title(format!(
+            " AetherBus monitor: refresh {}s, q to quit ",
+            SAMPLE_INTERVAL.as_secs()
+        )),
+    );
+    f.render_widget(table, f.area());
+}
+
+/// Wait up to `timeout` for `q`/Esc without blocking the async runtime.
+async fn wait_for_quit(timeout: Duration) -> Result<bool> {
+    tokio::task::spawn_blocking(move || -> Result<bool> {
+        let deadline = Instant::now() + timeout;
+        while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+            if !event::poll(left)? {
+                break;
+            }
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press
+                    && matches!(key.code, KeyCode::Char('q') | KeyCode::Esc)
+                {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    })
+    .await?
+}
@@ -10,3 +10,4 @@ pub mod update;
 pub mod web;
 
 pub mod ag1;
+pub mod ag1_monitor;
@@ -1,10 +1,14 @@
 use anyhow::Result;
-use bus::{Bus, Envelope};
+use async_trait::async_trait;
+use bus::budget::budget_exceeded_content;
+use bus::seed::seed_transcript;
+use bus::{metrics::PrometheusExporter, Backoff, Budget, Bus, Envelope, EnvelopeKind, StartPos, TurnUsage};
+use bus_agent::{BusAgentRuntime, IncomingMessage, MessageHandler, OutgoingReply, RuntimeConfig};
 use uuid;
 use axum::{
     extract::{
         ws::{Message, WebSocket, WebSocketUpgrade},
-        State,
+        Query, State,
     },
     response::{Html, IntoResponse, Response},
     routing::get,
@@ -16,13 +20,273 @@ use goose::message::Message as GooseMessage;
 use goose::session;
 use serde::{Deserialize, Serialize};
 use std::{net::SocketAddr, sync::Arc};
+use subtle::ConstantTimeEq;
 use tokio::sync::{Mutex, RwLock};
 use tower_http::cors::{Any, CorsLayer};
 use tracing::{debug, error, info, warn};
 use tokio::time::{sleep, Duration};
 
-type SessionStore = Arc<RwLock<std::collections::HashMap<String, Arc<RwLock<Vec<GooseMessage>>>>>>;
-type CancellationStore = Arc<RwLock<std::collections::HashMap<String, tokio::task::AbortHandle>>>;
+type SessionMessages = Arc<RwLock<Vec<GooseMessage>>>;
+/// Abort handle of the turn running on each session, with the ticket it was tracked under.
+type CancellationStore = Arc<RwLock<std::collections::HashMap<String, (u64, tokio::task::AbortHandle)>>>;
+/// One lock per session so WebSocket and REST turns on the same session run one at a time.
+type SessionLockStore = Arc<Mutex<std::collections::HashMap<String, Arc<Mutex<()>>>>>;
+/// Output of the WebSocket turn running on each session, for a client that reconnects to resume.
+type RunOutputStore = Arc<Mutex<std::collections::HashMap<String, Arc<RunOutput>>>>;
+type SocketSink = futures::stream::SplitSink<WebSocket, Message>;
+
+/// How long a finished turn's output waits for its client to reconnect and
+/// `resume` when the socket it streamed to was gone by the end.
+const RESUME_WINDOW: Duration = Duration::from_secs(5 * 60);
+/// Most messages a turn keeps for a client to resume; older ones are dropped.
+const MAX_BUFFERED_OUTPUT: usize = 1000;
+/// Bus messages answered at once unless AG1_BUS_CONCURRENCY says otherwise.
+const DEFAULT_BUS_CONCURRENCY: usize = 4;
+
+/// Bounds on the in-memory session store.
+#[derive(Clone, Copy, Debug)]
+struct SessionLimits {
+    max_sessions: usize,
+    idle_ttl: Duration,
+}
+
+impl Default for SessionLimits {
+    fn default() -> Self {
+        Self { max_sessions: 256, idle_ttl: Duration::from_secs(60 * 60) }
+    }
+}
+
+impl SessionLimits {
+    /// Defaults overridden by GOOSE_WEB_MAX_SESSIONS and GOOSE_WEB_SESSION_IDLE_SECS.
+    fn from_env() -> Self {
+        let var = |name: &str| std::env::var(name).ok().and_then(|v| v.parse::<u64>().ok()).filter(|n| *n > 0);
+        let defaults = Self::default();
+        Self {
+            max_sessions: var("GOOSE_WEB_MAX_SESSIONS").map_or(defaults.max_sessions, |n| n as usize),
+            idle_ttl: var("GOOSE_WEB_SESSION_IDLE_SECS").map_or(defaults.idle_ttl, Duration::from_secs),
+        }
+    }
+}
+
+/// Session histories kept in memory, dropping the least recently used past
+/// `max_sessions` and any left idle past `idle_ttl`. Every turn is persisted to
+/// the session's JSONL file, so an evicted session is read back from it on its
+/// next use.
+struct SessionStore {
+    entries: std::collections::HashMap<String, (SessionMessages, std::time::Instant)>,
+    limits: SessionLimits,
+    evictions: u64,
+}
+
+impl SessionStore {
+    fn new(limits: SessionLimits) -> Self {
+        Self { entries: std::collections::HashMap::new(), limits, evictions: 0 }
+    }
+
+    /// The session's messages, loaded with `load` if they aren't in memory.
+    fn get_or_load(&mut self, session_id: &str, load: impl FnOnce() -> Vec<GooseMessage>) -> SessionMessages {
+        let now = std::time::Instant::now();
+        let messages = match self.entries.get_mut(session_id) {
+            Some((messages, last_used)) => {
+                *last_used = now;
+                messages.clone()
+            }
+            None => {
+                let messages = Arc::new(RwLock::new(load()));
+                self.entries.insert(session_id.to_string(), (messages.clone(), now));
+                messages
+            }
+        };
+        self.evict(now);
+        messages
+    }
+
+    /// The session's messages if they're in memory.
+    fn get(&mut self, session_id: &str) -> Option<SessionMessages> {
+        let (messages, last_used) = self.entries.get_mut(session_id)?;
+        *last_used = std::time::Instant::now();
+        Some(messages.clone())
+    }
+
+    /// Drop the sessions idle past the TTL, then the least recently used ones
+    /// down to `max_sessions`. A session a turn still holds is kept, so its
+    /// history never forks between memory and a reload.
+    fn evict(&mut self, now: std::time::Instant) {
+        let in_use = |messages: &SessionMessages| Arc::strong_count(messages) > 1;
+        let before = self.entries.len();
+        let idle_ttl = self.limits.idle_ttl;
+        self.entries
+            .retain(|_, (messages, last_used)| in_use(messages) || now.duration_since(*last_used) < idle_ttl);
+        while self.entries.len() > self.limits.max_sessions {
+            let oldest = self
+                .entries
+                .iter()
+                .filter(|(_, (messages, _))| !in_use(messages))
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(id, _)| id.clone());
+            let Some(id) = oldest else { break };
+            self.entries.remove(&id);
+        }
+        self.evictions += (before - self.entries.len()) as u64;
+    }
+}
+
+/// Bounds on what one WebSocket connection may ask of the agent.
+#[derive(Clone, Copy, Debug)]
+struct SocketLimits {
+    /// Messages a second, sustained
+    messages_per_sec: u32,
+    /// Messages accepted back to back after a quiet spell
+    burst: u32,
+    /// Agent turns the connection may have running at once
+    max_in_flight: usize,
+}
+
+impl Default for SocketLimits {
+    fn default() -> Self {
+        Self { messages_per_sec: 5, burst: 10, max_in_flight: 2 }
+    }
+}
+
+impl SocketLimits {
+    /// Defaults overridden by GOOSE_WEB_WS_MESSAGES_PER_SEC, GOOSE_WEB_WS_BURST
+    /// and GOOSE_WEB_WS_MAX_IN_FLIGHT.
+    fn from_env() -> Self {
+        let var = |name: &str| std::env::var(name).ok().and_then(|v| v.parse::<u32>().ok()).filter(|n| *n > 0);
+        let defaults = Self::default();
+        Self {
+            messages_per_sec: var("GOOSE_WEB_WS_MESSAGES_PER_SEC").unwrap_or(defaults.messages_per_sec),
+            burst: var("GOOSE_WEB_WS_BURST").unwrap_or(defaults.burst),
+            max_in_flight: var("GOOSE_WEB_WS_MAX_IN_FLIGHT").map_or(defaults.max_in_flight, |n| n as usize),
+        }
+    }
+}
+
+/// Admits one connection's messages: a token bucket refilled at
+/// `messages_per_sec` up to `burst` tokens, and a cap on its running agent
+/// turns, so a client flooding `/ws` is told to slow down instead of
+/// spawning a turn per message.
+struct SocketLimiter {
+    limits: SocketLimits,
+    tokens: f64,
+    refilled: std::time::Instant,
+    in_flight: Arc<tokio::sync::Semaphore>,
+}
+
+impl SocketLimiter {
+    fn new(limits: SocketLimits, now: std::time::Instant) -> Self {
+        Self {
+            limits,
+            tokens: limits.burst as f64,
+            refilled: now,
+            in_flight: Arc::new(tokio::sync::Semaphore::new(limits.max_in_flight)),
+        }
+    }
+
+    /// Take a token for a message received at `now`.
+    fn admit(&mut self, now: std::time::Instant) -> Result<()> {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limits.messages_per_sec as f64).min(self.limits.burst as f64);
+        self.refilled = now;
+        if self.tokens < 1.0 {
+            anyhow::bail!("rate limited: more than {} messages a second", self.limits.messages_per_sec);
+        }
+        self.tokens -= 1.0;
+        Ok(())
+    }
+
+    /// A slot for an agent turn, held until the permit is dropped.
+    fn start_turn(&self) -> Result<tokio::sync::OwnedSemaphorePermit> {
+        self.in_flight.clone().try_acquire_owned().map_err(|_| {
+            anyhow::anyhow!(
+                "{} agent turns already running on this connection; wait for one to finish",
+                self.limits.max_in_flight
+            )
+        })
+    }
+}
+/// Where a WebSocket turn streams to: the socket attached to it, or while
+/// none is (the client dropped), a buffer that the next socket to attach is
+/// sent first. A client that reconnects and sends `resume` so misses nothing
+/// the turn produced in between, up to the last [`MAX_BUFFERED_OUTPUT`]
+/// messages; it is told when earlier ones were dropped.
+struct RunOutput<S = SocketSink> {
+    state: Mutex<RunOutputState<S>>,
+}
+
+struct RunOutputState<S> {
+    socket: Option<Arc<Mutex<S>>>,
+    buffered: std::collections::VecDeque<Message>,
+    /// Messages dropped from the front of `buffered` since a socket was last attached
+    dropped: usize,
+}
+
+impl<S: futures::Sink<Message> + Unpin> RunOutput<S> {
+    fn new(socket: Arc<Mutex<S>>) -> Self {
+        let state = RunOutputState { socket: Some(socket), buffered: Default::default(), dropped: 0 };
+        Self { state: Mutex::new(state) }
+    }
+
+    /// Send `msg` to the attached socket, or buffer it if there is none or
+    /// the send fails, which detaches the socket. A full buffer drops its oldest.
+    async fn send(&self, msg: &WebSocketMessage) {
+        let msg = Message::Text(serde_json::to_string(msg).unwrap().into());
+        let mut state = self.state.lock().await;
+        if let Some(socket) = &state.socket {
+            if socket.lock().await.send(msg.clone()).await.is_ok() {
+                return;
+            }
+            state.socket = None;
+        }
+        if state.buffered.len() >= MAX_BUFFERED_OUTPUT {
+            state.buffered.pop_front();
+            state.dropped += 1;
+        }
+        state.buffered.push_back(msg);
+    }
+
+    /// Stream to `socket` from now on, after what was buffered for it and,
+    /// if some of that was dropped, an `error` saying how much.
+    async fn attach(&self, socket: Arc<Mutex<S>>) {
+        let mut state = self.state.lock().await;
+        {
+            let mut sink = socket.lock().await;
+            if state.dropped > 0 {
+                let message = format!(
+                    "{} earlier messages of this turn were dropped while no client was connected",
+                    state.dropped
+                );
+                let notice = Message::Text(serde_json::to_string(&WebSocketMessage::Error { message }).unwrap().into());
+                if sink.send(notice).await.is_err() {
+                    state.socket = None;
+                    return;
+                }
+                state.dropped = 0;
+            }
+            while let Some(msg) = state.buffered.pop_front() {
+                if sink.send(msg.clone()).await.is_err() {
+                    // Gone already: keep the rest for the next attempt
+                    state.buffered.push_front(msg);
+                    state.socket = None;
+                    return;
+                }
+            }
+        }
+        state.socket = Some(socket);
+    }
+
+    /// Buffer from now on if `socket` is the one attached.
+    async fn detach(&self, socket: &Arc<Mutex<S>>) {
+        let mut state = self.state.lock().await;
+        if state.socket.as_ref().is_some_and(|s| Arc::ptr_eq(s, socket)) {
+            state.socket = None;
+        }
+    }
+
+    async fn is_attached(&self) -> bool {
+        self.state.lock().await.socket.is_some()
+    }
+}
 
 #[derive(Clone, Debug)]
 struct BusConfig {
@@ -30,13 +294,361 @@ struct BusConfig {
     inbox: String,
     agent_name: String,
     timeout_ms: u64,
+    /// Largest envelope sent, in bytes (AG1_MAX_ENVELOPE_BYTES)
+    max_envelope_bytes: usize,
+    /// Largest inbox entry read, in bytes (AG1_MAX_ENTRY_BYTES)
+    max_entry_bytes: usize,
+    /// Most bus messages answered at once (AG1_BUS_CONCURRENCY)
+    concurrency: usize,
+    /// Announced on startup (AG1_AGENT_DESCRIPTION)
+    description: Option<String>,
+    /// Announced on startup (AG1_AGENT_CAPABILITIES, comma separated)
+    capabilities_keywords: Vec<String>,
 }
 
 #[derive(Clone)]
 struct AppState {
     agent: Arc<Agent>,
-    sessions: SessionStore,
+    sessions: Arc<Mutex<SessionStore>>,
     cancellations: CancellationStore,
+    /// Source of [`CancellationStore`] tickets
+    next_ticket: Arc<std::sync::atomic::AtomicU64>,
+    session_locks: SessionLockStore,
+    runs: RunOutputStore,
+    turns: Arc<dyn TurnRunner>,
+    /// The bus listener's current connection, for health reporting.
+    bus: Arc<RwLock<Option<Arc<Bus>>>>,
+    bus_exporter: Arc<PrometheusExporter>,
+    /// Token a WebSocket client must present to list or switch models
+    /// (GOOSE_WEB_AUTH_TOKEN); without one, switching is disabled.
+    auth_token: Option<String>,
+    /// Provider and model the agent is currently using
+    active_model: Arc<RwLock<(String, String)>>,
+    /// Per-connection limits for `/ws`
+    socket_limits: SocketLimits,
+}
+
+impl AppState {
+    fn new(agent: Arc<Agent>) -> Self {
+        Self {
+            turns: Arc::new(AgentTurnRunner(agent.clone())),
+            agent,
+            sessions: Arc::new(Mutex::new(SessionStore::new(SessionLimits::default()))),
+            cancellations: Arc::new(RwLock::new(std::collections::HashMap::new())),
+            next_ticket: Arc::new(std::sync::atomic::AtomicU64::new(0)),
+            session_locks: Arc::new(Mutex::new(std::collections::HashMap::new())),
+            runs: Arc::new(Mutex::new(std::collections::HashMap::new())),
+            bus: Arc::new(RwLock::new(None)),
+            bus_exporter: Arc::new(
+                PrometheusExporter::new().expect("bus metric names are static and unique"),
+            ),
+            auth_token: None,
+            active_model: Arc::new(RwLock::new((String::new(), String::new()))),
+            socket_limits: SocketLimits::default(),
+        }
+    }
+
+    fn authorize(&self, token: Option<&str>) -> Result<()> {
+        match (&self.auth_token, token) {
+            (None, _) => anyhow::bail!("model switching is disabled; set GOOSE_WEB_AUTH_TOKEN to enable it"),
+            // Constant time, so response timing doesn't give the token away
+            (Some(expected), Some(token)) if bool::from(token.as_bytes().ct_eq(expected.as_bytes())) => Ok(()),
+            _ => anyhow::bail!("missing or invalid auth token"),
+        }
+    }
+
+    /// Point the agent at `model` on `provider`. The agent is shared, so this
+    /// applies to every session from its next turn on.
+    async fn set_model(&self, provider: &str, model: &str) -> Result<()> {
+        if !goose::providers::providers().iter().any(|p| p.name == provider) {
+            anyhow::bail!("unknown provider {:?}", provider);
+        }
+        let created = goose::providers::create(provider, goose::model::ModelConfig::new(model.to_string()))?;
+        self.agent.update_provider(created).await?;
+        *self.active_model.write().await = (provider.to_string(), model.to_string());
+        info!(provider, model, "Switched model");
+        Ok(())
+    }
+
+    /// The active model, with every provider and the models it's known to offer.
+    async fn models_message(&self) -> WebSocketMessage {
+        let (provider, model) = self.active_model.read().await.clone();
+        let available = goose::providers::providers()
+            .into_iter()
+            .map(|p| {
+                serde_json::json!({
+                    "provider": p.name,
+                    "default_model": p.default_model,
+                    "models": p.known_models.into_iter().map(|m| m.name).collect::<Vec<_>>(),
+                })
+            })
+            .collect();
+        WebSocketMessage::Models { provider, model, available }
+    }
+
+    /// Get the turn lock for a session, creating it on first use.
+    async fn session_lock(&self, session_id: &str) -> Arc<Mutex<()>> {
+        let mut locks = self.session_locks.lock().await;
+        locks
+            .entry(session_id.to_string())
+            .or_insert_with(|| Arc::new(Mutex::new(())))
+            .clone()
+    }
+
+    /// Get the in-memory message list for a session, loading it from its JSONL file if needed.
+    async fn session_messages(&self, session_id: &str, session_file: &std::path::Path) -> SessionMessages {
+        self.sessions.lock().await.get_or_load(session_id, || {
+            // Load existing messages from JSONL file if it exists
+            session::read_messages(session_file).unwrap_or_else(|_| Vec::new())
+        })
+    }
+
+    /// Record `handle` as the cancellable turn of `session_id`. Returns the
+    /// ticket to [`AppState::untrack_task`] it with once it has finished.
+    async fn track_task(&self, session_id: &str, handle: tokio::task::AbortHandle) -> u64 {
+        let ticket = self.next_ticket.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        self.cancellations.write().await.insert(session_id.to_string(), (ticket, handle));
+        ticket
+    }
+
+    /// Forget a finished turn, unless a newer one on the session replaced it.
+    async fn untrack_task(&self, session_id: &str, ticket: u64) {
+        let mut cancellations = self.cancellations.write().await;
+        if cancellations.get(session_id).is_some_and(|(t, _)| *t == ticket) {
+            cancellations.remove(session_id);
+        }
+    }
+
+    async fn stats(&self) -> serde_json::Value {
+        let (sessions, evictions, limits) = {
+            let store = self.sessions.lock().await;
+            let sessions: Vec<_> = store.entries.values().map(|(messages, _)| messages.clone()).collect();
+            (sessions, store.evictions, store.limits)
+        };
+        let mut messages = 0;
+        for session in &sessions {
+            messages += session.read().await.len();
+        }
+        serde_json::json!({
+            "sessions": sessions.len(),
+            "messages": messages,
+            "evictions": evictions,
+            "max_sessions": limits.max_sessions,
+            "idle_ttl_secs": limits.idle_ttl.as_secs(),
+            "running_turns": self.cancellations.read().await.len(),
+        })
+    }
+}
+
+/// Result of one agent turn run outside the WebSocket stream.
+#[derive(Debug, Default, Serialize)]
+struct TurnOutcome {
+    response: String,
+    tools_used: Vec<String>,
+    usage: serde_json::Value,
+}
+
+/// Runs a single user turn against a session; the agent-backed impl is swapped out in tests.
+#[async_trait]
+trait TurnRunner: Send + Sync {
+    async fn run_turn(
+        &self,
+        session_messages: Arc<RwLock<Vec<GooseMessage>>>,
+        session_file: std::path::PathBuf,
+        content: String,
+    ) -> Result<TurnOutcome>;
+}
+
+/// Where [`agent_turn`] reports what the agent produces as it goes.
+#[async_trait]
+trait TurnSink: Send + Sync {
+    async fn report(&self, msg: &WebSocketMessage);
+}
+
+#[async_trait]
+impl TurnSink for RunOutput {
+    async fn report(&self, msg: &WebSocketMessage) {
+        self.send(msg).await;
+    }
+}
+
+/// Collects the text and tools of a turn for the callers that answer once it is done.
+#[derive(Default)]
+struct TurnCollector(Mutex<TurnOutcome>);
+
+#[async_trait]
+impl TurnSink for TurnCollector {
+    async fn report(&self, msg: &WebSocketMessage) {
+        let mut outcome = self.0.lock().await;
+        match msg {
+            WebSocketMessage::Response { content, .. } => outcome.response.push_str(content),
+            WebSocketMessage::ToolRequest { tool_name, .. } => outcome.tools_used.push(tool_name.clone()),
+            _ => {}
+        }
+    }
+}
+
+/// Run one user turn of `agent` against a session, persisting every message
+/// and reporting it to `sink`. Returns the model that finished the turn.
+async fn agent_turn(
+    agent: &Agent,
+    session_messages: Arc<RwLock<Vec<GooseMessage>>>,
+    session_file: &std::path::Path,
+    content: String,
+    sink: &dyn TurnSink,
+) -> Result<String> {
+    use goose::agents::SessionConfig;
+    use goose::message::MessageContent;
+
+    let mut messages = {
+        let mut session_msgs = session_messages.write().await;
+        session_msgs.push(GooseMessage::user().with_text(content));
+        session_msgs.clone()
+    };
+
+    // Persist with the provider so the session gets its description generated
+    let provider = agent.provider().await?;
+    let mut model = provider.get_model_config().model_name;
+    let working_dir = Some(std::env::current_dir()?);
+    session::persist_messages(session_file, &messages, Some(provider), working_dir.clone()).await?;
+
+    let session_config = SessionConfig {
+        id: session::Identifier::Path(session_file.to_path_buf()),
+        working_dir: std::env::current_dir()?,
+        schedule_id: None,
+        execution_mode: None,
+        max_turns: None,
+        retry_config: None,
+    };
+
+    let mut stream = agent.reply(&messages, Some(session_config), None).await?;
+    while let Some(event) = stream.next().await {
+        match event? {
+            AgentEvent::Message(message) => {
+                let current_messages = {
+                    let mut session_msgs = session_messages.write().await;
+                    session_msgs.push(message.clone());
+                    session_msgs.clone()
+                };
+                session::persist_messages(session_file, &current_messages, None, working_dir.clone())
+                    .await?;
+
+                for content in &message.content {
+                    match content {
+                        MessageContent::Text(text) => {
+                            sink.report(&WebSocketMessage::Response {
+                                content: text.text.clone(),
+                                role: "assistant".to_string(),
+                                timestamp: chrono::Utc::now().timestamp_millis(),
+                            })
+                            .await;
+                        }
+                        MessageContent::ToolRequest(req) => {
+                            if let Ok(tool_call) = &req.tool_call {
+                                sink.report(&WebSocketMessage::ToolRequest {
+                                    id: req.id.clone(),
+                                    tool_name: tool_call.name.clone(),
+                                    arguments: tool_call.arguments.clone(),
+                                })
+                                .await;
+                            }
+                        }
+                        MessageContent::ToolConfirmationRequest(confirmation) => {
+                            sink.report(&WebSocketMessage::ToolConfirmation {
+                                id: confirmation.id.clone(),
+                                tool_name: confirmation.tool_name.clone(),
+                                arguments: confirmation.arguments.clone(),
+                                needs_confirmation: true,
+                            })
+                            .await;
+
+                            // For now, auto-approve in web mode
+                            // TODO: Implement proper confirmation UI
+                            agent.handle_confirmation(
+                                confirmation.id.clone(),
+                                goose::permission::PermissionConfirmation {
+                                    principal_type: goose::permission::permission_confirmation::PrincipalType::Tool,
+                                    permission: goose::permission::Permission::AllowOnce,
+                                }
+                            ).await;
+                        }
+                        MessageContent::Thinking(thinking) => {
+                            sink.report(&WebSocketMessage::Thinking {
+                                message: thinking.thinking.clone(),
+                            })
+                            .await;
+                        }
+                        MessageContent::ContextLengthExceeded(msg) => {
+                            sink.report(&WebSocketMessage::ContextExceeded {
+                                message: msg.msg.clone(),
+                            })
+                            .await;
+
+                            // For now, auto-summarize in web mode
+                            // TODO: Implement proper UI for context handling
+                            let (summarized_messages, _) = agent.summarize_context(&messages).await?;
+                            messages = summarized_messages;
+                        }
+                        // Tool responses are persisted with the rest of the message;
+                        // reporting them separately would duplicate them.
+                        _ => {}
+                    }
+                }
+            }
+            AgentEvent::McpNotification(_notification) => {
+                debug!("Received MCP notification in web interface");
+            }
+            AgentEvent::ModelChange { model: active, mode } => {
+                debug!("Model changed to {} in {} mode", active, mode);
+                model = active;
+            }
+        }
+    }
+    Ok(model)
+}
+
+struct AgentTurnRunner(Arc<Agent>);
+
+#[async_trait]
+impl TurnRunner for AgentTurnRunner {
+    async fn run_turn(
+        &self,
+        session_messages: Arc<RwLock<Vec<GooseMessage>>>,
+        session_file: std::path::PathBuf,
+        content: String,
+    ) -> Result<TurnOutcome> {
+        let collector = TurnCollector::default();
+        agent_turn(&self.0, session_messages, &session_file, content, &collector).await?;
+        let mut outcome = collector.0.into_inner();
+
+        if let Ok(metadata) = session::read_metadata(&session_file) {
+            outcome.usage = serde_json::json!({
+                "input_tokens": metadata.input_tokens,
+                "output_tokens": metadata.output_tokens,
+                "total_tokens": metadata.total_tokens,
+            });
+        }
+        Ok(outcome)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct InjectMessageRequest {
+    content: String,
+    #[serde(default)]
+    wait: bool,
+    #[serde(default = "default_inject_timeout_ms")]
+    timeout_ms: u64,
+}
+
+fn default_inject_timeout_ms() -> u64 {
+    120_000
+}
+
+#[derive(Debug, Deserialize)]
+struct MessagesQuery {
+    #[serde(default)]
+    after: usize,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -50,6 +662,10 @@ enum WebSocketMessage {
     },
     #[serde(rename = "cancel")]
     Cancel { session_id: String },
+    /// Stream the session's running turn to this socket, starting with what
+    /// it produced since the socket it was started from dropped
+    #[serde(rename = "resume")]
+    Resume { session_id: String },
     #[serde(rename = "response")]
     Response {
         content: String,
@@ -85,6 +701,71 @@ enum WebSocketMessage {
     Cancelled { message: String },
     #[serde(rename = "complete")]
     Complete { message: String },
+    /// Ask for the active model and the available ones; answered with `models`
+    #[serde(rename = "list_models")]
+    ListModels {
+        #[serde(default)]
+        token: Option<String>,
+    },
+    /// Switch the agent's provider and model; answered with `models`
+    #[serde(rename = "set_model")]
+    SetModel {
+        provider: String,
+        model: String,
+        #[serde(default)]
+        token: Option<String>,
+    },
+    #[serde(rename = "models")]
+    Models {
+        provider: String,
+        model: String,
+        available: Vec<serde_json::Value>,
+    },
+    /// Tokens a turn used and its session's running total, sent before `complete`
+    #[serde(rename = "usage")]
+    Usage { turn: Usage, session: Usage },
+}
+
+/// Provider-reported token usage, for the UI's cost meter. Counts the
+/// provider doesn't report are zero.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct Usage {
+    input_tokens: u64,
+    output_tokens: u64,
+    total_tokens: u64,
+    model: String,
+}
+
+impl Usage {
+    /// The running total the agent keeps in a session's metadata, adding
+    /// each provider call's usage as it goes.
+    fn session_total(metadata: &session::SessionMetadata, model: &str) -> Self {
+        let count = |n: Option<i32>| n.unwrap_or(0).max(0) as u64;
+        Usage {
+            input_tokens: count(metadata.accumulated_input_tokens),
+            output_tokens: count(metadata.accumulated_output_tokens),
+            total_tokens: count(metadata.accumulated_total_tokens),
+            model: model.to_string(),
+        }
+    }
+
+    /// [`Self::session_total`] read from `session_file`; zeros until it has one.
+    fn read_session_total(session_file: &std::path::Path, model: &str) -> Self {
+        match session::read_metadata(session_file) {
+            Ok(metadata) => Self::session_total(&metadata, model),
+            Err(_) => Usage { model: model.to_string(), ..Default::default() },
+        }
+    }
+
+    /// What was used since `earlier`, a total of the same session.
+    fn since(&self, earlier: &Usage) -> Self {
+        Usage {
+            input_tokens: self.input_tokens.saturating_sub(earlier.input_tokens),
+            output_tokens: self.output_tokens.saturating_sub(earlier.output_tokens),
+            total_tokens: self.total_tokens.saturating_sub(earlier.total_tokens),
+            model: self.model.clone(),
+        }
+    }
 }
 
 pub async fn handle_web(port: u16, host: String, open: bool) -> Result<()> {
@@ -131,11 +812,21 @@ pub async fn handle_web(port: u16, host: String, open: bool) -> Result<()> {
         }
     }
 
-    let state = AppState {
-        agent: Arc::new(agent),
-        sessions: Arc::new(RwLock::new(std::collections::HashMap::new())),
-        cancellations: Arc::new(RwLock::new(std::collections::HashMap::new())),
-    };
+    let mut state = AppState::new(Arc::new(agent));
+    state.auth_token = std::env::var("GOOSE_WEB_AUTH_TOKEN").ok().filter(|t| !t.is_empty());
+    state.active_model = Arc::new(RwLock::new((provider_name.clone(), model.clone())));
+    state.sessions = Arc::new(Mutex::new(SessionStore::new(SessionLimits::from_env())));
+    state.socket_limits = SocketLimits::from_env();
+
+    // Idle sessions are otherwise only dropped when another session is touched
+    let sessions = state.sessions.clone();
+    tokio::spawn(async move {
+        let mut sweep = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            sweep.tick().await;
+            sessions.lock().await.evict(std::time::Instant::now());
+        }
+    });
 
     // Start Redis bus listener
     println!("Initializing Redis bus listener...");
@@ -145,6 +836,21 @@ pub async fn handle_web(port: u16, host: String, open: bool) -> Result<()> {
             .unwrap_or_else(|_| "AG1:agent:GooseAgent:inbox".into()),
         agent_name: std::env::var("AG1_AGENT_NAME").unwrap_or_else(|_| "GooseAgent".into()),
         timeout_ms: 1000,
+        max_envelope_bytes: std::env::var("AG1_MAX_ENVELOPE_BYTES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(bus::DEFAULT_MAX_ENVELOPE_BYTES),
+        max_entry_bytes: std::env::var("AG1_MAX_ENTRY_BYTES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(bus::DEFAULT_MAX_ENTRY_BYTES),
+        concurrency: std::env::var("AG1_BUS_CONCURRENCY")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|n| *n > 0)
+            .unwrap_or(DEFAULT_BUS_CONCURRENCY),
+        description: std::env::var("AG1_AGENT_DESCRIPTION").ok().filter(|d| !d.is_empty()),
+        capabilities_keywords: bus_agent::capabilities_keywords_from_env(),
     };
     println!("Bus configuration: {:?}", bus_cfg);
     
@@ -160,22 +866,7 @@ pub async fn handle_web(port: u16, host: String, open: bool) -> Result<()> {
         }
     });
 
-    // Build router
-    let app = Router::new()
-        .route("/", get(serve_index))
-        .route("/session/{session_name}", get(serve_session))
-        .route("/ws", get(websocket_handler))
-        .route("/api/health", get(health_check))
-        .route("/api/sessions", get(list_sessions))
-        .route("/api/sessions/{session_id}", get(get_session))
-        .route("/static/{*path}", get(serve_static))
-        .layer(
-            CorsLayer::new()
-                .allow_origin(Any)
-                .allow_methods(Any)
-                .allow_headers(Any),
-        )
-        .with_state(state);
+    let app = build_router(state);
 
     let addr: SocketAddr = format!("{}:{}", host, port).parse()?;
 
@@ -204,6 +895,30 @@ pub async fn handle_web(port: u16, host: String, open: bool) -> Result<()> {
     Ok(())
 }
 
+fn build_router(state: AppState) -> Router {
+    Router::new()
+        .route("/", get(serve_index))
+        .route("/session/{session_name}", get(serve_session))
+        .route("/ws", get(websocket_handler))
+        .route("/api/health", get(health_check))
+        .route("/api/stats", get(stats))
+        .route("/metrics", get(prometheus_metrics))
+        .route("/api/sessions", get(list_sessions))
+        .route("/api/sessions/{session_id}", get(get_session))
+        .route(
+            "/api/sessions/{session_id}/messages",
+            get(get_session_messages).post(inject_message),
+        )
+        .route("/static/{*path}", get(serve_static))
+        .layer(
+            CorsLayer::new()
+                .allow_origin(Any)
+                .allow_methods(Any)
+                .allow_headers(Any),
+        )
+        .with_state(state)
+}
+
 async fn serve_index() -> Html<&'static str> {
     Html(include_str!("../../static/index.html"))
 }
@@ -249,13 +964,38 @@ async fn serve_static(axum::extract::Path(path): axum::extract::Path<String>) ->
     }
 }
 
-async fn health_check() -> Json<serde_json::Value> {
+async fn health_check(State(state): State<AppState>) -> Json<serde_json::Value> {
+    let bus_metrics = state.bus.read().await.as_ref().map(|bus| bus.metrics());
     Json(serde_json::json!({
         "status": "ok",
-        "service": "goose-web"
+        "service": "goose-web",
+        "bus": bus_metrics,
     }))
 }
 
+/// In-memory session store size and evictions, and turns currently running.
+async fn stats(State(state): State<AppState>) -> Json<serde_json::Value> {
+    Json(state.stats().await)
+}
+
+/// Prometheus scrape endpoint for bus traffic.
+async fn prometheus_metrics(State(state): State<AppState>) -> Response {
+    // Stream gauges are refreshed on scrape rather than on every message
+    if let Some(bus) = state.bus.read().await.clone() {
+        for stream in state.bus_exporter.streams() {
+            match bus.stream_info(&stream).await {
+                Ok(info) => state.bus_exporter.set_stream_info(&stream, &info),
+                Err(e) => warn!("Failed to read stream info for {}: {}", stream, e),
+            }
+        }
+    }
+    (
+        [("content-type", "text/plain; version=0.0.4")],
+        state.bus_exporter.render(),
+    )
+        .into_response()
+}
+
 async fn list_sessions() -> Json<serde_json::Value> {
     match session::list_sessions() {
         Ok(sessions) => {
@@ -313,25 +1053,157 @@ async fn get_session(
     }
 }
 
-async fn websocket_handler(
-    ws: WebSocketUpgrade,
+/// Run one user message through a session without a WebSocket.
+///
+/// With `wait: true` the call returns the assistant's final text; otherwise it returns
+/// 202 right away and the reply is only persisted to the session.
+async fn inject_message(
     State(state): State<AppState>,
-) -> impl IntoResponse {
-    ws.on_upgrade(|socket| handle_socket(socket, state))
-}
+    axum::extract::Path(session_id): axum::extract::Path<String>,
+    Json(req): Json<InjectMessageRequest>,
+) -> Response {
+    let session_file = match session::get_path(session::Identifier::Name(session_id.clone())) {
+        Ok(path) => path,
+        Err(e) => {
+            return (
+                http::StatusCode::BAD_REQUEST,
+                Json(serde_json::json!({ "error": format!("Invalid session ID: {}", e) })),
+            )
+                .into_response();
+        }
+    };
 
-async fn handle_socket(socket: WebSocket, state: AppState) {
-    let (sender, mut receiver) = socket.split();
-    let sender = Arc::new(Mutex::new(sender));
+    let message_id = uuid::Uuid::new_v4().to_string();
+    let session_messages = state.session_messages(&session_id, &session_file).await;
+    let session_lock = state.session_lock(&session_id).await;
+    let turns = state.turns.clone();
+    let content = req.content;
 
-    while let Some(msg) = receiver.next().await {
-        if let Ok(msg) = msg {
-            match msg {
-                Message::Text(text) => {
-                    println!("WebSocket message received: {}", text);
-                    println!("WebSocket message length: {} bytes", text.len());
-                    
-                    match serde_json::from_str::<WebSocketMessage>(&text.to_string()) {
+    let task_handle = tokio::spawn(async move {
+        let _turn = session_lock.lock().await;
+        turns.run_turn(session_messages, session_file, content).await
+    });
+
+    let ticket = state.track_task(&session_id, task_handle.abort_handle()).await;
+
+    if !req.wait {
+        tokio::spawn(async move {
+            if let Ok(Err(e)) = task_handle.await {
+                error!("Error processing injected message: {}", e);
+            }
+            state.untrack_task(&session_id, ticket).await;
+        });
+        return (
+            http::StatusCode::ACCEPTED,
+            Json(serde_json::json!({ "message_id": message_id, "status": "accepted" })),
+        )
+            .into_response();
+    }
+
+    let mut task_handle = task_handle;
+    let result = tokio::time::timeout(Duration::from_millis(req.timeout_ms), &mut task_handle).await;
+    match result {
+        Ok(joined) => {
+            state.untrack_task(&session_id, ticket).await;
+            match joined {
+                Ok(Ok(outcome)) => Json(serde_json::json!({
+                    "message_id": message_id,
+                    "response": outcome.response,
+                    "tools_used": outcome.tools_used,
+                    "usage": outcome.usage,
+                }))
+                .into_response(),
+                Ok(Err(e)) => (
+                    http::StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(serde_json::json!({ "message_id": message_id, "error": e.to_string() })),
+                )
+                    .into_response(),
+                Err(e) => (
+                    http::StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(serde_json::json!({ "message_id": message_id, "error": e.to_string() })),
+                )
+                    .into_response(),
+            }
+        }
+        // The turn keeps running and is persisted; the caller can poll for it.
+        Err(_) => {
+            tokio::spawn(async move {
+                let _ = task_handle.await;
+                state.untrack_task(&session_id, ticket).await;
+            });
+            (
+                http::StatusCode::GATEWAY_TIMEOUT,
+                Json(serde_json::json!({
+                    "message_id": message_id,
+                    "error": format!("no reply within {} ms", req.timeout_ms),
+                })),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Return a session's messages after index `after`, for polling.
+async fn get_session_messages(
+    State(state): State<AppState>,
+    axum::extract::Path(session_id): axum::extract::Path<String>,
+    Query(query): Query<MessagesQuery>,
+) -> Response {
+    let in_memory = state.sessions.lock().await.get(&session_id);
+    let messages = match in_memory {
+        Some(session) => session.read().await.clone(),
+        None => {
+            let session_file =
+                match session::get_path(session::Identifier::Name(session_id.clone())) {
+                    Ok(path) => path,
+                    Err(e) => {
+                        return (
+                            http::StatusCode::BAD_REQUEST,
+                            Json(serde_json::json!({ "error": format!("Invalid session ID: {}", e) })),
+                        )
+                            .into_response();
+                    }
+                };
+            session::read_messages(&session_file).unwrap_or_default()
+        }
+    };
+
+    let total = messages.len();
+    let new_messages: Vec<_> = messages.into_iter().skip(query.after).collect();
+    Json(serde_json::json!({
+        "messages": new_messages,
+        "next": total,
+    }))
+    .into_response()
+}
+
+async fn websocket_handler(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    ws.on_upgrade(|socket| handle_socket(socket, state))
+}
+
+async fn handle_socket(socket: WebSocket, state: AppState) {
+    let (sender, mut receiver) = socket.split();
+    let sender = Arc::new(Mutex::new(sender));
+    let mut limiter = SocketLimiter::new(state.socket_limits, std::time::Instant::now());
+    // Turns streaming to this socket, to leave buffering when it drops
+    let mut runs: Vec<Arc<RunOutput>> = Vec::new();
+
+    while let Some(msg) = receiver.next().await {
+        if let Ok(msg) = msg {
+            match msg {
+                Message::Text(text) => {
+                    if let Err(e) = limiter.admit(std::time::Instant::now()) {
+                        warn!("Rejecting WebSocket message: {}", e);
+                        send_socket_message(&sender, &WebSocketMessage::Error { message: e.to_string() }).await;
+                        continue;
+                    }
+                    println!("WebSocket message received: {}", text);
+                    println!("WebSocket message length: {} bytes", text.len());
+                    
+                    match serde_json::from_str::<WebSocketMessage>(&text.to_string()) {
                         Ok(WebSocketMessage::Message {
                             content,
                             session_id,
@@ -339,6 +1211,15 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
                         }) => {
                             println!("[WEBSOCKET] Received message for session: {}", session_id);
                             println!("[WEBSOCKET] Message content: {:?}", content);
+
+                            let turn_slot = match limiter.start_turn() {
+                                Ok(permit) => permit,
+                                Err(e) => {
+                                    warn!(session_id = %session_id, "Rejecting WebSocket message: {}", e);
+                                    send_socket_message(&sender, &WebSocketMessage::Error { message: e.to_string() }).await;
+                                    continue;
+                                }
+                            };
                             
                             // Get session file path from session_id
                             let session_file = match session::get_path(session::Identifier::Name(
@@ -352,31 +1233,22 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
                             };
 
                             // Get or create session in memory (for fast access during processing)
-                            let session_messages = {
-                                let sessions = state.sessions.read().await;
-                                if let Some(session) = sessions.get(&session_id) {
-                                    session.clone()
-                                } else {
-                                    drop(sessions);
-                                    let mut sessions = state.sessions.write().await;
-
-                                    // Load existing messages from JSONL file if it exists
-                                    let existing_messages = session::read_messages(&session_file)
-                                        .unwrap_or_else(|_| Vec::new());
-
-                                    //let new_session = Arc::new(Mutex::new(existing_messages));
-                                    let new_session = Arc::new(RwLock::new(existing_messages));
-                                    sessions.insert(session_id.clone(), new_session.clone());
-                                    new_session
-                                }
-                            };
+                            let session_messages =
+                                state.session_messages(&session_id, &session_file).await;
+                            let session_lock = state.session_lock(&session_id).await;
 
-                            // Clone sender for async processing
-                            let sender_clone = sender.clone();
+                            let output = Arc::new(RunOutput::new(sender.clone()));
+                            state.runs.lock().await.insert(session_id.clone(), output.clone());
+                            runs.push(output.clone());
                             let agent = state.agent.clone();
+                            let output_for_task = output.clone();
 
                             // Process message in a separate task to allow streaming
                             let task_handle = tokio::spawn(async move {
+                                // Released when the turn ends or is cancelled
+                                let _turn_slot = turn_slot;
+                                // Wait for any REST-injected turn on this session to finish
+                                let _turn = session_lock.lock().await;
                                 println!("Starting message processing task");
                                 println!("Content to process: {}", content);
                                 println!("Session file: {}", session_file.display());
@@ -386,7 +1258,7 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
                                     session_messages,
                                     session_file,
                                     content,
-                                    sender_clone,
+                                    output_for_task,
                                 )
                                 .await;
 
@@ -396,16 +1268,11 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
                             });
 
                             // Store the abort handle
-                            {
-                                let mut cancellations = state.cancellations.write().await;
-                                cancellations
-                                    .insert(session_id.clone(), task_handle.abort_handle());
-                            }
+                            let ticket = state.track_task(&session_id, task_handle.abort_handle()).await;
 
                             // Wait for task completion and handle abort
-                            let sender_for_abort = sender.clone();
                             let session_id_for_cleanup = session_id.clone();
-                            let cancellations_for_cleanup = state.cancellations.clone();
+                            let state_for_cleanup = state.clone();
 
                             tokio::spawn(async move {
                                 match task_handle.await {
@@ -414,18 +1281,10 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
                                     }
                                     Err(e) if e.is_cancelled() => {
                                         // Task was aborted
-                                        let mut sender = sender_for_abort.lock().await;
-                                        let _ = sender
-                                            .send(Message::Text(
-                                                serde_json::to_string(
-                                                    &WebSocketMessage::Cancelled {
-                                                        message: "Operation cancelled by user"
-                                                            .to_string(),
-                                                    },
-                                                )
-                                                .unwrap()
-                                                .into(),
-                                            ))
+                                        output
+                                            .send(&WebSocketMessage::Cancelled {
+                                                message: "Operation cancelled by user".to_string(),
+                                            })
                                             .await;
                                     }
                                     Err(e) => {
@@ -434,9 +1293,15 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
                                 }
 
                                 // Clean up cancellation token
-                                {
-                                    let mut cancellations = cancellations_for_cleanup.write().await;
-                                    cancellations.remove(&session_id_for_cleanup);
+                                state_for_cleanup.untrack_task(&session_id_for_cleanup, ticket).await;
+
+                                // Give a client that dropped mid-turn a while to come back for the end of it
+                                if !output.is_attached().await {
+                                    sleep(RESUME_WINDOW).await;
+                                }
+                                let mut runs = state_for_cleanup.runs.lock().await;
+                                if runs.get(&session_id_for_cleanup).is_some_and(|o| Arc::ptr_eq(o, &output)) {
+                                    runs.remove(&session_id_for_cleanup);
                                 }
                             });
                         }
@@ -447,7 +1312,7 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
                                 cancellations.remove(&session_id)
                             };
 
-                            if let Some(handle) = abort_handle {
+                            if let Some((_, handle)) = abort_handle {
                                 handle.abort();
 
                                 // Send cancellation confirmation
@@ -463,6 +1328,40 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
                                     .await;
                             }
                         }
+                        Ok(WebSocketMessage::Resume { session_id }) => {
+                            let output = state.runs.lock().await.get(&session_id).cloned();
+                            match output {
+                                Some(output) => {
+                                    info!(session_id = %session_id, "Resuming turn on a new socket");
+                                    output.attach(sender.clone()).await;
+                                    runs.push(output);
+                                }
+                                None => {
+                                    let message = format!("no turn to resume on session {}", session_id);
+                                    send_socket_message(&sender, &WebSocketMessage::Error { message }).await;
+                                }
+                            }
+                        }
+                        Ok(WebSocketMessage::ListModels { token }) => {
+                            let reply = match state.authorize(token.as_deref()) {
+                                Ok(()) => state.models_message().await,
+                                Err(e) => WebSocketMessage::Error { message: e.to_string() },
+                            };
+                            send_socket_message(&sender, &reply).await;
+                        }
+                        Ok(WebSocketMessage::SetModel { provider, model, token }) => {
+                            let switched = match state.authorize(token.as_deref()) {
+                                Ok(()) => state.set_model(&provider, &model).await,
+                                Err(e) => Err(e),
+                            };
+                            let reply = match switched {
+                                Ok(()) => state.models_message().await,
+                                Err(e) => WebSocketMessage::Error {
+                                    message: format!("could not switch to {}/{}: {}", provider, model, e),
+                                },
+                            };
+                            send_socket_message(&sender, &reply).await;
+                        }
                         Ok(_) => {
                             // Ignore other message types
                         }
@@ -478,270 +1377,63 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
             break;
         }
     }
+
+    // Hold on to what the turns still running produce until their client resumes
+    for output in runs {
+        output.detach(&sender).await;
+    }
+}
+
+async fn send_socket_message(
+    sender: &Mutex<futures::stream::SplitSink<WebSocket, Message>>,
+    msg: &WebSocketMessage,
+) {
+    let text = serde_json::to_string(msg).unwrap();
+    let _ = sender.lock().await.send(Message::Text(text.into())).await;
 }
 
 async fn process_message_streaming(
     agent: &Agent,
-    //session_messages: Arc<Mutex<Vec<GooseMessage>>>,
     session_messages: Arc<RwLock<Vec<GooseMessage>>>,
     session_file: std::path::PathBuf,
     content: String,
-    sender: Arc<Mutex<futures::stream::SplitSink<WebSocket, Message>>>,
+    output: Arc<RunOutput>,
 ) -> Result<()> {
-    use futures::StreamExt;
-    use goose::agents::SessionConfig;
-    use goose::message::MessageContent;
-    use goose::session;
-
-    println!("[Web] Received content: {}", content);
-    println!("[Web] Content length: {} bytes", content.len());
-    
-    // Create a user message
-    let user_message = GooseMessage::user().with_text(content.clone());
-    println!("[Web] Created user message with content: {}", content);
-
-    // Get existing messages from session and add the new user message
-    let mut messages = {
-        println!("[Web] Acquiring session messages write lock");
-        let mut session_msgs = session_messages.write().await;
-        println!("[Web] Session has {} existing messages", session_msgs.len());
-        session_msgs.push(user_message.clone());
-        println!("[Web] Added user message to session");
-        session_msgs.clone()
-    };
-
-    // Persist messages to JSONL file with provider for automatic description generation
-    let provider = agent.provider().await;
-    if provider.is_err() {
+    let Ok(provider) = agent.provider().await else {
         let error_msg = "I'm not properly configured yet. Please configure a provider through the CLI first using `goose configure`.".to_string();
-        let mut sender = sender.lock().await;
-        let _ = sender
-            .send(Message::Text(
-                serde_json::to_string(&WebSocketMessage::Response {
-                    content: error_msg,
-                    role: "assistant".to_string(),
-                    timestamp: chrono::Utc::now().timestamp_millis(),
-                })
-                .unwrap()
-                .into(),
-            ))
+        output
+            .send(&WebSocketMessage::Response {
+                content: error_msg,
+                role: "assistant".to_string(),
+                timestamp: chrono::Utc::now().timestamp_millis(),
+            })
             .await;
         return Ok(());
-    }
-
-    let provider = provider.unwrap();
-    let working_dir = Some(std::env::current_dir()?);
-    session::persist_messages(
-        &session_file,
-        &messages,
-        Some(provider.clone()),
-        working_dir.clone(),
-    )
-    .await?;
-
-    let session_config = SessionConfig {
-        id: session::Identifier::Path(session_file.clone()),
-        working_dir: std::env::current_dir()?,
-        schedule_id: None,
-        execution_mode: None,
-        max_turns: None,
-        retry_config: None,
     };
 
-    match agent.reply(&messages, Some(session_config), None).await {
-        Ok(mut stream) => {
-            println!("[Web] Successfully got response stream from agent");
-            while let Some(result) = stream.next().await {
-                println!("[Web] Got result from stream");
-                match result {
-                    Ok(AgentEvent::Message(message)) => {
-                        println!("[Web] Received agent message with {} content items", message.content.len());
-                        // Add message to our session
-                        {
-                            println!("[Web] Acquiring session messages write lock");
-                            let mut session_msgs = session_messages.write().await;
-                            println!("[Web] Session has {} messages before adding", session_msgs.len());
-                            session_msgs.push(message.clone());
-                            println!("[Web] Added message to session, now has {} messages", session_msgs.len());
-                        }
-
-                        // Persist messages to JSONL file (no provider needed for assistant messages)
-                        let current_messages = {
-                            //let session_msgs = session_messages.lock().await;
-                            let session_msgs = session_messages.read().await;
-                            session_msgs.clone()
-                        };
-                        session::persist_messages(
-                            &session_file,
-                            &current_messages,
-                            None,
-                            working_dir.clone(),
-                        )
-                        .await?;
-                        // Handle different message content types
-                        for content in &message.content {
-                            println!("[Web] Processing message content: {:?}", content);
-                            match content {
-                                MessageContent::Text(text) => {
-                                    println!("[Web] Found text content: {}", text.text);
-                                    // Send the text response
-                                    let mut sender = sender.lock().await;
-                                    let _ = sender
-                                        .send(Message::Text(
-                                            serde_json::to_string(&WebSocketMessage::Response {
-                                                content: text.text.clone(),
-                                                role: "assistant".to_string(),
-                                                timestamp: chrono::Utc::now().timestamp_millis(),
-                                            })
-                                            .unwrap()
-                                            .into(),
-                                        ))
-                                        .await;
-                                }
-                                MessageContent::ToolRequest(req) => {
-                                    // Send tool request notification
-                                    let mut sender = sender.lock().await;
-                                    if let Ok(tool_call) = &req.tool_call {
-                                        let _ = sender
-                                            .send(Message::Text(
-                                                serde_json::to_string(
-                                                    &WebSocketMessage::ToolRequest {
-                                                        id: req.id.clone(),
-                                                        tool_name: tool_call.name.clone(),
-                                                        arguments: tool_call.arguments.clone(),
-                                                    },
-                                                )
-                                                .unwrap()
-                                                .into(),
-                                            ))
-                                            .await;
-                                    }
-                                }
-                                MessageContent::ToolResponse(_resp) => {
-                                    // Tool responses are already included in the complete message stream
-                                    // and will be persisted to session history. No need to send separate
-                                    // WebSocket messages as this would cause duplicates.
-                                }
-                                MessageContent::ToolConfirmationRequest(confirmation) => {
-                                    // Send tool confirmation request
-                                    let mut sender = sender.lock().await;
-                                    let _ = sender
-                                        .send(Message::Text(
-                                            serde_json::to_string(
-                                                &WebSocketMessage::ToolConfirmation {
-                                                    id: confirmation.id.clone(),
-                                                    tool_name: confirmation.tool_name.clone(),
-                                                    arguments: confirmation.arguments.clone(),
-                                                    needs_confirmation: true,
-                                                },
-                                            )
-                                            .unwrap()
-                                            .into(),
-                                        ))
-                                        .await;
-
-                                    // For now, auto-approve in web mode
-                                    // TODO: Implement proper confirmation UI
-                                    agent.handle_confirmation(
-                                        confirmation.id.clone(),
-                                        goose::permission::PermissionConfirmation {
-                                            principal_type: goose::permission::permission_confirmation::PrincipalType::Tool,
-                                            permission: goose::permission::Permission::AllowOnce,
-                                        }
-                                    ).await;
-                                }
-                                MessageContent::Thinking(thinking) => {
-                                    // Send thinking indicator
-                                    let mut sender = sender.lock().await;
-                                    let _ = sender
-                                        .send(Message::Text(
-                                            serde_json::to_string(&WebSocketMessage::Thinking {
-                                                message: thinking.thinking.clone(),
-                                            })
-                                            .unwrap()
-                                            .into(),
-                                        ))
-                                        .await;
-                                }
-                                MessageContent::ContextLengthExceeded(msg) => {
-                                    // Send context exceeded notification
-                                    let mut sender = sender.lock().await;
-                                    let _ = sender
-                                        .send(Message::Text(
-                                            serde_json::to_string(
-                                                &WebSocketMessage::ContextExceeded {
-                                                    message: msg.msg.clone(),
-                                                },
-                                            )
-                                            .unwrap()
-                                            .into(),
-                                        ))
-                                        .await;
-
-                                    // For now, auto-summarize in web mode
-                                    // TODO: Implement proper UI for context handling
-                                    let (summarized_messages, _) =
-                                        agent.summarize_context(&messages).await?;
-                                    messages = summarized_messages;
-                                }
-                                _ => {
-                                    // Handle other message types as needed
-                                }
-                            }
-                        }
-                    }
-                    Ok(AgentEvent::McpNotification(_notification)) => {
-                        // Handle MCP notifications if needed
-                        // For now, we'll just log them
-                        tracing::error!("Received MCP notification in web interface");
-                    }
-                    Ok(AgentEvent::ModelChange { model, mode }) => {
-                        // Log model change
-                        tracing::error!("Model changed to {} in {} mode", model, mode);
-                    }
-
-                    Err(e) => {
-                        error!("Error in message stream: {}", e);
-                        let mut sender = sender.lock().await;
-                        let _ = sender
-                            .send(Message::Text(
-                                serde_json::to_string(&WebSocketMessage::Error {
-                                    message: format!("Error: {}", e),
-                                })
-                                .unwrap()
-                                .into(),
-                            ))
-                            .await;
-                        break;
-                    }
-                }
-            }
-        }
+    let initial_model = provider.get_model_config().model_name;
+    let usage_before = Usage::read_session_total(&session_file, &initial_model);
+    let model = match agent_turn(agent, session_messages, &session_file, content, output.as_ref()).await {
+        Ok(model) => model,
         Err(e) => {
-            error!("Error calling agent: {}", e);
-            let mut sender = sender.lock().await;
-            let _ = sender
-                .send(Message::Text(
-                    serde_json::to_string(&WebSocketMessage::Error {
-                        message: format!("Error: {}", e),
-                    })
-                    .unwrap()
-                    .into(),
-                ))
+            error!("Error in message stream: {}", e);
+            output
+                .send(&WebSocketMessage::Error {
+                    message: format!("Error: {}", e),
+                })
                 .await;
+            initial_model
         }
-    }
+    };
+
+    let usage = Usage::read_session_total(&session_file, &model);
+    output.send(&WebSocketMessage::Usage { turn: usage.since(&usage_before), session: usage }).await;
 
     // Send completion message
-    let mut sender = sender.lock().await;
-    let _ = sender
-        .send(Message::Text(
-            serde_json::to_string(&WebSocketMessage::Complete {
-                message: "Response complete".to_string(),
-            })
-            .unwrap()
-            .into(),
-        ))
+    output
+        .send(&WebSocketMessage::Complete {
+            message: "Response complete".to_string(),
+        })
         .await;
 
     Ok(())
@@ -751,261 +1443,194 @@ async fn process_message_streaming(
 use webbrowser;
 
 async fn run_bus_listener(state: AppState, cfg: BusConfig) -> Result<()> {
-    use tokio::time::{sleep, Duration};
-    let mut backoff = 1u64;
+    let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(30));
     
     println!("🚀 Starting Redis bus listener with config: {:?}", cfg);
     
-    loop {
+    let bus = loop {
         println!("Attempting to connect to Redis at {}...", cfg.redis_url);
-        let bus = match Bus::new(&cfg.redis_url) {
+        match Bus::new(&cfg.redis_url) {
             Ok(bus) => {
                 println!("✅ Successfully connected to Redis at {}", cfg.redis_url);
-                bus
+                break bus
+                    .with_exporter(state.bus_exporter.clone())
+                    .with_max_envelope_bytes(cfg.max_envelope_bytes)
+                    .with_max_entry_bytes(cfg.max_entry_bytes);
             },
             Err(e) => {
                 error!("❌ Failed to connect to Redis at {}: {}", cfg.redis_url, e);
-                println!("Retrying in {} seconds...", backoff);
-                sleep(Duration::from_secs(backoff)).await;
-                backoff = (backoff * 2).min(30);
-                continue;
+                let delay = backoff.next_delay();
+                println!("Retrying in {:.1} seconds...", delay.as_secs_f64());
+                sleep(delay).await;
             }
-        };
+        }
+    };
+
+    println!("starting bus listener");
+    let consumer_id = format!("{}--{}", cfg.agent_name, uuid::Uuid::new_v4());
+    let runtime_cfg = bus_runtime_config(&cfg, consumer_id);
+    
+    println!("[WEBSOCKET] Stream: {}", &runtime_cfg.inbox);
+    println!("[WEBSOCKET] Consumer Group: {}", &runtime_cfg.group);
+    println!("[WEBSOCKET] Consumer ID: {}", &runtime_cfg.consumer);
+    println!("📡 Listening for messages on stream: {}", cfg.inbox);
+    
+    // Debug: Print Redis connection details
+    println!("🔌 Redis URL: {}", cfg.redis_url);
+    println!("🔌 Inbox stream: {}", cfg.inbox);
+    println!("🔌 Timeout: {}ms", cfg.timeout_ms);
+    
+    // Create an Arc to share the bus connection
+    let bus_arc = std::sync::Arc::new(bus);
+    *state.bus.write().await = Some(bus_arc.clone());
 
-        println!("starting bus listener");
+    let runtime = BusAgentRuntime::new(bus_arc.as_ref().clone(), runtime_cfg);
+    let handler = BusAgent { state, bus: bus_arc, agent_name: cfg.agent_name };
+    runtime.run(&handler).await
+}
+
+/// How the bus listener reads its inbox, as consumer `consumer`.
+fn bus_runtime_config(cfg: &BusConfig, consumer: String) -> RuntimeConfig {
+    RuntimeConfig {
+        inbox: cfg.inbox.clone(),
         // Use the same consumer group as ag1_meta for proper message sharing
-        let group = "ag1_meta";
-        let consumer_id = format!("{}--{}", cfg.agent_name, uuid::Uuid::new_v4());
-        
-        println!("[WEBSOCKET] Setting up consumer group for session: {}", cfg.agent_name);
-        println!("[WEBSOCKET] Stream: {}", &cfg.inbox);
-        println!("[WEBSOCKET] Consumer Group: {}", &group);
-        println!("[WEBSOCKET] Consumer ID: {}", &consumer_id);
-        
-        if let Err(e) = bus.create_consumer_group(&cfg.inbox, group).await {
-            if !e.to_string().contains("BUSYGROUP") {
-                eprintln!("[WEBSOCKET] ❌ Failed to create consumer group: {}", e);
-            } else {
-                println!("[WEBSOCKET] ℹ️ Consumer group already exists");
-            }
-        } else {
-            println!("[WEBSOCKET] ✅ Successfully created consumer group");
+        group: "ag1_meta".into(),
+        consumer,
+        start: StartPos::Earliest,
+        block_ms: cfg.timeout_ms,
+        concurrency: cfg.concurrency,
+        agent_name: cfg.agent_name.clone(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        accept_kinds: None,
+        // Allow both 'user' and 'agent' roles to be processed
+        accept_roles: vec!["user".into(), "agent".into(), "assistant".into()],
+        default_reply_to: cfg.inbox.clone(),
+        description: cfg.description.clone(),
+        capabilities_keywords: cfg.capabilities_keywords.clone(),
+    }
+}
+
+/// The in-process agent answering the bus inbox. Each message is a turn on
+/// the session named by its `session_code`.
+struct BusAgent {
+    state: AppState,
+    bus: Arc<Bus>,
+    agent_name: String,
+}
+
+#[async_trait]
+impl MessageHandler for BusAgent {
+    async fn handle(&self, msg: IncomingMessage) -> Result<Option<OutgoingReply>> {
+        let env = &msg.envelope;
+        println!("\n[WEBSOCKET] ✅ Received message from Redis");
+        println!("[WEBSOCKET] {}", env);
+        println!("[WEBSOCKET] Reply To: {}", msg.reply_to);
+        if let Some(latency) = msg.queue_latency {
+            println!("[WEBSOCKET] Queued for {}ms", latency.as_millis());
         }
-        
-        println!("📡 Listening for messages on stream: {}", cfg.inbox);
-        
-        // Debug: Print Redis connection details
-        println!("🔌 Redis URL: {}", cfg.redis_url);
-        println!("🔌 Inbox stream: {}", cfg.inbox);
-        println!("🔌 Timeout: {}ms", cfg.timeout_ms);
-        
-        // Create an Arc to share the bus connection
-        let bus_arc = std::sync::Arc::new(bus);
-        
-        loop {
-            println!("\n--- New Poll Cycle ---");
-            println!("⏳ Waiting for message on stream: {}", cfg.inbox);
-
-            let start = std::time::Instant::now();
-            println!("[WEBSOCKET] Waiting for messages from Redis...");
-            println!("[WEBSOCKET] Stream: {}", &cfg.inbox);
-            println!("[WEBSOCKET] Consumer Group: {}", &group);
-            println!("[WEBSOCKET] Consumer ID: {}", &consumer_id);
-            
-            let result = bus_arc
-                .recv_block_group(&cfg.inbox, group, &consumer_id, cfg.timeout_ms)
-                .await
-                .map(|opt_env| {
-                    if let Some(ref env) = opt_env {
-                        println!("\n[WEBSOCKET] ✅ Received message from Redis");
-                        println!("[WEBSOCKET] Envelope ID: {:?}", env.envelope_id);
-                        println!("[WEBSOCKET] Correlation ID: {:?}", env.correlation_id);
-                        println!("[WEBSOCKET] Role: {}", env.role);
-                        println!("[WEBSOCKET] Reply To: {:?}", env.reply_to);
-                        println!("[WEBSOCKET] Content: {}", env.content);
-                    } else {
-                        println!("[WEBSOCKET] No messages received (timeout)");
-                    }
-                    opt_env
-                });
-            let elapsed = start.elapsed();
-            
-            println!("⏱️  Redis call took: {:?}", elapsed);
-            println!("📦 Received result: {:?}", result);
-            
-            match result {
-                Ok(Some(env)) => {
-                    println!("📩 Received message on stream: {}", cfg.inbox);
-                    println!("Message envelope: {:?}", env);
-                    println!("Envelope content: {:?}", env.content);
-                    
-                    backoff = 1;
-                    
-                    // Skip processing if this is a message we already processed
-                    // or a reply to our own message (to prevent loops)
-                    if env.envelope_type.as_deref() == Some("message_reply") {
-                        // Check if this is a reply to a message we sent
-                        if let Some(correlation_id) = &env.correlation_id {
-                            // If the correlation ID matches our message pattern, skip it
-                            if correlation_id.starts_with(&cfg.agent_name) {
-                                println!("🔄 Skipping message to prevent loop (correlation_id: {})", correlation_id);
-                                if let Some(id) = &env.envelope_id {
-                                    if let Err(e) = bus_arc.as_ref().ack_message(&cfg.inbox, group, id).await {
-                                        error!("❌ Failed to acknowledge looped message {}: {}", id, e);
-                                    }
-                                }
-                                continue;
-                            }
-                        }
-                    }
-                    
-                    // Allow both 'user' and 'agent' roles to be processed
-                    if env.role != "user" && env.role != "agent" && env.role != "assistant" {
-                        println!("-->>>Skipping message with unsupported role: {}", env.role);
-                        if let Some(id) = &env.envelope_id {
-                            if let Err(e) = bus_arc.as_ref().ack_message(&cfg.inbox, group, id).await {
-                                error!("❌ Failed to acknowledge skipped message {}: {}", id, e);
-                            }
-                        }
-                        continue;
-                    }
-                    println!("📝 Processing message from envelope");
-                    println!("📦 Envelope content type: {:?}", env.content); // Add content type logging
-                                        
-                    // Normalize the content to ensure it has a text field
-                    use serde_json::{json, Value};
-                    let normalized_content = match env.content {
-                        Value::String(s) => {
-                            println!("📝 Found string content: {}", s);
-                            json!({ "text": s })
-                        },
-                        Value::Object(mut map) => {
-                            let keys: Vec<_> = map.keys().collect();
-                            println!("📝 Found object content with keys: {:?}", keys);
-                            
-                            // If there's no text field, add one with the first string value or empty string
-                            if !map.contains_key("text") {
-                                let first_string = map.values().find(|v| v.is_string())
-                                    .and_then(|v| v.as_str())
-                                    .unwrap_or("");
-                                map.insert("text".to_string(), Value::String(first_string.to_string()));
-                            }
-                            
-                            Value::Object(map)
-                        },
-                        Value::Null => {
-                            println!("⚠️  Found null content, using empty text");
-                            json!({ "text": "" })
-                        },
-                        other => {
-                            println!("⚠️  Unknown content type, converting to text: {:?}", other);
-                            json!({ "text": other.to_string() })
-                        }
-                    };
-                    
-                    // Extract the text content for logging
-                    let text = normalized_content["text"]
-                        .as_str()
-                        .unwrap_or("")
-                        .to_string();
-                    println!("📝 Normalized text content: {}", text);
-                    
-                    if text.is_empty() {
-                        warn!("Received empty message content");
-                    }
-                    
-                    let sid = env.session_code.clone().unwrap_or_else(|| "default".into());
-                    let reply_to = env.reply_to.clone().unwrap_or_else(|| cfg.inbox.clone());
-                    
-                    println!("📋 Session ID: {}, Reply To: {}", sid, reply_to);
-                    
-                    let session_messages = {
-                        println!("🔒 Acquiring write lock on sessions");
-                        let mut sessions = state.sessions.write().await;
-                        println!("🔍 Looking up or creating session: {}", sid);
-                        let session = sessions
-                            .entry(sid.clone())
-                            .or_insert_with(|| {
-                                println!("➕ Created new session: {}", sid);
-                                Arc::new(RwLock::new(Vec::new()))
-                            })
-                            .clone();
-                        println!("🔓 Released sessions lock");
-                        session
-                    };
-                    
-                    println!("🔄 Processing message through agent");
-                    match process_bus_message(&state.agent, session_messages, text, &bus_arc).await {
-                        Ok(response) => {
-                            println!("✅ Successfully processed message");
-                            
-                            let reply_env = Envelope {
-                                role: "assistant".into(),
-                                content: serde_json::json!({ "text": response }),
-                                session_code: Some(sid),
-                                agent_name: Some(cfg.agent_name.clone()),
-                                usage: serde_json::json!({}),
-                                billing_hint: None,
-                                trace: vec![],
-                                user_id: None,
-                                task_id: None,
-                                target: None,
-                                reply_to: Some(reply_to.clone()),
-                                envelope_type: Some("message_reply".into()),
-                                tools_used: vec![],
-                                auth_signature: None,
-                                timestamp: Some(chrono::Utc::now().to_rfc3339()),
-                                headers: Default::default(),
-                                meta: serde_json::json!({}),
-                                envelope_id: env.correlation_id.clone(),
-                                correlation_id: env.correlation_id.clone(),
-                                consumer_group: None,
-                                consumer_id: None,
-                                delivery_count: None,
-                            };
-                            
-                            println!("📤 Sending response to: {}", reply_to);
-                            println!("Response envelope: {:?}", reply_env);
-                            match bus_arc.as_ref().send(&reply_to, &reply_env).await {
-                                Ok(_) => {
-                                    println!("✅ Successfully sent response to {}", reply_to);
-                                    // Acknowledge the message using bus_arc
-                                    if let Some(id) = &env.envelope_id {
-                                        if let Err(e) = bus_arc.as_ref().ack_message(&cfg.inbox, group, id).await {
-                                            error!("❌ Failed to acknowledge message {}: {}", id, e);
-                                        }
-                                    }
-                                },
-                                Err(e) => error!("❌ Failed to send response to {}: {}", reply_to, e),
-                            };
-                            if let Some(id) = &env.envelope_id {
-                                let _ = bus_arc.as_ref().ack_message(&cfg.inbox, group, id).await;
-                            }
-                        }
-                        Err(e) => {
-                            error!("bus message error: {}", e);
-                        }
-                    }
-                }
-                Ok(None) => {}
-                Err(e) => {
-                    error!("bus recv error: {}", e);
-                    break;
-                }
-            }
+
+        // Skip a reply to our own message (to prevent loops)
+        if env.kind() == Some(EnvelopeKind::MessageReply) && msg.correlation_id.starts_with(&self.agent_name) {
+            println!("🔄 Skipping message to prevent loop (correlation_id: {})", msg.correlation_id);
+            return Ok(None);
+        }
+        println!("📝 Normalized text content: {}", msg.text);
+        if msg.text.is_empty() {
+            warn!("Received empty message content");
         }
-        println!("bus listener reconnecting in {}s", backoff);
-        sleep(Duration::from_secs(backoff)).await;
-        backoff = (backoff * 2).min(30);
+
+        let sid = env.session_code.clone().unwrap_or_else(|| "default".into());
+        println!("📋 Session ID: {}, Reply To: {}", sid, msg.reply_to);
+        // Messages are answered concurrently, but one turn at a time per session
+        let session_lock = self.state.session_lock(&sid).await;
+        let _turn = session_lock.lock().await;
+
+        // Persisted like WebSocket sessions, so one evicted from memory reloads intact
+        let session_file = session::get_path(session::Identifier::Name(sid.clone()))
+            .map_err(|e| anyhow::anyhow!("invalid session ID {}: {}", sid, e))?;
+        println!("🔍 Looking up or loading session: {}", sid);
+        let session_messages = self.state.session_messages(&sid, &session_file).await;
+        seed_session(&sid, &session_messages, &env.meta).await?;
+
+        println!("🔄 Processing message through agent");
+        let budget = Budget::from_meta(&env.meta);
+        // Run as a task so a WebSocket `cancel` for the session can stop it too
+        let task = tokio::spawn({
+            let (agent, bus, text) = (self.state.agent.clone(), self.bus.clone(), msg.text.clone());
+            async move { process_bus_message(&agent, session_messages, session_file, text, budget, &bus).await }
+        });
+        let ticket = self.state.track_task(&sid, task.abort_handle()).await;
+        let joined = task.await;
+        self.state.untrack_task(&sid, ticket).await;
+        let turn = match joined.map_err(anyhow::Error::from).and_then(|turn| turn) {
+            Ok(turn) => turn,
+            Err(e) => {
+                // The runtime answers reply_to with an `error` envelope on the request's correlation id
+                println!("❌ Failed processing message: {:#}", e);
+                return Err(e);
+            }
+        };
+        println!("✅ Successfully processed message");
+
+        let (content, kind) = match turn.exceeded {
+            Some(limit) => {
+                println!("⛔ Turn stopped over budget ({}): {:?}", limit, turn.usage);
+                (budget_exceeded_content(limit, &turn.text), EnvelopeKind::Error)
+            }
+            None => (serde_json::json!({ "text": turn.text }), EnvelopeKind::MessageReply),
+        };
+        let mut reply = OutgoingReply::new(kind, content);
+        reply.session_code = Some(sid);
+        reply.usage = serde_json::json!(turn.usage);
+        Ok(Some(reply))
+    }
+
+    async fn active_sessions(&self) -> usize {
+        self.state.sessions.lock().await.entries.len()
+    }
+}
+
+/// Start a new bus session from `meta.seed_transcript`: its turns become the
+/// session's first messages, and are persisted to its session file along with
+/// the turn. A session that already has history ignores the seed.
+async fn seed_session(sid: &str, session_messages: &SessionMessages, meta: &serde_json::Value) -> Result<()> {
+    if meta.get("seed_transcript").is_none() {
+        return Ok(());
+    }
+    let mut msgs = session_messages.write().await;
+    if !msgs.is_empty() {
+        warn!(session_id = %sid, "Ignoring seed_transcript for a session that already has history");
+        return Ok(());
     }
+    let Some(seed) = seed_transcript(meta)? else {
+        return Ok(());
+    };
+    println!("🌱 Seeding session {} with {} earlier turns", sid, seed.len());
+    msgs.extend(seed.into_iter().map(|turn| match turn.role.as_str() {
+        "assistant" => GooseMessage::assistant().with_text(turn.text),
+        _ => GooseMessage::user().with_text(turn.text),
+    }));
+    Ok(())
+}
+
+/// What a bus turn produced and used.
+struct BusTurn {
+    /// The reply, or the text produced before the turn was stopped
+    text: String,
+    usage: TurnUsage,
+    /// The budget limit the turn was stopped on
+    exceeded: Option<&'static str>,
 }
 
+/// Run one bus message through the agent, stopping early once `budget` is used up.
 async fn process_bus_message(
     agent: &Agent,
     session_messages: Arc<RwLock<Vec<GooseMessage>>>,
+    session_file: std::path::PathBuf,
     content: String,
+    budget: Option<Budget>,
     bus: &std::sync::Arc<Bus>,
-) -> Result<String> {
+) -> Result<BusTurn> {
     use futures::StreamExt;
     use goose::agents::SessionConfig;
 
@@ -1027,10 +1652,12 @@ async fn process_bus_message(
         println!("📋 Cloning messages for processing");
         session_messages.read().await.clone() 
     };
+    let working_dir = Some(std::env::current_dir()?);
+    session::persist_messages(&session_file, &messages, None, working_dir.clone()).await?;
     
     println!("⚙️  Creating session configuration");
     let session_config = SessionConfig {
-        id: session::Identifier::Name("bus".into()),
+        id: session::Identifier::Path(session_file.clone()),
         working_dir: std::env::current_dir()?,
         schedule_id: None,
         execution_mode: None,
@@ -1053,8 +1680,25 @@ async fn process_bus_message(
     println!("📥 Processing agent response stream");
     let mut response = String::new();
     let mut message_count = 0;
-    
-    while let Some(item) = stream.next().await {
+    let started = std::time::Instant::now();
+    let budget_deadline = budget
+        .and_then(|b| b.max_duration_ms)
+        .map(|ms| tokio::time::Instant::now() + Duration::from_millis(ms));
+    let mut usage = TurnUsage::default();
+    let mut exceeded = None;
+
+    loop {
+        let item = match budget_deadline {
+            Some(deadline) => match tokio::time::timeout_at(deadline, stream.next()).await {
+                Ok(item) => item,
+                Err(_) => {
+                    exceeded = Some("max_duration_ms");
+                    break;
+                }
+            },
+            None => stream.next().await,
+        };
+        let Some(item) = item else { break };
         message_count += 1;
         match item {
             Ok(AgentEvent::Message(msg)) => {
@@ -1067,9 +1711,11 @@ async fn process_bus_message(
                         goose::message::MessageContent::Text(t) => {
                             println!("   {}. Text ({} chars): {}", i+1, t.text.len(), t.text);
                             response.push_str(&t.text);
+                            usage.add_output(&t.text);
                         },
                         goose::message::MessageContent::ToolRequest(tr) => {
                             println!("   {}. Tool Request: {} - {}", i+1, tr.id, serde_json::to_string(&tr.tool_call).unwrap_or_default());
+                            usage.tool_calls += 1;
                         },
                         goose::message::MessageContent::ToolResponse(tr) => {
                             println!("   {}. Tool Response: {} - {}", i+1, tr.id, serde_json::to_string(&tr.tool_result).unwrap_or_default());
@@ -1089,10 +1735,11 @@ async fn process_bus_message(
                                     "type": "thinking",
                                     "signature": thinking.signature
                                 }),
+                                content_type: None,
                                 agent_name: Some("goose".to_string()),
                                 target: Some("frontend".to_string()),
                                 reply_to: None,
-                                envelope_type: Some("thinking".to_string()),
+                                envelope_type: Some(EnvelopeKind::Thinking.into()),
                                 envelope_id: envelope_id,
                                 correlation_id: correlation_id,
                                 timestamp: Some(chrono::Utc::now().to_rfc3339()),
@@ -1102,6 +1749,7 @@ async fn process_bus_message(
                                 consumer_group: None,
                                 consumer_id: None,
                                 delivery_count: None,
+                                priority: None,
                                 // Required fields
                                 session_code: None,
                                 task_id: None,
@@ -1159,6 +1807,12 @@ async fn process_bus_message(
                 }
                 
                 println!("🔓 Released write lock");
+                drop(msgs);
+
+                if let Some(limit) = budget.and_then(|b| b.exceeded(&usage)) {
+                    exceeded = Some(limit);
+                    break;
+                }
             },
             Ok(event) => {
                 println!("ℹ️  Received agent event: {:?}", event);
@@ -1170,6 +1824,22 @@ async fn process_bus_message(
         }
     }
     
+    // Dropping the stream stops the agent; a tool request left without its
+    // response would be rejected on the session's next turn
+    drop(stream);
+    usage.duration_ms = started.elapsed().as_millis() as u64;
+    if exceeded.is_some() {
+        let mut msgs = session_messages.write().await;
+        let dangling = msgs.last().is_some_and(|m| {
+            m.content.iter().any(|c| matches!(c, goose::message::MessageContent::ToolRequest(_)))
+        });
+        if dangling {
+            msgs.pop();
+        }
+    }
+    let messages = session_messages.read().await.clone();
+    session::persist_messages(&session_file, &messages, None, working_dir).await?;
+
     println!("✅ Finished processing agent response ({} events, {} response chars)", 
           message_count, response.len());
     
@@ -1184,5 +1854,427 @@ async fn process_bus_message(
         println!("📝 Final response (first 100 chars): {}", truncated);
     }
     
-    Ok(response)
-}
\ No newline at end of file
+    Ok(BusTurn { text: response, usage, exceeded })
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use http::{Request, StatusCode};
+    use rmcp::model::Role;
+    use serde_json::{json, Value};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tower::ServiceExt;
+
+    /// Echoes the user's text back and records how many turns ran at once.
+    #[derive(Default)]
+    struct EchoTurns {
+        active: AtomicUsize,
+        max_active: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl TurnRunner for EchoTurns {
+        async fn run_turn(
+            &self,
+            session_messages: Arc<RwLock<Vec<GooseMessage>>>,
+            _session_file: std::path::PathBuf,
+            content: String,
+        ) -> Result<TurnOutcome> {
+            let active = self.active.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_active.fetch_max(active, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+
+            let response = format!("echo: {}", content);
+            {
+                let mut msgs = session_messages.write().await;
+                msgs.push(GooseMessage::user().with_text(content));
+                msgs.push(GooseMessage::assistant().with_text(response.clone()));
+            }
+
+            self.active.fetch_sub(1, Ordering::SeqCst);
+            Ok(TurnOutcome {
+                response,
+                tools_used: vec!["developer__shell".into()],
+                usage: json!({ "total_tokens": 3 }),
+            })
+        }
+    }
+
+    fn test_app(turns: Arc<EchoTurns>) -> Router {
+        let mut state = AppState::new(Arc::new(Agent::new()));
+        state.turns = turns;
+        build_router(state)
+    }
+
+    fn test_session_id() -> String {
+        format!("web-test-{}", uuid::Uuid::new_v4())
+    }
+
+    async fn call(app: &Router, req: Request<Body>) -> (StatusCode, Value) {
+        let resp = app.clone().oneshot(req).await.unwrap();
+        let status = resp.status();
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    fn post_message(session_id: &str, body: Value) -> Request<Body> {
+        Request::post(format!("/api/sessions/{}/messages", session_id))
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn inject_message_waits_for_reply() {
+        let app = test_app(Arc::new(EchoTurns::default()));
+        let sid = test_session_id();
+
+        let (status, body) = call(&app, post_message(&sid, json!({ "content": "hi", "wait": true }))).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["response"], "echo: hi");
+        assert_eq!(body["tools_used"], json!(["developer__shell"]));
+        assert_eq!(body["usage"]["total_tokens"], 3);
+        assert!(body["message_id"].is_string());
+    }
+
+    #[tokio::test]
+    async fn inject_message_without_wait_is_accepted_and_pollable() {
+        let app = test_app(Arc::new(EchoTurns::default()));
+        let sid = test_session_id();
+
+        let (status, body) = call(&app, post_message(&sid, json!({ "content": "later" }))).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert!(body["message_id"].is_string());
+
+        let mut messages = json!([]);
+        for _ in 0..50 {
+            let req = Request::get(format!("/api/sessions/{}/messages?after=1", sid))
+                .body(Body::empty())
+                .unwrap();
+            let (status, body) = call(&app, req).await;
+            assert_eq!(status, StatusCode::OK);
+            if body["next"] == 2 {
+                messages = body["messages"].clone();
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(messages.as_array().map(|m| m.len()), Some(1));
+        assert_eq!(messages[0]["role"], "assistant");
+    }
+
+    #[tokio::test]
+    async fn concurrent_turns_on_one_session_are_serialized() {
+        let turns = Arc::new(EchoTurns::default());
+        let app = test_app(turns.clone());
+        let sid = test_session_id();
+
+        let (a, b) = tokio::join!(
+            call(&app, post_message(&sid, json!({ "content": "one", "wait": true }))),
+            call(&app, post_message(&sid, json!({ "content": "two", "wait": true }))),
+        );
+
+        assert_eq!(a.0, StatusCode::OK);
+        assert_eq!(b.0, StatusCode::OK);
+        assert_eq!(turns.max_active.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn session_store_evicts_past_its_limits_and_reloads_from_disk() {
+        // Stands in for the JSONL files every turn is persisted to
+        let mut disk: std::collections::HashMap<String, Vec<GooseMessage>> = Default::default();
+        let limits = SessionLimits { max_sessions: 3, idle_ttl: Duration::from_secs(60) };
+        let mut store = SessionStore::new(limits);
+        for i in 0..10 {
+            let id = format!("s{}", i);
+            let messages = store.get_or_load(&id, || disk.get(&id).cloned().unwrap_or_default());
+            let mut msgs = messages.try_write().unwrap();
+            msgs.push(GooseMessage::user().with_text(format!("hello {}", i)));
+            disk.insert(id, msgs.clone());
+        }
+        assert_eq!(store.entries.len(), 3);
+        assert_eq!(store.evictions, 7);
+        assert!(store.get("s0").is_none());
+        assert!(store.get("s9").is_some());
+
+        // An evicted session comes back with its history, pushing out the least recently used
+        let s0 = store.get_or_load("s0", || disk["s0"].clone());
+        assert_eq!(s0.try_read().unwrap()[0].as_concat_text(), "hello 0");
+        let mut ids: Vec<_> = store.entries.keys().cloned().collect();
+        ids.sort();
+        assert_eq!(ids, ["s0", "s8", "s9"]);
+        assert_eq!(store.evictions, 8);
+
+        // Idle sessions go, except one a turn is still using
+        store.evict(std::time::Instant::now() + Duration::from_secs(61));
+        assert_eq!(store.entries.keys().collect::<Vec<_>>(), ["s0"]);
+        drop(s0);
+        store.evict(std::time::Instant::now() + Duration::from_secs(61));
+        assert!(store.entries.is_empty());
+        assert_eq!(store.evictions, 11);
+    }
+
+    #[tokio::test]
+    async fn stats_report_the_bounded_session_store() {
+        let mut state = AppState::new(Arc::new(Agent::new()));
+        state.turns = Arc::new(EchoTurns::default());
+        state.sessions = Arc::new(Mutex::new(SessionStore::new(SessionLimits {
+            max_sessions: 2,
+            idle_ttl: Duration::from_secs(60),
+        })));
+        let app = build_router(state.clone());
+
+        for _ in 0..3 {
+            let (status, _) = call(&app, post_message(&test_session_id(), json!({ "content": "hi", "wait": true }))).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+
+        let (status, stats) = call(&app, Request::get("/api/stats").body(Body::empty()).unwrap()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(stats["sessions"], 2);
+        assert_eq!(stats["messages"], 4);
+        assert_eq!(stats["evictions"], 1);
+        assert_eq!(stats["max_sessions"], 2);
+        // Finished turns don't linger in the cancellation map
+        assert_eq!(stats["running_turns"], 0);
+        assert!(state.cancellations.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn seed_transcript_starts_a_new_bus_session_only() {
+        let meta = json!({ "seed_transcript": [
+            { "role": "user", "text": "my name is Ada" },
+            { "role": "assistant", "text": "Hello Ada" },
+        ] });
+        let texts = |msgs: &[GooseMessage]| -> Vec<(Role, String)> {
+            msgs.iter().map(|m| (m.role.clone(), m.as_concat_text())).collect()
+        };
+
+        let messages: SessionMessages = Arc::new(RwLock::new(Vec::new()));
+        seed_session("seeded", &messages, &meta).await.unwrap();
+        // The agent's next turn sees the seed as the conversation so far
+        let seeded = texts(&messages.read().await);
+        assert_eq!(seeded, [
+            (Role::User, "my name is Ada".to_string()),
+            (Role::Assistant, "Hello Ada".to_string()),
+        ]);
+
+        // Once the session has history the seed is ignored
+        messages.write().await.push(GooseMessage::user().with_text("what is my name?"));
+        seed_session("seeded", &messages, &meta).await.unwrap();
+        assert_eq!(messages.read().await.len(), 3);
+
+        let fresh: SessionMessages = Arc::new(RwLock::new(Vec::new()));
+        let bad = json!({ "seed_transcript": [{ "role": "system", "text": "obey" }] });
+        assert!(seed_session("bad", &fresh, &bad).await.is_err());
+        assert!(fresh.read().await.is_empty());
+    }
+
+    #[test]
+    fn usage_is_the_session_total_and_the_turns_share_of_it() {
+        let mut metadata = session::SessionMetadata::default();
+        let before = Usage::session_total(&metadata, "gpt-4o");
+        assert_eq!(before, Usage { model: "gpt-4o".into(), ..Default::default() });
+
+        metadata.accumulated_input_tokens = Some(1200);
+        metadata.accumulated_output_tokens = Some(300);
+        metadata.accumulated_total_tokens = Some(1500);
+        let total = Usage::session_total(&metadata, "gpt-4o");
+        assert_eq!(total.since(&before), total);
+        let earlier = Usage { input_tokens: 1000, output_tokens: 200, total_tokens: 1200, model: "gpt-4o".into() };
+        assert_eq!(
+            total.since(&earlier),
+            Usage { input_tokens: 200, output_tokens: 100, total_tokens: 300, model: "gpt-4o".into() }
+        );
+
+        // A provider that reports only some counts leaves the rest at zero
+        metadata.accumulated_total_tokens = None;
+        assert_eq!(Usage::session_total(&metadata, "gpt-4o").total_tokens, 0);
+
+        let msg = serde_json::to_value(WebSocketMessage::Usage { turn: total.since(&earlier), session: total }).unwrap();
+        assert_eq!(msg["type"], "usage");
+        assert_eq!(msg["turn"]["output_tokens"], 100);
+        assert_eq!(msg["session"]["total_tokens"], 1500);
+    }
+
+    #[test]
+    fn socket_limiter_rejects_floods_and_excess_turns() {
+        let limits = SocketLimits { messages_per_sec: 2, burst: 3, max_in_flight: 2 };
+        let start = std::time::Instant::now();
+        let mut limiter = SocketLimiter::new(limits, start);
+
+        // A burst is admitted, the message after it is not
+        for _ in 0..3 {
+            limiter.admit(start).unwrap();
+        }
+        let err = limiter.admit(start).unwrap_err();
+        assert!(err.to_string().contains("rate limited"), "{err}");
+        // Tokens come back at messages_per_sec
+        limiter.admit(start + Duration::from_millis(500)).unwrap();
+        assert!(limiter.admit(start + Duration::from_millis(500)).is_err());
+        for _ in 0..3 {
+            limiter.admit(start + Duration::from_secs(60)).unwrap();
+        }
+        assert!(limiter.admit(start + Duration::from_secs(60)).is_err());
+
+        let first = limiter.start_turn().unwrap();
+        let _second = limiter.start_turn().unwrap();
+        let err = limiter.start_turn().unwrap_err();
+        assert!(err.to_string().contains("2 agent turns already running"), "{err}");
+        drop(first);
+        assert!(limiter.start_turn().is_ok());
+    }
+
+    /// A listener config whose Redis nothing answers on.
+    fn test_bus_config() -> BusConfig {
+        BusConfig {
+            redis_url: "redis://127.0.0.1:1".into(),
+            inbox: "AG1:agent:GooseWeb:inbox".into(),
+            agent_name: "GooseWeb".into(),
+            timeout_ms: 1000,
+            max_envelope_bytes: bus::DEFAULT_MAX_ENVELOPE_BYTES,
+            max_entry_bytes: bus::DEFAULT_MAX_ENTRY_BYTES,
+            concurrency: DEFAULT_BUS_CONCURRENCY,
+            description: None,
+            capabilities_keywords: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_bus_turns_are_answered_with_an_error() {
+        // No provider is configured, so the agent fails the turn
+        let state = AppState::new(Arc::new(Agent::new()));
+        let cfg = test_bus_config();
+        let bus = Bus::new(&cfg.redis_url).unwrap();
+        let runtime = BusAgentRuntime::new(bus.clone(), bus_runtime_config(&cfg, "tester".into()));
+        let handler = BusAgent { state, bus: Arc::new(bus), agent_name: cfg.agent_name.clone() };
+
+        let request = Envelope {
+            role: "user".into(),
+            content: json!({ "text": "hi" }),
+            reply_to: Some("AG1:agent:caller:inbox".into()),
+            correlation_id: Some("cid-failed-turn".into()),
+            session_code: Some(test_session_id()),
+            ..Default::default()
+        };
+        let bus_agent::Answer::Reply { reply_to, envelope: reply } = runtime.answer(&handler, request).await else {
+            panic!("failed turn went unanswered");
+        };
+        assert_eq!(reply_to, "AG1:agent:caller:inbox");
+        assert_eq!(reply.kind(), Some(EnvelopeKind::Error));
+        assert_eq!(reply.correlation_id.as_deref(), Some("cid-failed-turn"));
+        assert!(!reply.text_or_empty().is_empty());
+    }
+
+    #[tokio::test]
+    async fn pong_reports_the_session_store() {
+        let mut state = AppState::new(Arc::new(Agent::new()));
+        state.turns = Arc::new(EchoTurns::default());
+        let app = build_router(state.clone());
+        let (status, _) = call(&app, post_message(&test_session_id(), json!({ "content": "hi", "wait": true }))).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let cfg = test_bus_config();
+        let bus = Bus::new(&cfg.redis_url).unwrap();
+        let runtime = BusAgentRuntime::new(bus.clone(), bus_runtime_config(&cfg, "tester".into()));
+        let handler = BusAgent { state, bus: Arc::new(bus), agent_name: cfg.agent_name.clone() };
+
+        let ping = Envelope::ping("AG1:agent:pinger:inbox");
+        let bus_agent::Answer::Reply { reply_to, envelope: pong } = runtime.answer(&handler, ping.clone()).await else {
+            panic!("ping went unanswered");
+        };
+        assert_eq!(reply_to, "AG1:agent:pinger:inbox");
+        assert_eq!(pong.kind(), Some(EnvelopeKind::Pong));
+        assert_eq!(pong.correlation_id, ping.correlation_id);
+        let info = pong.pong_info().unwrap();
+        assert_eq!(info.agent_name, "GooseWeb");
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(info.active_sessions, 1);
+        // Nothing listening to read the lag from
+        assert_eq!(info.inbox_lag, None);
+    }
+
+    /// The `content` (else `message`) of each message sent to `rx` so far.
+    fn received(rx: &mut futures::channel::mpsc::UnboundedReceiver<Message>) -> Vec<String> {
+        let mut texts = Vec::new();
+        while let Ok(Some(Message::Text(text))) = rx.try_next() {
+            let msg: Value = serde_json::from_str(&text.to_string()).unwrap();
+            texts.push(msg["content"].as_str().or(msg["message"].as_str()).unwrap().to_string());
+        }
+        texts
+    }
+
+    #[tokio::test]
+    async fn a_resumed_turn_gets_what_it_produced_while_disconnected() {
+        let say = |text: &str| WebSocketMessage::Response { content: text.into(), role: "assistant".into(), timestamp: 0 };
+        let (first, mut first_rx) = futures::channel::mpsc::unbounded();
+        let output = RunOutput::new(Arc::new(Mutex::new(first)));
+        output.send(&say("one")).await;
+
+        // The browser drops mid-stream and the turn carries on
+        first_rx.close();
+        output.send(&say("two")).await;
+        assert!(!output.is_attached().await);
+        output.send(&say("three")).await;
+
+        let (second, mut second_rx) = futures::channel::mpsc::unbounded();
+        let second = Arc::new(Mutex::new(second));
+        output.attach(second.clone()).await;
+        output.send(&WebSocketMessage::Complete { message: "Response complete".into() }).await;
+        assert_eq!(received(&mut first_rx), ["one"]);
+        assert_eq!(received(&mut second_rx), ["two", "three", "Response complete"]);
+
+        // A socket that closes is detached, and the next one gets what it missed
+        output.detach(&second).await;
+        output.send(&say("four")).await;
+        assert!(received(&mut second_rx).is_empty());
+        let (third, mut third_rx) = futures::channel::mpsc::unbounded();
+        output.attach(Arc::new(Mutex::new(third))).await;
+        assert_eq!(received(&mut third_rx), ["four"]);
+    }
+
+    #[tokio::test]
+    async fn a_long_disconnect_keeps_the_latest_output_and_says_what_was_dropped() {
+        let say = |n: usize| WebSocketMessage::Response { content: n.to_string(), role: "assistant".into(), timestamp: 0 };
+        let (first, mut first_rx) = futures::channel::mpsc::unbounded();
+        let output = RunOutput::new(Arc::new(Mutex::new(first)));
+        first_rx.close();
+        for n in 0..MAX_BUFFERED_OUTPUT + 2 {
+            output.send(&say(n)).await;
+        }
+
+        let (second, mut second_rx) = futures::channel::mpsc::unbounded();
+        output.attach(Arc::new(Mutex::new(second))).await;
+        let got = received(&mut second_rx);
+        assert_eq!(got.len(), MAX_BUFFERED_OUTPUT + 1);
+        assert_eq!(got[0], "2 earlier messages of this turn were dropped while no client was connected");
+        assert_eq!(got[1], "2");
+        assert_eq!(got[MAX_BUFFERED_OUTPUT], (MAX_BUFFERED_OUTPUT + 1).to_string());
+    }
+
+    #[tokio::test]
+    async fn model_switching_needs_the_token_and_a_known_provider() {
+        let mut state = AppState::new(Arc::new(Agent::new()));
+        state.active_model = Arc::new(RwLock::new(("openai".into(), "gpt-4o".into())));
+        assert!(state.authorize(Some("secret")).is_err());
+
+        state.auth_token = Some("secret".into());
+        assert!(state.authorize(None).is_err());
+        for wrong in ["guess", "secreT", "secre", "secret2", ""] {
+            assert!(state.authorize(Some(wrong)).is_err(), "{wrong}");
+        }
+        assert!(state.authorize(Some("secret")).is_ok());
+
+        let err = state.set_model("no-such-provider", "m").await.unwrap_err();
+        assert!(err.to_string().contains("unknown provider"), "{err}");
+        match state.models_message().await {
+            WebSocketMessage::Models { provider, model, available } => {
+                assert_eq!((provider.as_str(), model.as_str()), ("openai", "gpt-4o"));
+                assert!(available.iter().any(|p| p["provider"] == "openai"));
+            }
+            _ => panic!("expected a models message"),
+        }
+    }
+}
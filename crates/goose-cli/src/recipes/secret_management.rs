@@ -1,6 +1,6 @@
 use goose::config::ExtensionConfig;
 use goose::recipe::{Recipe, RecipeParameterRequirement};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
 /// Represents a secret that needs to be collected from the user
 #[derive(Debug, Clone)]
@@ -80,6 +80,16 @@ fn get_extension_strings(config: &ExtensionConfig) -> Vec<String> {
     }
 }
 
+/// Helper function to get the environment variables an extension is configured with
+fn get_extension_envs(config: &ExtensionConfig) -> HashMap<String, String> {
+    match config {
+        ExtensionConfig::Sse { envs, .. }
+        | ExtensionConfig::Stdio { envs, .. }
+        | ExtensionConfig::StreamableHttp { envs, .. } => envs.get_env(),
+        ExtensionConfig::Builtin { .. } | ExtensionConfig::Frontend { .. } => HashMap::new(),
+    }
+}
+
 /// Discovers secrets in a recipe by analyzing its extensions and parameters
 pub fn discover_recipe_secrets(recipe: &Recipe) -> Vec<SecretRequirement> {
     let mut secrets = Vec::new();
@@ -123,6 +133,21 @@ pub fn discover_recipe_secrets(recipe: &Recipe) -> Vec<SecretRequirement> {
                     }
                 }
             }
+
+            // Check env var names, e.g. `envs: {OPENAI_API_KEY: ""}`
+            let mut env_keys: Vec<String> = get_extension_envs(ext).into_keys().collect();
+            env_keys.sort();
+            for key in env_keys.into_iter().filter(|key| is_secret_key(key)) {
+                let secret_key = format!("extension.{}.{}", ext_name, key);
+                if seen.insert(secret_key.clone()) {
+                    secrets.push(SecretRequirement {
+                        key: secret_key,
+                        extension_name: ext_name.to_string(),
+                        description: format!("Environment variable '{}' for {} extension", key, ext_name),
+                        required: true,
+                    });
+                }
+            }
         }
     }
 
@@ -144,3 +169,45 @@ pub fn discover_recipe_secrets(recipe: &Recipe) -> Vec<SecretRequirement> {
 
     secrets
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secret_env_vars_of_extensions_are_discovered() {
+        let recipe: Recipe = serde_json::from_value(serde_json::json!({
+            "version": "1.0.0",
+            "title": "Test Recipe",
+            "description": "A test recipe",
+            "instructions": "Test instructions",
+            "extensions": [
+                {
+                    "type": "stdio",
+                    "name": "search",
+                    "cmd": "uvx",
+                    "args": ["mcp-search"],
+                    "envs": { "OPENAI_API_KEY": "", "GITHUB_TOKEN": "", "LOG_LEVEL": "debug" },
+                    "timeout": 300
+                },
+                {
+                    "type": "sse",
+                    "name": "remote",
+                    "uri": "http://localhost:8080/sse",
+                    "envs": { "REMOTE_SECRET": "" }
+                }
+            ]
+        }))
+        .unwrap();
+
+        let keys: Vec<_> = discover_recipe_secrets(&recipe).into_iter().map(|s| s.key).collect();
+        assert_eq!(
+            keys,
+            [
+                "extension.search.GITHUB_TOKEN",
+                "extension.search.OPENAI_API_KEY",
+                "extension.remote.REMOTE_SECRET",
+            ]
+        );
+    }
+}